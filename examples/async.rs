@@ -5,6 +5,7 @@ use std::{
     time::Duration,
 };
 
+use image::DynamicImage;
 use ratatui::{
     backend::CrosstermBackend,
     crossterm::{
@@ -24,12 +25,14 @@ use ratatui_image::{
 };
 
 struct App {
+    picker: Picker,
+    image: DynamicImage,
     async_state: ThreadProtocol,
 }
 
 enum AppEvent {
     KeyEvent(KeyEvent),
-    Redraw(StatefulProtocol),
+    Redraw(Box<StatefulProtocol>),
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -40,7 +43,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let backend = CrosstermBackend::new(stdout);
     let mut terminal = Terminal::new(backend)?;
 
-    let picker = Picker::from_query_stdio()?;
+    let picker = Picker::from_query_stdio_in_raw_mode()?;
     let dyn_img = image::io::Reader::open("./assets/Ada.png")?.decode()?;
 
     // Send a [ResizeProtocol] to resize and encode it in a separate thread.
@@ -54,7 +57,9 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     thread::spawn(move || loop {
         if let Ok((mut protocol, resize, area)) = rec_worker.recv() {
             protocol.resize_encode(&resize, protocol.background_color(), area);
-            tx_main_render.send(AppEvent::Redraw(protocol)).unwrap();
+            tx_main_render
+                .send(AppEvent::Redraw(Box::new(protocol)))
+                .unwrap();
         }
     });
 
@@ -74,7 +79,9 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     });
 
     let mut app = App {
-        async_state: ThreadProtocol::new(tx_worker, picker.new_resize_protocol(dyn_img)),
+        async_state: ThreadProtocol::new(tx_worker, picker.new_resize_protocol(dyn_img.clone())),
+        picker,
+        image: dyn_img,
     };
 
     loop {
@@ -84,13 +91,21 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             match ev {
                 AppEvent::KeyEvent(key) => {
                     if key.kind == KeyEventKind::Press {
-                        if key.code == KeyCode::Char('q') {
-                            break;
+                        match key.code {
+                            KeyCode::Char('q') => break,
+                            // Cycle protocols, showing the current one until the next is ready.
+                            KeyCode::Char('i') => {
+                                let next = app.picker.protocol_type().next();
+                                app.picker.set_protocol_type(next);
+                                let protocol = app.picker.new_resize_protocol(app.image.clone());
+                                app.async_state.switch_protocol(protocol);
+                            }
+                            _ => {}
                         }
                     }
                 }
                 AppEvent::Redraw(protocol) => {
-                    app.async_state.set_protocol(protocol);
+                    app.async_state.set_protocol(*protocol);
                 }
             }
         }
@@ -154,28 +154,20 @@ impl App {
                 self.image_source_path = path.into();
                 self.reset_images();
             }
-            'H' => {
-                if self.split_percent >= 10 {
-                    self.split_percent -= 10;
-                }
+            'H' if self.split_percent >= 10 => {
+                self.split_percent -= 10;
             }
-            'L' => {
-                if self.split_percent <= 90 {
-                    self.split_percent += 10;
-                }
+            'L' if self.split_percent <= 90 => {
+                self.split_percent += 10;
             }
-            'h' => {
-                if self.image_static_offset.0 > 0 {
-                    self.image_static_offset.0 -= 1;
-                }
+            'h' if self.image_static_offset.0 > 0 => {
+                self.image_static_offset.0 -= 1;
             }
             'j' => {
                 self.image_static_offset.1 += 1;
             }
-            'k' => {
-                if self.image_static_offset.1 > 0 {
-                    self.image_static_offset.1 -= 1;
-                }
+            'k' if self.image_static_offset.1 > 0 => {
+                self.image_static_offset.1 -= 1;
             }
             'l' => {
                 self.image_static_offset.0 += 1;
@@ -198,9 +190,13 @@ impl App {
 
     fn render_resized_image(&mut self, f: &mut Frame<'_>, resize: Resize, area: Rect) {
         let (state, name, color) = match resize {
-            Resize::Fit(_) => (&mut self.image_fit_state, "Fit", Color::Magenta),
+            Resize::Fit(_) | Resize::PixelPerfect | Resize::FitInteger => {
+                (&mut self.image_fit_state, "Fit", Color::Magenta)
+            }
             Resize::Crop(_) => (&mut self.image_crop_state, "Crop", Color::Green),
-            Resize::Scale(_) => (&mut self.image_scale_state, "Scale", Color::Blue),
+            Resize::Scale(_) | Resize::Fill(_) | Resize::Stretch(_) | Resize::Viewport(_) => {
+                (&mut self.image_scale_state, "Scale", Color::Blue)
+            }
         };
         let block = block(name);
         let inner_area = block.inner(area);
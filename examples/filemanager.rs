@@ -0,0 +1,336 @@
+//! An image file manager: a list of the images in a directory, a grid of thumbnails, and a
+//! zoomable detail view.
+//!
+//! `cargo run --example filemanager --features crossterm -- [directory]`
+//!
+//! Keys: arrows or hjkl to select, enter to open, esc to close, `p` to cycle protocols, `q` to
+//! quit. In the detail view: `+`/`-` to zoom, arrows or hjkl to pan.
+//!
+//! Thumbnails are decoded and encoded on a worker thread, and the full images of the detail view
+//! are decoded on another one. The detail view still encodes the visible tiles on the UI thread.
+use std::{
+    collections::HashMap,
+    env, fs,
+    io::{self, Write},
+    path::PathBuf,
+    sync::mpsc::{self, Receiver, Sender},
+    thread,
+    time::Duration,
+};
+
+use image::DynamicImage;
+use ratatui::{
+    backend::CrosstermBackend,
+    crossterm::{
+        event::{self, Event, KeyCode, KeyEvent, KeyEventKind},
+        execute,
+        terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
+    },
+    layout::{Constraint, Layout, Rect},
+    style::{Modifier, Style},
+    widgets::{Block, Borders, List, ListItem, ListState, Paragraph},
+    Frame, Terminal,
+};
+use ratatui_image::{
+    cleanup::{self, CleanupGuard},
+    grid::{ImageGrid, VirtualGrid},
+    picker::Picker,
+    protocol::{kitty::KittyOptions, StatefulProtocol},
+    zoom::{ImagePyramid, ZoomPanImage, ZoomPanState},
+    Resize, ResizeEncodeRender,
+};
+
+const THUMBNAIL_CELLS: (u16, u16) = (16, 8);
+const THUMBNAIL_PIXELS: u32 = 256;
+const EXTENSIONS: &[&str] = &["png", "jpg", "jpeg", "gif", "bmp", "webp", "tiff"];
+
+enum AppEvent {
+    Key(KeyEvent),
+    Resize,
+    // The generation of the picker, the index, and the encoded thumbnail.
+    Thumbnail(usize, usize, Option<Box<StatefulProtocol>>),
+    Detail(usize, Box<ImagePyramid>, (u32, u32)),
+}
+
+// The generation of the picker, the index and path of the file, and the picker to encode with.
+type ThumbnailRequest = (usize, usize, PathBuf, Picker);
+
+struct Detail {
+    index: usize,
+    // None until the worker has built the pyramid.
+    state: Option<(ZoomPanState, (u32, u32))>,
+}
+
+struct App {
+    picker: Picker,
+    files: Vec<PathBuf>,
+    list: ListState,
+    grid: VirtualGrid,
+    // Scroll offset of the grid, in rows of cells.
+    scroll: usize,
+    // Encoded thumbnails until the grid takes them, None while encoding or if the file could not
+    // be decoded.
+    thumbnails: HashMap<usize, Option<StatefulProtocol>>,
+    // Bumped when the picker changes, to drop the thumbnails that are still encoded for the old one.
+    generation: usize,
+    detail: Option<Detail>,
+    tx_thumbnails: Sender<ThumbnailRequest>,
+    tx_details: Sender<(usize, PathBuf, Picker)>,
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = env::args().nth(1).unwrap_or_else(|| "./assets".to_string());
+    let mut files: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.extension()
+                .and_then(|ext| ext.to_str())
+                .is_some_and(|ext| EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+        })
+        .collect();
+    files.sort();
+
+    // Delete the Kitty images of the app from the terminal's memory, even after a panic.
+    cleanup::install_panic_hook();
+    let _guard = CleanupGuard::new();
+
+    enable_raw_mode()?;
+    let mut stdout = io::stdout();
+    execute!(stdout, EnterAlternateScreen)?;
+    let mut terminal = Terminal::new(CrosstermBackend::new(stdout))?;
+
+    let mut picker = Picker::from_query_stdio_in_raw_mode()?;
+    // Free the terminal memory of thumbnails that scroll out of the prefetch range.
+    picker.set_kitty_options(KittyOptions::new().delete_on_drop(true));
+
+    let (tx_main, rec_main) = mpsc::channel();
+    let tx_thumbnails = spawn_thumbnail_worker(tx_main.clone());
+    let tx_details = spawn_detail_worker(tx_main.clone());
+    thread::spawn(move || -> io::Result<()> {
+        loop {
+            if event::poll(Duration::from_millis(250))? {
+                let event = match event::read()? {
+                    Event::Key(key) => AppEvent::Key(key),
+                    Event::Resize(..) => AppEvent::Resize,
+                    _ => continue,
+                };
+                if tx_main.send(event).is_err() {
+                    return Ok(());
+                }
+            }
+        }
+    });
+
+    let mut app = App {
+        grid: VirtualGrid::new(picker.clone(), THUMBNAIL_CELLS).prefetch_rows(2),
+        picker,
+        files,
+        list: ListState::default().with_selected(Some(0)),
+        scroll: 0,
+        thumbnails: HashMap::new(),
+        generation: 0,
+        detail: None,
+        tx_thumbnails,
+        tx_details,
+    };
+
+    loop {
+        terminal.draw(|f| ui(f, &mut app))?;
+        match rec_main.recv()? {
+            AppEvent::Key(key) if key.kind == KeyEventKind::Press => {
+                if !on_key(&mut app, key.code) {
+                    break;
+                }
+            }
+            AppEvent::Key(_) | AppEvent::Resize => {}
+            AppEvent::Thumbnail(generation, index, protocol) => {
+                if generation == app.generation {
+                    app.thumbnails
+                        .insert(index, protocol.map(|protocol| *protocol));
+                }
+            }
+            AppEvent::Detail(index, pyramid, size) => {
+                if let Some(detail) = app.detail.as_mut().filter(|detail| detail.index == index) {
+                    detail.state = Some((ZoomPanState::from_pyramid(*pyramid), size));
+                }
+            }
+        }
+        // Protocols that were dropped since the last frame, e.g. the closed detail view.
+        let dropped = cleanup::take_dropped_sequence();
+        if !dropped.is_empty() {
+            terminal.backend_mut().write_all(dropped.as_bytes())?;
+            terminal.backend_mut().flush()?;
+        }
+    }
+
+    disable_raw_mode()?;
+    execute!(terminal.backend_mut(), LeaveAlternateScreen)?;
+    terminal.show_cursor()?;
+    Ok(())
+}
+
+// Decode, downscale and encode thumbnails in the background, in the order they are requested.
+// The decoded thumbnails are kept, so that they are only encoded again when they scroll back into
+// the prefetch range, or when the protocol changes.
+fn spawn_thumbnail_worker(tx_main: Sender<AppEvent>) -> Sender<ThumbnailRequest> {
+    let (tx, rec): (_, Receiver<ThumbnailRequest>) = mpsc::channel();
+    thread::spawn(move || {
+        let mut decoded: HashMap<PathBuf, Option<DynamicImage>> = HashMap::new();
+        let area = Rect::new(0, 0, THUMBNAIL_CELLS.0, THUMBNAIL_CELLS.1);
+        let resize = Resize::Fit(None);
+        for (generation, index, path, picker) in rec {
+            let thumbnail = decoded.entry(path).or_insert_with_key(|path| {
+                image::open(path)
+                    .ok()
+                    .map(|image| image.thumbnail(THUMBNAIL_PIXELS, THUMBNAIL_PIXELS))
+            });
+            let protocol = thumbnail.clone().map(|image| {
+                let mut protocol = picker.new_resize_protocol(image);
+                if let Some(rect) = protocol.needs_resize(&resize, area) {
+                    ResizeEncodeRender::resize_encode(&mut protocol, &resize, rect);
+                }
+                Box::new(protocol)
+            });
+            let event = AppEvent::Thumbnail(generation, index, protocol);
+            if tx_main.send(event).is_err() {
+                return;
+            }
+        }
+    });
+    tx
+}
+
+// Decode the full image and build its pyramid in the background, which is slow for large images.
+fn spawn_detail_worker(tx_main: Sender<AppEvent>) -> Sender<(usize, PathBuf, Picker)> {
+    let (tx, rec): (_, Receiver<(usize, PathBuf, Picker)>) = mpsc::channel();
+    thread::spawn(move || {
+        for (index, path, picker) in rec {
+            let Ok(image) = image::open(path) else {
+                continue;
+            };
+            let size = (image.width(), image.height());
+            let pyramid = Box::new(ImagePyramid::new(&picker, image, 512));
+            if tx_main
+                .send(AppEvent::Detail(index, pyramid, size))
+                .is_err()
+            {
+                return;
+            }
+        }
+    });
+    tx
+}
+
+// Returns false to quit.
+fn on_key(app: &mut App, code: KeyCode) -> bool {
+    if let Some((state, size)) = app.detail.as_mut().and_then(|detail| detail.state.as_mut()) {
+        let viewport = state.viewport_mut();
+        let step = (16.0 / viewport.zoom) as i64;
+        let center = (size.0 / 2, size.1 / 2);
+        match code {
+            KeyCode::Char('+') => viewport.zoom_around(viewport.zoom * 1.5, center),
+            KeyCode::Char('-') => viewport.zoom_around(viewport.zoom / 1.5, center),
+            KeyCode::Left | KeyCode::Char('h') => viewport.pan(-step, 0),
+            KeyCode::Right | KeyCode::Char('l') => viewport.pan(step, 0),
+            KeyCode::Up | KeyCode::Char('k') => viewport.pan(0, -step),
+            KeyCode::Down | KeyCode::Char('j') => viewport.pan(0, step),
+            _ => {}
+        }
+    }
+    let selected = app.list.selected().unwrap_or(0);
+    match code {
+        KeyCode::Char('q') => return false,
+        KeyCode::Esc => app.detail = None,
+        KeyCode::Char('p') => {
+            let next = app.picker.protocol_type().next();
+            app.picker.set_protocol_type(next);
+            // The worker keeps the decoded thumbnails, and only encodes them again.
+            app.grid = VirtualGrid::new(app.picker.clone(), THUMBNAIL_CELLS).prefetch_rows(2);
+            app.thumbnails.clear();
+            app.generation += 1;
+            if let Some(index) = app.detail.as_ref().map(|detail| detail.index) {
+                open_detail(app, index);
+            }
+        }
+        _ if app.detail.is_some() => {}
+        KeyCode::Enter if !app.files.is_empty() => open_detail(app, selected),
+        KeyCode::Up | KeyCode::Char('k') => app.list.select(Some(selected.saturating_sub(1))),
+        KeyCode::Down | KeyCode::Char('j') => {
+            let last = app.files.len().saturating_sub(1);
+            app.list.select(Some((selected + 1).min(last)));
+        }
+        _ => {}
+    }
+    true
+}
+
+fn open_detail(app: &mut App, index: usize) {
+    app.detail = Some(Detail { index, state: None });
+    let _ = app
+        .tx_details
+        .send((index, app.files[index].clone(), app.picker.clone()));
+}
+
+fn ui(f: &mut Frame<'_>, app: &mut App) {
+    let [list_area, main_area] =
+        Layout::horizontal([Constraint::Length(32), Constraint::Min(0)]).areas(f.area());
+
+    let items: Vec<ListItem> = app
+        .files
+        .iter()
+        .map(|path| ListItem::new(path.file_name().unwrap_or_default().to_string_lossy()))
+        .collect();
+    let title = format!("Files ({:?})", app.picker.protocol_type());
+    let list = List::new(items)
+        .block(Block::default().borders(Borders::ALL).title(title))
+        .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+    f.render_stateful_widget(list, list_area, &mut app.list);
+
+    let block = Block::default().borders(Borders::ALL);
+    let inner = block.inner(main_area);
+    match &mut app.detail {
+        Some(detail) => {
+            let title = app.files[detail.index].display().to_string();
+            f.render_widget(block.title(title), main_area);
+            match &mut detail.state {
+                Some((state, _)) => f.render_stateful_widget(ZoomPanImage, inner, state),
+                None => f.render_widget(Paragraph::new("Loading..."), inner),
+            }
+        }
+        None => {
+            f.render_widget(block.title("Thumbnails"), main_area);
+            render_grid(f, app, inner);
+        }
+    }
+}
+
+fn render_grid(f: &mut Frame<'_>, app: &mut App, area: Rect) {
+    // Keep the selected item in view.
+    let columns = app.grid.columns(area);
+    let row_height = THUMBNAIL_CELLS.1 as usize;
+    let top = app.list.selected().unwrap_or(0) / columns * row_height;
+    if top < app.scroll {
+        app.scroll = top;
+    } else if top + row_height > app.scroll + area.height as usize {
+        app.scroll = (top + row_height).saturating_sub(area.height as usize);
+    }
+
+    // Request thumbnails that are not encoded yet, the grid takes them on a later frame. Once
+    // taken, they are requested again if the grid evicts them and they come back into range.
+    let (files, thumbnails, tx) = (&app.files, &mut app.thumbnails, &app.tx_thumbnails);
+    let (generation, picker) = (app.generation, &app.picker);
+    app.grid
+        .update_protocols(files.len(), app.scroll, area, |index| {
+            match thumbnails.remove(&index) {
+                Some(Some(protocol)) => return Some(protocol),
+                Some(None) => {}
+                None => {
+                    let request = (generation, index, files[index].clone(), picker.clone());
+                    let _ = tx.send(request);
+                }
+            }
+            thumbnails.insert(index, None);
+            None
+        });
+    f.render_stateful_widget(ImageGrid, area, &mut app.grid);
+}
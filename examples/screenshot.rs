@@ -54,22 +54,24 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     terminal.draw(|f| ui(f, &mut app))?;
     std::thread::sleep(std::time::Duration::from_secs(1)); // let the terminal actually draw.
-    let xwd = Command::new("xwd")
+    let mut xwd = Command::new("xwd")
         .args(["-root", "-silent"])
         .stdout(Stdio::piped())
         .spawn()
         .expect("Failed to start xwd command");
     let screenshot_term = env::var("SCREENSHOT_TERM_NAME").unwrap_or("unknown".to_string());
-    std::process::Command::new("convert")
+    let convert = std::process::Command::new("convert")
         .args([
             "xwd:-",
             &format!("png:./target/screenshot_{screenshot_term}.png"),
         ])
-        .stdin(xwd.stdout.expect("failed to get stdout"))
+        .stdin(xwd.stdout.take().expect("failed to get stdout"))
         .stdout(std::process::Stdio::null())
         .stderr(std::process::Stdio::null())
         .spawn()
-        .and_then(|mut child| child.wait())?;
+        .and_then(|mut child| child.wait());
+    xwd.wait()?;
+    convert?;
 
     // restore terminal
     disable_raw_mode()?;
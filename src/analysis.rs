@@ -0,0 +1,102 @@
+//! Color analysis of images, e.g. to color borders or accents after the image that is shown.
+//!
+//! See [crate::protocol::StatefulProtocol::analysis].
+
+use image::{imageops::FilterType, DynamicImage, Rgb};
+
+use crate::picker::luminance;
+
+/// Side of the thumbnail that is analyzed instead of the full image.
+const THUMBNAIL_SIZE: u32 = 64;
+/// How many dominant colors are kept.
+const DOMINANT_COLORS: usize = 5;
+
+/// Dominant colors and luminance of an image. Transparent pixels are ignored.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ImageAnalysis {
+    /// The most common colors, most common first, with their share of the opaque pixels.
+    pub dominant_colors: Vec<(Rgb<u8>, f32)>,
+    /// The average color of the opaque pixels.
+    pub average_color: Rgb<u8>,
+    /// The average relative luminance of the opaque pixels, in `0.0..=1.0`.
+    pub average_luminance: f32,
+}
+
+impl ImageAnalysis {
+    pub fn new(image: &DynamicImage) -> ImageAnalysis {
+        let thumbnail = image
+            .resize(THUMBNAIL_SIZE, THUMBNAIL_SIZE, FilterType::Nearest)
+            .to_rgba8();
+
+        // Buckets of 4 bits per channel, summing up the actual colors to average them later.
+        let mut buckets = vec![(0u32, [0u32; 3]); 1 << 12];
+        let (mut count, mut sum, mut luminance_sum) = (0u32, [0u32; 3], 0.0);
+        for pixel in thumbnail.pixels().filter(|pixel| pixel[3] >= 128) {
+            let [r, g, b, _] = pixel.0;
+            let index = ((r as usize >> 4) << 8) | ((g as usize >> 4) << 4) | (b as usize >> 4);
+            let bucket = &mut buckets[index];
+            bucket.0 += 1;
+            for (i, c) in [r, g, b].into_iter().enumerate() {
+                bucket.1[i] += c as u32;
+                sum[i] += c as u32;
+            }
+            count += 1;
+            luminance_sum += luminance(Rgb([r, g, b]));
+        }
+        if count == 0 {
+            return ImageAnalysis {
+                dominant_colors: vec![],
+                average_color: Rgb([0, 0, 0]),
+                average_luminance: 0.0,
+            };
+        }
+
+        let mut buckets: Vec<_> = buckets.into_iter().filter(|(n, _)| *n > 0).collect();
+        buckets.sort_by_key(|(n, _)| std::cmp::Reverse(*n));
+        let dominant_colors = buckets
+            .into_iter()
+            .take(DOMINANT_COLORS)
+            .map(|(n, sum)| (Rgb(sum.map(|c| (c / n) as u8)), n as f32 / count as f32))
+            .collect();
+
+        ImageAnalysis {
+            dominant_colors,
+            average_color: Rgb(sum.map(|c| (c / count) as u8)),
+            average_luminance: luminance_sum / count as f32,
+        }
+    }
+
+    /// The most common color, if there are any opaque pixels.
+    pub fn dominant_color(&self) -> Option<Rgb<u8>> {
+        self.dominant_colors.first().map(|(color, _)| *color)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, ImageBuffer, Rgb, Rgba};
+
+    use super::ImageAnalysis;
+
+    #[test]
+    fn test_analysis() {
+        // Three quarters red, one quarter white, and a transparent column that is ignored.
+        let image: DynamicImage = ImageBuffer::from_fn(41, 40, |x, y| match (x, y) {
+            (40, _) => Rgba::<u8>([0, 0, 255, 0]),
+            (20.., 20..) => Rgba([255, 255, 255, 255]),
+            _ => Rgba([255, 0, 0, 255]),
+        })
+        .into();
+        let analysis = ImageAnalysis::new(&image);
+        assert_eq!(analysis.dominant_color(), Some(Rgb([255, 0, 0])));
+        assert_eq!(analysis.dominant_colors.len(), 2);
+        assert!((analysis.dominant_colors[0].1 - 0.75).abs() < 0.01);
+        assert_eq!(analysis.dominant_colors[1].0, Rgb([255, 255, 255]));
+        assert_eq!(analysis.average_color, Rgb([255, 63, 63]));
+        assert!(analysis.average_luminance > 0.4 && analysis.average_luminance < 0.5);
+
+        let transparent: DynamicImage =
+            ImageBuffer::from_pixel(4, 4, Rgba::<u8>([255, 255, 255, 0])).into();
+        assert_eq!(ImageAnalysis::new(&transparent).dominant_color(), None);
+    }
+}
@@ -0,0 +1,124 @@
+//! Per-frame audit log of what the image widgets did, for debugging artifacts.
+//!
+//! Chasing overdraw or leftover graphics by looking at the terminal is guesswork. When enabled,
+//! every image render is recorded with the cells it claimed, the bytes of escape sequences it put
+//! into the buffer, or why it rendered nothing:
+//!
+//! ```rust
+//! # use ratatui::{backend::TestBackend, Terminal};
+//! # let mut terminal = Terminal::new(TestBackend::new(10, 10)).unwrap();
+//! ratatui_image::audit::enable(true);
+//! terminal.draw(|f| { /* render images */ }).unwrap();
+//! for record in ratatui_image::audit::take() {
+//!     eprintln!("{record:?}");
+//! }
+//! ```
+//!
+//! Note that ratatui only writes the cells that changed since the previous frame, so a payload
+//! in the buffer is not necessarily written to the terminal again.
+
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Mutex,
+};
+
+use ratatui::{buffer::Buffer, layout::Rect};
+
+use crate::picker::ProtocolType;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static RECORDS: Mutex<Vec<AuditRecord>> = Mutex::new(Vec::new());
+
+/// What one render of an image did.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AuditRecord {
+    pub protocol: ProtocolType,
+    /// The area that the widget was rendered into.
+    pub area: Rect,
+    /// The cells covered by the image or its placeholder, empty if skipped.
+    pub claimed: Rect,
+    /// Bytes of escape sequences in the claimed cells, e.g. sixel data or a Kitty transmission.
+    pub payload_bytes: usize,
+    /// Whether this was an additional placement, see [crate::PlacementView].
+    pub placement: bool,
+    pub skipped: Option<SkipReason>,
+}
+
+/// Why an image render produced nothing, or only a placeholder.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SkipReason {
+    /// The area has no cells inside the buffer.
+    EmptyArea,
+    /// The stateful protocol has not been resized and encoded for any area yet.
+    NotEncoded,
+    /// A fixed sixel or iTerm2 image is larger than the area, and would be cut off.
+    DoesNotFit,
+    /// The image is redacted, and a hatched placeholder was rendered instead.
+    Redacted,
+}
+
+/// Start or stop recording. Nothing is recorded before this has been called.
+pub fn enable(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+    if !enabled {
+        take();
+    }
+}
+
+/// Take the records since the last call, typically right after `terminal.draw()`.
+pub fn take() -> Vec<AuditRecord> {
+    match RECORDS.lock() {
+        Ok(mut records) => std::mem::take(&mut *records),
+        Err(poisoned) => std::mem::take(&mut *poisoned.into_inner()),
+    }
+}
+
+pub(crate) fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+// Record a render that already happened. `image_area` is the size of the encoding.
+pub(crate) fn record(
+    protocol: ProtocolType,
+    area: Rect,
+    image_area: Rect,
+    buf: &Buffer,
+    placement: bool,
+    skipped: Option<SkipReason>,
+) {
+    let claimed = Rect::new(area.x, area.y, image_area.width, image_area.height)
+        .intersection(area)
+        .intersection(buf.area);
+    let skipped = skipped.or(if area.intersection(buf.area).is_empty() {
+        Some(SkipReason::EmptyArea)
+    } else if image_area.is_empty() {
+        Some(SkipReason::NotEncoded)
+    } else {
+        None
+    });
+    let claimed = match skipped {
+        Some(SkipReason::Redacted) | None => claimed,
+        Some(_) => Rect::new(area.x, area.y, 0, 0),
+    };
+    let mut payload_bytes = 0;
+    for y in claimed.top()..claimed.bottom() {
+        for x in claimed.left()..claimed.right() {
+            let symbol = buf[(x, y)].symbol();
+            if symbol.contains('\x1b') {
+                payload_bytes += symbol.len();
+            }
+        }
+    }
+    let record = AuditRecord {
+        protocol,
+        area,
+        claimed,
+        payload_bytes,
+        placement,
+        skipped,
+    };
+    match RECORDS.lock() {
+        Ok(mut records) => records.push(record),
+        Err(poisoned) => poisoned.into_inner().push(record),
+    }
+}
@@ -0,0 +1,204 @@
+//! Images behind text, like a watermark.
+//!
+//! The Kitty graphics protocol can layer an image below the text with a negative z-index. The
+//! image is placed directly at the cursor instead of with unicode placeholders, so the cells
+//! stay free for regular text. Other protocols cannot layer images, and render nothing.
+//!
+//! ```rust
+//! # use ratatui::{widgets::Paragraph, Frame};
+//! # use ratatui_image::background::{BackgroundImage, BackgroundProtocol};
+//! fn ui(f: &mut Frame<'_>, background: &mut BackgroundProtocol) {
+//!     f.render_widget(Paragraph::new("Text over the image"), f.area());
+//!     // After the widgets on top, see [BackgroundImage].
+//!     f.render_stateful_widget(BackgroundImage, f.area(), background);
+//! }
+//! ```
+
+use image::{imageops::FilterType, DynamicImage, Rgb};
+use ratatui::{buffer::Buffer, layout::Rect, widgets::StatefulWidget};
+
+use crate::{
+    analysis::ImageAnalysis,
+    picker::{cap_parser::Parser, luminance},
+    protocol::kitty::{transmit, KittyOptions, KittyProtoState},
+    FontSize,
+};
+
+// Below the text and below cells with a non-default background color.
+const Z_INDEX: i32 = -1_073_741_825;
+
+/// State of a [BackgroundImage], see [crate::picker::Picker::new_background_protocol].
+#[derive(Clone)]
+pub struct BackgroundProtocol {
+    image: DynamicImage,
+    // None if the protocol cannot layer images.
+    kitty: Option<KittyBackground>,
+    font_size: FontSize,
+    background: Rgb<u8>,
+    opacity: Option<f32>,
+    // The area of the current placement.
+    placed: Option<Rect>,
+    transmission: KittyProtoState,
+}
+
+#[derive(Clone)]
+struct KittyBackground {
+    id: u32,
+    is_tmux: bool,
+    options: KittyOptions,
+}
+
+impl BackgroundProtocol {
+    pub(crate) fn new(
+        image: DynamicImage,
+        kitty: Option<(u32, bool, KittyOptions)>,
+        font_size: FontSize,
+        background: Rgb<u8>,
+    ) -> BackgroundProtocol {
+        BackgroundProtocol {
+            image,
+            kitty: kitty.map(|(id, is_tmux, options)| KittyBackground {
+                id,
+                is_tmux,
+                options,
+            }),
+            font_size,
+            background,
+            opacity: None,
+            placed: None,
+            transmission: KittyProtoState::Place,
+        }
+    }
+
+    /// Whether the protocol can layer the image behind text. Otherwise nothing is rendered.
+    pub fn is_supported(&self) -> bool {
+        self.kitty.is_some()
+    }
+
+    /// Blend the image into the terminal background by `opacity` (`0.0..=1.0`), or pick it
+    /// automatically with `None` (the default), so that the image stays close enough to the
+    /// background for the text to be readable.
+    pub fn set_opacity(&mut self, opacity: Option<f32>) {
+        self.opacity = opacity.map(|opacity| opacity.clamp(0.0, 1.0));
+        self.placed = None;
+    }
+
+    /// The escape sequence that removes the image, e.g. when it is not rendered anymore.
+    pub fn delete_sequence(&self) -> String {
+        let Some(kitty) = &self.kitty else {
+            return String::new();
+        };
+        let (start, escape, end) = Parser::escape_tmux(kitty.is_tmux);
+        format!("{start}{escape}_Gq=2,a=d,d=I,i={}{escape}\\{end}", kitty.id)
+    }
+
+    // Resize to cover the area, and blend into the background.
+    fn encode(&self, kitty: &KittyBackground, area: Rect) -> KittyProtoState {
+        crate::cleanup::register_kitty(kitty.id, kitty.is_tmux);
+        let (width, height) = (
+            area.width as u32 * self.font_size.0 as u32,
+            area.height as u32 * self.font_size.1 as u32,
+        );
+        let image = self
+            .image
+            .resize_to_fill(width, height, FilterType::Triangle);
+        let opacity = self.opacity.unwrap_or_else(|| {
+            // Keep the average luminance within some distance of the background's.
+            let analysis = ImageAnalysis::new(&image);
+            let distance = (analysis.average_luminance - luminance(self.background)).abs();
+            (0.15 / distance.max(0.15)).min(1.0)
+        });
+        let Rgb(background) = self.background;
+        let mut rgba = image.to_rgba8();
+        for pixel in rgba.pixels_mut() {
+            for (channel, background) in pixel.0.iter_mut().zip(background) {
+                let blended = background as f32 + (*channel as f32 - background as f32) * opacity;
+                *channel = blended.round() as u8;
+            }
+        }
+        let placement = format!("p=1,z={Z_INDEX},C=1");
+        KittyProtoState::TransmitAndPlace(transmit(
+            &rgba,
+            kitty.id,
+            kitty.is_tmux,
+            &kitty.options,
+            &placement,
+            (area.width, area.height),
+        ))
+    }
+}
+
+/// Widget that renders a [BackgroundProtocol] behind the text of its area.
+///
+/// It must be rendered after the widgets on top of it: the image data is prefixed to the first
+/// cell of the area, which would otherwise be overwritten. The cells are not changed otherwise.
+pub struct BackgroundImage;
+
+impl StatefulWidget for BackgroundImage {
+    type State = BackgroundProtocol;
+    fn render(self, area: Rect, buf: &mut Buffer, state: &mut Self::State) {
+        let area = area.intersection(buf.area);
+        if area.is_empty() {
+            return;
+        }
+        let Some(kitty) = &state.kitty else {
+            return;
+        };
+        if state.placed != Some(area) {
+            // Placing with the same placement id replaces the previous placement.
+            state.transmission = state.encode(kitty, area);
+            state.placed = Some(area);
+        }
+        let Some(mut seq) = state
+            .transmission
+            .make_transmit(kitty.is_tmux, &kitty.options)
+        else {
+            return;
+        };
+        let cell = &mut buf[(area.x, area.y)];
+        seq.push_str(cell.symbol());
+        cell.set_symbol(&seq);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, ImageBuffer, Rgba};
+    use ratatui::{buffer::Buffer, layout::Rect, widgets::StatefulWidget};
+
+    use super::BackgroundImage;
+    use crate::picker::{Picker, ProtocolType};
+
+    #[test]
+    fn test_background_image() {
+        let image: DynamicImage =
+            ImageBuffer::from_pixel(40, 40, Rgba::<u8>([255, 255, 255, 255])).into();
+        let mut picker = Picker::from_fontsize((10, 20));
+        let area = Rect::new(0, 0, 4, 2);
+
+        picker.set_protocol_type(ProtocolType::Sixel);
+        let mut background = picker.new_background_protocol(image.clone());
+        assert!(!background.is_supported());
+        let mut buf = Buffer::empty(area);
+        BackgroundImage.render(area, &mut buf, &mut background);
+        assert_eq!(buf, Buffer::empty(area));
+
+        picker.set_protocol_type(ProtocolType::Kitty);
+        let mut background = picker.new_background_protocol(image);
+        let mut buf = Buffer::empty(area);
+        buf.set_string(0, 0, "text", ratatui::style::Style::new());
+        BackgroundImage.render(area, &mut buf, &mut background);
+        let symbol = buf[(0, 0)].symbol();
+        assert!(symbol.contains(",a=T,p=1,z=-1073741825,C=1,f=32,t=d,s=40,v=40,c=4,r=2,"));
+        assert!(symbol.ends_with('t'));
+        assert!(!buf[(1, 0)].skip);
+        // White on the default black background is dimmed a lot.
+        let pixel = &symbol[symbol.find(';').unwrap() + 1..][..4];
+        assert_ne!(pixel, "////");
+
+        // Transmitted once, until the area changes.
+        let mut buf = Buffer::empty(area);
+        BackgroundImage.render(area, &mut buf, &mut background);
+        assert_eq!(buf, Buffer::empty(area));
+    }
+}
@@ -1,5 +1,7 @@
 use std::{
-    env, io,
+    env,
+    fs::File,
+    io::{self, BufReader, Write},
     time::{Duration, Instant},
 };
 
@@ -7,16 +9,22 @@ use image::DynamicImage;
 use ratatui::{
     backend::CrosstermBackend,
     crossterm::{
+        cursor::MoveTo,
         event::{self, Event, KeyCode, KeyEventKind},
-        execute,
-        terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
+        execute, queue,
+        terminal::{
+            disable_raw_mode, enable_raw_mode, Clear, ClearType, EnterAlternateScreen,
+            LeaveAlternateScreen,
+        },
     },
     layout::{Constraint, Direction, Layout},
     text::Line,
     widgets::{Block, Borders, Paragraph, Wrap},
     Frame, Terminal,
 };
-use ratatui_image::{picker::Picker, protocol::StatefulProtocol, StatefulImage};
+use ratatui_image::{
+    picker::Picker, protocol::StatefulProtocol, sequence_log::read_records, StatefulImage,
+};
 
 struct App {
     pub filename: String,
@@ -28,7 +36,13 @@ struct App {
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let filename = env::args()
         .nth(1)
-        .expect("Usage: <program> <path/to/image>");
+        .expect("Usage: <program> <path/to/image> | --replay <path/to/log>");
+    if filename == "--replay" {
+        let log = env::args()
+            .nth(2)
+            .expect("Usage: <program> --replay <path/to/log>");
+        return replay(&log);
+    }
 
     let picker = Picker::from_query_stdio().unwrap_or_else(|_| {
         let font_width = env::args()
@@ -101,6 +115,40 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
+/// Replay a [ratatui_image::sequence_log::SequenceLog] with its original timing and positions.
+/// Any key quits, also after the last record.
+fn replay(path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let records = read_records(BufReader::new(File::open(path)?))?;
+
+    enable_raw_mode()?;
+    let mut stdout = io::stdout();
+    execute!(stdout, EnterAlternateScreen, Clear(ClearType::All))?;
+
+    let start = Instant::now();
+    let mut quit = false;
+    for record in &records {
+        let wait = record.time.saturating_sub(start.elapsed());
+        if event::poll(wait)?
+            && matches!(event::read()?, Event::Key(key) if key.kind == KeyEventKind::Press)
+        {
+            quit = true;
+            break;
+        }
+        queue!(stdout, MoveTo(record.area.x, record.area.y))?;
+        stdout.write_all(record.payload.as_bytes())?;
+        stdout.flush()?;
+    }
+    while !quit {
+        if let Event::Key(key) = event::read()? {
+            quit = key.kind == KeyEventKind::Press;
+        }
+    }
+
+    disable_raw_mode()?;
+    execute!(stdout, LeaveAlternateScreen)?;
+    Ok(())
+}
+
 fn ui(f: &mut Frame<'_>, app: &mut App) {
     let chunks = Layout::default()
         .direction(Direction::Vertical)
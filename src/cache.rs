@@ -0,0 +1,459 @@
+//! Keyed protocol cache for immediate-mode style image widgets.
+//!
+//! Instead of holding a [StatefulProtocol] per image, an app can hold one [ImageCacheContext]
+//! and render images by key:
+//!
+//! ```rust
+//! # use ratatui::Frame;
+//! # use ratatui_image::{cache::ImageCacheContext, Image};
+//! struct App {
+//!     logo: image::DynamicImage,
+//!     cache: ImageCacheContext,
+//! }
+//! fn ui(f: &mut Frame<'_>, app: &mut App) {
+//!     f.render_widget(Image::from_source(&app.logo).key("logo").cache(&mut app.cache), f.area());
+//! }
+//! ```
+//!
+//! The protocols are created on first use, resized when the area changes, and evicted by
+//! [ImageCacheContext::end_frame] when they have not been rendered for a while.
+//!
+//! For a single fixed [Protocol], a [ProtocolCache] re-encodes it when the area changes, see
+//! [CachedImage].
+
+use std::{
+    collections::HashMap,
+    hash::{DefaultHasher, Hash, Hasher},
+};
+
+use image::{ColorType, DynamicImage, Rgba};
+use ratatui::{buffer::Buffer, layout::Rect, widgets::Widget};
+
+use crate::{
+    errors::Errors,
+    metrics,
+    picker::Picker,
+    protocol::{ImageSource, Protocol, StatefulProtocol},
+    Resize, ResizeEncodeRender, Result,
+};
+
+struct CacheEntry {
+    protocol: StatefulProtocol,
+    fingerprint: Fingerprint,
+    last_used: u64,
+}
+
+// The content of a source image, to notice when a key is reused for a different image, or the
+// image was changed in place. Hashed like the ImageSource hash.
+#[derive(PartialEq)]
+struct Fingerprint {
+    hash: u64,
+    width: u32,
+    height: u32,
+    color: ColorType,
+}
+
+impl Fingerprint {
+    fn new(image: &DynamicImage) -> Fingerprint {
+        let mut state = DefaultHasher::new();
+        image.as_bytes().hash(&mut state);
+        Fingerprint {
+            hash: state.finish(),
+            width: image.width(),
+            height: image.height(),
+            color: image.color(),
+        }
+    }
+}
+
+/// Cache of [StatefulProtocol]s keyed by name, for [KeyedImage] widgets.
+pub struct ImageCacheContext {
+    picker: Picker,
+    entries: HashMap<String, CacheEntry>,
+    frame: u64,
+    max_idle_frames: u64,
+}
+
+impl ImageCacheContext {
+    /// Create an empty cache that creates its protocols with the given [Picker].
+    pub fn new(picker: Picker) -> ImageCacheContext {
+        ImageCacheContext {
+            picker,
+            entries: HashMap::new(),
+            frame: 0,
+            max_idle_frames: 1,
+        }
+    }
+
+    /// Set after how many frames without rendering an entry is evicted (default 1).
+    pub fn max_idle_frames(mut self, max_idle_frames: u64) -> ImageCacheContext {
+        self.max_idle_frames = max_idle_frames;
+        self
+    }
+
+    /// Replace the picker, dropping all entries so that they are created again with it.
+    pub fn set_picker(&mut self, picker: Picker) {
+        self.picker = picker;
+        self.entries.clear();
+    }
+
+    /// Call once after each `terminal.draw()` to evict entries that were not rendered recently.
+    pub fn end_frame(&mut self) {
+        let (frame, max_idle_frames) = (self.frame, self.max_idle_frames);
+        self.entries
+            .retain(|_, entry| frame - entry.last_used < max_idle_frames);
+        self.frame += 1;
+    }
+
+    /// Remove one entry.
+    pub fn remove(&mut self, key: &str) {
+        self.entries.remove(key);
+    }
+
+    /// Remove all entries.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// Number of cached entries.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Whether the cache has no entries.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn render(
+        &mut self,
+        key: &str,
+        source: &DynamicImage,
+        resize: &Resize,
+        area: Rect,
+        buf: &mut Buffer,
+    ) {
+        let fingerprint = Fingerprint::new(source);
+        let hit = self
+            .entries
+            .get(key)
+            .is_some_and(|entry| entry.fingerprint == fingerprint);
+        metrics::cache_lookup("keyed", hit);
+        if !hit {
+            let protocol = self.picker.new_resize_protocol(source.clone());
+            self.entries.insert(
+                key.to_string(),
+                CacheEntry {
+                    protocol,
+                    fingerprint,
+                    last_used: self.frame,
+                },
+            );
+        }
+        let Some(entry) = self.entries.get_mut(key) else {
+            return;
+        };
+        entry.last_used = self.frame;
+        ResizeEncodeRender::resize_encode_render(&mut entry.protocol, resize, area, buf);
+    }
+}
+
+/// Image widget that renders through an [ImageCacheContext], see [crate::Image::from_source].
+///
+/// Both a key and a cache must be set, otherwise nothing is rendered.
+pub struct KeyedImage<'a> {
+    source: &'a DynamicImage,
+    key: Option<String>,
+    cache: Option<&'a mut ImageCacheContext>,
+    resize: Resize,
+}
+
+impl<'a> KeyedImage<'a> {
+    pub fn new(source: &'a DynamicImage) -> KeyedImage<'a> {
+        KeyedImage {
+            source,
+            key: None,
+            cache: None,
+            resize: Resize::Fit(None),
+        }
+    }
+
+    /// The key of the cache entry. Rendering a different image with the same key replaces it.
+    pub fn key(mut self, key: impl Into<String>) -> KeyedImage<'a> {
+        self.key = Some(key.into());
+        self
+    }
+
+    /// The cache that holds the protocol.
+    pub fn cache(mut self, cache: &'a mut ImageCacheContext) -> KeyedImage<'a> {
+        self.cache = Some(cache);
+        self
+    }
+
+    pub fn resize(mut self, resize: Resize) -> KeyedImage<'a> {
+        self.resize = resize;
+        self
+    }
+}
+
+impl Widget for KeyedImage<'_> {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        if area.width == 0 || area.height == 0 {
+            return;
+        }
+        if let (Some(key), Some(cache)) = (self.key, self.cache) {
+            cache.render(&key, self.source, &self.resize, area, buf);
+        }
+    }
+}
+
+/// One re-encoded [Protocol] for a [CachedImage], keyed by the size it needs.
+pub struct ProtocolCache {
+    picker: Picker,
+    source: ImageSource,
+    // The size and resize that the protocol was encoded for, and the result, which is kept when
+    // it failed so that it is not encoded again on every frame.
+    encoded: Option<(Rect, Resize, Result<Protocol>)>,
+}
+
+impl ProtocolCache {
+    /// Create an empty cache that re-encodes the `source` image with the given [Picker].
+    pub fn new(picker: Picker, source: DynamicImage) -> ProtocolCache {
+        let source = ImageSource::new(source, picker.font_size(), Rgba([0, 0, 0, 0]));
+        ProtocolCache {
+            picker,
+            source,
+            encoded: None,
+        }
+    }
+
+    /// Drop the encoded protocol, also after an error, to encode again on the next render.
+    pub fn clear(&mut self) {
+        self.encoded = None;
+    }
+
+    /// The error of the last encoding, if it failed. It is not retried until the needed size
+    /// changes or [ProtocolCache::clear] is called.
+    pub fn last_error(&self) -> Option<&Errors> {
+        match &self.encoded {
+            Some((_, _, Err(err))) => Some(err),
+            _ => None,
+        }
+    }
+
+    // The protocol to render instead of `protocol`, or None if `protocol` already has the size
+    // that the area needs, or if encoding failed. Encodes only when the needed size or the resize
+    // changed.
+    fn get(&mut self, protocol: &Protocol, resize: &Resize, area: Rect) -> Option<&mut Protocol> {
+        let font_size = self.picker.font_size();
+        let current = protocol.area();
+        let needed = match resize.needs_resize(&self.source, font_size, current, area, false) {
+            Some(needed) if needed != current => needed,
+            _ => {
+                metrics::cache_lookup("protocol", true);
+                return None;
+            }
+        };
+        let hit = matches!(&self.encoded, Some((n, r, _)) if *n == needed && r == resize);
+        metrics::cache_lookup("protocol", hit);
+        if !hit {
+            let encoded = self
+                .picker
+                .new_protocol(self.source.image.clone(), area, resize.clone());
+            self.encoded = Some((needed, resize.clone(), encoded));
+        }
+        match &mut self.encoded {
+            Some((_, _, Ok(protocol))) => Some(protocol),
+            _ => None,
+        }
+    }
+}
+
+/// Fixed size image widget that re-encodes for the current area through a [ProtocolCache].
+///
+/// The [Protocol] is rendered as is, like [crate::Image] does, when it already has the size that
+/// the area needs, without a cache, or if re-encoding failed, see [ProtocolCache::last_error].
+/// The cache must hold the image that the protocol was created from.
+///
+/// ```rust
+/// # use ratatui::Frame;
+/// # use ratatui_image::{cache::{CachedImage, ProtocolCache}, protocol::Protocol};
+/// fn ui(f: &mut Frame<'_>, logo: &Protocol, cache: &mut ProtocolCache) {
+///     f.render_widget(CachedImage::new(logo).cache(cache), f.area());
+/// }
+/// ```
+pub struct CachedImage<'a> {
+    protocol: &'a Protocol,
+    cache: Option<&'a mut ProtocolCache>,
+    resize: Resize,
+}
+
+impl<'a> CachedImage<'a> {
+    pub fn new(protocol: &'a Protocol) -> CachedImage<'a> {
+        CachedImage {
+            protocol,
+            cache: None,
+            resize: Resize::Fit(None),
+        }
+    }
+
+    /// The cache that holds the re-encoded protocol.
+    pub fn cache(mut self, cache: &'a mut ProtocolCache) -> CachedImage<'a> {
+        self.cache = Some(cache);
+        self
+    }
+
+    pub fn resize(mut self, resize: Resize) -> CachedImage<'a> {
+        self.resize = resize;
+        self
+    }
+}
+
+impl Widget for CachedImage<'_> {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        if area.width == 0 || area.height == 0 {
+            return;
+        }
+        let cached = self
+            .cache
+            .and_then(|cache| cache.get(self.protocol, &self.resize, area));
+        if let Some(protocol) = cached {
+            protocol.render(area, buf);
+            return;
+        }
+        // Rendering is stateful for Kitty, which transmits on the first render only. A fresh
+        // clone produces the same cells on every frame, so ratatui does not write them again.
+        self.protocol.clone().render(area, buf);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, ImageBuffer, Rgba};
+    use ratatui::{buffer::Buffer, layout::Rect, style::Color, widgets::Widget};
+
+    use super::{CachedImage, ImageCacheContext, ProtocolCache};
+    use crate::{
+        errors::Errors,
+        picker::{Picker, ProtocolType},
+        Image, Resize,
+    };
+
+    #[test]
+    fn test_cache_evicts_unused() {
+        let mut picker = Picker::from_fontsize((10, 20));
+        picker.set_protocol_type(ProtocolType::Halfblocks);
+        let mut cache = ImageCacheContext::new(picker);
+        let image: DynamicImage =
+            ImageBuffer::from_pixel(40, 40, Rgba::<u8>([255, 0, 0, 255])).into();
+        let area = Rect::new(0, 0, 4, 4);
+        let mut buf = Buffer::empty(area);
+
+        for key in ["a", "b"] {
+            Image::from_source(&image)
+                .key(key)
+                .cache(&mut cache)
+                .render(area, &mut buf);
+        }
+        assert_eq!(buf[(0, 0)].symbol(), "▀");
+        cache.end_frame();
+        assert_eq!(cache.len(), 2);
+
+        Image::from_source(&image)
+            .key("a")
+            .cache(&mut cache)
+            .render(area, &mut buf);
+        cache.end_frame();
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn test_cache_changed_in_place() {
+        let mut picker = Picker::from_fontsize((10, 20));
+        picker.set_protocol_type(ProtocolType::Halfblocks);
+        let mut cache = ImageCacheContext::new(picker);
+        let mut image: DynamicImage =
+            ImageBuffer::from_pixel(40, 40, Rgba::<u8>([255, 0, 0, 255])).into();
+        let area = Rect::new(0, 0, 4, 4);
+        let mut buf = Buffer::empty(area);
+
+        Image::from_source(&image)
+            .key("a")
+            .cache(&mut cache)
+            .render(area, &mut buf);
+        assert_eq!(buf[(0, 0)].fg, Color::Rgb(255, 0, 0));
+
+        // Same key and same buffer, new pixels.
+        let ptr = image.as_bytes().as_ptr();
+        for pixel in image.as_mut_rgba8().unwrap().pixels_mut() {
+            *pixel = Rgba([0, 0, 255, 255]);
+        }
+        assert_eq!(image.as_bytes().as_ptr(), ptr);
+        Image::from_source(&image)
+            .key("a")
+            .cache(&mut cache)
+            .render(area, &mut buf);
+        assert_eq!(buf[(0, 0)].fg, Color::Rgb(0, 0, 255));
+    }
+
+    #[test]
+    fn test_protocol_cache() {
+        let mut picker = Picker::from_fontsize((10, 20));
+        picker.set_protocol_type(ProtocolType::Halfblocks);
+        let image: DynamicImage =
+            ImageBuffer::from_pixel(40, 80, Rgba::<u8>([255, 0, 0, 255])).into();
+        let small = Rect::new(0, 0, 2, 2);
+        let protocol = picker
+            .new_protocol(image.clone(), small, Resize::Fit(None))
+            .unwrap();
+        let mut cache = ProtocolCache::new(picker, image);
+
+        let large = Rect::new(0, 0, 4, 4);
+        let mut buf = Buffer::empty(large);
+        CachedImage::new(&protocol).render(large, &mut buf);
+        assert_eq!(buf[(3, 3)].symbol(), " ");
+
+        let mut buf = Buffer::empty(large);
+        CachedImage::new(&protocol)
+            .cache(&mut cache)
+            .render(large, &mut buf);
+        assert_eq!(buf[(3, 3)].symbol(), "▀");
+        assert!(matches!(cache.encoded, Some((area, _, Ok(_))) if area == large));
+
+        // The protocol already has the size that the small area needs.
+        let mut buf = Buffer::empty(small);
+        CachedImage::new(&protocol)
+            .cache(&mut cache)
+            .render(small, &mut buf);
+        assert_eq!(buf[(1, 1)].symbol(), "▀");
+        assert!(matches!(cache.encoded, Some((area, _, Ok(_))) if area == large));
+    }
+
+    #[test]
+    fn test_protocol_cache_error() {
+        let mut picker = Picker::from_fontsize((10, 20));
+        picker.set_protocol_type(ProtocolType::Halfblocks);
+        let image: DynamicImage =
+            ImageBuffer::from_pixel(40, 80, Rgba::<u8>([255, 0, 0, 255])).into();
+        let small = Rect::new(0, 0, 2, 2);
+        let protocol = picker
+            .new_protocol(image.clone(), small, Resize::Fit(None))
+            .unwrap();
+        let mut cache = ProtocolCache::new(picker, image);
+        let large = Rect::new(0, 0, 4, 4);
+        // As if encoding for the large area had failed.
+        cache.encoded = Some((large, Resize::Fit(None), Err(Errors::NoCap)));
+
+        let mut buf = Buffer::empty(large);
+        CachedImage::new(&protocol)
+            .cache(&mut cache)
+            .render(large, &mut buf);
+        // Rendered as is, and not encoded again.
+        assert_eq!(buf[(1, 1)].symbol(), "▀");
+        assert_eq!(buf[(3, 3)].symbol(), " ");
+        assert!(matches!(cache.last_error(), Some(Errors::NoCap)));
+
+        cache.clear();
+        assert!(cache.last_error().is_none());
+    }
+}
@@ -0,0 +1,482 @@
+//! High-resolution plots, drawn like ratatui's [Canvas](ratatui::widgets::canvas::Canvas) widget but with one point per pixel.
+//!
+//! A [PixelCanvas] has the bounds, background color and painter of a [Canvas](ratatui::widgets::canvas::Canvas), and its painter
+//! draws the same shapes ([Line], [Points], [Rectangle] and [Circle]) straight into an image. The
+//! image is rendered with the graphics protocol of a [Picker] by a [CanvasImage]:
+//!
+//! ```rust
+//! # use ratatui::{Frame, style::Color, widgets::canvas::Line};
+//! # use ratatui_image::canvas::{CanvasImage, CanvasState, PixelCanvas};
+//! fn ui(f: &mut Frame<'_>, state: &mut CanvasState, slope: f64) {
+//!     let canvas = PixelCanvas::default()
+//!         .x_bounds([0.0, 10.0])
+//!         .y_bounds([0.0, 10.0])
+//!         .paint(|ctx| {
+//!             ctx.draw(&Line::new(0.0, 0.0, 10.0, 10.0 * slope, Color::Yellow));
+//!         });
+//!     let image = CanvasImage::new(canvas).key(slope.to_bits());
+//!     f.render_stateful_widget(image, f.area(), state);
+//! }
+//! ```
+//!
+//! Other shapes can implement [PixelShape]. Labels are not supported, and should be rendered as
+//! regular widgets over the image instead.
+
+use std::hash::{DefaultHasher, Hash, Hasher};
+
+use image::{Rgba, RgbaImage};
+use ratatui::{
+    buffer::Buffer,
+    layout::Rect,
+    style::Color,
+    widgets::{
+        canvas::{Circle, Line, Points, Rectangle},
+        StatefulWidget, Widget,
+    },
+};
+
+use crate::{
+    picker::Picker, protocol::halfblocks::ansi256, protocol::Protocol, FontSize, Image, Resize,
+};
+
+/// The bounds, background color and painter of a drawing, like a [Canvas](ratatui::widgets::canvas::Canvas).
+pub struct PixelCanvas<F>
+where
+    F: Fn(&mut PixelContext),
+{
+    x_bounds: [f64; 2],
+    y_bounds: [f64; 2],
+    background_color: Color,
+    painter: Option<F>,
+}
+
+impl<F> Default for PixelCanvas<F>
+where
+    F: Fn(&mut PixelContext),
+{
+    fn default() -> PixelCanvas<F> {
+        PixelCanvas {
+            x_bounds: [0.0, 0.0],
+            y_bounds: [0.0, 0.0],
+            background_color: Color::Reset,
+            painter: None,
+        }
+    }
+}
+
+impl<F> PixelCanvas<F>
+where
+    F: Fn(&mut PixelContext),
+{
+    /// The canvas coordinates of the left and right edges.
+    pub fn x_bounds(mut self, x_bounds: [f64; 2]) -> PixelCanvas<F> {
+        self.x_bounds = x_bounds;
+        self
+    }
+
+    /// The canvas coordinates of the bottom and top edges.
+    pub fn y_bounds(mut self, y_bounds: [f64; 2]) -> PixelCanvas<F> {
+        self.y_bounds = y_bounds;
+        self
+    }
+
+    /// The color of unpainted pixels, transparent if it is [Color::Reset] (default).
+    pub fn background_color(mut self, color: Color) -> PixelCanvas<F> {
+        self.background_color = color;
+        self
+    }
+
+    /// The function that draws the shapes.
+    pub fn paint(mut self, painter: F) -> PixelCanvas<F> {
+        self.painter = Some(painter);
+        self
+    }
+
+    /// Draw into an image of `width` x `height` pixels.
+    pub fn rasterize(&self, width: u32, height: u32) -> RgbaImage {
+        let mut ctx = PixelContext {
+            image: RgbaImage::from_pixel(width, height, to_rgba(self.background_color)),
+            x_bounds: self.x_bounds,
+            y_bounds: self.y_bounds,
+        };
+        if let Some(painter) = &self.painter {
+            painter(&mut ctx);
+        }
+        ctx.image
+    }
+}
+
+/// The image that the painter of a [PixelCanvas] draws into.
+pub struct PixelContext {
+    image: RgbaImage,
+    x_bounds: [f64; 2],
+    y_bounds: [f64; 2],
+}
+
+impl PixelContext {
+    pub fn draw(&mut self, shape: &impl PixelShape) {
+        shape.draw(self);
+    }
+
+    /// The pixel of a point in canvas coordinates, or None if it is out of bounds. Like with a
+    /// [Canvas](ratatui::widgets::canvas::Canvas), the y axis points up.
+    pub fn get_point(&self, x: f64, y: f64) -> Option<(u32, u32)> {
+        let [left, right] = self.x_bounds;
+        let [bottom, top] = self.y_bounds;
+        if x < left || x > right || y < bottom || y > top {
+            return None;
+        }
+        let (width, height) = (right - left, top - bottom);
+        if width == 0.0 || height == 0.0 || self.image.width() == 0 || self.image.height() == 0 {
+            return None;
+        }
+        let x = (x - left) * (self.image.width() - 1) as f64 / width;
+        let y = (top - y) * (self.image.height() - 1) as f64 / height;
+        Some((x as u32, y as u32))
+    }
+
+    /// Paint a pixel, if it is inside the image.
+    pub fn paint(&mut self, x: u32, y: u32, color: Color) {
+        if x < self.image.width() && y < self.image.height() {
+            self.image.put_pixel(x, y, to_rgba(color));
+        }
+    }
+
+    // Bresenham's line between two pixels.
+    fn paint_line(&mut self, (x1, y1): (u32, u32), (x2, y2): (u32, u32), color: Color) {
+        let (mut x, mut y) = (x1 as i64, y1 as i64);
+        let (x2, y2) = (x2 as i64, y2 as i64);
+        let (dx, dy) = ((x2 - x).abs(), -(y2 - y).abs());
+        let (step_x, step_y) = ((x2 - x).signum(), (y2 - y).signum());
+        let mut error = dx + dy;
+        loop {
+            self.paint(x as u32, y as u32, color);
+            if x == x2 && y == y2 {
+                return;
+            }
+            if 2 * error >= dy {
+                error += dy;
+                x += step_x;
+            }
+            if 2 * error <= dx {
+                error += dx;
+                y += step_y;
+            }
+        }
+    }
+}
+
+/// A shape that can be drawn into a [PixelContext], like a ratatui canvas
+/// [Shape](ratatui::widgets::canvas::Shape).
+pub trait PixelShape {
+    fn draw(&self, ctx: &mut PixelContext);
+}
+
+impl PixelShape for Line {
+    fn draw(&self, ctx: &mut PixelContext) {
+        let (Some(start), Some(end)) = (
+            ctx.get_point(self.x1, self.y1),
+            ctx.get_point(self.x2, self.y2),
+        ) else {
+            return;
+        };
+        ctx.paint_line(start, end, self.color);
+    }
+}
+
+impl PixelShape for Points<'_> {
+    fn draw(&self, ctx: &mut PixelContext) {
+        for &(x, y) in self.coords {
+            if let Some((x, y)) = ctx.get_point(x, y) {
+                ctx.paint(x, y, self.color);
+            }
+        }
+    }
+}
+
+impl PixelShape for Rectangle {
+    fn draw(&self, ctx: &mut PixelContext) {
+        let (left, right) = (self.x, self.x + self.width);
+        let (bottom, top) = (self.y, self.y + self.height);
+        for (x1, y1, x2, y2) in [
+            (left, bottom, left, top),
+            (left, top, right, top),
+            (right, bottom, right, top),
+            (left, bottom, right, bottom),
+        ] {
+            ctx.draw(&Line::new(x1, y1, x2, y2, self.color));
+        }
+    }
+}
+
+impl PixelShape for Circle {
+    // Segments between points at every degree, so that large circles have no gaps.
+    fn draw(&self, ctx: &mut PixelContext) {
+        let point = |degree: u32| {
+            let radians = f64::from(degree).to_radians();
+            (
+                self.radius.mul_add(radians.cos(), self.x),
+                self.radius.mul_add(radians.sin(), self.y),
+            )
+        };
+        for degree in 0..360 {
+            let ((x1, y1), (x2, y2)) = (point(degree), point(degree + 1));
+            ctx.draw(&Line::new(x1, y1, x2, y2, self.color));
+        }
+    }
+}
+
+// The RGBA value of a terminal color, with xterm's defaults for the indexed colors.
+fn to_rgba(color: Color) -> Rgba<u8> {
+    let index = match color {
+        Color::Reset => return Rgba([0, 0, 0, 0]),
+        Color::Rgb(r, g, b) => return Rgba([r, g, b, 255]),
+        Color::Indexed(index) => index,
+        Color::Black => 0,
+        Color::Red => 1,
+        Color::Green => 2,
+        Color::Yellow => 3,
+        Color::Blue => 4,
+        Color::Magenta => 5,
+        Color::Cyan => 6,
+        Color::Gray => 7,
+        Color::DarkGray => 8,
+        Color::LightRed => 9,
+        Color::LightGreen => 10,
+        Color::LightYellow => 11,
+        Color::LightBlue => 12,
+        Color::LightMagenta => 13,
+        Color::LightCyan => 14,
+        Color::White => 15,
+    };
+    let [r, g, b] = ansi256(index);
+    Rgba([r, g, b, 255])
+}
+
+/// State of a [CanvasImage]: the picker, and the protocol of the last drawing.
+pub struct CanvasState {
+    picker: Picker,
+    protocol: Option<Protocol>,
+    // What the protocol was drawn for: the area, the font size, and the key or else the pixels.
+    drawn: Option<(Rect, FontSize, Drawn)>,
+}
+
+#[derive(PartialEq)]
+enum Drawn {
+    Key(u64),
+    Pixels(RgbaImage),
+}
+
+impl CanvasState {
+    pub fn new(picker: Picker) -> CanvasState {
+        CanvasState {
+            picker,
+            protocol: None,
+            drawn: None,
+        }
+    }
+}
+
+/// Widget that draws a [PixelCanvas] at the pixel resolution of the area, see the
+/// [module docs](self).
+///
+/// With a [CanvasImage::key], the canvas is only drawn and encoded again when the key or the area
+/// changed. Without one, it is drawn on every render, but only encoded again if the pixels or the
+/// area changed.
+pub struct CanvasImage<F>
+where
+    F: Fn(&mut PixelContext),
+{
+    canvas: PixelCanvas<F>,
+    key: Option<u64>,
+}
+
+impl<F> CanvasImage<F>
+where
+    F: Fn(&mut PixelContext),
+{
+    pub fn new(canvas: PixelCanvas<F>) -> CanvasImage<F> {
+        CanvasImage { canvas, key: None }
+    }
+
+    /// The inputs of the painter, such as the plotted data or a version number of it. The same
+    /// key promises the same drawing.
+    pub fn key(mut self, key: impl Hash) -> CanvasImage<F> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        self.key = Some(hasher.finish());
+        self
+    }
+}
+
+impl<F> StatefulWidget for CanvasImage<F>
+where
+    F: Fn(&mut PixelContext),
+{
+    type State = CanvasState;
+    fn render(self, area: Rect, buf: &mut Buffer, state: &mut Self::State) {
+        if area.width == 0 || area.height == 0 {
+            return;
+        }
+        let font_size = state.picker.font_size();
+        let width = area.width as u32 * font_size.0 as u32;
+        let height = area.height as u32 * font_size.1 as u32;
+        let previous = state
+            .drawn
+            .as_ref()
+            .filter(|(drawn_area, drawn_font_size, _)| {
+                *drawn_area == area && *drawn_font_size == font_size
+            })
+            .map(|(_, _, drawn)| drawn);
+        // The new drawing to encode, if it changed.
+        let changed = match self.key {
+            Some(key) if previous == Some(&Drawn::Key(key)) => None,
+            Some(key) => Some((self.canvas.rasterize(width, height), Drawn::Key(key))),
+            None => {
+                let image = self.canvas.rasterize(width, height);
+                match previous {
+                    Some(Drawn::Pixels(pixels)) if *pixels == image => None,
+                    _ => Some((image.clone(), Drawn::Pixels(image))),
+                }
+            }
+        };
+        if let Some((image, drawn)) = changed {
+            let size = Rect::new(0, 0, area.width, area.height);
+            state.protocol = state
+                .picker
+                .new_protocol(image.into(), size, Resize::Fit(None))
+                .ok();
+            state.drawn = Some((area, font_size, drawn));
+        }
+        if let Some(protocol) = &mut state.protocol {
+            Image::new(protocol).render(area, buf);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use ratatui::{
+        buffer::Buffer,
+        layout::Rect,
+        style::Color,
+        widgets::{
+            canvas::{Circle, Line, Points, Rectangle},
+            StatefulWidget,
+        },
+    };
+
+    use super::{CanvasImage, CanvasState, PixelCanvas};
+    use crate::picker::{Picker, ProtocolType};
+
+    #[test]
+    fn test_rasterize() {
+        let canvas = PixelCanvas::default()
+            .x_bounds([0.0, 19.0])
+            .y_bounds([0.0, 9.0])
+            .background_color(Color::Rgb(1, 2, 3))
+            .paint(|ctx| {
+                ctx.draw(&Line::new(0.0, 0.0, 19.0, 0.0, Color::Red));
+                ctx.draw(&Points {
+                    coords: &[(0.0, 9.0)],
+                    color: Color::Indexed(196),
+                });
+            });
+        let image = canvas.rasterize(20, 10);
+        assert_eq!(image.dimensions(), (20, 10));
+        // The y axis points up.
+        for x in 0..20 {
+            assert_eq!(image.get_pixel(x, 9).0, [205, 0, 0, 255]);
+        }
+        assert_eq!(image.get_pixel(0, 0).0, [255, 0, 0, 255]);
+        assert_eq!(image.get_pixel(5, 5).0, [1, 2, 3, 255]);
+
+        let empty = PixelCanvas::default().paint(|_| {}).rasterize(2, 2);
+        assert_eq!(empty.get_pixel(1, 1).0, [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_shapes() {
+        let canvas = PixelCanvas::default()
+            .x_bounds([0.0, 20.0])
+            .y_bounds([0.0, 20.0])
+            .paint(|ctx| {
+                ctx.draw(&Line::new(0.0, 20.0, 20.0, 0.0, Color::White));
+                ctx.draw(&Rectangle {
+                    x: 0.0,
+                    y: 0.0,
+                    width: 10.0,
+                    height: 10.0,
+                    color: Color::Red,
+                });
+                ctx.draw(&Circle {
+                    x: 10.0,
+                    y: 10.0,
+                    radius: 5.0,
+                    color: Color::Blue,
+                });
+            });
+        let image = canvas.rasterize(21, 21);
+        let white = [255, 255, 255, 255];
+        // The diagonal has no gaps.
+        for i in 0..21 {
+            assert_ne!(image.get_pixel(i, i).0, [0; 4], "{i}");
+        }
+        assert_eq!(image.get_pixel(0, 0).0, white);
+        assert_eq!(image.get_pixel(0, 15).0, [205, 0, 0, 255]);
+        assert_eq!(image.get_pixel(5, 20).0, [205, 0, 0, 255]);
+        assert_eq!(image.get_pixel(15, 10).0, [0, 0, 238, 255]);
+        assert_eq!(image.get_pixel(10, 5).0, [0, 0, 238, 255]);
+        assert_eq!(image.get_pixel(12, 8).0, [0; 4]);
+    }
+
+    #[test]
+    fn test_canvas_image() {
+        let mut picker = Picker::from_fontsize((10, 20));
+        picker.set_protocol_type(ProtocolType::Halfblocks);
+        let mut state = CanvasState::new(picker);
+        let area = Rect::new(0, 0, 4, 2);
+        let painted = Cell::new(0);
+        let canvas = |color| {
+            let painted = &painted;
+            PixelCanvas::default()
+                .x_bounds([0.0, 1.0])
+                .y_bounds([0.0, 1.0])
+                .background_color(color)
+                .paint(move |ctx| {
+                    painted.set(painted.get() + 1);
+                    ctx.draw(&Line::new(0.0, 0.0, 1.0, 1.0, Color::White));
+                })
+        };
+        let render = |image: CanvasImage<_>, state: &mut CanvasState| {
+            let mut buf = Buffer::empty(area);
+            image.render(area, &mut buf, state);
+            buf
+        };
+
+        let green = Color::Rgb(0, 255, 0);
+        let buf = render(CanvasImage::new(canvas(green)).key(1), &mut state);
+        assert_eq!(buf[(3, 1)].bg, green);
+        assert_eq!(painted.get(), 1);
+
+        // The same key is neither drawn nor encoded again.
+        let buf = render(CanvasImage::new(canvas(green)).key(1), &mut state);
+        assert_eq!(buf[(3, 1)].bg, green);
+        assert_eq!(painted.get(), 1);
+
+        // A new key, or another area, draws again.
+        let red = Color::Rgb(255, 0, 0);
+        let buf = render(CanvasImage::new(canvas(red)).key(2), &mut state);
+        assert_eq!(painted.get(), 2);
+        assert_eq!(buf[(3, 1)].bg, red);
+
+        // Without a key, the canvas is drawn on every render.
+        let buf = render(CanvasImage::new(canvas(green)), &mut state);
+        assert_eq!(painted.get(), 3);
+        assert_eq!(buf[(3, 1)].bg, green);
+        render(CanvasImage::new(canvas(green)), &mut state);
+        assert_eq!(painted.get(), 4);
+    }
+}
@@ -0,0 +1,243 @@
+//! Cleanup of terminal graphics state when the program exits abnormally.
+//!
+//! Kitty keeps transmitted images in memory until they are deleted, and a panic mid-transmission
+//! can leave artifacts after the terminal is restored. When enabled, every transmitted Kitty image
+//! and every sixel encoding is registered here, so that the images can be deleted and the sixel
+//! scrolling mode reset from a panic hook or a guard's [Drop]:
+//!
+//! ```rust
+//! ratatui_image::cleanup::install_panic_hook();
+//! // Also cleans up on regular exit, or when unwinding without the hook.
+//! let _guard = ratatui_image::cleanup::CleanupGuard::new();
+//! // ... setup terminal and run the app ...
+//! ```
+//!
+//! Signal handlers are out of scope, an app handling e.g. `SIGTERM` can call [cleanup] itself.
+
+use std::{
+    collections::BTreeSet,
+    io::{self, Write},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+
+use crate::picker::cap_parser::Parser;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static REGISTRY: Mutex<Registry> = Mutex::new(Registry {
+    kitty_ids: BTreeSet::new(),
+    sixel: None,
+});
+// Kitty images of dropped protocols, see KittyOptions::delete_on_drop.
+static DROPPED: Mutex<Vec<(u32, bool)>> = Mutex::new(Vec::new());
+// Desktop notifications of ErrorIndicator::Notify.
+static NOTIFICATIONS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+struct Registry {
+    // Kitty image ids, and whether they were transmitted through tmux.
+    kitty_ids: BTreeSet<(u32, bool)>,
+    // Whether sixel was used, through tmux or not.
+    sixel: Option<bool>,
+}
+
+/// Start registering images for cleanup. Nothing is registered before this has been called.
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Enable registering, and install a panic hook that runs [cleanup] before the previous hook.
+pub fn install_panic_hook() {
+    enable();
+    let previous = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        cleanup();
+        previous(info);
+    }));
+}
+
+/// Delete all registered Kitty images and reset the sixel scrolling mode, on stdout.
+///
+/// The registry is emptied, so calling this more than once is harmless.
+pub fn cleanup() {
+    let seq = take_cleanup_sequence();
+    if !seq.is_empty() {
+        let mut stdout = io::stdout();
+        // Nothing sensible to do about errors while tearing down.
+        let _ = stdout.write_all(seq.as_bytes());
+        let _ = stdout.flush();
+    }
+}
+
+/// The escape sequences that [cleanup] would write, for apps that write to another backend.
+///
+/// The registry is emptied.
+pub fn take_cleanup_sequence() -> String {
+    // Don't block (or panic again) if the panic happened while the registry was locked.
+    let mut registry = match REGISTRY.try_lock() {
+        Ok(registry) => registry,
+        Err(std::sync::TryLockError::Poisoned(poisoned)) => poisoned.into_inner(),
+        Err(std::sync::TryLockError::WouldBlock) => return String::new(),
+    };
+    let mut seq = String::new();
+    for (id, is_tmux) in std::mem::take(&mut registry.kitty_ids) {
+        seq.push_str(&kitty_delete_sequence(id, is_tmux));
+    }
+    if let Some(is_tmux) = registry.sixel.take() {
+        let (start, escape, end) = Parser::escape_tmux(is_tmux);
+        // DECSDM off, the default sixel scrolling mode.
+        seq.push_str(&format!("{start}{escape}[?80l{end}"));
+    }
+    seq
+}
+
+/// The escape sequences that delete the Kitty images of dropped protocols, see
+/// [crate::protocol::kitty::KittyOptions::delete_on_drop].
+///
+/// They are also sent along with the next transmission of such a protocol, this is for apps that
+/// want to free the terminal memory without rendering another image.
+pub fn take_dropped_sequence() -> String {
+    let dropped = match DROPPED.lock() {
+        Ok(mut dropped) => std::mem::take(&mut *dropped),
+        Err(poisoned) => std::mem::take(&mut *poisoned.into_inner()),
+    };
+    dropped
+        .into_iter()
+        .map(|(id, is_tmux)| kitty_delete_sequence(id, is_tmux))
+        .collect()
+}
+
+/// The desktop notifications (OSC 9) of failed images, see [crate::ErrorIndicator::Notify].
+///
+/// They are not part of the buffer, where they would be written again with every change of the
+/// cell, so the app has to write them to the terminal itself, e.g. after every draw like
+/// [take_dropped_sequence].
+pub fn take_notification_sequence() -> String {
+    let notifications = match NOTIFICATIONS.lock() {
+        Ok(mut notifications) => std::mem::take(&mut *notifications),
+        Err(poisoned) => std::mem::take(&mut *poisoned.into_inner()),
+    };
+    notifications.concat()
+}
+
+// Notify the user of an error with OSC 9, on the next take_notification_sequence.
+pub(crate) fn queue_notification(message: &str) {
+    // OSC 9 ends at a control character, so the message must not contain any.
+    let message: String = message.chars().filter(|c| !c.is_control()).collect();
+    let seq = format!("\x1b]9;ratatui-image: {message}\x07");
+    match NOTIFICATIONS.lock() {
+        Ok(mut notifications) => notifications.push(seq),
+        Err(poisoned) => poisoned.into_inner().push(seq),
+    }
+}
+
+// Delete a Kitty image and its placements.
+pub(crate) fn kitty_delete_sequence(id: u32, is_tmux: bool) -> String {
+    let (start, escape, end) = Parser::escape_tmux(is_tmux);
+    // Uppercase `I` also frees the image data, not only the placements.
+    format!("{start}{escape}_Ga=d,d=I,i={id},q=2{escape}\\{end}")
+}
+
+/// A guard that runs [cleanup] when dropped, see the [module docs](self).
+pub struct CleanupGuard {
+    _private: (),
+}
+
+impl CleanupGuard {
+    /// Enable registering, and clean up when the guard is dropped.
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> CleanupGuard {
+        enable();
+        CleanupGuard { _private: () }
+    }
+}
+
+impl Drop for CleanupGuard {
+    fn drop(&mut self) {
+        cleanup();
+    }
+}
+
+pub(crate) fn register_kitty(id: u32, is_tmux: bool) {
+    if ENABLED.load(Ordering::Relaxed) {
+        if let Ok(mut registry) = REGISTRY.lock() {
+            registry.kitty_ids.insert((id, is_tmux));
+        }
+    }
+}
+
+// Forget a Kitty image that was deleted.
+pub(crate) fn unregister_kitty(id: u32, is_tmux: bool) {
+    if let Ok(mut registry) = REGISTRY.lock() {
+        registry.kitty_ids.remove(&(id, is_tmux));
+    }
+}
+
+// Delete a Kitty image with the next transmission, or take_dropped_sequence.
+pub(crate) fn queue_kitty_deletion(id: u32, is_tmux: bool) {
+    unregister_kitty(id, is_tmux);
+    match DROPPED.lock() {
+        Ok(mut dropped) => dropped.push((id, is_tmux)),
+        Err(poisoned) => poisoned.into_inner().push((id, is_tmux)),
+    }
+}
+
+pub(crate) fn register_sixel(is_tmux: bool) {
+    if ENABLED.load(Ordering::Relaxed) {
+        if let Ok(mut registry) = REGISTRY.lock() {
+            registry.sixel = Some(is_tmux);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, ImageBuffer, Rgba};
+    use ratatui::{buffer::Buffer, layout::Rect};
+
+    use crate::protocol::{
+        kitty::{Kitty, KittyOptions},
+        Protocol,
+    };
+
+    use super::{enable, take_cleanup_sequence, take_dropped_sequence};
+
+    #[test]
+    fn test_cleanup_sequence() {
+        enable();
+        let image: DynamicImage =
+            ImageBuffer::from_pixel(2, 4, Rgba::<u8>([255, 0, 0, 255])).into();
+        Kitty::new(image.clone(), Rect::new(0, 0, 1, 1), 0xc1ea, false).unwrap();
+        Kitty::new(image, Rect::new(0, 0, 1, 1), 0xc1eb, true).unwrap();
+
+        let seq = take_cleanup_sequence();
+        assert!(seq.contains("\x1b_Ga=d,d=I,i=49642,q=2\x1b\\"));
+        assert!(seq.contains("\x1bPtmux;\x1b\x1b_Ga=d,d=I,i=49643,q=2\x1b\x1b\\\x1b\\"));
+        assert!(!take_cleanup_sequence().contains("i=49642"));
+    }
+
+    #[test]
+    fn test_delete_on_drop() {
+        let image: DynamicImage =
+            ImageBuffer::from_pixel(2, 4, Rgba::<u8>([255, 0, 0, 255])).into();
+        let options = KittyOptions::new().delete_on_drop(true);
+        let area = Rect::new(0, 0, 1, 1);
+        let kitty = Kitty::with_transmit_options(image.clone(), area, 0xd0d0, false, options);
+        let kitty = kitty.unwrap();
+        let clone = kitty.clone();
+        drop(kitty);
+        assert!(!take_dropped_sequence().contains("i=53456"));
+        drop(clone);
+
+        // Sent along with the next transmission.
+        let next = Kitty::with_transmit_options(image, area, 0xd0d1, false, options).unwrap();
+        assert_eq!(next.delete_sequence(), "\x1b_Ga=d,d=I,i=53457,q=2\x1b\\");
+        let mut buf = Buffer::empty(area);
+        Protocol::Kitty(next).render(area, &mut buf);
+        assert!(buf[(0, 0)]
+            .symbol()
+            .starts_with("\x1b_Ga=d,d=I,i=53456,q=2\x1b\\\x1b_Gq=2,i=53457,a=T,"));
+        assert!(!take_dropped_sequence().contains("i=53456"));
+    }
+}
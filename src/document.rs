@@ -0,0 +1,481 @@
+//! Helpers for documents that embed images, such as markdown or HTML previews.
+//!
+//! [find_image_refs] scans a document for image references, and [DocumentImages] keeps a pool of
+//! [ThreadProtocol]s keyed by the resolved path, loading the images that are near the viewport
+//! and dropping the ones that are far away.
+//!
+//! The pool does not spawn any threads, and does not decode on the caller's thread either. The
+//! images to decode and the resize requests of its [ThreadProtocol]s are collected with
+//! [DocumentImages::take_requests], and should be sent to a worker thread just like in
+//! `examples/async.rs`. The worker results must be handed back with [DocumentImages::set_image]
+//! and [DocumentImages::set_protocol].
+
+use std::{
+    collections::{HashMap, HashSet},
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::mpsc::{self, Receiver},
+};
+
+use image::{DynamicImage, ImageResult};
+use ratatui::layout::Rect;
+
+use crate::{picker::Picker, protocol::StatefulProtocol, thread::ThreadProtocol, Resize};
+
+/// An image reference found in a document.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageRef {
+    /// Zero-based line of the document where the reference starts.
+    pub line: usize,
+    /// The alt text, empty if there was none.
+    pub alt: String,
+    /// The source as written in the document, not resolved.
+    pub src: String,
+}
+
+/// Find markdown `![alt](src "title")` and HTML `<img src="...">` image references.
+///
+/// HTML tags may span several lines, and are reported at the line where they start.
+/// Reference-style markdown images are not supported.
+pub fn find_image_refs(text: &str) -> Vec<ImageRef> {
+    // The references with their byte offset in the text, to keep them in document order.
+    let mut found: Vec<(usize, ImageRef)> = vec![];
+    let mut line_start = 0;
+    for (line, content) in text.split_inclusive('\n').enumerate() {
+        let trimmed = content.trim_end_matches(['\n', '\r']);
+        find_markdown_refs(line, line_start, trimmed, &mut found);
+        line_start += content.len();
+    }
+    find_html_refs(text, &mut found);
+    found.sort_by_key(|(offset, _)| *offset);
+    found.into_iter().map(|(_, image_ref)| image_ref).collect()
+}
+
+fn find_markdown_refs(
+    line: usize,
+    line_start: usize,
+    content: &str,
+    found: &mut Vec<(usize, ImageRef)>,
+) {
+    let mut rest = content;
+    let mut offset = line_start;
+    while let Some(start) = rest.find("![") {
+        let after_bang = &rest[start + 2..];
+        let Some(alt_end) = after_bang.find("](") else {
+            break;
+        };
+        let target = &after_bang[alt_end + 2..];
+        let Some(target_end) = target.find(')') else {
+            break;
+        };
+        let src = markdown_target_src(&target[..target_end]);
+        if !src.is_empty() {
+            found.push((
+                offset + start,
+                ImageRef {
+                    line,
+                    alt: after_bang[..alt_end].to_string(),
+                    src: src.to_string(),
+                },
+            ));
+        }
+        let consumed = start + 2 + alt_end + 2 + target_end + 1;
+        offset += consumed;
+        rest = &rest[consumed..];
+    }
+}
+
+// The target may be `<path with spaces>`, and may be followed by a title.
+fn markdown_target_src(target: &str) -> &str {
+    let target = target.trim();
+    if let Some(bracketed) = target.strip_prefix('<') {
+        return bracketed.split('>').next().unwrap_or_default();
+    }
+    target.split_whitespace().next().unwrap_or_default()
+}
+
+fn find_html_refs(content: &str, found: &mut Vec<(usize, ImageRef)>) {
+    // ASCII lowercasing keeps byte offsets intact.
+    let lower = content.to_ascii_lowercase();
+    let mut offset = 0;
+    while let Some(start) = lower[offset..].find("<img") {
+        let tag_start = offset + start;
+        let tag_end = lower[tag_start..]
+            .find('>')
+            .map_or(content.len(), |end| tag_start + end);
+        let tag = &content[tag_start..tag_end];
+        if let Some(src) = html_attribute(tag, "src") {
+            found.push((
+                tag_start,
+                ImageRef {
+                    line: content[..tag_start].matches('\n').count(),
+                    alt: html_attribute(tag, "alt").unwrap_or_default().to_string(),
+                    src: src.to_string(),
+                },
+            ));
+        }
+        offset = tag_end;
+    }
+}
+
+fn html_attribute<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    let lower = tag.to_ascii_lowercase();
+    let mut offset = 0;
+    while let Some(start) = lower[offset..].find(name) {
+        let name_start = offset + start;
+        offset = name_start + name.len();
+        // Must be a whole attribute name, e.g. not `data-src`.
+        if !lower[..name_start].ends_with(char::is_whitespace) {
+            continue;
+        }
+        // A valueless attribute, or a longer name such as `srcset`.
+        let Some(value) = tag[offset..].trim_start().strip_prefix('=') else {
+            continue;
+        };
+        let value = value.trim_start();
+        return match value.chars().next()? {
+            quote @ ('"' | '\'') => value[1..].split(quote).next(),
+            _ => value.split(|c: char| c.is_whitespace() || c == '/').next(),
+        };
+    }
+    None
+}
+
+/// Resolve an image source relative to the document's directory.
+///
+/// Returns `None` for remote sources (`http://`, `data:`, ...), which are not loaded.
+pub fn resolve_path(base_dir: &Path, src: &str) -> Option<PathBuf> {
+    if src.is_empty() || src.contains("://") || src.starts_with("data:") {
+        return None;
+    }
+    let src = src.strip_prefix("file:").unwrap_or(src);
+    let path = Path::new(src);
+    if path.is_absolute() {
+        Some(path.to_path_buf())
+    } else {
+        Some(base_dir.join(path))
+    }
+}
+
+/// Work for a worker thread, see [DocumentImages::take_requests].
+pub enum DocumentRequest {
+    /// Decode the image, e.g. with `image::open`, and hand it back with
+    /// [DocumentImages::set_image].
+    Decode(PathBuf),
+    /// Resize and encode the protocol of a pooled image, and hand it back with
+    /// [DocumentImages::set_protocol].
+    Resize(PathBuf, Box<StatefulProtocol>, Resize, Rect),
+}
+
+struct PoolEntry {
+    protocol: ThreadProtocol,
+    rx: Receiver<(StatefulProtocol, Resize, Rect)>,
+}
+
+/// A pool of [ThreadProtocol]s for the images referenced by a document.
+///
+/// Call [DocumentImages::prefetch] with the visible line range whenever the document scrolls.
+pub struct DocumentImages {
+    picker: Picker,
+    base_dir: PathBuf,
+    refs: Vec<ImageRef>,
+    margin: usize,
+    pool: HashMap<PathBuf, PoolEntry>,
+    // Images that are near the viewport but not decoded yet, and those of them that have not been
+    // handed out by take_requests yet.
+    loading: HashSet<PathBuf>,
+    queued: Vec<PathBuf>,
+    failed: HashSet<PathBuf>,
+}
+
+impl DocumentImages {
+    /// Scan `text` for image references, resolving relative paths against `base_dir`.
+    pub fn new(picker: Picker, base_dir: impl Into<PathBuf>, text: &str) -> DocumentImages {
+        DocumentImages {
+            picker,
+            base_dir: base_dir.into(),
+            refs: find_image_refs(text),
+            margin: 20,
+            pool: HashMap::new(),
+            loading: HashSet::new(),
+            queued: vec![],
+            failed: HashSet::new(),
+        }
+    }
+
+    /// Set how many lines above and below the viewport should be prefetched (default 20).
+    ///
+    /// Images further away than twice the margin are dropped from the pool.
+    pub fn margin(mut self, margin: usize) -> DocumentImages {
+        self.margin = margin;
+        self
+    }
+
+    /// Replace the document text, keeping already loaded images that are still referenced.
+    pub fn set_text(&mut self, text: &str) {
+        self.refs = find_image_refs(text);
+        let paths: HashSet<PathBuf> = self.paths(0..usize::MAX).collect();
+        self.pool.retain(|path, _| paths.contains(path));
+        self.loading.retain(|path| paths.contains(path));
+        self.queued.retain(|path| paths.contains(path));
+        self.failed.clear();
+    }
+
+    /// The image references of the document, in document order.
+    pub fn refs(&self) -> &[ImageRef] {
+        &self.refs
+    }
+
+    /// The resolved path of an image reference, if it is a local file.
+    pub fn path(&self, image_ref: &ImageRef) -> Option<PathBuf> {
+        resolve_path(&self.base_dir, &image_ref.src)
+    }
+
+    /// Queue the images near `viewport` (a range of lines) for decoding, and drop images far away
+    /// from it.
+    ///
+    /// The images are decoded by the worker, see [DocumentImages::take_requests]. Images that
+    /// failed to decode are not retried until the text is replaced with
+    /// [DocumentImages::set_text].
+    pub fn prefetch(&mut self, viewport: Range<usize>) {
+        let keep = viewport.start.saturating_sub(self.margin * 2)
+            ..viewport.end.saturating_add(self.margin * 2);
+        let keep: HashSet<PathBuf> = self.paths(keep).collect();
+        self.pool.retain(|path, _| keep.contains(path));
+        self.loading.retain(|path| keep.contains(path));
+        self.queued.retain(|path| keep.contains(path));
+
+        let load =
+            viewport.start.saturating_sub(self.margin)..viewport.end.saturating_add(self.margin);
+        let load: Vec<PathBuf> = self.paths(load).collect();
+        for path in load {
+            if self.pool.contains_key(&path)
+                || self.failed.contains(&path)
+                || !self.loading.insert(path.clone())
+            {
+                continue;
+            }
+            self.queued.push(path);
+        }
+    }
+
+    /// Hand back a decoded image, or the error if it could not be decoded. It is discarded if the
+    /// image is not near the viewport anymore.
+    pub fn set_image(&mut self, path: &Path, image: ImageResult<DynamicImage>) {
+        if !self.loading.remove(path) {
+            return;
+        }
+        match image {
+            Ok(image) => {
+                let (tx, rx) = mpsc::channel();
+                let protocol = ThreadProtocol::new(tx, self.picker.new_resize_protocol(image));
+                self.pool
+                    .insert(path.to_path_buf(), PoolEntry { protocol, rx });
+            }
+            Err(_) => {
+                self.failed.insert(path.to_path_buf());
+            }
+        }
+    }
+
+    /// Get the [ThreadProtocol] of a loaded image, to render it with a [crate::thread::ThreadImage].
+    pub fn get_mut(&mut self, path: &Path) -> Option<&mut ThreadProtocol> {
+        self.pool.get_mut(path).map(|entry| &mut entry.protocol)
+    }
+
+    /// Whether the image at `path` is loaded in the pool.
+    pub fn is_loaded(&self, path: &Path) -> bool {
+        self.pool.contains_key(path)
+    }
+
+    /// Collect the images to decode since the last [DocumentImages::prefetch], and the resize
+    /// requests that the pooled protocols made while rendering.
+    ///
+    /// Each request should be handled on a worker thread, and the result returned with
+    /// [DocumentImages::set_image] or [DocumentImages::set_protocol].
+    pub fn take_requests(&mut self) -> Vec<DocumentRequest> {
+        let mut requests: Vec<DocumentRequest> =
+            self.queued.drain(..).map(DocumentRequest::Decode).collect();
+        for (path, entry) in &self.pool {
+            while let Ok((protocol, resize, area)) = entry.rx.try_recv() {
+                requests.push(DocumentRequest::Resize(
+                    path.clone(),
+                    Box::new(protocol),
+                    resize,
+                    area,
+                ));
+            }
+        }
+        requests
+    }
+
+    /// Hand back a resized and encoded protocol. It is discarded if the image has been dropped
+    /// from the pool in the meantime.
+    pub fn set_protocol(&mut self, path: &Path, protocol: StatefulProtocol) {
+        if let Some(entry) = self.pool.get_mut(path) {
+            entry.protocol.set_protocol(protocol);
+        }
+    }
+
+    fn paths(&self, lines: Range<usize>) -> impl Iterator<Item = PathBuf> + '_ {
+        self.refs
+            .iter()
+            .filter(move |image_ref| lines.contains(&image_ref.line))
+            .filter_map(|image_ref| self.path(image_ref))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io,
+        path::{Path, PathBuf},
+    };
+
+    use image::{DynamicImage, ImageError};
+
+    use super::{find_image_refs, resolve_path, DocumentImages, DocumentRequest, ImageRef};
+    use crate::picker::{Picker, ProtocolType};
+
+    fn r(line: usize, alt: &str, src: &str) -> ImageRef {
+        ImageRef {
+            line,
+            alt: alt.to_string(),
+            src: src.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_find_image_refs() {
+        for (name, text, expected) in [
+            ("none", "# Title\n\nsome [link](x.png)", vec![]),
+            (
+                "markdown",
+                "intro\n![Ada](./assets/Ada.png)",
+                vec![r(1, "Ada", "./assets/Ada.png")],
+            ),
+            (
+                "markdown title and brackets",
+                "![a](a.png \"title\") and ![b](<b c.png>)",
+                vec![r(0, "a", "a.png"), r(0, "b", "b c.png")],
+            ),
+            (
+                "html",
+                "<p><IMG alt='x' data-src=\"no.png\" src=\"yes.png\"/></p>",
+                vec![r(0, "x", "yes.png")],
+            ),
+            (
+                "mixed order",
+                "<img src=a.png> ![b](b.png)",
+                vec![r(0, "", "a.png"), r(0, "b", "b.png")],
+            ),
+            (
+                "html valueless and longer attribute names",
+                "<img alt srcset=\"a.png 2x\" src=b.png>",
+                vec![r(0, "", "b.png")],
+            ),
+            (
+                "html multiline",
+                "![a](a.png)\r\n<img\n  alt=\"b\"\n  src=\"b.png\">\n![c](c.png)",
+                vec![r(0, "a", "a.png"), r(1, "b", "b.png"), r(4, "c", "c.png")],
+            ),
+            ("unterminated", "![broken](", vec![]),
+        ] {
+            assert_eq!(find_image_refs(text), expected, "{name}");
+        }
+    }
+
+    #[test]
+    fn test_resolve_path() {
+        let base = Path::new("/docs");
+        assert_eq!(
+            resolve_path(base, "img/a.png"),
+            Some(PathBuf::from("/docs/img/a.png"))
+        );
+        assert_eq!(
+            resolve_path(base, "/abs.png"),
+            Some(PathBuf::from("/abs.png"))
+        );
+        assert_eq!(resolve_path(base, "https://example.com/a.png"), None);
+        assert_eq!(resolve_path(base, "data:image/png;base64,AAAA"), None);
+    }
+
+    fn images(text: &str) -> DocumentImages {
+        let mut picker = Picker::from_fontsize((10, 20));
+        picker.set_protocol_type(ProtocolType::Halfblocks);
+        DocumentImages::new(picker, "/docs", text).margin(10)
+    }
+
+    // The paths to decode, in the order they were queued.
+    fn decodes(images: &mut DocumentImages) -> Vec<PathBuf> {
+        images
+            .take_requests()
+            .into_iter()
+            .filter_map(|request| match request {
+                DocumentRequest::Decode(path) => Some(path),
+                DocumentRequest::Resize(..) => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_prefetch_margins() {
+        let text = format!(
+            "![a](a.png)\n{}![b](b.png)\n{}![c](c.png)\n",
+            "\n".repeat(11),
+            "\n".repeat(12),
+        );
+        let mut images = images(&text);
+        let [a, b, c] = ["a", "b", "c"].map(|name| PathBuf::from(format!("/docs/{name}.png")));
+
+        // a (line 0) and b (line 12) are within the load margin, c (line 25) is not.
+        images.prefetch(0..5);
+        assert_eq!(decodes(&mut images), [a.clone(), b.clone()]);
+        assert!(!images.is_loaded(&a));
+        assert!(images.get_mut(&a).is_none());
+        images.set_image(&a, Ok(DynamicImage::new_rgb8(20, 20)));
+        images.set_image(&b, Ok(DynamicImage::new_rgb8(20, 20)));
+        assert!(images.is_loaded(&a));
+        assert!(images.get_mut(&b).is_some());
+
+        // Within the keep margin, a and b stay, and only c is queued.
+        images.prefetch(20..25);
+        assert_eq!(decodes(&mut images), vec![c.clone()]);
+        assert!(images.is_loaded(&a));
+
+        // Out of the keep margin, a and b are dropped, and the late result for c is still taken.
+        images.prefetch(40..45);
+        assert!(!images.is_loaded(&a) && !images.is_loaded(&b));
+        images.set_image(&c, Ok(DynamicImage::new_rgb8(20, 20)));
+        assert!(images.get_mut(&c).is_some());
+
+        // A result for an image that went out of range in the meantime is discarded.
+        images.prefetch(0..5);
+        assert_eq!(decodes(&mut images), [a.clone(), b.clone()]);
+        images.prefetch(100..105);
+        images.set_image(&a, Ok(DynamicImage::new_rgb8(20, 20)));
+        assert!(!images.is_loaded(&a));
+    }
+
+    #[test]
+    fn test_prefetch_failed() {
+        let text = "![a](a.png)";
+        let mut images = images(text);
+        let a = PathBuf::from("/docs/a.png");
+
+        images.prefetch(0..1);
+        assert_eq!(decodes(&mut images), vec![a.clone()]);
+        let error = ImageError::IoError(io::Error::new(io::ErrorKind::NotFound, "a.png"));
+        images.set_image(&a, Err(error));
+        assert!(!images.is_loaded(&a));
+
+        // Not retried.
+        images.prefetch(0..1);
+        assert!(decodes(&mut images).is_empty());
+
+        // Until the text is replaced.
+        images.set_text(text);
+        images.prefetch(0..1);
+        assert_eq!(decodes(&mut images), [a]);
+    }
+}
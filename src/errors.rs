@@ -6,8 +6,12 @@ pub enum Errors {
     NoCap,
     #[error("No response from stdin")]
     NoStdinResponse,
+    #[error("Timed out waiting for the rest of the query responses")]
+    QueryTimeout,
     #[error("Sixel error: {0}")]
     Sixel(String),
+    #[error("Kitty error: {0}")]
+    Kitty(String),
     #[error("Tmux error: {0}")]
     Tmux(&'static str),
     #[error("IO error: {0}")]
@@ -0,0 +1,157 @@
+//! Detecting font size changes without escape queries.
+//!
+//! The font size of a [crate::picker::Picker] is queried once, but users zoom the terminal. A
+//! [FontSizeWatcher] cheaply re-reads the window size in pixels and cells from the tty
+//! (`TIOCGWINSZ`, or the console font on Windows), and reports when the cell size drifted:
+//!
+//! ```rust
+//! # use std::time::Duration;
+//! # use ratatui_image::{font_watch::FontSizeWatcher, picker::Picker};
+//! let picker = Picker::from_fontsize((8, 16));
+//! let mut watcher = picker.font_size_watcher().interval(Duration::from_secs(2));
+//! // On every tick or resize event:
+//! if let Some(font_size) = watcher.poll() {
+//!     // Create a new picker with the font size, and adopt the protocols.
+//! }
+//! ```
+//!
+//! Terminals differ in whether the pixel size includes padding, so the first reading is only a
+//! baseline. Later readings are compared to it, and the queried font size is scaled by the same
+//! ratio, instead of taking the padded size as the font size.
+
+use std::time::{Duration, Instant};
+
+use crate::{picker, reflow::ReflowTracker, FontSize};
+
+/// Watches the cell size of the terminal, see the [module docs](self).
+#[derive(Clone, Debug)]
+pub struct FontSizeWatcher {
+    // The font size that the baseline reading corresponds to.
+    initial: FontSize,
+    font_size: FontSize,
+    // The first reading of the tty, and the last one.
+    baseline: Option<FontSize>,
+    last: Option<FontSize>,
+    interval: Duration,
+    last_check: Option<Instant>,
+    tracker: Option<ReflowTracker>,
+}
+
+impl FontSizeWatcher {
+    /// Watch from a known font size, e.g. [crate::picker::Picker::font_size].
+    pub fn new(font_size: FontSize) -> FontSizeWatcher {
+        FontSizeWatcher {
+            initial: font_size,
+            font_size,
+            baseline: None,
+            last: None,
+            interval: Duration::from_secs(1),
+            last_check: None,
+            tracker: None,
+        }
+    }
+
+    /// How often [FontSizeWatcher::poll] reads the tty (default every second).
+    pub fn interval(mut self, interval: Duration) -> FontSizeWatcher {
+        self.interval = interval;
+        self
+    }
+
+    /// Mark the protocols of the tracker as stale when the font size changes, see
+    /// [crate::reflow].
+    pub fn reflow_tracker(mut self, tracker: Option<ReflowTracker>) -> FontSizeWatcher {
+        self.tracker = tracker;
+        self
+    }
+
+    /// The current font size, the initial one or the last detected change.
+    pub fn font_size(&self) -> FontSize {
+        self.font_size
+    }
+
+    /// Read the tty if the interval elapsed since the last check, and return the new font size
+    /// if it changed.
+    pub fn poll(&mut self) -> Option<FontSize> {
+        if self
+            .last_check
+            .is_some_and(|last| last.elapsed() < self.interval)
+        {
+            return None;
+        }
+        self.check()
+    }
+
+    /// Read the tty now, and return the new font size if it changed.
+    pub fn check(&mut self) -> Option<FontSize> {
+        self.last_check = Some(Instant::now());
+        self.update(picker::font_size_fallback()?)
+    }
+
+    /// Record a cell size that was measured by other means, e.g. from a resize event with pixel
+    /// sizes. Returns the new font size if it changed since the last reading: the initial font
+    /// size, scaled by the measured size relative to the first reading.
+    ///
+    /// The first reading is only recorded as the baseline.
+    pub fn update(&mut self, measured: FontSize) -> Option<FontSize> {
+        if measured.0 == 0 || measured.1 == 0 {
+            return None;
+        }
+        let baseline = *self.baseline.get_or_insert(measured);
+        if self.last.replace(measured).unwrap_or(measured) == measured {
+            return None;
+        }
+        let scale = |initial: u16, measured: u16, baseline: u16| {
+            let (initial, measured, baseline) = (initial as u32, measured as u32, baseline as u32);
+            ((initial * measured + baseline / 2) / baseline).clamp(1, u16::MAX as u32) as u16
+        };
+        let font_size = (
+            scale(self.initial.0, measured.0, baseline.0),
+            scale(self.initial.1, measured.1, baseline.1),
+        );
+        if font_size == self.font_size {
+            return None;
+        }
+        self.font_size = font_size;
+        if let Some(tracker) = &self.tracker {
+            tracker.invalidate();
+        }
+        Some(font_size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::FontSizeWatcher;
+    use crate::reflow::ReflowTracker;
+
+    #[test]
+    fn test_font_size_watcher() {
+        let tracker = ReflowTracker::new();
+        let mut watcher = FontSizeWatcher::new((8, 16))
+            .interval(Duration::from_secs(60))
+            .reflow_tracker(Some(tracker.clone()));
+        // The first reading is a baseline, even if it differs from the queried size, e.g.
+        // because the terminal includes padding.
+        assert_eq!(watcher.update((10, 20)), None);
+        assert_eq!(watcher.update((10, 20)), None);
+        assert_eq!(watcher.update((0, 0)), None);
+        assert_eq!(watcher.font_size(), (8, 16));
+        assert_eq!(tracker.generation(), 0);
+
+        // Zoomed in by half, which scales the queried size and not the padded one.
+        assert_eq!(watcher.update((15, 30)), Some((12, 24)));
+        assert_eq!(watcher.font_size(), (12, 24));
+        assert_eq!(tracker.generation(), 1);
+        assert_eq!(watcher.update((15, 30)), None);
+
+        // Back to the baseline.
+        assert_eq!(watcher.update((10, 20)), Some((8, 16)));
+        assert_eq!(tracker.generation(), 2);
+
+        // Polling within the interval does not read the tty.
+        watcher.check();
+        assert_eq!(watcher.poll(), None);
+    }
+}
@@ -0,0 +1,264 @@
+//! Tiny images as scaled glyphs, for terminals without graphics but with text sizing.
+//!
+//! The text sizing protocol (OSC 66) can draw a character several cells wide and tall. Without
+//! any graphics protocol, an icon-sized image can then be approximated by a few scaled colored
+//! glyphs, like `🟥` or `🟦`, which is coarser than halfblocks but reads well at a glance.
+//! Terminals without text sizing render nothing, see [GlyphProtocol::is_supported].
+//!
+//! ```rust
+//! # use ratatui::Frame;
+//! # use ratatui_image::glyphs::{GlyphImage, GlyphProtocol};
+//! fn ui(f: &mut Frame<'_>, icon: &GlyphProtocol) {
+//!     f.render_widget(GlyphImage::new(icon), f.area());
+//! }
+//! ```
+
+use image::{imageops::FilterType, DynamicImage, Rgb};
+use ratatui::{buffer::Buffer, layout::Rect, widgets::Widget};
+
+use crate::FontSize;
+
+/// The glyphs and their scale for a [GlyphProtocol].
+#[derive(PartialEq, Clone, Debug)]
+pub struct GlyphOptions {
+    scale: u8,
+    glyphs: Vec<(String, Rgb<u8>)>,
+}
+
+impl Default for GlyphOptions {
+    fn default() -> Self {
+        let glyphs = [
+            ("🟥", [221, 46, 68]),
+            ("🟧", [244, 144, 12]),
+            ("🟨", [253, 203, 88]),
+            ("🟩", [120, 177, 89]),
+            ("🟦", [85, 172, 238]),
+            ("🟪", [170, 142, 214]),
+            ("🟫", [193, 105, 79]),
+            ("⬛", [49, 55, 61]),
+            ("⬜", [230, 231, 232]),
+        ];
+        GlyphOptions {
+            scale: 2,
+            glyphs: glyphs
+                .into_iter()
+                .map(|(glyph, color)| (glyph.to_string(), Rgb(color)))
+                .collect(),
+        }
+    }
+}
+
+impl GlyphOptions {
+    pub fn new() -> GlyphOptions {
+        GlyphOptions::default()
+    }
+
+    /// Scale of each glyph within `1..=7` (default 2), so that it covers `2 * scale` columns and
+    /// `scale` rows.
+    pub fn scale(mut self, scale: u8) -> GlyphOptions {
+        self.scale = scale.clamp(1, 7);
+        self
+    }
+
+    /// The glyphs to pick from by the closest color, instead of the colored squares. Each glyph
+    /// must be two cells wide, like emoji.
+    pub fn glyphs<S: Into<String>>(
+        mut self,
+        glyphs: impl IntoIterator<Item = (S, Rgb<u8>)>,
+    ) -> GlyphOptions {
+        self.glyphs = glyphs
+            .into_iter()
+            .map(|(glyph, color)| (glyph.into(), color))
+            .collect();
+        self
+    }
+}
+
+/// A tiny image encoded as scaled glyphs, see [crate::picker::Picker::new_glyph_protocol].
+#[derive(Clone, Debug)]
+pub struct GlyphProtocol {
+    // One sequence per glyph, row by row, or None where the image is transparent.
+    glyphs: Vec<Option<String>>,
+    columns: u16,
+    scale: u16,
+    area: Rect,
+    supported: bool,
+}
+
+impl GlyphProtocol {
+    pub(crate) fn new(
+        image: &DynamicImage,
+        size: Rect,
+        font_size: FontSize,
+        options: &GlyphOptions,
+        supported: bool,
+    ) -> GlyphProtocol {
+        let scale = options.scale as u16;
+        let (max_columns, max_rows) = (size.width / (2 * scale), size.height / scale);
+        if !supported || max_columns == 0 || max_rows == 0 || options.glyphs.is_empty() {
+            return GlyphProtocol {
+                glyphs: vec![],
+                columns: 0,
+                scale,
+                area: Rect::default(),
+                supported,
+            };
+        }
+
+        // Fit the image into whole glyphs, then each pixel is the average color of one glyph.
+        let glyph_width = 2.0 * scale as f64 * font_size.0 as f64;
+        let glyph_height = scale as f64 * font_size.1 as f64;
+        let (width, height) = (image.width().max(1) as f64, image.height().max(1) as f64);
+        let fit =
+            (max_columns as f64 * glyph_width / width).min(max_rows as f64 * glyph_height / height);
+        let columns = ((width * fit / glyph_width).round() as u16).clamp(1, max_columns);
+        let rows = ((height * fit / glyph_height).round() as u16).clamp(1, max_rows);
+        let pixels = image
+            .resize_exact(columns as u32, rows as u32, FilterType::Triangle)
+            .to_rgba8();
+
+        let glyphs = pixels
+            .pixels()
+            .map(|pixel| {
+                let [r, g, b, a] = pixel.0;
+                if a < 128 {
+                    return None;
+                }
+                let distance = |Rgb(color): &Rgb<u8>| {
+                    [r, g, b]
+                        .iter()
+                        .zip(color)
+                        .map(|(a, b)| (*a as i32 - *b as i32).pow(2))
+                        .sum::<i32>()
+                };
+                options
+                    .glyphs
+                    .iter()
+                    .min_by_key(|(_, color)| distance(color))
+                    .map(|(glyph, _)| format!("\x1b]66;s={scale};{glyph}\x1b\\"))
+            })
+            .collect();
+
+        GlyphProtocol {
+            glyphs,
+            columns,
+            scale,
+            area: Rect::new(0, 0, columns * 2 * scale, rows * scale),
+            supported,
+        }
+    }
+
+    /// Whether the terminal supports text sizing. Otherwise nothing is rendered.
+    pub fn is_supported(&self) -> bool {
+        self.supported
+    }
+
+    /// The area in cells covered by the glyphs.
+    pub fn area(&self) -> Rect {
+        self.area
+    }
+}
+
+/// Widget that renders a [GlyphProtocol].
+///
+/// Like [crate::Image], nothing is rendered if the area is too small.
+pub struct GlyphImage<'a> {
+    protocol: &'a GlyphProtocol,
+}
+
+impl<'a> GlyphImage<'a> {
+    pub fn new(protocol: &'a GlyphProtocol) -> GlyphImage<'a> {
+        GlyphImage { protocol }
+    }
+}
+
+impl Widget for GlyphImage<'_> {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        let protocol = self.protocol;
+        let area = area.intersection(buf.area);
+        if protocol.glyphs.is_empty()
+            || area.width < protocol.area.width
+            || area.height < protocol.area.height
+        {
+            return;
+        }
+        let (width, height) = (2 * protocol.scale, protocol.scale);
+        for (i, glyph) in protocol.glyphs.iter().enumerate() {
+            let Some(glyph) = glyph else {
+                continue;
+            };
+            let i = i as u16;
+            let x = area.x + i % protocol.columns * width;
+            let y = area.y + i / protocol.columns * height;
+            // The terminal draws the glyph over all the cells of the block.
+            for dy in 0..height {
+                for dx in 0..width {
+                    buf[(x + dx, y + dy)].set_skip(true);
+                }
+            }
+            buf[(x, y)].set_symbol(glyph).set_skip(false);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, ImageBuffer, Rgba};
+    use ratatui::{buffer::Buffer, layout::Rect, widgets::Widget};
+
+    use super::{GlyphImage, GlyphOptions};
+    use crate::picker::Picker;
+
+    #[test]
+    fn test_glyph_image() {
+        let image: DynamicImage = ImageBuffer::from_fn(80, 40, |x, _| {
+            if x < 40 {
+                Rgba::<u8>([255, 0, 0, 255])
+            } else {
+                Rgba([80, 170, 240, 255])
+            }
+        })
+        .into();
+        let mut picker = Picker::from_fontsize((10, 20));
+        let size = Rect::new(0, 0, 9, 3);
+
+        picker.set_text_sizing(false);
+        let glyphs = picker.new_glyph_protocol(image.clone(), size, &GlyphOptions::new());
+        assert!(!glyphs.is_supported());
+        let mut buf = Buffer::empty(size);
+        GlyphImage::new(&glyphs).render(size, &mut buf);
+        assert_eq!(buf, Buffer::empty(size));
+
+        picker.set_text_sizing(true);
+        let glyphs = picker.new_glyph_protocol(image, size, &GlyphOptions::new());
+        assert_eq!(glyphs.area(), Rect::new(0, 0, 8, 2));
+        let mut buf = Buffer::empty(size);
+        GlyphImage::new(&glyphs).render(size, &mut buf);
+        assert_eq!(buf[(0, 0)].symbol(), "\x1b]66;s=2;🟥\x1b\\");
+        assert_eq!(buf[(4, 0)].symbol(), "\x1b]66;s=2;🟦\x1b\\");
+        for (x, y) in [(1, 0), (3, 1), (5, 0), (7, 1)] {
+            assert!(buf[(x, y)].skip);
+        }
+        assert!(!buf[(8, 0)].skip && !buf[(0, 2)].skip);
+
+        // Too small to render.
+        let mut buf = Buffer::empty(size);
+        GlyphImage::new(&glyphs).render(Rect::new(0, 0, 7, 3), &mut buf);
+        assert_eq!(buf, Buffer::empty(size));
+
+        // Custom glyphs and scale.
+        let options = GlyphOptions::new()
+            .scale(1)
+            .glyphs([("🍎", image::Rgb([255, 0, 0]))]);
+        let glyphs = picker.new_glyph_protocol(
+            ImageBuffer::from_pixel(20, 20, Rgba::<u8>([0, 255, 0, 255])).into(),
+            size,
+            &options,
+        );
+        // Scaled up to fill the size.
+        assert_eq!(glyphs.area(), Rect::new(0, 0, 6, 3));
+        let mut buf = Buffer::empty(size);
+        GlyphImage::new(&glyphs).render(size, &mut buf);
+        assert_eq!(buf[(0, 0)].symbol(), "\x1b]66;s=1;🍎\x1b\\");
+    }
+}
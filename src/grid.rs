@@ -0,0 +1,281 @@
+//! Virtualized image grids, e.g. a thumbnail browser with thousands of items.
+//!
+//! A [VirtualGrid] only holds protocols for the items in the viewport and a prefetch margin
+//! around it, so memory and encoding work stay proportional to the viewport instead of the number
+//! of items:
+//!
+//! ```rust
+//! # use ratatui::Frame;
+//! # use ratatui_image::grid::{ImageGrid, VirtualGrid};
+//! # fn load_thumbnail(index: usize) -> Option<image::DynamicImage> { None }
+//! fn ui(f: &mut Frame<'_>, grid: &mut VirtualGrid, scroll: usize) {
+//!     grid.update(10_000, scroll, f.area(), load_thumbnail);
+//!     f.render_stateful_widget(ImageGrid, f.area(), grid);
+//! }
+//! ```
+
+use std::{collections::BTreeMap, ops::Range};
+
+use image::DynamicImage;
+use ratatui::{buffer::Buffer, layout::Rect, widgets::StatefulWidget};
+
+use crate::{picker::Picker, protocol::StatefulProtocol, Resize, ResizeEncodeRender};
+
+/// The protocols of the visible items of a grid, see the [module docs](self).
+pub struct VirtualGrid {
+    picker: Picker,
+    item_size: (u16, u16),
+    prefetch_rows: usize,
+    resize: Resize,
+    protocols: BTreeMap<usize, StatefulProtocol>,
+    // The item count, scroll offset and viewport of the last update.
+    layout: Option<(usize, usize, Rect)>,
+}
+
+impl VirtualGrid {
+    /// Create an empty grid of items that are `item_size` cells (width, height) each.
+    pub fn new(picker: Picker, item_size: (u16, u16)) -> VirtualGrid {
+        VirtualGrid {
+            picker,
+            item_size: (item_size.0.max(1), item_size.1.max(1)),
+            prefetch_rows: 1,
+            resize: Resize::Fit(None),
+            protocols: BTreeMap::new(),
+            layout: None,
+        }
+    }
+
+    /// Keep this many rows of items above and below the viewport encoded (default 1).
+    pub fn prefetch_rows(mut self, prefetch_rows: usize) -> VirtualGrid {
+        self.prefetch_rows = prefetch_rows;
+        self
+    }
+
+    pub fn resize(mut self, resize: Resize) -> VirtualGrid {
+        self.resize = resize;
+        self
+    }
+
+    /// Number of items in each row of the viewport.
+    pub fn columns(&self, viewport: Rect) -> usize {
+        (viewport.width / self.item_size.0).max(1) as usize
+    }
+
+    /// The items that are at least partially visible with the scroll offset, in rows of cells.
+    pub fn visible(&self, item_count: usize, scroll: usize, viewport: Rect) -> Range<usize> {
+        self.items(item_count, scroll, viewport, 0)
+    }
+
+    // The items of the rows in the viewport, extended by `margin` rows on both sides.
+    fn items(
+        &self,
+        item_count: usize,
+        scroll: usize,
+        viewport: Rect,
+        margin: usize,
+    ) -> Range<usize> {
+        let columns = self.columns(viewport);
+        let item_height = self.item_size.1 as usize;
+        let first_row = (scroll / item_height).saturating_sub(margin);
+        let last_row = (scroll + viewport.height as usize).div_ceil(item_height) + margin;
+        (first_row * columns).min(item_count)..(last_row * columns).min(item_count)
+    }
+
+    /// Evict the protocols that went out of range, and create and encode the ones that came into
+    /// range, visible items first. `load` returns the image of an item, or `None` to skip it.
+    pub fn update(
+        &mut self,
+        item_count: usize,
+        scroll: usize,
+        viewport: Rect,
+        mut load: impl FnMut(usize) -> Option<DynamicImage>,
+    ) {
+        self.fill(item_count, scroll, viewport, |picker, index| {
+            load(index).map(|image| picker.new_resize_protocol(image))
+        });
+    }
+
+    /// Like [VirtualGrid::update], but `load` returns the protocol of an item, e.g. one that was
+    /// created and encoded on another thread with a clone of the grid's picker. Protocols that are
+    /// already encoded for [VirtualGrid::item_area] are not encoded again.
+    pub fn update_protocols(
+        &mut self,
+        item_count: usize,
+        scroll: usize,
+        viewport: Rect,
+        mut load: impl FnMut(usize) -> Option<StatefulProtocol>,
+    ) {
+        self.fill(item_count, scroll, viewport, |_, index| load(index));
+    }
+
+    /// The area of an item, at the origin.
+    pub fn item_area(&self) -> Rect {
+        Rect::new(0, 0, self.item_size.0, self.item_size.1)
+    }
+
+    fn fill(
+        &mut self,
+        item_count: usize,
+        scroll: usize,
+        viewport: Rect,
+        mut load: impl FnMut(&Picker, usize) -> Option<StatefulProtocol>,
+    ) {
+        let visible = self.visible(item_count, scroll, viewport);
+        let prefetch = self.items(item_count, scroll, viewport, self.prefetch_rows);
+        self.protocols.retain(|index, _| prefetch.contains(index));
+
+        let area = self.item_area();
+        let prefetched = (prefetch.start..visible.start)
+            .rev()
+            .chain(visible.end..prefetch.end);
+        for index in visible.clone().chain(prefetched) {
+            if self.protocols.contains_key(&index) {
+                continue;
+            }
+            let Some(mut protocol) = load(&self.picker, index) else {
+                continue;
+            };
+            if let Some(rect) = protocol.needs_resize(&self.resize, area) {
+                ResizeEncodeRender::resize_encode(&mut protocol, &self.resize, rect);
+            }
+            self.protocols.insert(index, protocol);
+        }
+        self.layout = Some((item_count, scroll, viewport));
+    }
+
+    /// The protocol of an item, if it is in range.
+    pub fn protocol_mut(&mut self, index: usize) -> Option<&mut StatefulProtocol> {
+        self.protocols.get_mut(&index)
+    }
+
+    /// Number of protocols that are currently held.
+    pub fn len(&self) -> usize {
+        self.protocols.len()
+    }
+
+    /// Whether no protocols are held.
+    pub fn is_empty(&self) -> bool {
+        self.protocols.is_empty()
+    }
+
+    /// Drop all protocols, e.g. after the items changed.
+    pub fn clear(&mut self) {
+        self.protocols.clear();
+    }
+}
+
+/// Widget that renders the items of a [VirtualGrid] at the scroll offset of its last
+/// [VirtualGrid::update].
+///
+/// Images cannot be cut, so only the items that are completely inside the area are rendered.
+pub struct ImageGrid;
+
+impl StatefulWidget for ImageGrid {
+    type State = VirtualGrid;
+    fn render(self, area: Rect, buf: &mut Buffer, grid: &mut Self::State) {
+        let Some((item_count, scroll, _)) = grid.layout else {
+            return;
+        };
+        let columns = grid.columns(area);
+        let (width, height) = grid.item_size;
+        for index in grid.visible(item_count, scroll, area) {
+            let y = (index / columns) * height as usize;
+            let x = (index % columns) as u16 * width;
+            if y < scroll || y + height as usize > scroll + area.height as usize {
+                continue;
+            }
+            let item = Rect::new(area.x + x, area.y + (y - scroll) as u16, width, height);
+            if let Some(protocol) = grid.protocols.get_mut(&index) {
+                ResizeEncodeRender::resize_encode_render(protocol, &grid.resize, item, buf);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use image::{DynamicImage, ImageBuffer, Rgba};
+    use ratatui::{buffer::Buffer, layout::Rect, style::Color, widgets::StatefulWidget};
+
+    use super::{ImageGrid, VirtualGrid};
+    use crate::{
+        picker::{Picker, ProtocolType},
+        Resize, ResizeEncodeRender,
+    };
+
+    #[test]
+    fn test_virtual_grid() {
+        let mut picker = Picker::from_fontsize((10, 20));
+        picker.set_protocol_type(ProtocolType::Halfblocks);
+        let mut grid = VirtualGrid::new(picker, (4, 2));
+        let image: DynamicImage =
+            ImageBuffer::from_pixel(40, 40, Rgba::<u8>([255, 0, 0, 255])).into();
+        let viewport = Rect::new(0, 0, 20, 6);
+        let loaded = RefCell::new(vec![]);
+        let load = |index| {
+            loaded.borrow_mut().push(index);
+            Some(image.clone())
+        };
+
+        // 5 columns and 3 rows are visible, and one more row is prefetched.
+        assert_eq!(grid.columns(viewport), 5);
+        grid.update(10_000, 0, viewport, load);
+        assert_eq!(grid.len(), 20);
+        assert_eq!(loaded.borrow()[..15], (0..15).collect::<Vec<_>>());
+
+        // Scrolled half a row: the rows are only partially visible.
+        assert_eq!(grid.visible(10_000, 201, viewport), 500..520);
+        loaded.borrow_mut().clear();
+        grid.update(10_000, 200, viewport, load);
+        assert_eq!(grid.visible(10_000, 200, viewport), 500..515);
+        assert_eq!(grid.len(), 25);
+        assert_eq!(loaded.borrow().len(), 25);
+        assert!(grid.protocol_mut(0).is_none());
+        assert!(grid.protocol_mut(495).is_some());
+
+        // Nothing to load at the same position.
+        loaded.borrow_mut().clear();
+        grid.update(10_000, 200, viewport, load);
+        assert!(loaded.borrow().is_empty());
+
+        let mut buf = Buffer::empty(viewport);
+        ImageGrid.render(viewport, &mut buf, &mut grid);
+        assert_eq!(buf[(0, 0)].symbol(), "▀");
+        assert_eq!(buf[(16, 4)].symbol(), "▀");
+
+        // The end of the items.
+        grid.update(12, 0, viewport, load);
+        assert_eq!(grid.len(), 12);
+        let mut buf = Buffer::empty(viewport);
+        ImageGrid.render(viewport, &mut buf, &mut grid);
+        assert_eq!(buf[(4, 4)].symbol(), "▀");
+        assert_eq!(buf[(8, 4)].symbol(), " ");
+    }
+
+    #[test]
+    fn test_update_protocols() {
+        let mut picker = Picker::from_fontsize((10, 20));
+        picker.set_protocol_type(ProtocolType::Halfblocks);
+        let mut grid = VirtualGrid::new(picker.clone(), (4, 2));
+        let image: DynamicImage =
+            ImageBuffer::from_pixel(40, 40, Rgba::<u8>([0, 255, 0, 255])).into();
+
+        // Encoded elsewhere, e.g. on a worker thread.
+        let (resize, area) = (Resize::Fit(None), grid.item_area());
+        let mut protocol = picker.new_resize_protocol(image);
+        let rect = protocol.needs_resize(&resize, area).unwrap();
+        ResizeEncodeRender::resize_encode(&mut protocol, &resize, rect);
+
+        let viewport = Rect::new(0, 0, 4, 2);
+        let mut protocol = Some(protocol);
+        grid.update_protocols(3, 0, viewport, |_| protocol.take());
+        assert_eq!(grid.len(), 1);
+        let protocol = grid.protocol_mut(0).unwrap();
+        assert_eq!(protocol.needs_resize(&resize, area), None);
+        let mut buf = Buffer::empty(viewport);
+        ImageGrid.render(viewport, &mut buf, &mut grid);
+        assert_eq!(buf[(0, 0)].fg, Color::Rgb(0, 255, 0));
+    }
+}
@@ -91,6 +91,21 @@
 //!
 //! The lib also includes a binary that renders an image file, but it is focused on testing.
 //!
+//! # Windows
+//!
+//! Windows Terminal (1.22 or later) supports sixels, and [picker::Picker::from_query_stdio] works
+//! over ConPTY like on other platforms: sixel support is detected with the Device Attributes query.
+//! If the terminal does not report the font size, it is taken from the console font API instead.
+//! Older consoles (`conhost.exe`) only support halfblocks.
+//!
+//! # Inline viewports
+//!
+//! Images work in inline viewports (`Viewport::Inline`), e.g. for installers that do not take over
+//! the screen. The widgets only write into the cells of the area they are given, and only use
+//! relative cursor movement, so ratatui's positioning of the viewport applies to the images too.
+//! Keep in mind that a sixel image reaching the last row of the screen can make the terminal
+//! scroll, so leave a row below it.
+//!
 //! # Features
 //! * `crossterm` or `termion` should match your ratatui backend. `termwiz` is available, but not
 //!   working correctly with ratatu-image.
@@ -104,20 +119,47 @@
 //! [ratatui]: https://github.com/ratatui-org/ratatui
 //! [sixel]: https://en.wikipedia.org/wiki/Sixel
 //! [`render_stateful_widget`]: https://docs.rs/ratatui/latest/ratatui/terminal/struct.Frame.html#method.render_stateful_widget
-use std::cmp::{max, min};
+use std::{
+    cmp::{max, min},
+    hash::{Hash, Hasher},
+    marker::PhantomData,
+    mem,
+};
 
 use image::{imageops, DynamicImage, ImageBuffer, Rgba};
-use protocol::{ImageSource, Protocol, StatefulProtocol};
+use protocol::{ImageSource, LazyProtocol, Protocol, StatefulProtocol};
 use ratatui::{
     buffer::Buffer,
     layout::Rect,
     widgets::{StatefulWidget, Widget},
 };
 
+pub mod analysis;
+pub mod audit;
+pub mod background;
+pub mod cache;
+pub mod canvas;
+pub mod cleanup;
+pub mod document;
 pub mod errors;
+pub mod font_watch;
+pub mod glyphs;
+pub mod grid;
+#[cfg(feature = "mmap")]
+pub mod mapped;
+pub mod metrics;
 pub mod picker;
 pub mod protocol;
+pub mod reflow;
+pub mod scroll;
+pub mod sequence_log;
+pub mod shared;
+#[cfg(feature = "test-utils")]
+pub mod testing;
 pub mod thread;
+pub mod tmux;
+pub mod watermark;
+pub mod zoom;
 pub use image::imageops::FilterType;
 
 type Result<T> = std::result::Result<T, errors::Errors>;
@@ -143,15 +185,74 @@ pub type FontSize = (u16, u16);
 /// ```
 pub struct Image<'a> {
     image: &'a mut Protocol,
+    origin: Option<(u16, u16)>,
 }
 
 impl<'a> Image<'a> {
     pub fn new(image: &'a mut Protocol) -> Image<'a> {
-        Image { image }
+        Image {
+            image,
+            origin: None,
+        }
+    }
+
+    /// Position the graphics absolutely, for a buffer that is drawn at `origin` (column, row) of
+    /// the terminal, see [StatefulImage::origin].
+    pub fn origin(mut self, origin: Option<(u16, u16)>) -> Image<'a> {
+        self.origin = origin;
+        self
+    }
+
+    /// Render an image through a keyed [cache::ImageCacheContext] instead of a [Protocol].
+    ///
+    /// ```rust
+    /// # use ratatui::Frame;
+    /// # use ratatui_image::{cache::ImageCacheContext, Image};
+    /// fn ui(f: &mut Frame<'_>, logo: &image::DynamicImage, cache: &mut ImageCacheContext) {
+    ///     f.render_widget(Image::from_source(logo).key("logo").cache(cache), f.area());
+    /// }
+    /// ```
+    pub fn from_source(source: &'a DynamicImage) -> cache::KeyedImage<'a> {
+        cache::KeyedImage::new(source)
     }
 }
 
 impl Widget for Image<'_> {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        if area.width == 0 || area.height == 0 {
+            return;
+        }
+
+        self.image.render(area, buf);
+        if let Some(origin) = self.origin {
+            protocol::position_at_origin(area, origin, buf);
+        }
+    }
+}
+
+/// Fixed size image widget that uses a [LazyProtocol], see [picker::Picker::new_protocol_lazy].
+///
+/// Renders nothing until the protocol has been encoded on a background thread, if
+/// [LazyProtocol::spawn] was called, otherwise encodes on the first render.
+///
+/// ```rust
+/// # use ratatui::Frame;
+/// # use ratatui_image::{LazyImage, protocol::LazyProtocol};
+/// fn ui(f: &mut Frame<'_>, splash: &mut LazyProtocol) {
+///     f.render_widget(LazyImage::new(splash), f.area());
+/// }
+/// ```
+pub struct LazyImage<'a> {
+    image: &'a mut LazyProtocol,
+}
+
+impl<'a> LazyImage<'a> {
+    pub fn new(image: &'a mut LazyProtocol) -> LazyImage<'a> {
+        LazyImage { image }
+    }
+}
+
+impl Widget for LazyImage<'_> {
     fn render(self, area: Rect, buf: &mut Buffer) {
         if area.width == 0 || area.height == 0 {
             return;
@@ -180,35 +281,211 @@ impl Widget for Image<'_> {
 ///     );
 /// }
 /// ```
-#[derive(Default)]
-pub struct StatefulImage {
+///
+/// The state can be anything that implements [ResizeEncodeRender], including
+/// `Box<dyn ResizeEncodeRender>`, so that thread-backed and blocking states can be mixed in one
+/// collection:
+///
+/// ```rust
+/// # use ratatui::Frame;
+/// # use ratatui_image::{ResizeEncodeRender, StatefulImage};
+/// struct App {
+///     images: Vec<Box<dyn ResizeEncodeRender>>,
+/// }
+/// fn ui(f: &mut Frame<'_>, app: &mut App) {
+///     for image in app.images.iter_mut() {
+///         f.render_stateful_widget(StatefulImage::new(), f.area(), image);
+///     }
+/// }
+/// ```
+pub struct StatefulImage<T = StatefulProtocol> {
     resize: Resize,
+    origin: Option<(u16, u16)>,
+    z_index: Option<i32>,
+    phantom: PhantomData<T>,
 }
 
-impl StatefulImage {
+impl<T> Default for StatefulImage<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> StatefulImage<T> {
     pub const fn resize(self, resize: Resize) -> Self {
-        Self { resize }
+        Self {
+            resize,
+            origin: self.origin,
+            z_index: self.z_index,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Position the graphics absolutely, for a buffer that is drawn at `origin` (column, row) of
+    /// the terminal, e.g. when several ratatui terminals share one tty side by side.
+    ///
+    /// Graphics are drawn at the cursor, so they land in the right place when the backend
+    /// offsets every cursor movement. Backends that don't, e.g. because they split the output
+    /// of each terminal by other means, need the absolute position prefixed to the data.
+    pub const fn origin(self, origin: Option<(u16, u16)>) -> Self {
+        Self {
+            resize: self.resize,
+            origin,
+            z_index: self.z_index,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Place the graphics below (negative) or above text, e.g. a background image behind other
+    /// widgets, see [ResizeEncodeRender::set_z_index]. Only Kitty layers graphics.
+    ///
+    /// ```rust
+    /// # use ratatui::{widgets::Paragraph, Frame};
+    /// # use ratatui_image::{Resize, StatefulImage, protocol::StatefulProtocol};
+    /// fn ui(f: &mut Frame<'_>, background: &mut StatefulProtocol) {
+    ///     let image = StatefulImage::new().resize(Resize::Fill(None)).z_index(Some(-1));
+    ///     f.render_stateful_widget(image, f.area(), background);
+    ///     f.render_widget(Paragraph::new("Text over the image"), f.area());
+    /// }
+    /// ```
+    pub const fn z_index(self, z_index: Option<i32>) -> Self {
+        Self {
+            resize: self.resize,
+            origin: self.origin,
+            z_index,
+            phantom: PhantomData,
+        }
     }
 
     pub const fn new() -> Self {
         Self {
             resize: Resize::Fit(None),
+            origin: None,
+            z_index: None,
+            phantom: PhantomData,
         }
     }
 }
 
-impl StatefulWidget for StatefulImage {
-    type State = StatefulProtocol;
+impl<T: ResizeEncodeRender> StatefulWidget for StatefulImage<T> {
+    type State = T;
     fn render(self, area: Rect, buf: &mut Buffer, state: &mut Self::State) {
         if area.width == 0 || area.height == 0 {
             return;
         }
 
-        state.resize_encode_render(&self.resize, state.background_color(), area, buf);
+        if let Some(z_index) = self.z_index {
+            state.set_z_index(z_index);
+        }
+        state.resize_encode_render(&self.resize, area, buf);
+        if let Some(origin) = self.origin {
+            protocol::position_at_origin(area, origin, buf);
+        }
     }
 }
 
-#[derive(Debug)]
+/// Additional placement of a [StatefulProtocol] that is rendered by a [StatefulImage] elsewhere.
+///
+/// The same image can be shown in several places without encoding it again. With Kitty, the view
+/// only places the image that was already transmitted. Sixel and iTerm2 have no placements, so
+/// the whole encoded payload is written to the terminal again for every view whenever its cells
+/// are redrawn. Halfblocks and the other text protocols copy their cells.
+///
+/// The view never resizes: it shows the image at the size of the last [StatefulImage] render,
+/// clipped to its area.
+///
+/// ```rust
+/// # use ratatui::{layout::Rect, Frame};
+/// # use ratatui_image::{PlacementView, StatefulImage, protocol::StatefulProtocol};
+/// fn ui(f: &mut Frame<'_>, image: &mut StatefulProtocol, preview: Rect, detail: Rect) {
+///     f.render_stateful_widget(StatefulImage::default(), preview, image);
+///     f.render_widget(PlacementView::new(image), detail);
+/// }
+/// ```
+pub struct PlacementView<'a> {
+    protocol: &'a StatefulProtocol,
+}
+
+impl<'a> PlacementView<'a> {
+    pub fn new(protocol: &'a StatefulProtocol) -> PlacementView<'a> {
+        PlacementView { protocol }
+    }
+}
+
+impl Widget for PlacementView<'_> {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        if area.width == 0 || area.height == 0 {
+            return;
+        }
+
+        self.protocol.render_placement(area, buf);
+    }
+}
+
+/// The resize, encode, and render steps of a stateful image, as used by [StatefulImage].
+///
+/// This trait is object safe, and is implemented for [StatefulProtocol],
+/// [thread::ThreadProtocol], boxes and mutable references.
+pub trait ResizeEncodeRender {
+    /// Check if the current image state would need resizing (grow or shrink) for the given area.
+    fn needs_resize(&mut self, resize: &Resize, area: Rect) -> Option<Rect>;
+
+    /// Resize the image and encode it for rendering, with the state's own background color.
+    fn resize_encode(&mut self, resize: &Resize, area: Rect);
+
+    /// Render the currently resized and encoded data to the buffer.
+    fn render(&mut self, area: Rect, buf: &mut Buffer);
+
+    /// Resize and encode if necessary, and render immediately.
+    fn resize_encode_render(&mut self, resize: &Resize, area: Rect, buf: &mut Buffer) {
+        if let Some(rect) = self.needs_resize(resize, area) {
+            self.resize_encode(resize, rect);
+        }
+        self.render(area, buf);
+    }
+
+    /// Place the graphics at this z-index, for protocols that layer graphics and text (Kitty).
+    /// Negative values are drawn below text. Other protocols ignore it.
+    fn set_z_index(&mut self, _z_index: i32) {}
+}
+
+impl<T: ResizeEncodeRender + ?Sized> ResizeEncodeRender for Box<T> {
+    fn needs_resize(&mut self, resize: &Resize, area: Rect) -> Option<Rect> {
+        (**self).needs_resize(resize, area)
+    }
+    fn resize_encode(&mut self, resize: &Resize, area: Rect) {
+        (**self).resize_encode(resize, area)
+    }
+    fn render(&mut self, area: Rect, buf: &mut Buffer) {
+        (**self).render(area, buf)
+    }
+    fn resize_encode_render(&mut self, resize: &Resize, area: Rect, buf: &mut Buffer) {
+        (**self).resize_encode_render(resize, area, buf)
+    }
+    fn set_z_index(&mut self, z_index: i32) {
+        (**self).set_z_index(z_index)
+    }
+}
+
+impl<T: ResizeEncodeRender + ?Sized> ResizeEncodeRender for &mut T {
+    fn needs_resize(&mut self, resize: &Resize, area: Rect) -> Option<Rect> {
+        (**self).needs_resize(resize, area)
+    }
+    fn resize_encode(&mut self, resize: &Resize, area: Rect) {
+        (**self).resize_encode(resize, area)
+    }
+    fn render(&mut self, area: Rect, buf: &mut Buffer) {
+        (**self).render(area, buf)
+    }
+    fn resize_encode_render(&mut self, resize: &Resize, area: Rect, buf: &mut Buffer) {
+        (**self).resize_encode_render(resize, area, buf)
+    }
+    fn set_z_index(&mut self, z_index: i32) {
+        (**self).set_z_index(z_index)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 /// Resize method
 pub enum Resize {
     /// Fit to area.
@@ -232,6 +509,60 @@ pub enum Resize {
     ///
     /// Same as `Resize::Fit` except it resizes the image even if the image is smaller than the render area
     Scale(Option<FilterType>),
+    /// Scale the image to cover the whole area, keeping proportions, and crop the overflow
+    /// evenly on both sides, like CSS `object-fit: cover`.
+    ///
+    /// The [FilterType] defaults to [FilterType::Nearest].
+    Fill(Option<FilterType>),
+    /// Resize the image to exactly the area, without keeping proportions, e.g. for spectrograms
+    /// or heatmaps where the distortion is wanted.
+    ///
+    /// The [FilterType] defaults to [FilterType::Nearest].
+    Stretch(Option<FilterType>),
+    /// Show a region of the image, zoomed and panned, e.g. for image viewers.
+    ///
+    /// The region starts at the [ViewportOptions] offset and is scaled by its zoom factor, so
+    /// it can be smaller than the area at the edges of the image. See [zoom::ZoomPanImage], which
+    /// re-encodes when the viewport changes.
+    Viewport(ViewportOptions),
+    /// Place images whose pixel size is an exact multiple of the font size without any rounding
+    /// or padding, e.g. icons or pixel art.
+    ///
+    /// The image is shown unscaled if it fits, or else scaled down with [FilterType::Nearest] to
+    /// the largest size that is still an exact multiple of the font size, keeping proportions.
+    /// Other images, see [Resize::pixel_perfect_cells], are handled like `Resize::Fit(None)`.
+    PixelPerfect,
+    /// Scale only by integer factors (1x, 2x, 3x…, or down by 1/2, 1/3… if the image is larger
+    /// than the area) with [FilterType::Nearest], and center the result in the area, e.g. for
+    /// game sprites and retro art where fractional scaling smears the pixels.
+    ///
+    /// The whole area is padded with the background color around the image.
+    FitInteger,
+}
+
+// FilterType does not implement Hash.
+impl Hash for Resize {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        mem::discriminant(self).hash(state);
+        match self {
+            Resize::Fit(filter_type)
+            | Resize::Scale(filter_type)
+            | Resize::Fill(filter_type)
+            | Resize::Stretch(filter_type) => {
+                filter_type.map(|filter_type| filter_type as u8).hash(state)
+            }
+            Resize::Crop(options) => options.hash(state),
+            Resize::Viewport(options) => {
+                options.zoom.to_bits().hash(state);
+                options.offset.hash(state);
+                options
+                    .filter_type
+                    .map(|filter_type| filter_type as u8)
+                    .hash(state)
+            }
+            Resize::PixelPerfect | Resize::FitInteger => {}
+        }
+    }
 }
 
 impl Default for Resize {
@@ -240,7 +571,7 @@ impl Default for Resize {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 /// Specifies which sides to be clipped when cropping an image.
 pub struct CropOptions {
     /// If `true`, the top side should be clipped.
@@ -249,9 +580,202 @@ pub struct CropOptions {
     pub clip_left: bool,
 }
 
+/// Which cells of the render area an image claims when it is smaller than the area, e.g. with
+/// [Resize::Fit] and a small image in a large area.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum AreaClaim {
+    /// Only the cells of the image itself. The rest of the area is left to other widgets.
+    #[default]
+    Image,
+    /// The whole area: a [Protocol] is padded with the background color to the area, and a
+    /// [StatefulProtocol] erases the rest of the area, for terminals that leave graphics behind
+    /// when text is drawn over them.
+    Area,
+}
+
+/// How a [StatefulProtocol] shows that its last encoding or transmission failed, as a debugging
+/// aid, see [picker::Picker::set_error_indicator]. Otherwise a failed image is just missing, and
+/// the error is only available from [StatefulProtocol::last_encoding_result].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum ErrorIndicator {
+    #[default]
+    Off,
+    /// A red `!` in the bottom right cell of the area.
+    Marker,
+    /// The marker, and a desktop notification with the error (OSC 9) once per failure, which the
+    /// app writes from [cleanup::take_notification_sequence]. Not all terminals support OSC 9,
+    /// and tmux does not pass it through.
+    Notify,
+}
+
+/// What kind of image a [StatefulProtocol] shows, to resize and encode it in a way that suits
+/// it, see [StatefulProtocol::set_content_hint].
+///
+/// The hint only fills in what the [Resize] leaves open: an explicit filter is kept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ContentHint {
+    /// Photos and other continuous-tone images: smooth [FilterType::Lanczos3] downscaling, and
+    /// dithering where colors are reduced.
+    Photo,
+    /// Pixel art: [FilterType::Nearest] without dithering, and [Resize::Fit] and [Resize::Scale]
+    /// snap to integer scale factors, so that every image pixel becomes the same number of
+    /// screen pixels.
+    PixelArt,
+    /// Drawings, diagrams and text: [FilterType::Triangle] without dithering, which keeps thin
+    /// lines from breaking up.
+    LineArt,
+}
+
+impl ContentHint {
+    pub fn filter_type(&self) -> FilterType {
+        match self {
+            ContentHint::Photo => FilterType::Lanczos3,
+            ContentHint::PixelArt => FilterType::Nearest,
+            ContentHint::LineArt => FilterType::Triangle,
+        }
+    }
+
+    pub fn dither(&self) -> bool {
+        *self == ContentHint::Photo
+    }
+}
+
+/// The region of the image for [Resize::Viewport].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ViewportOptions {
+    /// Screen pixels per image pixel, e.g. `2.0` shows every image pixel twice as large.
+    pub zoom: f32,
+    /// The top-left corner of the region, in image pixels.
+    pub offset: (u32, u32),
+    /// Defaults to [FilterType::Nearest], which keeps the pixels sharp when zooming in.
+    pub filter_type: Option<FilterType>,
+}
+
+impl Default for ViewportOptions {
+    fn default() -> Self {
+        ViewportOptions {
+            zoom: 1.0,
+            offset: (0, 0),
+            filter_type: None,
+        }
+    }
+}
+
+impl ViewportOptions {
+    pub fn new(zoom: f32, offset: (u32, u32)) -> ViewportOptions {
+        ViewportOptions {
+            zoom,
+            offset,
+            filter_type: None,
+        }
+    }
+
+    /// Change the zoom factor, keeping the image pixel at `anchor` at the same screen position,
+    /// e.g. the pixel under the mouse cursor.
+    pub fn zoom_around(&mut self, zoom: f32, anchor: (u32, u32)) {
+        let zoom = zoom.max(f32::EPSILON);
+        let scale = self.zoom / zoom;
+        let offset = |offset: u32, anchor: u32| {
+            let offset = anchor as f32 - (anchor as f32 - offset as f32) * scale;
+            offset.max(0.0).round() as u32
+        };
+        self.offset = (
+            offset(self.offset.0, anchor.0),
+            offset(self.offset.1, anchor.1),
+        );
+        self.zoom = zoom;
+    }
+
+    /// Move the region by image pixels, stopping at the top and left edges.
+    pub fn pan(&mut self, dx: i64, dy: i64) {
+        let pan =
+            |offset: u32, delta: i64| (offset as i64 + delta).clamp(0, u32::MAX as i64) as u32;
+        self.offset = (pan(self.offset.0, dx), pan(self.offset.1, dy));
+    }
+
+    // The region of the image that covers `width` by `height` screen pixels.
+    pub(crate) fn region(
+        &self,
+        (image_width, image_height): (u32, u32),
+        width: u32,
+        height: u32,
+    ) -> (u32, u32) {
+        let zoom = self.zoom.max(f32::EPSILON);
+        let size = |screen: u32, image: u32, offset: u32| {
+            ((screen as f32 / zoom).ceil() as u32).min(image.saturating_sub(offset))
+        };
+        (
+            size(width, image_width, self.offset.0),
+            size(height, image_height, self.offset.1),
+        )
+    }
+}
+
 impl Resize {
-    /// Resize [`ImageSource`] to fit the `area`.
-    fn resize(
+    /// The size in cells of an image whose pixel size is an exact multiple of the font size.
+    pub fn pixel_perfect_cells(
+        image_width: u32,
+        image_height: u32,
+        (char_width, char_height): FontSize,
+    ) -> Option<(u16, u16)> {
+        let (char_width, char_height) = (char_width as u32, char_height as u32);
+        if char_width == 0
+            || char_height == 0
+            || image_width % char_width != 0
+            || image_height % char_height != 0
+        {
+            return None;
+        }
+        let width = u16::try_from(image_width / char_width).ok()?;
+        let height = u16::try_from(image_height / char_height).ok()?;
+        Some((width, height))
+    }
+
+    // The filter of the resize, or None if it has no filter or uses the default one.
+    pub(crate) fn filter_type(&self) -> Option<FilterType> {
+        match self {
+            Resize::Fit(filter_type)
+            | Resize::Scale(filter_type)
+            | Resize::Fill(filter_type)
+            | Resize::Stretch(filter_type) => *filter_type,
+            Resize::Viewport(options) => options.filter_type,
+            Resize::Crop(_) | Resize::PixelPerfect | Resize::FitInteger => None,
+        }
+    }
+
+    // The same resize with another filter, if it has one.
+    pub(crate) fn with_filter(&self, filter_type: FilterType) -> Option<Resize> {
+        match self {
+            Resize::Fit(_) => Some(Resize::Fit(Some(filter_type))),
+            Resize::Scale(_) => Some(Resize::Scale(Some(filter_type))),
+            Resize::Fill(_) => Some(Resize::Fill(Some(filter_type))),
+            Resize::Stretch(_) => Some(Resize::Stretch(Some(filter_type))),
+            Resize::Viewport(options) => Some(Resize::Viewport(ViewportOptions {
+                filter_type: Some(filter_type),
+                ..*options
+            })),
+            Resize::Crop(_) | Resize::PixelPerfect | Resize::FitInteger => None,
+        }
+    }
+
+    // The largest proportional size in cells that fits into the area, for Resize::PixelPerfect.
+    fn pixel_perfect_rect(image: &DynamicImage, font_size: FontSize, area: Rect) -> Option<Rect> {
+        let (width, height) =
+            Resize::pixel_perfect_cells(image.width(), image.height(), font_size)?;
+        if width == 0 || height == 0 {
+            return None;
+        }
+        let gcd = gcd(width, height);
+        let (base_width, base_height) = (width / gcd, height / gcd);
+        let n = min(min(area.width / base_width, area.height / base_height), gcd);
+        if n == 0 {
+            return None;
+        }
+        Some(Rect::new(0, 0, base_width * n, base_height * n))
+    }
+
+    /// Resize [`ImageSource`] to fit the `area`, padded with the background color.
+    pub fn resize(
         &self,
         source: &ImageSource,
         font_size: FontSize,
@@ -264,12 +788,22 @@ impl Resize {
         // Resize/Crop/etc., fitting a multiple of font-size, but not necessarily the area.
         let mut image = self.resize_image(source, width, height);
 
-        // Always pad to area size with background color, Sixel doesn't have transparency
-        // and would get a white background by the sixel library.
-        // Once Sixel gets transparency support, only pad
-        // `if image.width() != width || image.height() != height`.
+        // Always pad to area size with background color. Sixel leaves transparent pixels out
+        // (see StatefulSixel::transparent), or else draws them with the background color
+        // instead of white by the sixel library.
         let mut bg: DynamicImage = ImageBuffer::from_pixel(width, height, background_color).into();
-        imageops::overlay(&mut bg, &image, 0, 0);
+        let (x, y) = match self {
+            Resize::FitInteger => (
+                width.saturating_sub(image.width()) / 2,
+                height.saturating_sub(image.height()) / 2,
+            ),
+            _ => (0, 0),
+        };
+        imageops::overlay(&mut bg, &image, x as i64, y as i64);
+        if let Some(watermark) = &source.watermark {
+            let content = (x, y, image.width().min(width), image.height().min(height));
+            watermark.apply_within(&mut bg, content);
+        }
         image = bg;
         image
     }
@@ -288,8 +822,14 @@ impl Resize {
     ) -> Option<Rect> {
         let desired = image.desired;
         // Check if resize is needed at all.
-        if !matches!(self, &Resize::Scale(_))
-            && desired.width <= area.width
+        if !matches!(
+            self,
+            &Resize::Scale(_)
+                | &Resize::Fill(_)
+                | &Resize::Stretch(_)
+                | &Resize::Viewport(_)
+                | &Resize::FitInteger
+        ) && desired.width <= area.width
             && desired.height <= area.height
             && desired == current
         {
@@ -300,12 +840,18 @@ impl Resize {
             }
         }
 
-        let (width, height) = self.needs_resize_pixels(
-            &image.image,
-            (area.width as u32) * (font_size.0 as u32),
-            (area.height as u32) * (font_size.1 as u32),
-        );
-        let rect = ImageSource::round_pixel_size_to_cells(width, height, font_size);
+        let rect = match self {
+            Resize::PixelPerfect => Resize::pixel_perfect_rect(&image.image, font_size, area),
+            _ => None,
+        };
+        let rect = rect.unwrap_or_else(|| {
+            let (width, height) = self.needs_resize_pixels(
+                &image.image,
+                (area.width as u32) * (font_size.0 as u32),
+                (area.height as u32) * (font_size.1 as u32),
+            );
+            ImageSource::round_pixel_size_to_cells(width, height, font_size)
+        });
         debug_assert!(rect.width <= area.width, "needs_resize exceeds area width");
         debug_assert!(
             rect.height <= area.height,
@@ -328,6 +874,54 @@ impl Resize {
             Self::Fit(filter_type) | Self::Scale(filter_type) => {
                 image.resize(width, height, filter_type.unwrap_or(DEFAULT_FILTER_TYPE))
             }
+            Self::PixelPerfect if image.width() == width && image.height() == height => {
+                image.clone()
+            }
+            Self::PixelPerfect => image.resize(width, height, FilterType::Nearest),
+            Self::FitInteger => {
+                let Some(zoom) =
+                    integer_zoom((image.width(), image.height()), (width, height), true)
+                else {
+                    return image.clone();
+                };
+                let scaled = |size: u32| ((size as f32 * zoom).round() as u32).max(1);
+                image.resize_exact(
+                    scaled(image.width()),
+                    scaled(image.height()),
+                    FilterType::Nearest,
+                )
+            }
+            Self::Fill(filter_type) => {
+                image.resize_to_fill(width, height, filter_type.unwrap_or(DEFAULT_FILTER_TYPE))
+            }
+            Self::Stretch(filter_type) => {
+                image.resize_exact(width, height, filter_type.unwrap_or(DEFAULT_FILTER_TYPE))
+            }
+            Self::Viewport(options) => {
+                let (region_width, region_height) =
+                    options.region((image.width(), image.height()), width, height);
+                if region_width == 0 || region_height == 0 {
+                    // Panned past the edge of the image.
+                    return DynamicImage::new_rgba8(0, 0);
+                }
+                let zoomed = |region: u32, screen: u32| {
+                    ((region as f32 * options.zoom).round() as u32)
+                        .min(screen)
+                        .max(1)
+                };
+                image
+                    .crop_imm(
+                        options.offset.0,
+                        options.offset.1,
+                        region_width,
+                        region_height,
+                    )
+                    .resize_exact(
+                        zoomed(region_width, width),
+                        zoomed(region_height, height),
+                        options.filter_type.unwrap_or(DEFAULT_FILTER_TYPE),
+                    )
+            }
             Self::Crop(options) => {
                 let options = options.as_ref().unwrap_or(&DEFAULT_CROP_OPTIONS);
                 let y = if options.clip_top {
@@ -345,9 +939,114 @@ impl Resize {
         }
     }
 
+    // The pixel of an image of `image_width` by `image_height` that is shown at pixel `(x, y)` of
+    // its resized encoding of `width` by `height`, or None on the padding. The inverse of
+    // resize_image and the padding of resize.
+    pub(crate) fn source_pixel(
+        &self,
+        (image_width, image_height): (u32, u32),
+        width: u32,
+        height: u32,
+        (x, y): (u32, u32),
+    ) -> Option<(u32, u32)> {
+        // Map a position on content of `content` pixels, showing `image` pixels from `offset`.
+        let map = |pos: u32, content: u32, image: u32, offset: u32| {
+            (pos < content).then(|| offset + (pos as u64 * image as u64 / content as u64) as u32)
+        };
+        let (x, y) = match self {
+            Resize::PixelPerfect if (image_width, image_height) == (width, height) => (x, y),
+            Resize::Fit(_) | Resize::Scale(_) | Resize::PixelPerfect => {
+                let (content_width, content_height) =
+                    fit_area_proportionally(image_width, image_height, width, height);
+                (
+                    map(x, content_width, image_width, 0)?,
+                    map(y, content_height, image_height, 0)?,
+                )
+            }
+            Resize::FitInteger => {
+                let zoom =
+                    integer_zoom((image_width, image_height), (width, height), true).unwrap_or(1.0);
+                let scaled = |size: u32| ((size as f32 * zoom).round() as u32).max(1);
+                let (content_width, content_height) = (scaled(image_width), scaled(image_height));
+                let left = width.saturating_sub(content_width) / 2;
+                let top = height.saturating_sub(content_height) / 2;
+                (
+                    map(x.checked_sub(left)?, content_width, image_width, 0)?,
+                    map(y.checked_sub(top)?, content_height, image_height, 0)?,
+                )
+            }
+            Resize::Fill(_) => {
+                // Scaled to cover the area, and cropped evenly on both sides.
+                let ratio = f64::max(
+                    width as f64 / image_width as f64,
+                    height as f64 / image_height as f64,
+                );
+                let scaled = |size: u32| ((size as f64 * ratio).round() as u32).max(1);
+                let (scaled_width, scaled_height) = (scaled(image_width), scaled(image_height));
+                let left = scaled_width.saturating_sub(width) / 2;
+                let top = scaled_height.saturating_sub(height) / 2;
+                (
+                    map(x + left, scaled_width, image_width, 0)?,
+                    map(y + top, scaled_height, image_height, 0)?,
+                )
+            }
+            Resize::Stretch(_) => (
+                map(x, width, image_width, 0)?,
+                map(y, height, image_height, 0)?,
+            ),
+            Resize::Viewport(options) => {
+                let (region_width, region_height) =
+                    options.region((image_width, image_height), width, height);
+                let zoomed = |region: u32, screen: u32| {
+                    ((region as f32 * options.zoom).round() as u32)
+                        .min(screen)
+                        .max(1)
+                };
+                (
+                    map(
+                        x,
+                        zoomed(region_width, width),
+                        region_width,
+                        options.offset.0,
+                    )?,
+                    map(
+                        y,
+                        zoomed(region_height, height),
+                        region_height,
+                        options.offset.1,
+                    )?,
+                )
+            }
+            Resize::Crop(options) => {
+                let options = options.clone().unwrap_or(CropOptions {
+                    clip_top: false,
+                    clip_left: false,
+                });
+                let clip = |clip: bool, image: u32, screen: u32| {
+                    if clip {
+                        image.saturating_sub(screen)
+                    } else {
+                        0
+                    }
+                };
+                let left = clip(options.clip_left, image_width, width);
+                let top = clip(options.clip_top, image_height, height);
+                let (content_width, content_height) = (
+                    min(image_width - left, width),
+                    min(image_height - top, height),
+                );
+                (
+                    map(x, content_width, content_width, left)?,
+                    map(y, content_height, content_height, top)?,
+                )
+            }
+        };
+        (x < image_width && y < image_height).then_some((x, y))
+    }
+
     fn needs_resize_pixels(&self, image: &DynamicImage, width: u32, height: u32) -> (u32, u32) {
         match self {
-            Self::Fit(_) => fit_area_proportionally(
+            Self::Fit(_) | Self::PixelPerfect => fit_area_proportionally(
                 image.width(),
                 image.height(),
                 min(width, image.width()),
@@ -356,10 +1055,52 @@ impl Resize {
 
             Self::Crop(_) => (min(image.width(), width), min(image.height(), height)),
             Self::Scale(_) => fit_area_proportionally(image.width(), image.height(), width, height),
+            // The image is centered in the whole area.
+            Self::Fill(_) | Self::Stretch(_) | Self::FitInteger => (width, height),
+            Self::Viewport(options) => {
+                let (region_width, region_height) =
+                    options.region((image.width(), image.height()), width, height);
+                let zoomed = |region: u32, screen: u32| {
+                    ((region as f32 * options.zoom).round() as u32).min(screen)
+                };
+                (zoomed(region_width, width), zoomed(region_height, height))
+            }
         }
     }
 }
 
+fn gcd(a: u16, b: u16) -> u16 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+// The largest integer scale factor (or its inverse, to scale down) at which an image fits on a
+// screen, both in pixels. Only scales up if `upscale`, None for empty images or screens.
+pub(crate) fn integer_zoom(
+    (width, height): (u32, u32),
+    (screen_width, screen_height): (u32, u32),
+    upscale: bool,
+) -> Option<f32> {
+    if width == 0 || height == 0 || screen_width == 0 || screen_height == 0 {
+        return None;
+    }
+    if width <= screen_width && height <= screen_height {
+        let factor = if upscale {
+            (screen_width / width).min(screen_height / height)
+        } else {
+            1
+        };
+        return Some(factor as f32);
+    }
+    let divisor = width
+        .div_ceil(screen_width)
+        .max(height.div_ceil(screen_height));
+    Some(1.0 / divisor as f32)
+}
+
 /// Ripped from https://github.com/image-rs/image/blob/master/src/math/utils.rs#L12
 /// Calculates the width and height an image should be resized to.
 /// This preserves aspect ratio, and based on the `fill` parameter
@@ -438,6 +1179,143 @@ mod tests {
         assert_eq!(None, to);
     }
 
+    #[test]
+    fn stateful_image_boxed_states() {
+        let mut picker = picker::Picker::from_fontsize(FONT_SIZE);
+        picker.set_protocol_type(picker::ProtocolType::Halfblocks);
+        let image: DynamicImage =
+            ImageBuffer::from_pixel(40, 40, Rgba::<u8>([255, 0, 0, 255])).into();
+        let mut states: Vec<Box<dyn ResizeEncodeRender>> = vec![
+            Box::new(picker.new_resize_protocol(image.clone())),
+            Box::new(picker.new_resize_protocol(image)),
+        ];
+        let mut buf = Buffer::empty(r(8, 8));
+        for (i, state) in states.iter_mut().enumerate() {
+            let area = Rect::new(i as u16 * 4, 0, 4, 4);
+            StatefulImage::new().render(area, &mut buf, state);
+        }
+        assert_eq!(buf[(0, 0)].symbol(), "▀");
+        assert_eq!(buf[(4, 0)].symbol(), "▀");
+    }
+
+    #[test]
+    fn needs_resize_fill() {
+        let resize = Resize::Fill(None);
+
+        // Always covers the whole area, even if the image is smaller or has other proportions.
+        let to = resize.needs_resize(&s(50, 100), FONT_SIZE, r(5, 10), r(10, 8), false);
+        assert_eq!(Some(r(10, 8)), to);
+        let to = resize.needs_resize(&s(50, 100), FONT_SIZE, r(10, 8), r(10, 8), false);
+        assert_eq!(None, to);
+
+        // Crops the overflow evenly: a red-blue-red striped image keeps the blue middle.
+        let image: DynamicImage = ImageBuffer::from_fn(30, 10, |x, _| match x {
+            10..20 => Rgba::<u8>([0, 0, 255, 255]),
+            _ => Rgba([255, 0, 0, 255]),
+        })
+        .into();
+        let source = ImageSource::new(image, FONT_SIZE, [0, 0, 0, 0].into());
+        let resized = resize.resize(&source, FONT_SIZE, r(1, 1), [0, 0, 0, 0].into());
+        assert_eq!((resized.width(), resized.height()), (10, 10));
+        assert!(resized
+            .to_rgba8()
+            .pixels()
+            .all(|pixel| *pixel == Rgba([0, 0, 255, 255])));
+    }
+
+    #[test]
+    fn needs_resize_fit_integer() {
+        let resize = Resize::FitInteger;
+
+        // Always claims the whole area, where the image is centered.
+        let to = resize.needs_resize(&s(20, 10), FONT_SIZE, r(2, 1), r(7, 4), false);
+        assert_eq!(Some(r(7, 4)), to);
+        let to = resize.needs_resize(&s(20, 10), FONT_SIZE, r(7, 4), r(7, 4), false);
+        assert_eq!(None, to);
+
+        // Scaled 3 times to 60x30, centered in 70x40 pixels.
+        let resized = resize.resize(&s(20, 10), FONT_SIZE, r(7, 4), [0, 0, 0, 0].into());
+        assert_eq!((resized.width(), resized.height()), (70, 40));
+        assert!(opaque(&resized, 5, 5) && opaque(&resized, 64, 34));
+        assert!(!opaque(&resized, 4, 5) && !opaque(&resized, 5, 4));
+        assert!(!opaque(&resized, 65, 34) && !opaque(&resized, 64, 35));
+
+        // Halved to 50x20, centered in 60x20 pixels.
+        let resized = resize.resize(&s(100, 40), FONT_SIZE, r(6, 2), [0, 0, 0, 0].into());
+        assert!(opaque(&resized, 5, 0) && opaque(&resized, 54, 19));
+        assert!(!opaque(&resized, 4, 0) && !opaque(&resized, 55, 19));
+
+        assert_eq!(integer_zoom((30, 40), (100, 200), true), Some(3.0));
+        assert_eq!(integer_zoom((300, 400), (80, 200), false), Some(0.25));
+        assert_eq!(integer_zoom((30, 40), (0, 200), true), None);
+    }
+
+    fn opaque(image: &DynamicImage, x: u32, y: u32) -> bool {
+        image.to_rgba8().get_pixel(x, y)[3] == 255
+    }
+
+    #[test]
+    fn needs_resize_stretch() {
+        let resize = Resize::Stretch(None);
+
+        // Always covers the whole area, even if the image is smaller or has other proportions.
+        let to = resize.needs_resize(&s(50, 100), FONT_SIZE, r(5, 10), r(10, 8), false);
+        assert_eq!(Some(r(10, 8)), to);
+        let to = resize.needs_resize(&s(50, 100), FONT_SIZE, r(10, 8), r(10, 8), false);
+        assert_eq!(None, to);
+
+        // Nothing is cropped: the red-blue-red stripes are squeezed.
+        let image: DynamicImage = ImageBuffer::from_fn(30, 10, |x, _| match x {
+            10..20 => Rgba::<u8>([0, 0, 255, 255]),
+            _ => Rgba([255, 0, 0, 255]),
+        })
+        .into();
+        let source = ImageSource::new(image, FONT_SIZE, [0, 0, 0, 0].into());
+        let resized = resize.resize(&source, FONT_SIZE, r(1, 1), [0, 0, 0, 0].into());
+        assert_eq!((resized.width(), resized.height()), (10, 10));
+        let resized = resized.to_rgba8();
+        assert_eq!(resized[(0, 5)], Rgba([255, 0, 0, 255]));
+        assert_eq!(resized[(5, 5)], Rgba([0, 0, 255, 255]));
+        assert_eq!(resized[(9, 5)], Rgba([255, 0, 0, 255]));
+    }
+
+    #[test]
+    fn needs_resize_viewport() {
+        // Red on the left half and blue on the right half.
+        let image: DynamicImage = ImageBuffer::from_fn(100, 100, |x, _| {
+            if x < 50 {
+                Rgba::<u8>([255, 0, 0, 255])
+            } else {
+                Rgba([0, 0, 255, 255])
+            }
+        })
+        .into();
+        let source = ImageSource::new(image, FONT_SIZE, [0, 0, 0, 0].into());
+
+        // Zoomed into the top of the blue half.
+        let resize = Resize::Viewport(ViewportOptions::new(2.0, (50, 0)));
+        let to = resize.needs_resize(&source, FONT_SIZE, r(10, 10), r(5, 5), false);
+        assert_eq!(Some(r(5, 5)), to);
+        let resized = resize.resize(&source, FONT_SIZE, r(5, 5), [0, 0, 0, 0].into());
+        assert_eq!((resized.width(), resized.height()), (50, 50));
+        assert!(resized
+            .to_rgba8()
+            .pixels()
+            .all(|pixel| *pixel == Rgba([0, 0, 255, 255])));
+
+        // At the bottom right edge, only the rest of the image is shown.
+        let resize = Resize::Viewport(ViewportOptions::new(2.0, (80, 90)));
+        let to = resize.needs_resize(&source, FONT_SIZE, r(10, 10), r(5, 5), false);
+        assert_eq!(Some(r(4, 2)), to);
+
+        // Zooming around a pixel keeps it in place.
+        let mut options = ViewportOptions::new(1.0, (10, 10));
+        options.zoom_around(2.0, (30, 50));
+        assert_eq!(options.offset, (20, 30));
+        options.pan(-30, 5);
+        assert_eq!(options.offset, (0, 35));
+    }
+
     #[test]
     fn needs_resize_crop() {
         let resize = Resize::Crop(None);
@@ -454,4 +1332,150 @@ mod tests {
         let to = resize.needs_resize(&s(100, 100), FONT_SIZE, r(10, 10), r(10, 8), false);
         assert_eq!(Some(r(10, 8)), to);
     }
+
+    #[test]
+    fn placement_view() {
+        let mut picker = picker::Picker::from_fontsize(FONT_SIZE);
+        picker.set_protocol_type(picker::ProtocolType::Kitty);
+        picker.set_deterministic_ids(true);
+        let mut protocol = picker.new_resize_protocol(s(20, 20).image);
+        let mut buf = Buffer::empty(r(4, 2));
+
+        StatefulImage::default().render(r(2, 2), &mut buf, &mut protocol);
+        PlacementView::new(&protocol).render(Rect::new(2, 0, 2, 2), &mut buf);
+
+        let (first, second) = (buf[(0, 0)].symbol(), buf[(2, 0)].symbol());
+        assert!(first.starts_with("\x1b_G"));
+        assert!(!second.contains("\x1b_G"));
+        let placeholders = &first[first.find("\x1b[s").unwrap()..];
+        assert_eq!(placeholders, second);
+        assert!(buf[(3, 1)].skip);
+    }
+
+    #[test]
+    fn origin() {
+        let mut picker = picker::Picker::from_fontsize(FONT_SIZE);
+        picker.set_protocol_type(picker::ProtocolType::Sixel);
+        let mut protocol = picker.new_resize_protocol(s(20, 20).image);
+        let mut buf = Buffer::empty(r(6, 4));
+        StatefulImage::default().origin(Some((40, 0))).render(
+            Rect::new(1, 2, 2, 2),
+            &mut buf,
+            &mut protocol,
+        );
+        assert!(buf[(1, 2)].symbol().starts_with("\x1b[3;42H\x1bP"));
+
+        // Kitty placeholders have escape sequences on every row.
+        picker.set_protocol_type(picker::ProtocolType::Kitty);
+        let mut protocol = picker.new_resize_protocol(s(20, 20).image);
+        let mut buf = Buffer::empty(r(6, 4));
+        StatefulImage::default().origin(Some((40, 0))).render(
+            Rect::new(1, 2, 2, 2),
+            &mut buf,
+            &mut protocol,
+        );
+        assert!(buf[(1, 2)].symbol().starts_with("\x1b[3;42H\x1b_G"));
+        assert!(buf[(1, 3)].symbol().starts_with("\x1b[4;42H"));
+
+        // Text is positioned by the backend.
+        picker.set_protocol_type(picker::ProtocolType::Halfblocks);
+        let mut protocol = picker.new_resize_protocol(s(20, 20).image);
+        StatefulImage::default().origin(Some((40, 0))).render(
+            Rect::new(1, 2, 2, 2),
+            &mut buf,
+            &mut protocol,
+        );
+        assert_eq!(buf[(1, 2)].symbol(), "▀");
+    }
+
+    #[test]
+    fn needs_resize_pixel_perfect() {
+        for font_size in [(7, 15), (8, 16), (9, 19), (10, 20), (13, 27)] {
+            for (cells_w, cells_h) in [(1, 1), (2, 3), (4, 4), (6, 2)] {
+                let (w, h) = (cells_w * font_size.0, cells_h * font_size.1);
+                let image: DynamicImage =
+                    ImageBuffer::from_pixel(w as _, h as _, Rgba::<u8>([255, 0, 0, 255])).into();
+                let source = ImageSource::new(image, font_size, [0, 0, 0, 0].into());
+                assert_eq!(
+                    Resize::pixel_perfect_cells(w as _, h as _, font_size),
+                    Some((cells_w, cells_h))
+                );
+
+                // Fits: exactly the desired cells, and the very same pixels.
+                let to = Resize::PixelPerfect.needs_resize(
+                    &source,
+                    font_size,
+                    Rect::default(),
+                    r(20, 20),
+                    false,
+                );
+                assert_eq!(to, Some(r(cells_w, cells_h)), "{font_size:?} {w}x{h}");
+                let resized = Resize::PixelPerfect.resize(
+                    &source,
+                    font_size,
+                    r(cells_w, cells_h),
+                    [0; 4].into(),
+                );
+                assert_eq!(resized.as_bytes(), source.image.as_bytes());
+
+                // Too small: scaled down by whole cells, without any padding.
+                if cells_w % 2 == 0 && cells_h % 2 == 0 {
+                    let half = r(cells_w / 2, cells_h / 2);
+                    let to = Resize::PixelPerfect.needs_resize(
+                        &source,
+                        font_size,
+                        Rect::default(),
+                        r(half.width, half.height + 1),
+                        false,
+                    );
+                    assert_eq!(to, Some(half));
+                    let resized =
+                        Resize::PixelPerfect.resize(&source, font_size, half, [0; 4].into());
+                    assert!(resized.to_rgba8().pixels().all(|p| p[3] == 255));
+                }
+            }
+        }
+        assert_eq!(Resize::pixel_perfect_cells(21, 40, (10, 20)), None);
+    }
+
+    #[test]
+    fn inline_viewport() {
+        use ratatui::{
+            backend::Backend, backend::TestBackend, Terminal, TerminalOptions, Viewport,
+        };
+
+        for protocol_type in [
+            picker::ProtocolType::Halfblocks,
+            picker::ProtocolType::Sixel,
+            picker::ProtocolType::Kitty,
+            picker::ProtocolType::Iterm2,
+        ] {
+            let mut backend = TestBackend::new(10, 10);
+            backend.set_cursor_position((0, 5)).unwrap();
+            let options = TerminalOptions {
+                viewport: Viewport::Inline(4),
+            };
+            let mut terminal = Terminal::with_options(backend, options).unwrap();
+            let mut picker = picker::Picker::from_fontsize(FONT_SIZE);
+            picker.set_protocol_type(protocol_type);
+            let mut protocol = picker.new_resize_protocol(s(20, 20).image);
+
+            let mut frame_area = Rect::default();
+            terminal
+                .draw(|f| {
+                    frame_area = f.area();
+                    f.render_stateful_widget(StatefulImage::default(), f.area(), &mut protocol);
+                })
+                .unwrap();
+            assert_eq!(frame_area, Rect::new(0, 5, 10, 4));
+
+            // The image starts at the top-left of the viewport, nothing is written above it.
+            let buf = terminal.backend().buffer();
+            assert_ne!(buf[(0, 5)].symbol(), " ", "{protocol_type:?}");
+            for y in 0..5 {
+                assert_eq!(buf[(0, y)].symbol(), " ", "{protocol_type:?}");
+            }
+            assert_eq!(protocol.area(), Rect::new(0, 0, 2, 2));
+        }
+    }
 }
@@ -0,0 +1,257 @@
+//! Memory-mapped huge images, e.g. scans or maps, that are decoded by region.
+//!
+//! Decoding a 200 megapixel image takes most of a gigabyte of RAM, even though only a small part
+//! of it is shown when zoomed in. A [MappedImage] maps the file instead of reading it, and
+//! decodes only the region that is shown, where the format allows it:
+//!
+//! ```rust,no_run
+//! # use ratatui::layout::Rect;
+//! # use ratatui_image::{mapped::MappedImage, picker::Picker, Resize, ViewportOptions};
+//! # fn main() -> Result<(), ratatui_image::errors::Errors> {
+//! let picker = Picker::from_fontsize((8, 16));
+//! let image = MappedImage::open("map.tiff")?;
+//! let viewport = ViewportOptions::new(1.0, (20_000, 8_000));
+//! // After every pan or zoom:
+//! let area = Rect::new(0, 0, 80, 24);
+//! let (region, viewport) = image.decode_viewport(&viewport, area, picker.font_size())?;
+//! let protocol = picker.new_resize_protocol(region);
+//! // Render the protocol with `Resize::Viewport(viewport)`.
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Regions are decoded from farbfeld images, and with the `tiff` feature from tiled or striped
+//! TIFF images with 8-bit gray, RGB or RGBA pixels, where only the tiles or strips that overlap
+//! the region are decompressed. Other formats are decoded completely and cropped, which still
+//! saves reading the file into memory, but not the memory of the decoded image.
+//!
+//! Needs the `mmap` feature.
+
+use std::{fs::File, io::Cursor, path::Path};
+
+use image::{
+    codecs::farbfeld::FarbfeldDecoder, DynamicImage, ImageBuffer, ImageDecoderRect, ImageFormat,
+    Rgba,
+};
+use memmap2::Mmap;
+use ratatui::layout::Rect;
+
+use crate::{FontSize, Result, ViewportOptions};
+
+/// A memory-mapped image file, see the [module docs](self).
+pub struct MappedImage {
+    map: Mmap,
+    format: ImageFormat,
+    dimensions: (u32, u32),
+}
+
+impl MappedImage {
+    /// Map the file at `path`, and read the format and dimensions from its header.
+    pub fn open(path: impl AsRef<Path>) -> Result<MappedImage> {
+        let file = File::open(path)?;
+        // SAFETY: the map is only read. The file must not be truncated while it is mapped, which
+        // would be a bug of the app like modifying any other file it is reading.
+        let map = unsafe { Mmap::map(&file)? };
+        let format = image::guess_format(&map)?;
+        let dimensions =
+            image::io::Reader::with_format(Cursor::new(&map[..]), format).into_dimensions()?;
+        Ok(MappedImage {
+            map,
+            format,
+            dimensions,
+        })
+    }
+
+    pub fn format(&self) -> ImageFormat {
+        self.format
+    }
+
+    /// The size of the whole image, in pixels.
+    pub fn dimensions(&self) -> (u32, u32) {
+        self.dimensions
+    }
+
+    /// Decode the whole image.
+    pub fn decode(&self) -> Result<DynamicImage> {
+        Ok(image::load_from_memory_with_format(&self.map, self.format)?)
+    }
+
+    /// Decode the region of `width` by `height` pixels at `x` and `y`, clipped to the image.
+    pub fn decode_region(&self, x: u32, y: u32, width: u32, height: u32) -> Result<DynamicImage> {
+        let (image_width, image_height) = self.dimensions;
+        let (x, y) = (x.min(image_width), y.min(image_height));
+        let width = width.min(image_width - x);
+        let height = height.min(image_height - y);
+        if width == 0 || height == 0 {
+            return Ok(DynamicImage::new_rgba8(0, 0));
+        }
+        match self.format {
+            ImageFormat::Farbfeld => {
+                let mut decoder = FarbfeldDecoder::new(Cursor::new(&self.map[..]))?;
+                let row_pitch = width as usize * 8;
+                let mut bytes = vec![0; row_pitch * height as usize];
+                decoder.read_rect(x, y, width, height, &mut bytes, row_pitch)?;
+                // The decoder writes the channels as native endian u16.
+                let channels = bytes
+                    .chunks_exact(2)
+                    .map(|channel| u16::from_ne_bytes([channel[0], channel[1]]))
+                    .collect();
+                if let Some(region) = ImageBuffer::<Rgba<u16>, _>::from_raw(width, height, channels)
+                {
+                    return Ok(region.into());
+                }
+            }
+            #[cfg(feature = "tiff")]
+            ImageFormat::Tiff => {
+                if let Some(region) = tiff_region(&self.map, x, y, width, height) {
+                    return Ok(region);
+                }
+            }
+            _ => {}
+        }
+        Ok(self.decode()?.crop_imm(x, y, width, height))
+    }
+
+    /// Decode the region that [crate::Resize::Viewport] would show in `area`, and return it with
+    /// the options to render it, whose offset is relative to the region.
+    pub fn decode_viewport(
+        &self,
+        options: &ViewportOptions,
+        area: Rect,
+        font_size: FontSize,
+    ) -> Result<(DynamicImage, ViewportOptions)> {
+        let (width, height) = options.region(
+            self.dimensions,
+            area.width as u32 * font_size.0 as u32,
+            area.height as u32 * font_size.1 as u32,
+        );
+        let region = self.decode_region(options.offset.0, options.offset.1, width, height)?;
+        let options = ViewportOptions {
+            offset: (0, 0),
+            ..*options
+        };
+        Ok((region, options))
+    }
+}
+
+// Decompress only the chunks (tiles or strips) that overlap the region. None if the layout or
+// pixel type is not supported, or on any error, to fall back to decoding the whole image.
+#[cfg(feature = "tiff")]
+fn tiff_region(bytes: &[u8], x: u32, y: u32, width: u32, height: u32) -> Option<DynamicImage> {
+    use tiff::{
+        decoder::{Decoder, DecodingResult},
+        tags::Tag,
+        ColorType,
+    };
+
+    let mut decoder = Decoder::new(Cursor::new(bytes)).ok()?;
+    // Planar images store every channel in separate chunks.
+    let planar = decoder
+        .find_tag_unsigned::<u16>(Tag::PlanarConfiguration)
+        .ok()?;
+    if planar.unwrap_or(1) != 1 {
+        return None;
+    }
+    let channels: usize = match decoder.colortype().ok()? {
+        ColorType::Gray(8) => 1,
+        ColorType::RGB(8) => 3,
+        ColorType::RGBA(8) => 4,
+        _ => return None,
+    };
+    let (image_width, _) = decoder.dimensions().ok()?;
+    let (chunk_width, chunk_height) = decoder.chunk_dimensions();
+    let chunks_across = image_width.div_ceil(chunk_width);
+
+    let mut pixels = vec![0; width as usize * height as usize * channels];
+    for chunk_row in y / chunk_height..=(y + height - 1) / chunk_height {
+        for chunk_column in x / chunk_width..=(x + width - 1) / chunk_width {
+            let index = chunk_row * chunks_across + chunk_column;
+            let DecodingResult::U8(chunk) = decoder.read_chunk(index).ok()? else {
+                return None;
+            };
+            let (data_width, data_height) = decoder.chunk_data_dimensions(index);
+            let (left, top) = (chunk_column * chunk_width, chunk_row * chunk_height);
+            let (from, to) = (x.max(left), (x + width).min(left + data_width));
+            if from >= to {
+                continue;
+            }
+            let len = (to - from) as usize * channels;
+            for row in y.max(top)..(y + height).min(top + data_height) {
+                let source = ((row - top) * data_width + from - left) as usize * channels;
+                let target = ((row - y) * width + from - x) as usize * channels;
+                pixels[target..target + len].copy_from_slice(chunk.get(source..source + len)?);
+            }
+        }
+    }
+    Some(match channels {
+        1 => ImageBuffer::from_raw(width, height, pixels).map(DynamicImage::ImageLuma8)?,
+        3 => ImageBuffer::from_raw(width, height, pixels).map(DynamicImage::ImageRgb8)?,
+        _ => ImageBuffer::from_raw(width, height, pixels).map(DynamicImage::ImageRgba8)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, path::PathBuf, process};
+
+    use image::{DynamicImage, GenericImageView, ImageBuffer, ImageFormat, Rgb};
+    use ratatui::layout::Rect;
+
+    use super::MappedImage;
+    use crate::ViewportOptions;
+
+    fn gradient() -> DynamicImage {
+        ImageBuffer::from_fn(300, 200, |x, y| Rgb::<u8>([x as u8, y as u8, 7])).into()
+    }
+
+    fn save(image: &DynamicImage, format: ImageFormat, extension: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!(
+            "ratatui-image-mapped-{}.{extension}",
+            process::id()
+        ));
+        image.save_with_format(&path, format).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_decode_region() {
+        let image = gradient();
+        let path = save(&image.to_rgba16().into(), ImageFormat::Farbfeld, "ff");
+        let mapped = MappedImage::open(&path).unwrap();
+        assert_eq!(mapped.format(), ImageFormat::Farbfeld);
+        assert_eq!(mapped.dimensions(), (300, 200));
+
+        let region = mapped.decode_region(250, 20, 100, 10).unwrap();
+        // Clipped to the right edge.
+        assert_eq!(region.dimensions(), (50, 10));
+        assert_eq!(region.to_rgb8().get_pixel(0, 0), &Rgb([250, 20, 7]));
+        assert_eq!(region.to_rgb8().get_pixel(49, 9), &Rgb([43, 29, 7]));
+        assert_eq!(
+            mapped.decode_region(300, 0, 10, 10).unwrap().dimensions(),
+            (0, 0)
+        );
+
+        // 10x20 screen pixels per cell, at zoom 2.
+        let viewport = ViewportOptions::new(2.0, (100, 50));
+        let (region, options) = mapped
+            .decode_viewport(&viewport, Rect::new(0, 0, 4, 2), (10, 20))
+            .unwrap();
+        assert_eq!(region.dimensions(), (20, 20));
+        assert_eq!(region.to_rgb8().get_pixel(0, 0), &Rgb([100, 50, 7]));
+        assert_eq!(options.offset, (0, 0));
+        assert_eq!(options.zoom, 2.0);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[cfg(feature = "tiff")]
+    #[test]
+    fn test_decode_tiff_region() {
+        let image = gradient();
+        let path = save(&image, ImageFormat::Tiff, "tiff");
+        let mapped = MappedImage::open(&path).unwrap();
+        // Spans several strips.
+        let region = super::tiff_region(&mapped.map, 10, 5, 280, 190).unwrap();
+        assert_eq!(region, image.crop_imm(10, 5, 280, 190));
+        fs::remove_file(path).unwrap();
+    }
+}
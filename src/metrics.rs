@@ -0,0 +1,280 @@
+//! Metrics of encoding, caching and transmitting, through the [metrics](https://docs.rs/metrics)
+//! crate.
+//!
+//! With the `metrics` feature enabled, the library records to whatever recorder the application
+//! installs, e.g. a Prometheus exporter. Without it, recording compiles to nothing.
+//!
+//! ```rust
+//! // Once at startup, after installing a recorder:
+//! ratatui_image::metrics::describe();
+//! ```
+//!
+//! | Name | Type | Labels |
+//! |------|------|--------|
+//! | [ENCODES] | counter | `protocol` |
+//! | [ENCODE_SECONDS] | histogram | `protocol` |
+//! | [PAYLOAD_BYTES] | counter | `protocol` |
+//! | [CACHE_HITS], [CACHE_MISSES] | counter | `cache` |
+//! | [QUEUE_DEPTH] | gauge | |
+
+#[cfg(feature = "metrics")]
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    time::Instant,
+};
+
+use ratatui::{buffer::Buffer, layout::Rect};
+
+use crate::picker::ProtocolType;
+
+/// Resizes and encodes of a [crate::protocol::StatefulProtocol].
+pub const ENCODES: &str = "ratatui_image_encodes_total";
+/// Duration of each resize and encode, in seconds.
+pub const ENCODE_SECONDS: &str = "ratatui_image_encode_seconds";
+/// Bytes of escape sequences that renders put into the buffer, counted only when they changed
+/// since the previous render, because ratatui only writes cells that changed.
+pub const PAYLOAD_BYTES: &str = "ratatui_image_payload_bytes_total";
+/// Lookups that reused an encoding, labelled by cache: `shared` ([crate::protocol::SharedEncodings]),
+/// `keyed` ([crate::cache::ImageCacheContext]) or `protocol` ([crate::cache::ProtocolCache]).
+pub const CACHE_HITS: &str = "ratatui_image_cache_hits_total";
+/// Lookups that had to encode, labelled like [CACHE_HITS].
+pub const CACHE_MISSES: &str = "ratatui_image_cache_misses_total";
+/// Protocols that were sent off by [crate::thread::ThreadProtocol] and not returned yet.
+pub const QUEUE_DEPTH: &str = "ratatui_image_encode_queue_depth";
+
+/// Describe the metrics to the installed recorder, which exporters show as help text.
+///
+/// Does nothing without the `metrics` feature.
+pub fn describe() {
+    #[cfg(feature = "metrics")]
+    {
+        use ::metrics::{describe_counter, describe_gauge, describe_histogram, Unit};
+        describe_counter!(ENCODES, "Resizes and encodes of stateful protocols.");
+        describe_histogram!(
+            ENCODE_SECONDS,
+            Unit::Seconds,
+            "Duration of resizes and encodes."
+        );
+        describe_counter!(
+            PAYLOAD_BYTES,
+            Unit::Bytes,
+            "Bytes of escape sequences rendered for transmission."
+        );
+        describe_counter!(CACHE_HITS, "Cache lookups that reused an encoding.");
+        describe_counter!(CACHE_MISSES, "Cache lookups that had to encode.");
+        describe_gauge!(
+            QUEUE_DEPTH,
+            "Protocols waiting to be encoded on another thread."
+        );
+    }
+}
+
+pub(crate) fn protocol_label(protocol_type: ProtocolType) -> &'static str {
+    match protocol_type {
+        ProtocolType::Halfblocks => "halfblocks",
+        ProtocolType::Sixel => "sixel",
+        ProtocolType::Kitty => "kitty",
+        ProtocolType::Iterm2 => "iterm2",
+        ProtocolType::Terminology => "terminology",
+        ProtocolType::Braille => "braille",
+        ProtocolType::Custom(name) => name,
+    }
+}
+
+// Times an encode, recorded on drop so that early returns are measured too.
+pub(crate) struct EncodeTimer {
+    #[cfg(feature = "metrics")]
+    start: Instant,
+    #[cfg(feature = "metrics")]
+    protocol: &'static str,
+}
+
+impl EncodeTimer {
+    pub(crate) fn start(protocol_type: ProtocolType) -> EncodeTimer {
+        let _protocol = protocol_label(protocol_type);
+        EncodeTimer {
+            #[cfg(feature = "metrics")]
+            start: Instant::now(),
+            #[cfg(feature = "metrics")]
+            protocol: _protocol,
+        }
+    }
+}
+
+impl Drop for EncodeTimer {
+    fn drop(&mut self) {
+        #[cfg(feature = "metrics")]
+        {
+            ::metrics::counter!(ENCODES, "protocol" => self.protocol).increment(1);
+            ::metrics::histogram!(ENCODE_SECONDS, "protocol" => self.protocol)
+                .record(self.start.elapsed());
+        }
+    }
+}
+
+// Count the escape sequences that a render put into the area, unless they are the same as in the
+// previous render, whose hash is in `_last`.
+pub(crate) fn payload_written(
+    _protocol_type: ProtocolType,
+    _area: Rect,
+    _buf: &Buffer,
+    _last: &mut Option<u64>,
+) {
+    #[cfg(feature = "metrics")]
+    {
+        let area = _area.intersection(_buf.area);
+        let mut hasher = DefaultHasher::new();
+        let mut len = 0;
+        for y in area.top()..area.bottom() {
+            for x in area.left()..area.right() {
+                let symbol = _buf[(x, y)].symbol();
+                if symbol.contains('\x1b') {
+                    (x, y, symbol).hash(&mut hasher);
+                    len += symbol.len();
+                }
+            }
+        }
+        if len == 0 {
+            *_last = None;
+            return;
+        }
+        let hash = hasher.finish();
+        if *_last != Some(hash) {
+            *_last = Some(hash);
+            ::metrics::counter!(PAYLOAD_BYTES, "protocol" => protocol_label(_protocol_type))
+                .increment(len as u64);
+        }
+    }
+}
+
+pub(crate) fn cache_lookup(_cache: &'static str, _hit: bool) {
+    #[cfg(feature = "metrics")]
+    {
+        let name = if _hit { CACHE_HITS } else { CACHE_MISSES };
+        ::metrics::counter!(name, "cache" => _cache).increment(1);
+    }
+}
+
+pub(crate) fn queue_depth(_delta: f64) {
+    #[cfg(feature = "metrics")]
+    ::metrics::gauge!(QUEUE_DEPTH).increment(_delta);
+}
+
+#[cfg(all(test, feature = "metrics"))]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc, Arc, Mutex,
+    };
+
+    use ::metrics::{Counter, Gauge, Histogram, Key, KeyName, Metadata, SharedString, Unit};
+    use image::{DynamicImage, ImageBuffer, Rgba};
+    use ratatui::{buffer::Buffer, layout::Rect};
+
+    use crate::{
+        picker::{Picker, ProtocolType},
+        thread::ThreadProtocol,
+        Resize, ResizeEncodeRender,
+    };
+
+    // Counters and gauges by name and labels, without histograms.
+    #[derive(Default)]
+    struct TestRecorder {
+        values: Mutex<Vec<(Key, Arc<AtomicU64>)>>,
+    }
+
+    impl TestRecorder {
+        fn value(&self, key: &Key) -> Arc<AtomicU64> {
+            let mut values = self.values.lock().unwrap();
+            if let Some((_, value)) = values.iter().find(|(k, _)| k == key) {
+                return value.clone();
+            }
+            let value = Arc::new(AtomicU64::new(0));
+            values.push((key.clone(), value.clone()));
+            value
+        }
+
+        fn counter(&self, key: &Key) -> u64 {
+            self.value(key).load(Ordering::Acquire)
+        }
+
+        fn gauge(&self, key: &Key) -> f64 {
+            f64::from_bits(self.value(key).load(Ordering::Acquire))
+        }
+    }
+
+    impl ::metrics::Recorder for TestRecorder {
+        fn describe_counter(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+        fn describe_gauge(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+        fn describe_histogram(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+        fn register_counter(&self, key: &Key, _: &Metadata<'_>) -> Counter {
+            Counter::from_arc(self.value(key))
+        }
+        fn register_gauge(&self, key: &Key, _: &Metadata<'_>) -> Gauge {
+            Gauge::from_arc(self.value(key))
+        }
+        fn register_histogram(&self, _: &Key, _: &Metadata<'_>) -> Histogram {
+            Histogram::noop()
+        }
+    }
+
+    #[test]
+    fn test_payload_bytes() {
+        let recorder = TestRecorder::default();
+        let key = Key::from_parts(super::PAYLOAD_BYTES, [("protocol", "sixel")].as_slice());
+        ::metrics::with_local_recorder(&recorder, || {
+            let image: DynamicImage =
+                ImageBuffer::from_pixel(40, 40, Rgba::<u8>([255, 0, 0, 255])).into();
+            let mut picker = Picker::from_fontsize((10, 20));
+            picker.set_protocol_type(ProtocolType::Sixel);
+            let mut protocol = picker.new_resize_protocol(image);
+            let area = Rect::new(0, 0, 4, 2);
+            protocol.resize_encode(&Resize::Fit(None), Rgba([0; 4]), area);
+            // Encoding alone writes nothing.
+            assert_eq!(recorder.counter(&key), 0);
+
+            let mut buf = Buffer::empty(area);
+            protocol.render(area, &mut buf);
+            let len = buf[(0, 0)].symbol().len() as u64;
+            assert_eq!(recorder.counter(&key), len);
+
+            // The same cells again are not written by ratatui.
+            protocol.render(area, &mut buf);
+            assert_eq!(recorder.counter(&key), len);
+
+            protocol.force_redraw_next_frame();
+            protocol.render(area, &mut buf);
+            assert!(recorder.counter(&key) > len * 2);
+        });
+    }
+
+    #[test]
+    fn test_queue_depth() {
+        let recorder = TestRecorder::default();
+        let key = Key::from_name(super::QUEUE_DEPTH);
+        ::metrics::with_local_recorder(&recorder, || {
+            let image: DynamicImage =
+                ImageBuffer::from_pixel(40, 40, Rgba::<u8>([255, 0, 0, 255])).into();
+            let picker = Picker::from_fontsize((10, 20));
+            let (tx, rx) = mpsc::channel();
+            let area = Rect::new(0, 0, 4, 2);
+            let mut buf = Buffer::empty(area);
+
+            let mut returned =
+                ThreadProtocol::new(tx.clone(), picker.new_resize_protocol(image.clone()));
+            returned.resize_encode_render(&Resize::Fit(None), area, &mut buf);
+            assert_eq!(recorder.gauge(&key), 1.0);
+            let (protocol, _, _) = rx.try_recv().unwrap();
+            returned.set_protocol(protocol);
+            assert_eq!(recorder.gauge(&key), 0.0);
+
+            // Dropped while away, it never comes back.
+            let mut dropped = ThreadProtocol::new(tx, picker.new_resize_protocol(image));
+            dropped.resize_encode_render(&Resize::Fit(None), area, &mut buf);
+            assert_eq!(recorder.gauge(&key), 1.0);
+            drop(dropped);
+            assert_eq!(recorder.gauge(&key), 0.0);
+        });
+    }
+}
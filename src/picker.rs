@@ -1,39 +1,315 @@
 //! Helper module to build a protocol, and swap protocols at runtime
 
 use std::{
-    env,
+    env, fmt,
+    fs::File,
     io::{self, Read, Write},
-    time::Duration,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
-use cap_parser::{Capability, Parser};
-use image::{DynamicImage, Rgba};
+use cap_parser::{Capability, Parser, Response};
+use image::{DynamicImage, ImageFormat, Rgb, Rgba};
 use ratatui::layout::Rect;
 #[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
 
 use crate::{
+    background::BackgroundProtocol,
     errors::Errors,
+    font_watch::FontSizeWatcher,
+    glyphs::{GlyphOptions, GlyphProtocol},
     protocol::{
-        halfblocks::{Halfblocks, StatefulHalfblocks},
-        iterm2::{Iterm2, StatefulIterm2},
-        kitty::{Kitty, StatefulKitty},
+        braille::{Braille, BrailleOptions, StatefulBraille},
+        clamp_area,
+        halfblocks::{Halfblocks, HalfblocksQuantizer, Palette, StatefulHalfblocks},
+        iterm2::{Iterm2, Iterm2Format, StatefulIterm2},
+        kitty::{self, Kitty, KittyFormat, KittyIdColor, KittyOptions, StatefulKitty},
         sixel::{Sixel, StatefulSixel},
-        Protocol, StatefulProtocol,
+        terminology::{StatefulTerminology, Terminology},
+        CellProtection, LazyProtocol, Protocol, ProtocolBackend, SharedEncodings, StatefulProtocol,
+        StatefulProtocolType,
     },
-    FontSize, ImageSource, Resize, Result,
+    reflow::ReflowTracker,
+    sequence_log::SequenceLog,
+    tmux::{OuterTerminal, TmuxPane},
+    watermark::Watermark,
+    AreaClaim, ErrorIndicator, FontSize, ImageSource, Resize, Result,
 };
 
 pub mod cap_parser;
 
 const DEFAULT_BACKGROUND: Rgba<u8> = Rgba([0, 0, 0, 0]);
 
-#[derive(Clone, Copy, Debug)]
+static DETERMINISTIC: AtomicBool = AtomicBool::new(false);
+
+/// Make every [Picker] that is created afterwards deterministic, see [Picker::set_deterministic_ids].
+///
+/// Two runs of a program then produce byte-identical output, which is useful for golden files,
+/// snapshot tests, or pre-encoding images at build time. Apart from the Kitty image ids, encoding
+/// has no random inputs: halfblocks and sixel dithering are plain error diffusion.
+pub fn set_deterministic(deterministic: bool) {
+    DETERMINISTIC.store(deterministic, Ordering::Relaxed);
+}
+
+fn default_kitty_ids() -> Option<Arc<AtomicU32>> {
+    DETERMINISTIC
+        .load(Ordering::Relaxed)
+        .then(|| Arc::new(AtomicU32::new(1)))
+}
+
+#[derive(Clone, Debug)]
 pub struct Picker {
     font_size: FontSize,
     protocol_type: ProtocolType,
     background_color: Rgba<u8>,
     is_tmux: bool,
+    halfblocks_quantizer: HalfblocksQuantizer,
+    color_depth: ColorDepth,
+    braille_options: BrailleOptions,
+    theme: Theme,
+    sixel_erase: bool,
+    cell_protections: PerProtocol<CellProtection>,
+    sixel_palette_reuse: Option<u32>,
+    sixel_transparency: bool,
+    iterm2_format: Iterm2Format,
+    max_image_sizes: PerProtocol<Option<(u32, u32)>>,
+    kitty_ids: Option<Arc<AtomicU32>>,
+    kitty_id_color: KittyIdColor,
+    kitty_options: KittyOptions,
+    shared_encodings: Option<SharedEncodings>,
+    text_sizing: bool,
+    reflow_tracker: Option<ReflowTracker>,
+    sequence_log: Option<SequenceLog>,
+    area_claim: AreaClaim,
+    error_indicator: ErrorIndicator,
+    protocol_policy: Option<ProtocolPolicy>,
+    // The protocols that could be used, the preferred first, see Picker::set_protocol_preference.
+    detected_protocols: Vec<ProtocolType>,
+    query_responses: Vec<u8>,
+    watermark: Option<Watermark>,
+    // Compares tty readings for Picker::refresh_font_size, from the first refresh on.
+    font_size_watcher: Option<FontSizeWatcher>,
+    backend: Option<Backend>,
+}
+
+/// Whether the terminal has a light or dark background.
+#[derive(PartialEq, Clone, Debug, Copy, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Deserialize, Serialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum ThemeMode {
+    Light,
+    Dark,
+    /// The terminal did not report its background color.
+    #[default]
+    Unknown,
+}
+
+/// The terminal's colors, as reported by OSC 10 and OSC 11.
+#[derive(PartialEq, Clone, Debug, Copy, Default)]
+pub struct Theme {
+    pub mode: ThemeMode,
+    pub foreground: Option<Rgb<u8>>,
+    pub background: Option<Rgb<u8>>,
+}
+
+impl Theme {
+    /// Derive the [ThemeMode] from the background's relative luminance.
+    pub fn new(foreground: Option<Rgb<u8>>, background: Option<Rgb<u8>>) -> Theme {
+        let mode = match background {
+            Some(background) if luminance(background) > 0.5 => ThemeMode::Light,
+            Some(_) => ThemeMode::Dark,
+            None => ThemeMode::Unknown,
+        };
+        Theme {
+            mode,
+            foreground,
+            background,
+        }
+    }
+
+    /// An opaque color slightly set apart from the terminal background.
+    ///
+    /// Using it as [Picker::set_background_color] keeps images with transparency readable, e.g.
+    /// dark line-art on a dark terminal, while still blending in with the theme.
+    pub fn contrast_color(&self) -> Rgba<u8> {
+        let Some(Rgb([r, g, b])) = self.background else {
+            return Rgba([128, 128, 128, 255]);
+        };
+        let adjust = |c: u8| match self.mode {
+            ThemeMode::Light => c.saturating_sub(32),
+            _ => c.saturating_add(32),
+        };
+        Rgba([adjust(r), adjust(g), adjust(b), 255])
+    }
+}
+
+/// Relative luminance in `0.0..=1.0`.
+pub(crate) fn luminance(Rgb([r, g, b]): Rgb<u8>) -> f32 {
+    (0.2126 * r as f32 + 0.7152 * g as f32 + 0.0722 * b as f32) / 255.0
+}
+
+struct QueryResult {
+    // The graphics protocols that the terminal reported, the best first.
+    protocol_types: Vec<ProtocolType>,
+    font_size: Option<FontSize>,
+    theme: Theme,
+    sixel_max_size: Option<(u32, u32)>,
+    text_sizing: bool,
+    responses: Vec<u8>,
+}
+
+/// Where the text of [Picker::rich_output] is shown.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum OutputTarget {
+    /// Written to the terminal as is, e.g. notebook cells or `cat`.
+    Terminal,
+    /// Shown through a pager like `less -R`, which drops all but color sequences.
+    Pager,
+}
+
+/// Rough estimate of the bytes written to the terminal for an image that fills `area`.
+///
+/// This can be used for pre-flight checks, e.g. to warn or to fall back to halfblocks when a
+/// full-screen sixel image would be several megabytes over a remote connection. Kitty and
+/// halfblocks payloads hardly depend on the content, and iTerm2 is estimated as an incompressible
+/// PNG. Sixel payloads are run-length encoded, and the estimate is on the high side of
+/// photographs; flat images like logos are much smaller.
+pub fn estimate_payload_size(
+    protocol_type: ProtocolType,
+    area: Rect,
+    font_size: FontSize,
+) -> usize {
+    let cells = area.width as usize * area.height as usize;
+    let pixels = cells * font_size.0 as usize * font_size.1 as usize;
+    match protocol_type {
+        // Two 24-bit SGR sequences and a 3-byte character per cell.
+        ProtocolType::Halfblocks => cells * 40,
+        // Run-length encoded six-pixel columns, per color.
+        ProtocolType::Sixel => pixels * 2,
+        // Base64 encoded RGBA, with the chunk headers and the unicode placeholders.
+        ProtocolType::Kitty => {
+            let base64 = pixels * 4 * 4 / 3;
+            base64 + (base64 / 4096 + 1) * 24 + cells * 4 + area.height as usize * 64
+        }
+        // Base64 encoded PNG (RGBA and a filter byte per row), and the erase sequence.
+        ProtocolType::Iterm2 => {
+            let rows = area.height as usize * font_size.1 as usize;
+            (pixels * 4 + rows) * 4 / 3 + cells * 8 + 256
+        }
+        // The image is written to a file, only the path and the placeholders are sent.
+        ProtocolType::Terminology => cells * 2 + area.height as usize * 16 + 256,
+        // One 24-bit SGR sequence and a 3-byte character per cell.
+        ProtocolType::Braille => cells * 22,
+        // Unknown, estimated as base64 encoded RGBA.
+        ProtocolType::Custom(_) => pixels * 4 * 4 / 3,
+    }
+}
+
+/// How many colors the terminal supports, for the halfblocks protocol.
+#[derive(PartialEq, Eq, Clone, Debug, Copy, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Deserialize, Serialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum ColorDepth {
+    /// 24-bit RGB colors.
+    #[default]
+    TrueColor,
+    /// The 256-color palette, see [Palette::Ansi256].
+    Ansi256,
+    /// The 16 ANSI colors, see [Palette::Ansi16].
+    Ansi16,
+}
+
+impl ColorDepth {
+    /// Guess from `COLORTERM` and `TERM`. Only a `TERM` that names a limited palette lowers the
+    /// depth, such as `linux`, `ansi` or `xterm-256color`, because `COLORTERM` is often lost over
+    /// ssh or sudo. A plain `xterm` or `screen` stays at [ColorDepth::TrueColor].
+    pub fn from_env() -> ColorDepth {
+        Self::from_term(
+            env::var("COLORTERM").ok().as_deref(),
+            env::var("TERM").ok().as_deref(),
+        )
+    }
+
+    fn from_term(colorterm: Option<&str>, term: Option<&str>) -> ColorDepth {
+        if matches!(colorterm, Some("truecolor" | "24bit")) {
+            return ColorDepth::TrueColor;
+        }
+        match term {
+            Some(term) if term.contains("direct") => ColorDepth::TrueColor,
+            Some(term) if term.contains("256color") => ColorDepth::Ansi256,
+            Some("linux" | "ansi") => ColorDepth::Ansi16,
+            Some(term) if term.ends_with("-16color") || term.ends_with("-color") => {
+                ColorDepth::Ansi16
+            }
+            _ => ColorDepth::TrueColor,
+        }
+    }
+
+    /// The palette that halfblocks are quantized to, if any.
+    pub fn palette(self) -> Option<Palette> {
+        match self {
+            ColorDepth::TrueColor => None,
+            ColorDepth::Ansi256 => Some(Palette::Ansi256),
+            ColorDepth::Ansi16 => Some(Palette::Ansi16),
+        }
+    }
+}
+
+/// How faithfully a protocol shows the colors and details of an image, see [Picker::fidelity].
+#[derive(PartialEq, Eq, Clone, Debug, Copy)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+pub struct Fidelity {
+    pub colors: ColorFidelity,
+    pub resolution: Resolution,
+}
+
+impl Fidelity {
+    /// Whether every pixel is shown with its exact color, e.g. for color-critical content.
+    pub fn is_exact(&self) -> bool {
+        self.colors == ColorFidelity::TrueColor && self.resolution == Resolution::Pixel
+    }
+}
+
+/// The colors that a protocol can show.
+#[derive(PartialEq, Eq, Clone, Debug, Copy)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Deserialize, Serialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum ColorFidelity {
+    /// 24-bit RGB colors.
+    TrueColor,
+    /// At most this many colors, to which the image is quantized.
+    Palette(u16),
+    /// Only one color on the background.
+    Mono,
+}
+
+/// The smallest area that a protocol shows one color for.
+#[derive(PartialEq, Eq, Clone, Debug, Copy)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Deserialize, Serialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum Resolution {
+    /// Every pixel of the font size.
+    Pixel,
+    /// The upper and lower half of a cell.
+    HalfCell,
+    /// A whole cell, like braille, whose dots all share one color.
+    Cell,
 }
 
 /// Serde-friendly protocol-type enum for [Picker].
@@ -43,21 +319,373 @@ pub struct Picker {
     derive(Deserialize, Serialize),
     serde(rename_all = "lowercase")
 )]
+#[non_exhaustive]
 pub enum ProtocolType {
     Halfblocks,
     Sixel,
     Kitty,
     Iterm2,
+    Terminology,
+    Braille,
+    /// A third-party backend by its name, see [Picker::set_backend].
+    #[cfg_attr(feature = "serde", serde(skip))]
+    Custom(BackendName),
 }
 
+/// The name of a third-party backend, see [ProtocolType::Custom].
+// Not spelled out in the variant, because serde would borrow a `&str` from the input even though
+// the variant is skipped.
+pub type BackendName = &'static str;
+
 impl ProtocolType {
     pub fn next(&self) -> ProtocolType {
         match self {
             ProtocolType::Halfblocks => ProtocolType::Sixel,
             ProtocolType::Sixel => ProtocolType::Kitty,
             ProtocolType::Kitty => ProtocolType::Iterm2,
-            ProtocolType::Iterm2 => ProtocolType::Halfblocks,
+            ProtocolType::Iterm2 => ProtocolType::Terminology,
+            ProtocolType::Terminology => ProtocolType::Braille,
+            ProtocolType::Braille | ProtocolType::Custom(_) => ProtocolType::Halfblocks,
+        }
+    }
+}
+
+/// The protocol types that only draw text, so that every terminal supports them, see
+/// [ProtocolPolicy::small].
+#[derive(PartialEq, Clone, Debug, Copy)]
+pub enum TextProtocolType {
+    Halfblocks,
+    Braille,
+}
+
+impl From<TextProtocolType> for ProtocolType {
+    fn from(protocol_type: TextProtocolType) -> Self {
+        match protocol_type {
+            TextProtocolType::Halfblocks => ProtocolType::Halfblocks,
+            TextProtocolType::Braille => ProtocolType::Braille,
+        }
+    }
+}
+
+// The third-party backend of ProtocolType::Custom, see Picker::set_backend.
+#[derive(Clone)]
+struct Backend(Arc<dyn ProtocolBackend>);
+
+impl fmt::Debug for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Backend").field(&self.0.name()).finish()
+    }
+}
+
+// A setting per protocol type.
+#[derive(Clone, Copy, Debug, Default)]
+struct PerProtocol<T> {
+    halfblocks: T,
+    sixel: T,
+    kitty: T,
+    iterm2: T,
+    terminology: T,
+    braille: T,
+    // Shared by all third-party backends.
+    custom: T,
+}
+
+impl<T> PerProtocol<T> {
+    fn get(&self, protocol_type: ProtocolType) -> &T {
+        match protocol_type {
+            ProtocolType::Halfblocks => &self.halfblocks,
+            ProtocolType::Sixel => &self.sixel,
+            ProtocolType::Kitty => &self.kitty,
+            ProtocolType::Iterm2 => &self.iterm2,
+            ProtocolType::Terminology => &self.terminology,
+            ProtocolType::Braille => &self.braille,
+            ProtocolType::Custom(_) => &self.custom,
+        }
+    }
+
+    fn get_mut(&mut self, protocol_type: ProtocolType) -> &mut T {
+        match protocol_type {
+            ProtocolType::Halfblocks => &mut self.halfblocks,
+            ProtocolType::Sixel => &mut self.sixel,
+            ProtocolType::Kitty => &mut self.kitty,
+            ProtocolType::Iterm2 => &mut self.iterm2,
+            ProtocolType::Terminology => &mut self.terminology,
+            ProtocolType::Braille => &mut self.braille,
+            ProtocolType::Custom(_) => &mut self.custom,
+        }
+    }
+}
+
+/// Picks the protocol per image by its size, within what the terminal supports, see
+/// [Picker::set_protocol_policy].
+///
+/// Tiny images like icons often look as good and cost much less as halfblocks than as a graphics
+/// payload, while huge images are smaller as PNG with Kitty:
+///
+/// ```rust
+/// # use ratatui_image::{picker::{Picker, ProtocolPolicy, TextProtocolType}, protocol::kitty::KittyFormat};
+/// let mut picker = Picker::from_fontsize((8, 16));
+/// picker.set_protocol_policy(Some(
+///     ProtocolPolicy::new()
+///         .small((2, 1), TextProtocolType::Halfblocks)
+///         .large(80 * 24, KittyFormat::Png),
+/// ));
+/// ```
+#[derive(PartialEq, Clone, Debug, Copy, Default)]
+pub struct ProtocolPolicy {
+    small: Option<((u16, u16), TextProtocolType)>,
+    large: Option<(u32, KittyFormat)>,
+}
+
+impl ProtocolPolicy {
+    pub fn new() -> ProtocolPolicy {
+        ProtocolPolicy::default()
+    }
+
+    /// Use a text protocol for images of at most `width` by `height` cells. Only text protocols
+    /// can be chosen, because the terminal may not support a graphics protocol.
+    pub fn small(mut self, (width, height): (u16, u16), protocol_type: TextProtocolType) -> Self {
+        self.small = Some(((width, height), protocol_type));
+        self
+    }
+
+    /// With the Kitty protocol, transmit images of at least `cells` cells in this format.
+    pub fn large(mut self, cells: u32, format: KittyFormat) -> Self {
+        self.large = Some((cells, format));
+        self
+    }
+
+    /// The protocol type, and the Kitty format if it changes, for an image of `width` by `height`
+    /// cells when the terminal supports `protocol_type`. None if the policy keeps both.
+    pub fn pick(
+        &self,
+        protocol_type: ProtocolType,
+        (width, height): (u16, u16),
+    ) -> Option<(ProtocolType, Option<KittyFormat>)> {
+        if let Some(((max_width, max_height), small)) = self.small {
+            let small = ProtocolType::from(small);
+            if width <= max_width && height <= max_height && small != protocol_type {
+                return Some((small, None));
+            }
+        }
+        match self.large {
+            Some((cells, format))
+                if protocol_type == ProtocolType::Kitty
+                    && width as u32 * height as u32 >= cells =>
+            {
+                Some((protocol_type, Some(format)))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// User configuration that is merged over the detected values, see [Picker::apply_overrides].
+///
+/// Every field is optional, so that a config file only lists what it changes, e.g. to turn off
+/// sixel on one host, or to assume a font size that the terminal does not report.
+#[derive(PartialEq, Clone, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize), serde(default))]
+pub struct Overrides {
+    /// Use this protocol, whether it was detected or not.
+    pub protocol_type: Option<ProtocolType>,
+    /// Never use these protocols, even if they were detected.
+    pub disabled_protocols: Vec<ProtocolType>,
+    pub font_size: Option<FontSize>,
+    pub color_depth: Option<ColorDepth>,
+}
+
+/// The configuration of a [Picker] after detection and overrides, see
+/// [Picker::effective_config], e.g. to log it for debugging.
+#[derive(PartialEq, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct EffectiveConfig {
+    pub protocol_type: ProtocolType,
+    pub detected_protocols: Vec<ProtocolType>,
+    pub font_size: FontSize,
+    pub color_depth: ColorDepth,
+    pub is_tmux: bool,
+    /// The RGBA background color, see [Picker::set_background_color].
+    pub background_color: [u8; 4],
+    pub theme_mode: ThemeMode,
+}
+
+/// Whether the picker turns on tmux's `allow-passthrough` option when it detects tmux, which
+/// graphics protocols need to reach the outer terminal.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum TmuxPassthrough {
+    /// For the pane of the program, with `tmux set -p` (the default).
+    #[default]
+    Pane,
+    /// For all panes, with `tmux set -g`.
+    Global,
+    /// Leave tmux's options alone, e.g. when they are managed in the tmux configuration.
+    Unchanged,
+}
+
+impl TmuxPassthrough {
+    // The arguments of the tmux command, or None to not run it.
+    fn args(&self) -> Option<[&'static str; 4]> {
+        match self {
+            TmuxPassthrough::Pane => Some(["set", "-p", "allow-passthrough", "on"]),
+            TmuxPassthrough::Global => Some(["set", "-g", "allow-passthrough", "on"]),
+            TmuxPassthrough::Unchanged => None,
+        }
+    }
+}
+
+/// Builds a [Picker] from the capability query and explicit settings, see [Picker::builder].
+///
+/// ```rust,no_run
+/// # use std::time::Duration;
+/// # use ratatui_image::picker::{Picker, ProtocolType};
+/// let picker = Picker::builder()
+///     .timeout(Duration::from_millis(300))
+///     .disable_protocol(ProtocolType::Iterm2)
+///     .font_size((9, 18))
+///     .build()?;
+/// # Ok::<(), ratatui_image::errors::Errors>(())
+/// ```
+#[derive(Clone, Debug)]
+pub struct PickerBuilder {
+    query: bool,
+    timeout: Duration,
+    tmux: Option<bool>,
+    tmux_passthrough: TmuxPassthrough,
+    extra_queries: Vec<String>,
+    text_sizing_probe: bool,
+    background_color: Option<Rgba<u8>>,
+    overrides: Overrides,
+}
+
+impl Default for PickerBuilder {
+    fn default() -> Self {
+        PickerBuilder {
+            query: true,
+            timeout: Duration::from_secs(1),
+            tmux: None,
+            tmux_passthrough: TmuxPassthrough::default(),
+            extra_queries: vec![],
+            text_sizing_probe: false,
+            background_color: None,
+            overrides: Overrides::default(),
+        }
+    }
+}
+
+impl PickerBuilder {
+    pub fn new() -> PickerBuilder {
+        PickerBuilder::default()
+    }
+
+    /// Query the terminal over stdio (default true). Without the query, the protocol is guessed
+    /// from env vars like [Picker::from_fontsize], and the font size defaults to 10x20.
+    pub fn query(mut self, query: bool) -> PickerBuilder {
+        self.query = query;
+        self
+    }
+
+    /// How long to wait for the terminal's responses (default one second).
+    pub fn timeout(mut self, timeout: Duration) -> PickerBuilder {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Whether to wrap the escape sequences for tmux, instead of detecting it from the
+    /// environment with `None` (the default).
+    pub fn tmux(mut self, tmux: Option<bool>) -> PickerBuilder {
+        self.tmux = tmux;
+        self
+    }
+
+    /// Whether to turn on tmux's `allow-passthrough` option inside tmux, see [TmuxPassthrough].
+    pub fn tmux_passthrough(mut self, tmux_passthrough: TmuxPassthrough) -> PickerBuilder {
+        self.tmux_passthrough = tmux_passthrough;
+        self
+    }
+
+    /// Another escape sequence to write along with the capability query. The raw responses are
+    /// available as [Picker::query_responses].
+    ///
+    /// It is written as is, so inside tmux its escapes must be doubled like the passthrough
+    /// requires.
+    pub fn extra_query(mut self, query: impl Into<String>) -> PickerBuilder {
+        self.extra_queries.push(query.into());
+        self
+    }
+
+    /// Detect the text sizing protocol (OSC 66) for [Picker::text_sizing] (default false), see
+    /// [CapabilityQuery::probe_text_sizing].
+    pub fn probe_text_sizing(mut self, probe: bool) -> PickerBuilder {
+        self.text_sizing_probe = probe;
+        self
+    }
+
+    /// See [Picker::set_background_color]. By default, the terminal's background color if it was
+    /// reported, but transparent.
+    pub fn background_color<T: Into<Rgba<u8>>>(mut self, background_color: T) -> PickerBuilder {
+        self.background_color = Some(background_color.into());
+        self
+    }
+
+    /// Use this protocol, whether it was detected or not, see [Overrides::protocol_type].
+    pub fn protocol_type(mut self, protocol_type: ProtocolType) -> PickerBuilder {
+        self.overrides.protocol_type = Some(protocol_type);
+        self
+    }
+
+    /// Never use this protocol, see [Overrides::disabled_protocols].
+    pub fn disable_protocol(mut self, protocol_type: ProtocolType) -> PickerBuilder {
+        self.overrides.disabled_protocols.push(protocol_type);
+        self
+    }
+
+    /// Use this font size, even if the terminal does not report any.
+    pub fn font_size(mut self, font_size: FontSize) -> PickerBuilder {
+        self.overrides.font_size = Some(font_size);
+        self
+    }
+
+    /// Merge these over the detected values, replacing any of the other overrides of the builder.
+    pub fn overrides(mut self, overrides: Overrides) -> PickerBuilder {
+        self.overrides = overrides;
+        self
+    }
+
+    /// Query the terminal, if enabled, and apply the settings.
+    pub fn build(self) -> Result<Picker> {
+        let forced_font_size = self.overrides.font_size.filter(|(w, h)| *w > 0 && *h > 0);
+        let mut picker = if self.query {
+            let mut query = CapabilityQuery::with_tmux_passthrough(self.tmux_passthrough);
+            if let Some(tmux) = self.tmux {
+                query.is_tmux = tmux;
+                if !tmux {
+                    query.tmux_proto = None;
+                }
+            }
+            query.extra_queries = self.extra_queries;
+            query.text_sizing_probe = self.text_sizing_probe;
+            let (is_tmux, tmux_proto) = (query.is_tmux, query.tmux_proto);
+            let result = query_with_timeout(QueryTty::Stdio, query, true, self.timeout);
+            let result = result.map(|mut result| {
+                result.font_size = forced_font_size.or(result.font_size);
+                result
+            });
+            Picker::from_query_result(is_tmux, tmux_proto, result)?
+        } else {
+            Picker::from_fontsize_with_tmux_passthrough(
+                forced_font_size.unwrap_or((10, 20)),
+                self.tmux_passthrough,
+            )
+        };
+        if let Some(tmux) = self.tmux {
+            picker.is_tmux = tmux;
+        }
+        if let Some(background_color) = self.background_color {
+            picker.background_color = background_color;
         }
+        picker.apply_overrides(&self.overrides);
+        Ok(picker)
     }
 }
 
@@ -66,7 +694,9 @@ impl Picker {
     /// Query terminal stdio for graphics capabilities and font-size with some escape sequences.
     ///
     /// This writes and reads from stdio momentarily. WARNING: this method should be called after
-    /// entering alternate screen but before reading terminal events.
+    /// entering alternate screen but before reading terminal events. If another reader such as
+    /// crossterm's `EventStream` is already running, responses go missing and it fails with
+    /// [Errors::QueryTimeout]; use a [CapabilityQuery] instead.
     ///
     /// # Example
     /// ```rust
@@ -75,26 +705,174 @@ impl Picker {
     /// ```
     ///
     pub fn from_query_stdio() -> Result<Picker> {
+        Picker::from_query(QueryTty::Stdio, true)
+    }
+
+    /// A builder to combine the capability query with explicit settings, see [PickerBuilder].
+    pub fn builder() -> PickerBuilder {
+        PickerBuilder::new()
+    }
+
+    /// The raw responses of the capability query, including those of
+    /// [PickerBuilder::extra_query]. Empty if there was no query, or no capabilities reported.
+    pub fn query_responses(&self) -> &[u8] {
+        &self.query_responses
+    }
+
+    /// Like [Picker::from_query_stdio], but assume that the terminal is already in raw mode, e.g.
+    /// after `crossterm::terminal::enable_raw_mode()`, and never touch the terminal modes.
+    ///
+    /// [Picker::from_query_stdio] also leaves the modes alone if echo and canonical mode are
+    /// already disabled, but this avoids any conflict with how the app manages them.
+    pub fn from_query_stdio_in_raw_mode() -> Result<Picker> {
+        Picker::from_query(QueryTty::Stdio, false)
+    }
+
+    /// Like [Picker::from_query_stdio], but query over a tty handle instead of stdin and stdout,
+    /// e.g. when stdin is a pipe as in `cat list | my-tui`. With `None`, `/dev/tty` is opened,
+    /// which is the controlling terminal of the process.
+    ///
+    /// # Example
+    /// ```rust,no_run
+    /// use ratatui_image::picker::Picker;
+    /// let mut picker = Picker::from_query_tty(None);
+    /// ```
+    #[cfg(not(windows))]
+    pub fn from_query_tty(tty: Option<File>) -> Result<Picker> {
+        let tty = match tty {
+            Some(tty) => tty,
+            None => File::options().read(true).write(true).open("/dev/tty")?,
+        };
+        Picker::from_query(QueryTty::File(tty), true)
+    }
+
+    /// Like [Picker::from_query_stdio], but run `work` on another thread meanwhile, e.g. loading
+    /// and decoding the first image, which would otherwise only start after the query.
+    ///
+    /// # Example
+    /// ```rust,no_run
+    /// use ratatui_image::picker::Picker;
+    /// let (picker, image) = Picker::from_query_stdio_concurrently(|| image::open("image.png"));
+    /// let protocol = picker.unwrap().new_resize_protocol(image.unwrap());
+    /// ```
+    pub fn from_query_stdio_concurrently<T, F>(work: F) -> (Result<Picker>, T)
+    where
+        T: Send,
+        F: FnOnce() -> T + Send,
+    {
+        std::thread::scope(|scope| {
+            let work = scope.spawn(work);
+            let picker = Picker::from_query_stdio();
+            match work.join() {
+                Ok(output) => (picker, output),
+                Err(panic) => std::panic::resume_unwind(panic),
+            }
+        })
+    }
+
+    /// Like [Picker::from_query_stdio], but without blocking the async runtime.
+    ///
+    /// The query runs on tokio's blocking thread pool, so other tasks keep running meanwhile.
+    /// Needs the `tokio` feature, and must be called from within a tokio runtime.
+    ///
+    /// # Example
+    /// ```rust,no_run
+    /// use ratatui_image::picker::Picker;
+    /// # async fn init() -> Result<(), ratatui_image::errors::Errors> {
+    /// let picker = Picker::from_query_stdio_async().await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[cfg(feature = "tokio")]
+    pub async fn from_query_stdio_async() -> Result<Picker> {
+        Picker::from_query_async(QueryTty::Stdio).await
+    }
+
+    #[cfg(feature = "tokio")]
+    async fn from_query_async(tty: QueryTty) -> Result<Picker> {
+        match tokio::task::spawn_blocking(move || Picker::from_query(tty, true)).await {
+            Ok(result) => result,
+            Err(err) if err.is_panic() => std::panic::resume_unwind(err.into_panic()),
+            // The runtime is shutting down.
+            Err(_cancelled) => Err(Errors::NoStdinResponse),
+        }
+    }
+
+    fn from_query(tty: QueryTty, set_raw_mode: bool) -> Result<Picker> {
         // Detect tmux, and only if positive then take some risky guess for iTerm2 support.
-        let (is_tmux, tmux_proto) = detect_tmux_and_outer_protocol_from_env();
+        let query = CapabilityQuery::new();
+        let (is_tmux, tmux_proto) = (query.is_tmux, query.tmux_proto);
 
         // Write and read to stdin to query protocol capabilities and font-size.
-        match query_with_timeout(is_tmux, Duration::from_secs(1)) {
-            Ok((capability_proto, font_size)) => {
+        let result = query_with_timeout(tty, query, set_raw_mode, Duration::from_secs(1));
+        Picker::from_query_result(is_tmux, tmux_proto, result)
+    }
+
+    fn from_query_result(
+        is_tmux: bool,
+        tmux_proto: Option<ProtocolType>,
+        result: Result<QueryResult>,
+    ) -> Result<Picker> {
+        match result {
+            Ok(QueryResult {
+                protocol_types: capability_protos,
+                font_size,
+                theme,
+                sixel_max_size,
+                text_sizing,
+                responses,
+            }) => {
                 // If some env var says that we should try iTerm2, then disregard protocol-from-capabilities.
                 let iterm2_proto = iterm2_from_env();
 
-                let protocol_type = tmux_proto
-                    .or(iterm2_proto)
-                    .or(capability_proto)
-                    .unwrap_or(ProtocolType::Halfblocks);
+                let detected_protocols = detected_protocols(
+                    [tmux_proto, iterm2_proto]
+                        .into_iter()
+                        .flatten()
+                        .chain(capability_protos),
+                );
+                let protocol_type = detected_protocols[0];
+
+                // Pad with the terminal's background color, but keep it transparent where the
+                // protocol supports it.
+                let background_color = theme
+                    .background
+                    .map_or(DEFAULT_BACKGROUND, |Rgb([r, g, b])| Rgba([r, g, b, 0]));
+
+                let mut max_image_sizes = PerProtocol::default();
+                *max_image_sizes.get_mut(ProtocolType::Sixel) = sixel_max_size;
 
                 if let Some(font_size) = font_size {
                     Ok(Picker {
                         font_size,
-                        background_color: DEFAULT_BACKGROUND,
+                        background_color,
                         protocol_type,
                         is_tmux,
+                        halfblocks_quantizer: HalfblocksQuantizer::default(),
+                        color_depth: ColorDepth::from_env(),
+                        braille_options: BrailleOptions::default(),
+                        theme,
+                        sixel_erase: sixel_erase_from_env(),
+                        cell_protections: cell_protections_from_env(),
+                        sixel_palette_reuse: None,
+                        sixel_transparency: false,
+                        iterm2_format: Iterm2Format::Png,
+                        max_image_sizes,
+                        kitty_ids: default_kitty_ids(),
+                        kitty_id_color: KittyIdColor::default(),
+                        kitty_options: KittyOptions::default(),
+                        shared_encodings: None,
+                        text_sizing,
+                        reflow_tracker: None,
+                        sequence_log: None,
+                        area_claim: AreaClaim::default(),
+                        error_indicator: ErrorIndicator::default(),
+                        protocol_policy: None,
+                        detected_protocols,
+                        query_responses: responses,
+                        watermark: None,
+                        font_size_watcher: None,
+                        backend: None,
                     })
                 } else {
                     Err(Errors::NoFontSize)
@@ -108,13 +886,37 @@ impl Picker {
                 background_color: DEFAULT_BACKGROUND,
                 protocol_type: ProtocolType::Halfblocks,
                 is_tmux,
+                halfblocks_quantizer: HalfblocksQuantizer::default(),
+                color_depth: ColorDepth::from_env(),
+                braille_options: BrailleOptions::default(),
+                theme: Theme::default(),
+                sixel_erase: sixel_erase_from_env(),
+                cell_protections: cell_protections_from_env(),
+                sixel_palette_reuse: None,
+                sixel_transparency: false,
+                iterm2_format: Iterm2Format::Png,
+                max_image_sizes: PerProtocol::default(),
+                kitty_ids: default_kitty_ids(),
+                kitty_id_color: KittyIdColor::default(),
+                kitty_options: KittyOptions::default(),
+                shared_encodings: None,
+                text_sizing: false,
+                reflow_tracker: None,
+                sequence_log: None,
+                area_claim: AreaClaim::default(),
+                error_indicator: ErrorIndicator::default(),
+                protocol_policy: None,
+                detected_protocols: detected_protocols(None),
+                query_responses: vec![],
+                watermark: None,
+                font_size_watcher: None,
+                backend: None,
             }),
             Err(err) => Err(err),
         }
     }
 
     /// Create a picker from a given terminal [FontSize].
-    /// This is the only way to create a picker on windows, for now.
     ///
     /// # Example
     /// ```rust
@@ -125,25 +927,57 @@ impl Picker {
     /// let mut picker = Picker::from_fontsize(user_fontsize);
     /// ```
     pub fn from_fontsize(font_size: FontSize) -> Picker {
+        Picker::from_fontsize_with_tmux_passthrough(font_size, TmuxPassthrough::default())
+    }
+
+    fn from_fontsize_with_tmux_passthrough(
+        font_size: FontSize,
+        tmux_passthrough: TmuxPassthrough,
+    ) -> Picker {
         // Detect tmux, and if positive then take some risky guess for iTerm2 support.
-        let (is_tmux, tmux_proto) = detect_tmux_and_outer_protocol_from_env();
+        let (is_tmux, tmux_proto) = detect_tmux_and_outer_protocol_from_env(tmux_passthrough);
 
         // Disregard protocol-from-capabilities if some env var says that we could try iTerm2.
         let iterm2_proto = iterm2_from_env();
 
-        let protocol_type = tmux_proto
-            .or(iterm2_proto)
-            .unwrap_or(ProtocolType::Halfblocks);
+        let detected_protocols =
+            detected_protocols([tmux_proto, iterm2_proto].into_iter().flatten());
+        let protocol_type = detected_protocols[0];
 
         Picker {
             font_size,
             background_color: DEFAULT_BACKGROUND,
             protocol_type,
             is_tmux,
+            halfblocks_quantizer: HalfblocksQuantizer::default(),
+            color_depth: ColorDepth::TrueColor,
+            braille_options: BrailleOptions::default(),
+            theme: Theme::default(),
+            sixel_erase: sixel_erase_from_env(),
+            cell_protections: cell_protections_from_env(),
+            sixel_palette_reuse: None,
+            sixel_transparency: false,
+            iterm2_format: Iterm2Format::Png,
+            max_image_sizes: PerProtocol::default(),
+            kitty_ids: default_kitty_ids(),
+            kitty_id_color: KittyIdColor::default(),
+            kitty_options: KittyOptions::default(),
+            shared_encodings: None,
+            text_sizing: false,
+            reflow_tracker: None,
+            sequence_log: None,
+            area_claim: AreaClaim::default(),
+            error_indicator: ErrorIndicator::default(),
+            protocol_policy: None,
+            detected_protocols,
+            query_responses: vec![],
+            watermark: None,
+            font_size_watcher: None,
+            backend: None,
         }
     }
 
-    pub fn protocol_type(self) -> ProtocolType {
+    pub fn protocol_type(&self) -> ProtocolType {
         self.protocol_type
     }
 
@@ -151,100 +985,808 @@ impl Picker {
         self.protocol_type = protocol_type;
     }
 
-    pub fn font_size(self) -> FontSize {
-        self.font_size
+    /// Use a third-party image protocol, and switch to [ProtocolType::Custom] with its name.
+    ///
+    /// The backend stays registered when switching to another protocol type, so that
+    /// [Picker::set_protocol_type] can switch back. [ProtocolType::Custom] with another name
+    /// falls back to halfblocks.
+    pub fn set_backend(&mut self, backend: Arc<dyn ProtocolBackend>) {
+        self.protocol_type = ProtocolType::Custom(backend.name());
+        self.backend = Some(Backend(backend));
     }
 
-    // Change the default background color (transparent black).
-    pub fn set_background_color<T: Into<Rgba<u8>>>(&mut self, background_color: T) {
-        self.background_color = background_color.into();
+    // The backend of ProtocolType::Custom, or None to fall back to halfblocks.
+    fn custom_backend(&self) -> Option<&dyn ProtocolBackend> {
+        match self.protocol_type {
+            ProtocolType::Custom(name) => self
+                .backend
+                .as_ref()
+                .map(|backend| &*backend.0)
+                .filter(|backend| backend.name() == name),
+            _ => None,
+        }
     }
 
-    /// Returns a new protocol for [`crate::Image`] widgets that fits into the given size.
-    pub fn new_protocol(
-        &self,
-        image: DynamicImage,
-        size: Rect,
-        resize: Resize,
-    ) -> Result<Protocol> {
-        let source = ImageSource::new(image, self.font_size, self.background_color);
-
-        let (image, area) =
-            match resize.needs_resize(&source, self.font_size, source.desired, size, false) {
-                Some(area) => {
-                    // Not exactly sure why this is necessary only for Protocol and not
-                    // StatefulProtocol, but the image proportion comes out wrong if we don't
-                    // divide height by half here.
-                    let font_size = if self.protocol_type == ProtocolType::Halfblocks {
-                        (self.font_size.0, self.font_size.1 / 2)
-                    } else {
-                        self.font_size
-                    };
-                    let image = resize.resize(&source, font_size, size, self.background_color);
-                    (image, area)
-                }
-                None => (source.image, source.desired),
-            };
+    /// The protocols that the terminal supports, as far as detected, the best first. Halfblocks
+    /// and braille are always supported.
+    pub fn detected_protocols(&self) -> &[ProtocolType] {
+        &self.detected_protocols
+    }
 
-        match self.protocol_type {
-            ProtocolType::Halfblocks => Ok(Protocol::Halfblocks(Halfblocks::new(image, area)?)),
-            ProtocolType::Sixel => Ok(Protocol::Sixel(Sixel::new(image, area, self.is_tmux)?)),
-            ProtocolType::Kitty => Ok(Protocol::Kitty(Kitty::new(
-                image,
-                area,
-                rand::random(),
-                self.is_tmux,
-            )?)),
-            ProtocolType::Iterm2 => Ok(Protocol::ITerm2(Iterm2::new(image, area, self.is_tmux)?)),
+    /// Use the first protocol of `preference` that was detected, instead of the best one, e.g.
+    /// `[Sixel, Kitty, Halfblocks]` to prefer sixel, or a list without iTerm2 to never use it.
+    /// Falls back to halfblocks if none of them was detected. Returns the protocol.
+    ///
+    /// # Example
+    /// ```rust,no_run
+    /// use ratatui_image::picker::{Picker, ProtocolType};
+    /// let mut picker = Picker::from_query_stdio()?;
+    /// picker.set_protocol_preference(&[ProtocolType::Sixel, ProtocolType::Kitty]);
+    /// # Ok::<(), ratatui_image::errors::Errors>(())
+    /// ```
+    pub fn set_protocol_preference(&mut self, preference: &[ProtocolType]) -> ProtocolType {
+        self.protocol_type = preference
+            .iter()
+            .find(|protocol| self.detected_protocols.contains(protocol))
+            .copied()
+            .unwrap_or(ProtocolType::Halfblocks);
+        self.protocol_type
+    }
+
+    /// Merge user configuration over the detected values, see [Overrides].
+    ///
+    /// Disabled protocols are removed from [Picker::detected_protocols], and the best remaining
+    /// one is used, unless a protocol is forced.
+    pub fn apply_overrides(&mut self, overrides: &Overrides) {
+        let disabled = &overrides.disabled_protocols;
+        self.detected_protocols
+            .retain(|protocol| !disabled.contains(protocol));
+        if disabled.contains(&self.protocol_type) {
+            self.protocol_type = self
+                .detected_protocols
+                .first()
+                .copied()
+                .unwrap_or(ProtocolType::Halfblocks);
+        }
+        if let Some(protocol_type) = overrides.protocol_type {
+            self.protocol_type = protocol_type;
+        }
+        if let Some(font_size @ (1.., 1..)) = overrides.font_size {
+            self.font_size = font_size;
+            self.font_size_watcher = None;
+        }
+        if let Some(color_depth) = overrides.color_depth {
+            self.color_depth = color_depth;
         }
     }
 
-    /// Returns a new *stateful* protocol for [`crate::StatefulImage`] widgets.
-    pub fn new_resize_protocol(&self, image: DynamicImage) -> StatefulProtocol {
-        let source = ImageSource::new(image, self.font_size, self.background_color);
-        match self.protocol_type {
-            ProtocolType::Halfblocks => {
-                StatefulProtocol::Halfblocks(StatefulHalfblocks::new(source, self.font_size))
-            }
-            ProtocolType::Sixel => {
-                StatefulProtocol::Sixel(StatefulSixel::new(source, self.font_size, self.is_tmux))
-            }
-            ProtocolType::Kitty => StatefulProtocol::Kitty(StatefulKitty::new(
-                source,
-                self.font_size,
-                rand::random(),
-                self.is_tmux,
-            )),
-            ProtocolType::Iterm2 => {
-                StatefulProtocol::ITerm2(StatefulIterm2::new(source, self.font_size, self.is_tmux))
-            }
+    /// The configuration after detection and [Picker::apply_overrides].
+    pub fn effective_config(&self) -> EffectiveConfig {
+        EffectiveConfig {
+            protocol_type: self.protocol_type,
+            detected_protocols: self.detected_protocols.clone(),
+            font_size: self.font_size,
+            color_depth: self.color_depth,
+            is_tmux: self.is_tmux,
+            background_color: self.background_color.0,
+            theme_mode: self.theme.mode,
         }
     }
-}
 
-fn detect_tmux_and_outer_protocol_from_env() -> (bool, Option<ProtocolType>) {
-    // Check if we're inside tmux.
-    if !env::var("TERM").is_ok_and(|term| term.starts_with("tmux"))
-        && !env::var("TERM_PROGRAM").is_ok_and(|term_program| term_program == "tmux")
-    {
-        return (false, None);
+    pub fn font_size(&self) -> FontSize {
+        self.font_size
     }
 
-    let _ = std::process::Command::new("tmux")
-        .args(["set", "-p", "allow-passthrough", "on"])
-        .stdin(std::process::Stdio::null())
-        .stdout(std::process::Stdio::null())
-        .stderr(std::process::Stdio::null())
-        .spawn()
-        .and_then(|mut child| child.wait()); // wait(), for check_device_attrs.
+    /// The terminal's colors and light/dark mode, if [Picker::from_query_stdio] could detect them.
+    pub fn theme(&self) -> Theme {
+        self.theme
+    }
 
-    // Crude guess based on the *existence* of some magic program specific env vars.
-    // Produces false positives, for example xterm started from kitty inherits KITTY_WINDOW_ID.
-    // Furthermore, tmux shares env vars from the first session, for example tmux started in xterm
-    // after a previous tmux session started in kitty, inherits KITTY_WINDOW_ID.
-    const OUTER_TERM_HINTS: [(&str, ProtocolType); 3] = [
-        ("KITTY_WINDOW_ID", ProtocolType::Kitty), // TODO: query should work inside tmux, remove?
-        ("ITERM_SESSION_ID", ProtocolType::Iterm2),
+    /// Whether the terminal is light or dark, from the background color it reported, e.g. to
+    /// pick placeholder styles or letterbox colors. [ThemeMode::Unknown] if it did not report it.
+    pub fn theme_mode(&self) -> ThemeMode {
+        self.theme.mode
+    }
+
+    // Change the default background color (transparent black, or the transparent terminal
+    // background color if it could be queried).
+    pub fn set_background_color<T: Into<Rgba<u8>>>(&mut self, background_color: T) {
+        self.background_color = background_color.into();
+    }
+
+    /// Blend transparent images with the terminal's background color (OSC 11), see
+    /// [Picker::theme], instead of keeping them transparent.
+    ///
+    /// For terminals that render transparent pixels as black, or protocols without transparency.
+    /// Returns false, and keeps the background color, if the terminal did not report it.
+    pub fn use_terminal_background(&mut self) -> bool {
+        let Some(Rgb([r, g, b])) = self.theme.background else {
+            return false;
+        };
+        self.background_color = Rgba([r, g, b, 255]);
+        true
+    }
+
+    /// Erase the area before placing sixel images, see [Sixel::erase].
+    ///
+    /// Enabled by default when Konsole or WezTerm is detected by env vars.
+    pub fn set_sixel_erase(&mut self, erase: bool) {
+        self.sixel_erase = erase;
+    }
+
+    /// Which cells of the area an image claims when it is smaller than the area (default
+    /// [AreaClaim::Image]).
+    pub fn set_area_claim(&mut self, area_claim: AreaClaim) {
+        self.area_claim = area_claim;
+    }
+
+    /// Show failed encodings and transmissions of the protocols that are created afterwards, for
+    /// debugging (default [ErrorIndicator::Off]).
+    pub fn set_error_indicator(&mut self, error_indicator: ErrorIndicator) {
+        self.error_indicator = error_indicator;
+    }
+
+    /// Composite a watermark or attribution strip onto every image of the protocols that are
+    /// created afterwards, see [crate::watermark].
+    ///
+    /// Image files are then always encoded again, see [Picker::new_protocol_from_file].
+    pub fn set_watermark(&mut self, watermark: Option<Watermark>) {
+        self.watermark = watermark;
+    }
+
+    fn image_source(&self, image: DynamicImage) -> ImageSource {
+        ImageSource::new(image, self.font_size, self.background_color)
+            .with_watermark(self.watermark.clone())
+    }
+
+    /// Pick the protocol per image by its size, see [ProtocolPolicy]. By default, every image
+    /// uses [Picker::protocol_type].
+    ///
+    /// The size is that of the image in cells, at most the area of [Picker::new_protocol].
+    pub fn set_protocol_policy(&mut self, protocol_policy: Option<ProtocolPolicy>) {
+        self.protocol_policy = protocol_policy;
+    }
+
+    // A picker with the protocol type and Kitty format that the policy picks for an image within
+    // an area, or None to use this one.
+    fn with_policy(&self, image: &DynamicImage, area: Option<Rect>) -> Option<Picker> {
+        let policy = self.protocol_policy.as_ref()?;
+        let mut cells =
+            ImageSource::round_pixel_size_to_cells(image.width(), image.height(), self.font_size);
+        if let Some(area) = area {
+            cells.width = cells.width.min(area.width);
+            cells.height = cells.height.min(area.height);
+        }
+        let (protocol_type, format) =
+            policy.pick(self.protocol_type, (cells.width, cells.height))?;
+        let mut picker = self.clone();
+        picker.protocol_policy = None;
+        picker.protocol_type = protocol_type;
+        if let Some(format) = format {
+            picker.kitty_options = picker.kitty_options.format(format);
+        }
+        Some(picker)
+    }
+
+    /// How the cells covered by sixel or iTerm2 images are protected, see [CellProtection].
+    ///
+    /// The default comes from a table of known terminal quirks, detected by env vars. Halfblocks
+    /// and Kitty ignore this.
+    pub fn set_cell_protection(&mut self, protocol_type: ProtocolType, protection: CellProtection) {
+        *self.cell_protections.get_mut(protocol_type) = protection;
+    }
+
+    /// The cell protection of a protocol, see [Picker::set_cell_protection].
+    pub fn cell_protection(&self, protocol_type: ProtocolType) -> CellProtection {
+        *self.cell_protections.get(protocol_type)
+    }
+
+    /// Limit the size in pixels of the images encoded for a protocol, for terminals that crash or
+    /// hang on very large images. The image is then smaller than the area, like when it does not
+    /// have the area's proportions.
+    ///
+    /// [Picker::from_query_stdio] sets the sixel limit if the terminal reports it (XTSMGRAPHICS).
+    pub fn set_max_image_size(&mut self, protocol_type: ProtocolType, max: Option<(u32, u32)>) {
+        *self.max_image_sizes.get_mut(protocol_type) = max;
+    }
+
+    /// The maximum image size in pixels for a protocol, see [Picker::set_max_image_size].
+    pub fn max_image_size(&self, protocol_type: ProtocolType) -> Option<(u32, u32)> {
+        *self.max_image_sizes.get(protocol_type)
+    }
+
+    // The maximum image size of the current protocol in cells.
+    fn max_area(&self) -> Option<(u16, u16)> {
+        let (width, height) = self.max_image_size(self.protocol_type)?;
+        let cells =
+            |pixels: u32, font: u16| (pixels / font.max(1) as u32).clamp(1, u16::MAX as u32);
+        Some((
+            cells(width, self.font_size.0) as u16,
+            cells(height, self.font_size.1) as u16,
+        ))
+    }
+
+    /// Reuse sixel palettes across encodes of [StatefulProtocol]s, see
+    /// [StatefulSixel::reuse_palette].
+    pub fn set_sixel_palette_reuse(&mut self, refresh: Option<u32>) {
+        self.sixel_palette_reuse = refresh;
+    }
+
+    /// Leave transparent pixels of sixel images out, see [StatefulSixel::transparent] (default
+    /// false, like [Sixel::new] and [StatefulSixel::new]). Otherwise they are drawn with the
+    /// background color, see [Picker::set_background_color]. Only enable it for terminals that
+    /// draw them correctly.
+    pub fn set_sixel_transparency(&mut self, transparent: bool) {
+        self.sixel_transparency = transparent;
+    }
+
+    /// Encode iTerm2 payloads as PNG, JPEG or WebP, see [Iterm2Format] (default PNG).
+    pub fn set_iterm2_format(&mut self, format: Iterm2Format) {
+        self.iterm2_format = format;
+    }
+
+    /// Use sequential Kitty image ids starting at 1 instead of random ones.
+    ///
+    /// This makes the output reproducible, e.g. for snapshot tests. Clones of the picker share
+    /// the same sequence, so that their ids do not collide either.
+    pub fn set_deterministic_ids(&mut self, deterministic: bool) {
+        self.kitty_ids = deterministic.then(|| Arc::new(AtomicU32::new(1)));
+    }
+
+    /// Use sequential Kitty image ids starting at `seed`, see [Picker::set_deterministic_ids].
+    ///
+    /// Different seeds keep the ids of several deterministic programs apart, e.g. when they run
+    /// side by side in the same terminal. An id of 0 is invalid for Kitty, so 0 starts at 1.
+    pub fn set_deterministic_seed(&mut self, seed: u32) {
+        self.kitty_ids = Some(Arc::new(AtomicU32::new(seed.max(1))));
+    }
+
+    /// Set how Kitty image ids are encoded in the placeholders' foreground color.
+    ///
+    /// [KittyIdColor::Indexed] limits ids to 16 bits, see [kitty::indexed_id], so collisions with other images are more
+    /// likely, but it survives terminals and recorders that rewrite 24-bit colors.
+    pub fn set_kitty_id_color(&mut self, id_color: KittyIdColor) {
+        self.kitty_id_color = id_color;
+    }
+
+    /// Set how Kitty images are transmitted and placed, see [KittyOptions].
+    pub fn set_kitty_options(&mut self, options: KittyOptions) {
+        self.kitty_options = options;
+    }
+
+    #[deprecated(note = "renamed to set_kitty_options")]
+    pub fn set_kitty_transmit_options(&mut self, options: KittyOptions) {
+        self.set_kitty_options(options);
+    }
+
+    /// Whether the terminal supports the text sizing protocol (OSC 66), for
+    /// [Picker::new_glyph_protocol]. Only detected with [PickerBuilder::probe_text_sizing].
+    pub fn text_sizing(&self) -> bool {
+        self.text_sizing
+    }
+
+    /// Override the detected text sizing support.
+    pub fn set_text_sizing(&mut self, text_sizing: bool) {
+        self.text_sizing = text_sizing;
+    }
+
+    fn kitty_id(&self) -> u32 {
+        let indexed = self.kitty_id_color == KittyIdColor::Indexed;
+        match &self.kitty_ids {
+            Some(ids) => {
+                // Skip 0 when wrapping around.
+                let mut id = ids.fetch_add(1, Ordering::Relaxed);
+                if id == 0 {
+                    id = ids.fetch_add(1, Ordering::Relaxed);
+                }
+                if indexed {
+                    kitty::indexed_id(id as u16)
+                } else {
+                    id
+                }
+            }
+            // A random id that is not used by any live protocol, see kitty::is_id_in_use.
+            // 0 is not a valid id.
+            None => kitty::allocate_id(|| {
+                if indexed {
+                    kitty::indexed_id(rand::random::<u16>())
+                } else {
+                    rand::random::<u32>().max(1)
+                }
+            }),
+        }
+    }
+
+    /// Share equal encodings between the stateful protocols created by this picker and its clones.
+    ///
+    /// Two widgets that show the same image at the same size then only encode it once, see
+    /// [SharedEncodings].
+    pub fn set_share_encodings(&mut self, share: bool) {
+        self.shared_encodings = share.then(SharedEncodings::new);
+    }
+
+    /// Refresh the stateful protocols created by this picker and its clones after the terminal
+    /// reflows, see [crate::reflow].
+    pub fn set_reflow_tracker(&mut self, tracker: Option<ReflowTracker>) {
+        self.reflow_tracker = tracker;
+    }
+
+    /// Write the escape sequences of the protocols created by this picker and its clones to a
+    /// log, see [crate::sequence_log].
+    pub fn set_sequence_log(&mut self, log: Option<SequenceLog>) {
+        self.sequence_log = log;
+    }
+
+    /// The tmux pane of the program, with this picker's reflow tracker, or `None` outside of
+    /// tmux. See [crate::tmux].
+    pub fn tmux_pane(&self) -> Option<TmuxPane> {
+        if !self.is_tmux {
+            return None;
+        }
+        TmuxPane::from_env().map(|pane| pane.reflow_tracker(self.reflow_tracker.clone()))
+    }
+
+    /// Read the font size again, e.g. on a resize event, because it changes when the terminal
+    /// is zoomed or moved to another monitor. Returns the new font size if it changed, which the
+    /// protocols are then told with [StatefulProtocol::set_font_size]. Also marks the
+    /// protocols of the reflow tracker as stale.
+    ///
+    /// This reads the window size in pixels and cells from the tty (`TIOCGWINSZ`, or the
+    /// console font on Windows) instead of querying with escape sequences, so it does not race
+    /// with the app's stdin reader. Some terminals include padding in the pixel size, so like a
+    /// [FontSizeWatcher], the first refresh only records a baseline reading, and the queried
+    /// font size is scaled by how much later readings differ from it.
+    pub fn refresh_font_size(&mut self) -> Option<FontSize> {
+        let watcher = self
+            .font_size_watcher
+            .get_or_insert_with(|| FontSizeWatcher::new(self.font_size));
+        let font_size = watcher.check()?;
+        self.font_size = font_size;
+        if let Some(tracker) = &self.reflow_tracker {
+            tracker.invalidate();
+        }
+        Some(font_size)
+    }
+
+    /// A watcher for font size changes, starting at this picker's font size and with its reflow
+    /// tracker. See [crate::font_watch].
+    pub fn font_size_watcher(&self) -> FontSizeWatcher {
+        FontSizeWatcher::new(self.font_size).reflow_tracker(self.reflow_tracker.clone())
+    }
+
+    /// See [estimate_payload_size], with this picker's protocol type and font size.
+    pub fn estimate_payload_size(&self, area: Rect) -> usize {
+        estimate_payload_size(self.protocol_type, area, self.font_size)
+    }
+
+    /// Set the color quantizer for the halfblocks protocol, e.g. to target 16-color terminals.
+    pub fn set_halfblocks_quantizer(&mut self, quantizer: HalfblocksQuantizer) {
+        self.halfblocks_quantizer = quantizer;
+    }
+
+    /// The color depth that halfblocks are quantized to, see [ColorDepth::from_env].
+    pub fn color_depth(&self) -> ColorDepth {
+        self.color_depth
+    }
+
+    /// Quantize halfblocks to the palette of a color depth, with dithering (default
+    /// [ColorDepth::from_env] when querying, or [ColorDepth::TrueColor]). A palette of
+    /// [Picker::set_halfblocks_quantizer] takes precedence.
+    pub fn set_color_depth(&mut self, color_depth: ColorDepth) {
+        self.color_depth = color_depth;
+    }
+
+    fn halfblocks_quantizer(&self) -> HalfblocksQuantizer {
+        self.halfblocks_quantizer
+            .clone()
+            .or_color_depth(self.color_depth)
+    }
+
+    /// The colors and resolution of this picker's protocol type, see [Picker::protocol_fidelity].
+    ///
+    /// # Example
+    /// ```rust
+    /// use ratatui_image::picker::{Picker, ProtocolType};
+    /// let mut picker = Picker::from_fontsize((8, 16));
+    /// picker.set_protocol_type(ProtocolType::Halfblocks);
+    /// if !picker.fidelity().is_exact() {
+    ///     // Warn that the colors of the image are approximated.
+    /// }
+    /// ```
+    pub fn fidelity(&self) -> Fidelity {
+        self.protocol_fidelity(self.protocol_type)
+    }
+
+    /// The colors and resolution of a protocol type with this picker's settings, e.g. the
+    /// [ColorDepth] for halfblocks.
+    pub fn protocol_fidelity(&self, protocol_type: ProtocolType) -> Fidelity {
+        let (colors, resolution) = match protocol_type {
+            ProtocolType::Kitty | ProtocolType::Iterm2 | ProtocolType::Terminology => {
+                (ColorFidelity::TrueColor, Resolution::Pixel)
+            }
+            // Quantized to a palette of at most 256 colors for every image.
+            ProtocolType::Sixel => (ColorFidelity::Palette(256), Resolution::Pixel),
+            ProtocolType::Halfblocks => {
+                let colors = match self.halfblocks_quantizer().palette_len() {
+                    None => ColorFidelity::TrueColor,
+                    Some(0..=1) => ColorFidelity::Mono,
+                    Some(len) => ColorFidelity::Palette(len.min(u16::MAX as usize) as u16),
+                };
+                (colors, Resolution::HalfCell)
+            }
+            ProtocolType::Braille => (ColorFidelity::TrueColor, Resolution::Cell),
+            // Unknown for a third-party backend.
+            ProtocolType::Custom(_) => (ColorFidelity::TrueColor, Resolution::Pixel),
+        };
+        Fidelity { colors, resolution }
+    }
+
+    /// Set how pixels are turned into dots for the braille protocol.
+    pub fn set_braille_options(&mut self, options: BrailleOptions) {
+        self.braille_options = options;
+    }
+
+    /// Returns a new protocol for [`crate::Image`] widgets that fits into the given size.
+    pub fn new_protocol(
+        &self,
+        image: DynamicImage,
+        size: Rect,
+        resize: Resize,
+    ) -> Result<Protocol> {
+        if let Some(picker) = self.with_policy(&image, Some(size)) {
+            return picker.new_protocol(image, size, resize);
+        }
+        let source = self.image_source(image);
+        let size = clamp_area(size, self.max_area());
+
+        let needed = resize.needs_resize(&source, self.font_size, source.desired, size, false);
+        let (image, area) = match (needed, self.area_claim) {
+            (None, AreaClaim::Image) => {
+                let mut image = source.image;
+                if let Some(watermark) = &source.watermark {
+                    watermark.apply(&mut image);
+                }
+                (image, source.desired)
+            }
+            // Pad the image to the cells that it claims, like a StatefulProtocol.
+            (Some(area), AreaClaim::Image) => {
+                let image = resize.resize(&source, self.font_size, area, self.background_color);
+                (image, area)
+            }
+            (_, AreaClaim::Area) => {
+                let area = Rect::new(0, 0, size.width, size.height);
+                let image = resize.resize(&source, self.font_size, area, self.background_color);
+                (image, area)
+            }
+        };
+
+        let protocol = match self.protocol_type {
+            ProtocolType::Halfblocks => Ok(Protocol::Halfblocks(Halfblocks::with_quantizer(
+                image,
+                area,
+                &self.halfblocks_quantizer(),
+            )?)),
+            ProtocolType::Sixel => Ok(Protocol::Sixel(
+                Sixel::with_transparency(image, area, self.is_tmux, self.sixel_transparency)?
+                    .erase(self.sixel_erase)
+                    .cell_protection(self.cell_protection(ProtocolType::Sixel)),
+            )),
+            ProtocolType::Kitty => Ok(Protocol::Kitty(
+                Kitty::with_font_size(
+                    image,
+                    area,
+                    self.kitty_id(),
+                    self.is_tmux,
+                    self.kitty_options,
+                    self.font_size,
+                )?
+                .id_color(self.kitty_id_color),
+            )),
+            ProtocolType::Iterm2 => Ok(Protocol::ITerm2(
+                Iterm2::with_format(image, area, self.is_tmux, self.iterm2_format)?
+                    .cell_protection(self.cell_protection(ProtocolType::Iterm2)),
+            )),
+            ProtocolType::Terminology => Ok(Protocol::Terminology(
+                Terminology::new(image, area, self.is_tmux)?
+                    .cell_protection(self.cell_protection(ProtocolType::Terminology)),
+            )),
+            ProtocolType::Braille => Ok(Protocol::Braille(Braille::with_options(
+                image,
+                area,
+                &self.braille_options,
+            )?)),
+            ProtocolType::Custom(_) => match self.custom_backend() {
+                Some(backend) => Ok(Protocol::Custom(backend.new_protocol(image, area)?)),
+                None => Ok(Protocol::Halfblocks(Halfblocks::with_quantizer(
+                    image,
+                    area,
+                    &self.halfblocks_quantizer(),
+                )?)),
+            },
+        };
+        if let (Ok(protocol), Some(log)) = (&protocol, &self.sequence_log) {
+            protocol.log_sequences(log);
+        }
+        protocol
+    }
+
+    /// Like [Picker::new_protocol], for an image file that is still encoded, e.g. as read from
+    /// disk.
+    ///
+    /// If the image does not need resizing, the file is transmitted as it is, which skips
+    /// encoding it again. This applies to PNG and JPEG files with iTerm2, and to PNG files with
+    /// Kitty that cover whole cells, regardless of [KittyOptions::format]. The background color
+    /// must be transparent, and [AreaClaim::Image] set, because the file cannot be padded.
+    /// Anything else is decoded and encoded like with [Picker::new_protocol].
+    pub fn new_protocol_from_file(
+        &self,
+        file: &[u8],
+        size: Rect,
+        resize: Resize,
+    ) -> Result<Protocol> {
+        let format = image::guess_format(file)?;
+        let image = image::load_from_memory_with_format(file, format)?;
+        if let Some(picker) = self.with_policy(&image, Some(size)) {
+            if picker.protocol_type != self.protocol_type {
+                return picker.new_protocol(image, size, resize);
+            }
+        }
+        let pass_through = match (self.protocol_type, format) {
+            (ProtocolType::Iterm2, ImageFormat::Png | ImageFormat::Jpeg) => true,
+            (ProtocolType::Kitty, ImageFormat::Png) => {
+                let (font_width, font_height) = self.font_size;
+                image.width() % font_width.max(1) as u32 == 0
+                    && image.height() % font_height.max(1) as u32 == 0
+            }
+            _ => false,
+        };
+        if !pass_through
+            || self.background_color.0[3] != 0
+            || self.area_claim != AreaClaim::Image
+            || self.watermark.is_some()
+        {
+            return self.new_protocol(image, size, resize);
+        }
+
+        let source = self.image_source(image);
+        let size = clamp_area(size, self.max_area());
+        if resize
+            .needs_resize(&source, self.font_size, source.desired, size, false)
+            .is_some()
+        {
+            return self.new_protocol(source.image, size, resize);
+        }
+        let image_size = (source.image.width(), source.image.height());
+        let protocol = if self.protocol_type == ProtocolType::Kitty {
+            Protocol::Kitty(
+                Kitty::from_png(
+                    file,
+                    image_size,
+                    source.desired,
+                    self.kitty_id(),
+                    self.is_tmux,
+                    self.kitty_options,
+                    self.font_size,
+                )
+                .id_color(self.kitty_id_color),
+            )
+        } else {
+            Protocol::ITerm2(
+                Iterm2::from_file(file, image_size, source.desired, self.is_tmux)
+                    .cell_protection(self.cell_protection(ProtocolType::Iterm2)),
+            )
+        };
+        if let Some(log) = &self.sequence_log {
+            protocol.log_sequences(log);
+        }
+        Ok(protocol)
+    }
+
+    /// Print an image into the scrollback, `width` cells wide, like `kitty +kitten icat` does.
+    ///
+    /// This is for inline or log-style output above (or without) the live UI, e.g. after
+    /// `terminal.insert_before` or before starting ratatui. The image is written at the start of
+    /// the current line, and the cursor is left at the start of the line below it. The height
+    /// follows from the image's proportions.
+    pub fn print_image(&self, w: &mut impl Write, image: DynamicImage, width: u16) -> Result<()> {
+        let (font_width, font_height) = (self.font_size.0.max(1), self.font_size.1.max(1));
+        let width_px = width as u64 * font_width as u64;
+        let height_px = width_px * image.height() as u64 / image.width().max(1) as u64;
+        let rows = height_px
+            .div_ceil(font_height as u64)
+            .clamp(1, u16::MAX as u64) as u16;
+        let area = Rect::new(0, 0, width, rows);
+        let mut protocol = self.new_protocol(image, area, Resize::Scale(None))?;
+        protocol.print(w)?;
+        Ok(())
+    }
+
+    /// Render an image to text with escape sequences, `width` cells wide, for terminal notebooks,
+    /// pagers, or any other output stream that is shown in a terminal later.
+    ///
+    /// Only the Kitty and iTerm2 protocols are self-contained enough for a stream, everything
+    /// else falls back to halfblocks, except braille which is text too. For [OutputTarget::Pager]
+    /// it is always halfblocks or braille, because `less -R` only passes through color sequences.
+    pub fn rich_output(
+        &self,
+        image: DynamicImage,
+        width: u16,
+        target: OutputTarget,
+    ) -> Result<String> {
+        let mut picker = self.clone();
+        picker.protocol_type = match (target, self.protocol_type) {
+            (OutputTarget::Terminal, ProtocolType::Kitty) => ProtocolType::Kitty,
+            (OutputTarget::Terminal, ProtocolType::Iterm2) => ProtocolType::Iterm2,
+            (_, ProtocolType::Braille) => ProtocolType::Braille,
+            _ => ProtocolType::Halfblocks,
+        };
+        let mut out = vec![];
+        picker.print_image(&mut out, image, width)?;
+        Ok(String::from_utf8_lossy(&out).into_owned())
+    }
+
+    /// Like [Picker::new_protocol], but resize and encode only on the first render.
+    ///
+    /// Call [LazyProtocol::spawn] to do the work on a background thread instead, e.g. for a splash
+    /// screen that should not delay the first paint.
+    pub fn new_protocol_lazy(
+        &self,
+        image: DynamicImage,
+        size: Rect,
+        resize: Resize,
+    ) -> LazyProtocol {
+        LazyProtocol::new(self.clone(), image, size, resize)
+    }
+
+    /// Returns a new protocol for a [crate::background::BackgroundImage] behind text, which only renders anything
+    /// with the Kitty protocol.
+    pub fn new_background_protocol(&self, image: DynamicImage) -> BackgroundProtocol {
+        let kitty = (self.protocol_type == ProtocolType::Kitty)
+            .then(|| (self.kitty_id(), self.is_tmux, self.kitty_options));
+        let background = self.theme.background.unwrap_or(Rgb([0, 0, 0]));
+        BackgroundProtocol::new(image, kitty, self.font_size, background)
+    }
+
+    /// Returns a new protocol for a [crate::glyphs::GlyphImage], which approximates a tiny image
+    /// with scaled colored glyphs when the terminal supports text sizing, see [crate::glyphs].
+    pub fn new_glyph_protocol(
+        &self,
+        image: DynamicImage,
+        size: Rect,
+        options: &GlyphOptions,
+    ) -> GlyphProtocol {
+        let supported = self.text_sizing && !self.is_tmux;
+        GlyphProtocol::new(&image, size, self.font_size, options, supported)
+    }
+
+    /// Returns a new *stateful* protocol whose image is generated at the exact pixel size of the
+    /// area, e.g. for plots, charts or waveforms, instead of resizing a fixed image.
+    ///
+    /// `generate` is called with the width and height in pixels on every
+    /// [StatefulProtocol::resize_encode], so it runs on the worker thread with
+    /// [crate::thread::ThreadProtocol].
+    pub fn new_generated_protocol<F>(&self, generate: F) -> StatefulProtocol
+    where
+        F: Fn(u32, u32) -> DynamicImage + Send + Sync + 'static,
+    {
+        let mut protocol = self.new_resize_protocol(DynamicImage::new_rgba8(1, 1));
+        protocol.set_generator(Arc::new(generate), self.font_size);
+        protocol
+    }
+
+    /// Switch a stateful protocol that another picker created to this picker's protocol type and
+    /// font size, e.g. when a [PendingPicker] finished. Settings like
+    /// [StatefulProtocol::redact] are kept, see [StatefulProtocol::upgrade_protocol].
+    pub fn adopt_protocol(&self, protocol: &mut StatefulProtocol) {
+        protocol.upgrade_protocol(self);
+    }
+
+    /// Returns a new *stateful* protocol for [`crate::StatefulImage`] widgets.
+    pub fn new_resize_protocol(&self, image: DynamicImage) -> StatefulProtocol {
+        if let Some(picker) = self.with_policy(&image, None) {
+            return picker.new_resize_protocol(image);
+        }
+        let source = self.image_source(image);
+        let protocol_type = match self.protocol_type {
+            ProtocolType::Halfblocks => StatefulProtocolType::Halfblocks(
+                StatefulHalfblocks::new(source, self.font_size)
+                    .quantizer(self.halfblocks_quantizer())
+                    .shared(self.shared_encodings.clone()),
+            ),
+            ProtocolType::Sixel => StatefulProtocolType::Sixel(
+                StatefulSixel::new(source, self.font_size, self.is_tmux)
+                    .erase(self.sixel_erase)
+                    .cell_protection(self.cell_protection(ProtocolType::Sixel))
+                    .reuse_palette(self.sixel_palette_reuse)
+                    .transparent(self.sixel_transparency)
+                    .shared(self.shared_encodings.clone()),
+            ),
+            ProtocolType::Kitty => StatefulProtocolType::Kitty(
+                StatefulKitty::new(source, self.font_size, self.kitty_id(), self.is_tmux)
+                    .id_color(self.kitty_id_color)
+                    .transmit_options(self.kitty_options),
+            ),
+            ProtocolType::Iterm2 => StatefulProtocolType::ITerm2(
+                StatefulIterm2::new(source, self.font_size, self.is_tmux)
+                    .cell_protection(self.cell_protection(ProtocolType::Iterm2))
+                    .format(self.iterm2_format)
+                    .shared(self.shared_encodings.clone()),
+            ),
+            ProtocolType::Terminology => StatefulProtocolType::Terminology(
+                StatefulTerminology::new(source, self.font_size, self.is_tmux)
+                    .cell_protection(self.cell_protection(ProtocolType::Terminology)),
+            ),
+            ProtocolType::Braille => StatefulProtocolType::Braille(
+                StatefulBraille::new(source, self.font_size)
+                    .options(self.braille_options.clone())
+                    .shared(self.shared_encodings.clone()),
+            ),
+            ProtocolType::Custom(_) => match self.custom_backend() {
+                Some(backend) => StatefulProtocolType::Custom(
+                    backend.new_stateful_protocol(source, self.font_size),
+                ),
+                None => StatefulProtocolType::Halfblocks(
+                    StatefulHalfblocks::new(source, self.font_size)
+                        .quantizer(self.halfblocks_quantizer())
+                        .shared(self.shared_encodings.clone()),
+                ),
+            },
+        };
+        let mut protocol = StatefulProtocol::new(protocol_type);
+        protocol.set_max_area(self.max_area());
+        protocol.set_reflow_tracker(self.reflow_tracker.clone());
+        protocol.set_sequence_log(self.sequence_log.clone());
+        protocol.set_area_claim(self.area_claim);
+        protocol.set_error_indicator(self.error_indicator);
+        protocol
+    }
+}
+
+// The detected graphics protocols, the best first, without duplicates, and then the text
+// protocols that always work.
+fn detected_protocols(graphics: impl IntoIterator<Item = ProtocolType>) -> Vec<ProtocolType> {
+    let mut protocols: Vec<ProtocolType> = vec![];
+    let text = [ProtocolType::Halfblocks, ProtocolType::Braille];
+    for protocol in graphics.into_iter().chain(text) {
+        if !protocols.contains(&protocol) {
+            protocols.push(protocol);
+        }
+    }
+    protocols
+}
+
+fn detect_tmux_and_outer_protocol_from_env(
+    tmux_passthrough: TmuxPassthrough,
+) -> (bool, Option<ProtocolType>) {
+    // Check if we're inside tmux.
+    if !env::var("TERM").is_ok_and(|term| term.starts_with("tmux"))
+        && !env::var("TERM_PROGRAM").is_ok_and(|term_program| term_program == "tmux")
+    {
+        return (false, None);
+    }
+
+    if let Some(args) = tmux_passthrough.args() {
+        let _ = std::process::Command::new("tmux")
+            .args(args)
+            .stdin(std::process::Stdio::null())
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .spawn()
+            .and_then(|mut child| child.wait()); // wait(), for check_device_attrs.
+    }
+
+    // Ask tmux which terminal the client runs in.
+    if let Ok(outer) = OuterTerminal::query() {
+        return (true, outer.protocol_type());
+    }
+
+    // Otherwise, a crude guess based on the *existence* of some magic program specific env vars.
+    // Produces false positives, for example xterm started from kitty inherits KITTY_WINDOW_ID.
+    // Furthermore, tmux shares env vars from the first session, for example tmux started in xterm
+    // after a previous tmux session started in kitty, inherits KITTY_WINDOW_ID.
+    const OUTER_TERM_HINTS: [(&str, ProtocolType); 3] = [
+        ("KITTY_WINDOW_ID", ProtocolType::Kitty), // TODO: query should work inside tmux, remove?
+        ("ITERM_SESSION_ID", ProtocolType::Iterm2),
         ("WEZTERM_EXECUTABLE", ProtocolType::Iterm2),
     ];
     for (hint, proto) in OUTER_TERM_HINTS {
@@ -273,30 +1815,62 @@ fn iterm2_from_env() -> Option<ProtocolType> {
     None
 }
 
+// Terminals that never let text overdraw sixel regions, and need explicit erasing instead.
+fn sixel_erase_from_env() -> bool {
+    env::var("KONSOLE_VERSION").is_ok_and(|version| !version.is_empty())
+        || env::var("TERM_PROGRAM").is_ok_and(|term_program| term_program.contains("WezTerm"))
+}
+
+// Known terminal quirks: env var, a substring of its value, and the protection that works best.
+const CELL_PROTECTION_QUIRKS: &[(&str, &str, ProtocolType, CellProtection)] = &[
+    // foot draws sixels above text, skipped cells would keep stale text below transparent parts.
+    ("TERM", "foot", ProtocolType::Sixel, CellProtection::Spaces),
+];
+
+fn cell_protections_from_env() -> PerProtocol<CellProtection> {
+    let mut protections = PerProtocol::default();
+    for (var, needle, protocol_type, protection) in CELL_PROTECTION_QUIRKS {
+        if env::var(var).is_ok_and(|value| value.contains(needle)) {
+            *protections.get_mut(*protocol_type) = *protection;
+        }
+    }
+    protections
+}
+
+// Returns `None` if the terminal is already in raw mode, e.g. set by crossterm or termion, in
+// which case the modes are left alone.
 #[cfg(not(windows))]
-fn enable_raw_mode() -> Result<impl FnOnce() -> Result<()>> {
+fn enable_raw_mode(tty: &QueryTty) -> Result<Option<impl FnOnce() -> Result<()> + '_>> {
     use rustix::termios::{self, LocalModes, OptionalActions};
 
-    let stdin = io::stdin();
-    let mut termios = termios::tcgetattr(&stdin)?;
+    let input = tty.input();
+    let mut termios = termios::tcgetattr(input)?;
     let termios_original = termios.clone();
+    if !termios
+        .local_modes
+        .intersects(LocalModes::ICANON | LocalModes::ECHO)
+    {
+        return Ok(None);
+    }
 
     // Disable canonical mode to read without waiting for Enter, disable echoing.
     termios.local_modes &= !LocalModes::ICANON;
     termios.local_modes &= !LocalModes::ECHO;
-    termios::tcsetattr(&stdin, OptionalActions::Drain, &termios)?;
+    termios::tcsetattr(input, OptionalActions::Drain, &termios)?;
 
-    Ok(move || {
+    Ok(Some(move || {
         Ok(termios::tcsetattr(
-            io::stdin(),
+            input,
             OptionalActions::Now,
             &termios_original,
         )?)
-    })
+    }))
 }
 
+// Open the console input or output (`CONIN$` or `CONOUT$`, nul-terminated), even if stdio is
+// redirected.
 #[cfg(windows)]
-fn enable_raw_mode() -> Result<impl FnOnce() -> Result<()>> {
+fn open_console(name: &str) -> windows::core::Result<windows::Win32::Foundation::HANDLE> {
     use windows::{
         core::PCWSTR,
         Win32::{
@@ -304,16 +1878,13 @@ fn enable_raw_mode() -> Result<impl FnOnce() -> Result<()>> {
             Storage::FileSystem::{
                 self, FILE_FLAGS_AND_ATTRIBUTES, FILE_SHARE_READ, FILE_SHARE_WRITE, OPEN_EXISTING,
             },
-            System::Console::{
-                self, CONSOLE_MODE, ENABLE_ECHO_INPUT, ENABLE_LINE_INPUT, ENABLE_PROCESSED_INPUT,
-            },
         },
     };
 
-    let utf16: Vec<u16> = "CONIN$\0".encode_utf16().collect();
+    let utf16: Vec<u16> = name.encode_utf16().collect();
     let utf16_ptr: *const u16 = utf16.as_ptr();
 
-    let in_handle = unsafe {
+    unsafe {
         FileSystem::CreateFileW(
             PCWSTR(utf16_ptr),
             (GENERIC_READ | GENERIC_WRITE).0,
@@ -323,23 +1894,35 @@ fn enable_raw_mode() -> Result<impl FnOnce() -> Result<()>> {
             FILE_FLAGS_AND_ATTRIBUTES(0),
             HANDLE::default(),
         )
-    }?;
+    }
+}
+
+#[cfg(windows)]
+fn enable_raw_mode(_tty: &QueryTty) -> Result<Option<impl FnOnce() -> Result<()>>> {
+    use windows::Win32::System::Console::{
+        self, CONSOLE_MODE, ENABLE_ECHO_INPUT, ENABLE_LINE_INPUT, ENABLE_PROCESSED_INPUT,
+    };
+
+    let in_handle = open_console("CONIN$\0")?;
 
     let mut original_in_mode = CONSOLE_MODE::default();
     unsafe { Console::GetConsoleMode(in_handle, &mut original_in_mode) }?;
+    if (original_in_mode & (ENABLE_ECHO_INPUT | ENABLE_LINE_INPUT)).0 == 0 {
+        return Ok(None);
+    }
 
     let requested_in_modes = !ENABLE_ECHO_INPUT & !ENABLE_LINE_INPUT & !ENABLE_PROCESSED_INPUT;
     let in_mode = original_in_mode & requested_in_modes;
     unsafe { Console::SetConsoleMode(in_handle, in_mode) }?;
 
-    Ok(move || {
+    Ok(Some(move || {
         unsafe { Console::SetConsoleMode(in_handle, original_in_mode) }?;
         Ok(())
-    })
+    }))
 }
 
 #[cfg(not(windows))]
-fn font_size_fallback() -> Option<FontSize> {
+pub(crate) fn font_size_fallback() -> Option<FontSize> {
     use rustix::termios::{self, Winsize};
 
     let winsize = termios::tcgetwinsize(io::stdout()).ok()?;
@@ -356,89 +1939,441 @@ fn font_size_fallback() -> Option<FontSize> {
     Some((x / cols, y / rows))
 }
 
+// Windows Terminal does not answer `[16t` in all versions, but the console API knows the font.
 #[cfg(windows)]
-fn font_size_fallback() -> Option<FontSize> {
-    None
+pub(crate) fn font_size_fallback() -> Option<FontSize> {
+    use windows::Win32::{
+        Foundation::BOOL,
+        System::Console::{self, CONSOLE_FONT_INFO},
+    };
+
+    let out_handle = open_console("CONOUT$\0").ok()?;
+    let mut font_info = CONSOLE_FONT_INFO::default();
+    unsafe { Console::GetCurrentConsoleFont(out_handle, BOOL::from(false), &mut font_info) }
+        .ok()?;
+    let (x, y) = (font_info.dwFontSize.X, font_info.dwFontSize.Y);
+    if x <= 0 || y <= 0 {
+        return None;
+    }
+
+    Some((x as u16, y as u16))
 }
 
-fn query_stdio_capabilities(is_tmux: bool) -> Result<(Option<ProtocolType>, Option<FontSize>)> {
-    // Send several control sequences at once:
-    // `_Gi=...`: Kitty graphics support.
-    // `[c`: Capabilities including sixels.
-    // `[16t`: Cell-size (perhaps we should also do `[14t`).
-    // `[1337n`: iTerm2 (some terminals implement the protocol but sadly not this custom CSI)
-    // `[5n`: Device Status Report, implemented by all terminals, ensure that there is some
-    // response and we don't hang reading forever.
-    let query = Parser::query(is_tmux);
-    io::stdout().write_all(query.as_bytes())?;
-    io::stdout().flush()?;
+/// A [Picker::from_query_stdio] that runs on a background thread, with a fallback picker until it
+/// completes.
+///
+/// The first frames can then be rendered right away, e.g. with halfblocks, instead of waiting up
+/// to a second for the terminal. The app must not read stdin (e.g. terminal events) until the
+/// query completed, or the responses end up as input.
+///
+/// ```rust,no_run
+/// # use ratatui_image::picker::{PendingPicker, Picker};
+/// let mut pending = PendingPicker::spawn(Picker::from_fontsize((8, 16)));
+/// let mut protocol = pending.picker().new_resize_protocol(image::open("image.png").unwrap());
+/// // In the render loop:
+/// if let Some(Ok(())) = pending.poll() {
+///     pending.picker().adopt_protocol(&mut protocol);
+/// }
+/// ```
+pub struct PendingPicker {
+    picker: Picker,
+    query: Option<std::thread::JoinHandle<Result<Picker>>>,
+}
 
-    let mut parser = Parser::new();
-    let mut capabilities = vec![];
-    'out: loop {
-        let mut charbuf: [u8; 50] = [0; 50];
-        let result = io::stdin().read(&mut charbuf);
-        match result {
-            Ok(read) => {
-                for ch in charbuf.iter().take(read) {
-                    let mut more_caps = parser.push(char::from(*ch));
-                    if more_caps[..] == [Capability::Status] {
-                        break 'out;
-                    } else {
-                        capabilities.append(&mut more_caps);
-                    }
+impl PendingPicker {
+    /// Start the query, and use `fallback` until it completes.
+    pub fn spawn(fallback: Picker) -> PendingPicker {
+        PendingPicker {
+            picker: fallback,
+            query: Some(std::thread::spawn(Picker::from_query_stdio)),
+        }
+    }
+
+    /// The detected picker, or the fallback while the query is pending or if it failed.
+    pub fn picker(&self) -> &Picker {
+        &self.picker
+    }
+
+    /// Whether the query is still running.
+    pub fn is_pending(&self) -> bool {
+        self.query.is_some()
+    }
+
+    /// Returns the result of the query once, when it has completed. On success, the detected
+    /// picker replaces the fallback, and protocols created by the fallback can be switched with
+    /// [Picker::adopt_protocol].
+    pub fn poll(&mut self) -> Option<Result<()>> {
+        if !self.query.as_ref()?.is_finished() {
+            return None;
+        }
+        self.wait()
+    }
+
+    /// Wait for the query to complete, see [PendingPicker::poll].
+    pub fn wait(&mut self) -> Option<Result<()>> {
+        let result = match self.query.take()?.join() {
+            Ok(result) => result,
+            Err(panic) => std::panic::resume_unwind(panic),
+        };
+        Some(result.map(|picker| self.picker = picker))
+    }
+}
+
+/// Capability query driven by the application's own stdin reader.
+///
+/// [Picker::from_query_stdio] reads the responses from stdin itself, which races with any other
+/// reader, such as crossterm's `EventStream`: responses or keystrokes end up with the wrong
+/// reader. Instead, write [CapabilityQuery::request] to the terminal and [CapabilityQuery::feed]
+/// the bytes that the app reads until it returns `true`. Keystrokes that arrive in between are
+/// kept, see [CapabilityQuery::take_input].
+///
+/// ```rust
+/// # use ratatui_image::picker::CapabilityQuery;
+/// let mut query = CapabilityQuery::new();
+/// // Write `query.request()` to stdout, then feed what is read from stdin.
+/// # let _ = query.request();
+/// let done = query.feed(b"\x1b[?64;4c\x1b[6;20;10t\x1b[0n");
+/// assert!(done);
+/// let picker = query.into_picker().unwrap();
+/// assert_eq!(picker.font_size(), (10, 20));
+/// ```
+pub struct CapabilityQuery {
+    is_tmux: bool,
+    tmux_proto: Option<ProtocolType>,
+    parser: Parser,
+    capabilities: Vec<Capability>,
+    extra_queries: Vec<String>,
+    text_sizing_probe: bool,
+    responses: Vec<u8>,
+    // The bytes since the escape that started the current sequence, until it turns out to be a
+    // response or not.
+    pending: Vec<u8>,
+    // The bytes that are not part of a response, see take_input().
+    input: Vec<u8>,
+    // A color response ended with the escape of ST, so the next `\` belongs to it.
+    string_terminator: bool,
+    received: bool,
+    done: bool,
+}
+
+impl Default for CapabilityQuery {
+    fn default() -> Self {
+        CapabilityQuery::new()
+    }
+}
+
+impl CapabilityQuery {
+    /// Create a query, detecting tmux from the environment.
+    pub fn new() -> CapabilityQuery {
+        CapabilityQuery::with_tmux_passthrough(TmuxPassthrough::default())
+    }
+
+    /// Like [CapabilityQuery::new], but only changes tmux's `allow-passthrough` option as
+    /// chosen, see [TmuxPassthrough].
+    pub fn with_tmux_passthrough(tmux_passthrough: TmuxPassthrough) -> CapabilityQuery {
+        let (is_tmux, tmux_proto) = detect_tmux_and_outer_protocol_from_env(tmux_passthrough);
+        CapabilityQuery {
+            is_tmux,
+            tmux_proto,
+            parser: Parser::new(),
+            capabilities: vec![],
+            extra_queries: vec![],
+            text_sizing_probe: false,
+            responses: vec![],
+            pending: vec![],
+            input: vec![],
+            string_terminator: false,
+            received: false,
+            done: false,
+        }
+    }
+
+    /// Also detect the text sizing protocol (OSC 66), see [Picker::text_sizing] (default false).
+    ///
+    /// The probe prints a two cell wide space at the start of the current line, which terminals
+    /// without OSC 66 show as a single space, so only enable it on a cleared screen. It is never
+    /// sent inside tmux.
+    pub fn probe_text_sizing(mut self, probe: bool) -> CapabilityQuery {
+        self.text_sizing_probe = probe;
+        self
+    }
+
+    /// The escape sequences to write to the terminal.
+    pub fn request(&self) -> String {
+        let probe = (self.text_sizing_probe && !self.is_tmux).then_some(Parser::TEXT_SIZING_PROBE);
+        let extra_queries = self.extra_queries.iter().map(String::as_str);
+        Parser::query_with(self.is_tmux, probe.into_iter().chain(extra_queries))
+    }
+
+    /// Feed bytes read from stdin, returns `true` once all responses have arrived.
+    ///
+    /// Bytes that are not part of a response, such as keystrokes before, between, or after the
+    /// responses, are kept for [CapabilityQuery::take_input].
+    pub fn feed(&mut self, bytes: &[u8]) -> bool {
+        for &byte in bytes {
+            if self.done {
+                self.input.push(byte);
+                continue;
+            }
+            if std::mem::take(&mut self.string_terminator) && byte == b'\\' {
+                self.responses.push(b'\\');
+                continue;
+            }
+            let in_response = *self.parser.response() != Response::Unknown;
+            if !in_response && byte == 0x1b {
+                // Whatever did not turn into a response since the last escape is input, e.g. an
+                // arrow key.
+                self.input.append(&mut self.pending);
+            } else if !in_response && self.pending.is_empty() {
+                self.input.push(byte);
+                continue;
+            }
+            self.received = true;
+            self.pending.push(byte);
+            self.responses.push(byte);
+            let color = matches!(
+                self.parser.response(),
+                Response::ForegroundColor | Response::BackgroundColor
+            );
+            let mut more_caps = self.parser.push(char::from(byte));
+            // The sequence ended as a response, or a new escape cut a response off.
+            if self.parser.is_idle() && (in_response || byte != 0x1b) {
+                self.pending.clear();
+                if color && byte == 0x1b {
+                    // The escape of ST ended the color, the `\\` follows.
+                    self.string_terminator = true;
+                } else if byte == 0x1b {
+                    self.pending.push(byte);
+                }
+            }
+            if more_caps[..] == [Capability::Status] {
+                self.done = true;
+                self.pending.clear();
+            } else {
+                self.capabilities.append(&mut more_caps);
+            }
+        }
+        self.done
+    }
+
+    /// Take the bytes that were fed but are not part of a response, such as keystrokes, in the
+    /// order they arrived, so that the app can handle them as if it had read them itself.
+    ///
+    /// An escape sequence that has not ended yet is only returned once it turns out not to be a
+    /// response, or once all responses have arrived.
+    pub fn take_input(&mut self) -> Vec<u8> {
+        if self.done {
+            self.input.append(&mut self.pending);
+        }
+        std::mem::take(&mut self.input)
+    }
+
+    /// Whether all responses have arrived.
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    /// Build the [Picker] from the responses, like [Picker::from_query_stdio] would.
+    ///
+    /// Fails with [Errors::NoStdinResponse] if the responses are not complete.
+    pub fn into_picker(self) -> Result<Picker> {
+        let (is_tmux, tmux_proto) = (self.is_tmux, self.tmux_proto);
+        let result = self.into_result();
+        Picker::from_query_result(is_tmux, tmux_proto, result)
+    }
+
+    fn into_result(self) -> Result<QueryResult> {
+        if !self.done {
+            return Err(Errors::NoStdinResponse);
+        }
+        let capabilities = self.capabilities;
+        if capabilities.is_empty() {
+            return Err(Errors::NoCap);
+        }
+
+        let mut protos = vec![];
+        let mut font_size = None;
+        if capabilities.contains(&Capability::Kitty) {
+            protos.push(ProtocolType::Kitty);
+        }
+        if capabilities.contains(&Capability::Sixel) {
+            protos.push(ProtocolType::Sixel);
+        }
+
+        let (mut foreground, mut background, mut sixel_max_size) = (None, None, None);
+        let (mut text_area_pixels, mut text_area_cells) = (None, None);
+        let text_sizing = capabilities.contains(&Capability::TextSizing);
+        for cap in capabilities {
+            match cap {
+                Capability::SixelGeometry(w, h) => sixel_max_size = Some((w, h)),
+                Capability::CellSize(Some((w, h))) => {
+                    font_size = Some((w, h));
                 }
+                Capability::TextAreaPixels(w, h) => text_area_pixels = Some((w, h)),
+                Capability::TextAreaCells(columns, rows) => text_area_cells = Some((columns, rows)),
+                Capability::ForegroundColor(color) => foreground = Some(color),
+                Capability::BackgroundColor(color) => background = Some(color),
+                _ => {}
             }
-            Err(err) => {
-                return Err(err.into());
+        }
+        // In case some terminal didn't support the cell-size query, measure it from the text area.
+        if let (None, Some((w, h)), Some((columns, rows))) =
+            (font_size, text_area_pixels, text_area_cells)
+        {
+            let (w, h) = (w / columns as u32, h / rows as u32);
+            if let (Ok(w @ 1..), Ok(h @ 1..)) = (u16::try_from(w), u16::try_from(h)) {
+                font_size = Some((w, h));
             }
         }
+        font_size = font_size.or_else(font_size_fallback);
+
+        Ok(QueryResult {
+            protocol_types: protos,
+            font_size,
+            theme: Theme::new(foreground, background),
+            sixel_max_size,
+            text_sizing,
+            responses: self.responses,
+        })
     }
+}
 
-    if capabilities.is_empty() {
-        return Err(Errors::NoCap);
+// Where the capability query is written and its responses are read.
+enum QueryTty {
+    Stdio,
+    // An explicitly opened tty, e.g. when stdin is a pipe.
+    #[cfg_attr(windows, allow(dead_code))]
+    File(File),
+}
+
+impl QueryTty {
+    fn write_all(&self, bytes: &[u8]) -> io::Result<()> {
+        match self {
+            QueryTty::Stdio => {
+                io::stdout().write_all(bytes)?;
+                io::stdout().flush()
+            }
+            QueryTty::File(file) => {
+                let mut file: &File = file;
+                file.write_all(bytes)?;
+                file.flush()
+            }
+        }
     }
 
-    let mut proto = None;
-    let mut font_size = None;
-    if capabilities.contains(&Capability::Kitty) {
-        proto = Some(ProtocolType::Kitty);
-    } else if capabilities.contains(&Capability::Sixel) {
-        proto = Some(ProtocolType::Sixel);
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            QueryTty::Stdio => io::stdin().read(buf),
+            QueryTty::File(file) => {
+                let mut file: &File = file;
+                file.read(buf)
+            }
+        }
     }
 
-    for cap in capabilities {
-        if let Capability::CellSize(Some((w, h))) = cap {
-            font_size = Some((w, h));
+    #[cfg(not(windows))]
+    fn input(&self) -> rustix::fd::BorrowedFd<'_> {
+        use rustix::fd::AsFd;
+        match self {
+            QueryTty::Stdio => rustix::stdio::stdin(),
+            QueryTty::File(file) => file.as_fd(),
         }
     }
-    // In case some terminal didn't support the cell-size query.
-    font_size = font_size.or_else(font_size_fallback);
+}
+
+// Wait until the tty is readable. Returns `false` if the deadline passed first.
+#[cfg(not(windows))]
+fn wait_stdin(tty: &QueryTty, deadline: Instant) -> Result<bool> {
+    use rustix::event::{poll, PollFd, PollFlags};
 
-    Ok((proto, font_size))
+    let input = tty.input();
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Ok(false);
+        }
+        let mut fds = [PollFd::new(&input, PollFlags::IN)];
+        match poll(&mut fds, remaining.as_millis().max(1) as i32) {
+            Ok(0) => return Ok(false),
+            Ok(_) => return Ok(true),
+            Err(rustix::io::Errno::INTR) => continue,
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+// Reads block on Windows, the caller's timeout applies instead.
+#[cfg(windows)]
+fn wait_stdin(_tty: &QueryTty, _deadline: Instant) -> Result<bool> {
+    Ok(true)
+}
+
+fn query_stdio_capabilities(
+    tty: &QueryTty,
+    mut query: CapabilityQuery,
+    timeout: Duration,
+) -> Result<QueryResult> {
+    // Send several control sequences at once:
+    // `_Gi=...`: Kitty graphics support.
+    // `[c`: Capabilities including sixels.
+    // `[16t`: Cell-size.
+    // `[14t` and `[18t`: Text area size in pixels and cells, to measure the cell-size if `[16t` is
+    // not supported.
+    // `[?2;1S`: Maximum sixel graphics size.
+    // `]10;?` and `]11;?`: Foreground and background colors.
+    // `[1337n`: iTerm2 (some terminals implement the protocol but sadly not this custom CSI)
+    // `[5n`: Device Status Report, implemented by all terminals, ensure that there is some
+    // response and we don't hang reading forever.
+    tty.write_all(query.request().as_bytes())?;
+
+    // Poll before reading, so that this thread never stays blocked on stdin and swallows a
+    // keystroke meant for the app after giving up.
+    let deadline = Instant::now() + timeout;
+    while !query.is_done() {
+        if !wait_stdin(tty, deadline)? {
+            // Some responses arrived but not the final one, because the link is slow, or another
+            // reader got the rest.
+            return Err(match query.received {
+                true => Errors::QueryTimeout,
+                false => Errors::NoStdinResponse,
+            });
+        }
+        let mut charbuf: [u8; 50] = [0; 50];
+        let read = tty.read(&mut charbuf)?;
+        if read == 0 {
+            return Err(Errors::NoStdinResponse);
+        }
+        query.feed(&charbuf[..read]);
+    }
+    query.into_result()
 }
 
 fn query_with_timeout(
-    is_tmux: bool,
+    tty: QueryTty,
+    query: CapabilityQuery,
+    set_raw_mode: bool,
     timeout: Duration,
-) -> Result<(Option<ProtocolType>, Option<FontSize>)> {
+) -> Result<QueryResult> {
     use std::{sync::mpsc, thread};
     let (tx, rx) = mpsc::channel();
 
     thread::spawn(move || {
-        let _ = tx.send(
-            enable_raw_mode()
-                .map_err(Errors::into)
-                .and_then(|disable_raw_mode| {
-                    let result = query_stdio_capabilities(is_tmux);
-                    // Always try to return to raw_mode.
-                    disable_raw_mode()?;
-                    result
-                }),
-        );
+        let raw_mode = match set_raw_mode {
+            true => enable_raw_mode(&tty),
+            false => Ok(None),
+        };
+        let _ = tx.send(raw_mode.and_then(|disable_raw_mode| {
+            let result = query_stdio_capabilities(&tty, query, timeout);
+            // Always try to return to raw_mode.
+            if let Some(disable_raw_mode) = disable_raw_mode {
+                disable_raw_mode()?;
+            }
+            result
+        }));
     });
 
-    match rx.recv_timeout(timeout) {
+    // Leave the reader thread some slack to tell apart no response from a consumed one.
+    match rx.recv_timeout(timeout + Duration::from_millis(100)) {
         Ok(result) => Ok(result?),
         Err(_recvtimeout) => Err(Errors::NoStdinResponse),
     }
@@ -448,7 +2383,26 @@ fn query_with_timeout(
 mod tests {
     use std::assert_eq;
 
-    use crate::picker::{Picker, ProtocolType};
+    use image::{DynamicImage, ImageBuffer, Rgb, Rgba};
+    use ratatui::{buffer::Buffer, layout::Rect, style::Color, widgets::Widget};
+
+    use crate::{
+        errors::Errors,
+        picker::{
+            CapabilityQuery, ColorDepth, ColorFidelity, OutputTarget, Overrides, PendingPicker,
+            Picker, ProtocolPolicy, ProtocolType, Resolution, TextProtocolType, Theme, ThemeMode,
+            TmuxPassthrough,
+        },
+        protocol::halfblocks::{HalfblocksQuantizer, Palette},
+        protocol::kitty::KittyFormat,
+        protocol::sixel::Sixel,
+        protocol::Protocol,
+        protocol::{
+            ImageSource, ProtocolBackend, ProtocolTrait, StatefulProtocolTrait,
+            StatefulProtocolType,
+        },
+        AreaClaim, FontSize, Image, Resize, ResizeEncodeRender,
+    };
 
     #[test]
     fn test_cycle_protocol() {
@@ -460,11 +2414,808 @@ mod tests {
         proto = proto.next();
         assert_eq!(proto, ProtocolType::Iterm2);
         proto = proto.next();
+        assert_eq!(proto, ProtocolType::Terminology);
+        proto = proto.next();
+        assert_eq!(proto, ProtocolType::Braille);
+        proto = proto.next();
         assert_eq!(proto, ProtocolType::Halfblocks);
     }
 
+    #[test]
+    fn test_sixel_transparency_default() {
+        let image: DynamicImage =
+            ImageBuffer::from_pixel(10, 20, Rgba::<u8>([255, 0, 0, 0])).into();
+        let area = Rect::new(0, 0, 1, 1);
+        let mut picker = Picker::from_fontsize((10, 20));
+        picker.set_protocol_type(ProtocolType::Sixel);
+        let Ok(Protocol::Sixel(picked)) =
+            picker.new_protocol(image.clone(), area, Resize::Fit(None))
+        else {
+            panic!("expected a sixel protocol");
+        };
+        let sixel = Sixel::new(image, area, false).unwrap();
+        assert_eq!(picked.data, sixel.data);
+    }
+
+    #[test]
+    fn test_protocol_policy() {
+        let policy = ProtocolPolicy::new()
+            .small((2, 1), TextProtocolType::Halfblocks)
+            .large(100, KittyFormat::Png);
+        assert_eq!(
+            policy.pick(ProtocolType::Sixel, (2, 1)),
+            Some((ProtocolType::Halfblocks, None))
+        );
+        assert_eq!(policy.pick(ProtocolType::Sixel, (3, 1)), None);
+        assert_eq!(policy.pick(ProtocolType::Halfblocks, (1, 1)), None);
+        assert_eq!(
+            policy.pick(ProtocolType::Kitty, (10, 10)),
+            Some((ProtocolType::Kitty, Some(KittyFormat::Png)))
+        );
+        assert_eq!(policy.pick(ProtocolType::Sixel, (10, 10)), None);
+        let policy = ProtocolPolicy::new().small((2, 1), TextProtocolType::Braille);
+        assert_eq!(
+            policy.pick(ProtocolType::Kitty, (1, 1)),
+            Some((ProtocolType::Braille, None))
+        );
+
+        let mut picker = Picker::from_fontsize((10, 20));
+        picker.set_protocol_type(ProtocolType::Sixel);
+        picker.set_protocol_policy(Some(
+            ProtocolPolicy::new().small((2, 1), TextProtocolType::Halfblocks),
+        ));
+        let icon = DynamicImage::new_rgb8(16, 16);
+        assert!(matches!(
+            picker.new_resize_protocol(icon.clone()).protocol_type(),
+            StatefulProtocolType::Halfblocks(_)
+        ));
+        assert!(matches!(
+            picker
+                .new_resize_protocol(DynamicImage::new_rgb8(40, 40))
+                .protocol_type(),
+            StatefulProtocolType::Sixel(_)
+        ));
+        // Within the area, a larger image is shown as small as an icon.
+        let protocol = picker
+            .new_protocol(
+                DynamicImage::new_rgb8(40, 40),
+                Rect::new(0, 0, 2, 1),
+                Resize::Fit(None),
+            )
+            .unwrap();
+        assert!(matches!(protocol, crate::protocol::Protocol::Halfblocks(_)));
+        assert_eq!(picker.protocol_type(), ProtocolType::Sixel);
+    }
+
+    #[test]
+    fn test_color_depth_from_term() {
+        let depth = ColorDepth::from_term;
+        assert_eq!(depth(None, Some("xterm")), ColorDepth::TrueColor);
+        assert_eq!(depth(None, Some("screen")), ColorDepth::TrueColor);
+        assert_eq!(depth(None, None), ColorDepth::TrueColor);
+        assert_eq!(depth(None, Some("xterm-256color")), ColorDepth::Ansi256);
+        assert_eq!(depth(None, Some("linux")), ColorDepth::Ansi16);
+        assert_eq!(depth(None, Some("xterm-16color")), ColorDepth::Ansi16);
+        assert_eq!(
+            depth(Some("truecolor"), Some("xterm-256color")),
+            ColorDepth::TrueColor
+        );
+    }
+
+    #[test]
+    fn test_fidelity() {
+        let mut picker = Picker::from_fontsize((10, 20));
+        picker.set_protocol_type(ProtocolType::Kitty);
+        assert!(picker.fidelity().is_exact());
+
+        let sixel = picker.protocol_fidelity(ProtocolType::Sixel);
+        assert_eq!(sixel.colors, ColorFidelity::Palette(256));
+        assert_eq!(sixel.resolution, Resolution::Pixel);
+        let braille = picker.protocol_fidelity(ProtocolType::Braille);
+        assert_eq!(braille.resolution, Resolution::Cell);
+
+        picker.set_protocol_type(ProtocolType::Halfblocks);
+        assert_eq!(picker.fidelity().colors, ColorFidelity::TrueColor);
+        assert_eq!(picker.fidelity().resolution, Resolution::HalfCell);
+        picker.set_color_depth(ColorDepth::Ansi16);
+        assert_eq!(picker.fidelity().colors, ColorFidelity::Palette(16));
+        let palette = Palette::Custom(vec![[255, 255, 255]]);
+        picker.set_halfblocks_quantizer(HalfblocksQuantizer::new().palette(palette));
+        assert_eq!(picker.fidelity().colors, ColorFidelity::Mono);
+    }
+
+    #[test]
+    fn test_use_terminal_background() {
+        let mut picker = Picker::from_fontsize((10, 20));
+        assert_eq!(picker.theme_mode(), ThemeMode::Unknown);
+        assert!(!picker.use_terminal_background());
+        assert_eq!(picker.background_color, Rgba([0, 0, 0, 0]));
+        picker.theme = Theme::new(None, Some(Rgb([30, 40, 50])));
+        assert!(picker.use_terminal_background());
+        assert_eq!(picker.background_color, Rgba([30, 40, 50, 255]));
+        assert_eq!(picker.theme_mode(), ThemeMode::Dark);
+    }
+
+    #[test]
+    fn test_theme() {
+        let theme = Theme::new(None, Some(Rgb([250, 250, 240])));
+        assert_eq!(theme.mode, ThemeMode::Light);
+        assert_eq!(theme.contrast_color(), Rgba([218, 218, 208, 255]));
+
+        let theme = Theme::new(Some(Rgb([200, 200, 200])), Some(Rgb([30, 30, 30])));
+        assert_eq!(theme.mode, ThemeMode::Dark);
+
+        assert_eq!(Theme::new(None, None).mode, ThemeMode::Unknown);
+    }
+
     #[test]
     fn test_from_query_stdio_no_hang() {
         let _ = Picker::from_query_stdio();
+        let _ = Picker::from_query_stdio_in_raw_mode();
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn test_from_query_tty() {
+        use std::{
+            fs,
+            io::{Read, Write},
+            os::{fd::OwnedFd, unix::net::UnixStream},
+            time::Duration,
+        };
+
+        use super::{query_with_timeout, QueryTty};
+
+        // Not a tty, so raw mode cannot be set.
+        assert!(Picker::from_query_tty(Some(fs::File::open("/dev/null").unwrap())).is_err());
+        let _ = Picker::from_query_tty(None);
+
+        // The terminal end of a socket pair answers the request.
+        let (tty, mut terminal) = UnixStream::pair().unwrap();
+        let answer = std::thread::spawn(move || {
+            let mut request = [0; 8];
+            terminal.read_exact(&mut request).unwrap();
+            terminal
+                .write_all(b"\x1b_Gi=31;OK\x1b\\\x1b[6;20;10t\x1b[0n")
+                .unwrap();
+        });
+        let tty = QueryTty::File(fs::File::from(OwnedFd::from(tty)));
+        let query = CapabilityQuery::new();
+        let result = query_with_timeout(tty, query, false, Duration::from_secs(1)).unwrap();
+        answer.join().unwrap();
+        assert_eq!(result.font_size, Some((10, 20)));
+        assert_eq!(result.protocol_types, [ProtocolType::Kitty]);
+    }
+
+    #[cfg(all(feature = "tokio", not(windows)))]
+    #[tokio::test]
+    async fn test_from_query_async_without_tty() {
+        use std::{fs, time::Duration};
+
+        use super::QueryTty;
+
+        let tty = QueryTty::File(fs::File::open("/dev/null").unwrap());
+        let result = tokio::time::timeout(Duration::from_secs(5), Picker::from_query_async(tty))
+            .await
+            .expect("the query should not hang without a tty");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_concurrent_query_and_adopt() {
+        let (_, output) = Picker::from_query_stdio_concurrently(|| 42);
+        assert_eq!(output, 42);
+
+        let mut fallback = Picker::from_fontsize((10, 20));
+        fallback.set_protocol_type(ProtocolType::Halfblocks);
+        let mut pending = PendingPicker::spawn(fallback);
+        assert!(pending.wait().is_some());
+        assert!(!pending.is_pending());
+        assert!(pending.poll().is_none());
+
+        let image: DynamicImage =
+            ImageBuffer::from_pixel(40, 40, Rgba::<u8>([255, 0, 0, 255])).into();
+        let mut fallback = Picker::from_fontsize((10, 20));
+        fallback.set_protocol_type(ProtocolType::Halfblocks);
+        let mut protocol = fallback.new_resize_protocol(image);
+        protocol.redact(true);
+        let area = Rect::new(0, 0, 4, 2);
+        protocol.resize_encode(&Resize::Fit(None), Rgba([0; 4]), area);
+
+        let mut detected = Picker::from_fontsize((5, 10));
+        detected.set_protocol_type(ProtocolType::Kitty);
+        detected.adopt_protocol(&mut protocol);
+        assert!(matches!(
+            protocol.protocol_type(),
+            StatefulProtocolType::Kitty(_)
+        ));
+        assert!(protocol.is_redacted());
+        assert_eq!(protocol.area(), Rect::default());
+        // Twice the cells with the smaller font.
+        let area = Rect::new(0, 0, 8, 4);
+        assert_eq!(protocol.needs_resize(&Resize::Fit(None), area), Some(area));
+    }
+
+    #[test]
+    fn test_print_image() {
+        let image: DynamicImage =
+            ImageBuffer::from_pixel(20, 40, Rgba::<u8>([255, 0, 0, 255])).into();
+        let mut picker = Picker::from_fontsize((10, 20));
+        let print = |picker: &Picker| {
+            let mut out = vec![];
+            picker.print_image(&mut out, image.clone(), 4).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        // 4 cells wide is 40px, so 80px high, or 4 rows.
+        picker.set_protocol_type(ProtocolType::Halfblocks);
+        let out = print(&picker);
+        assert_eq!(out.matches("\r\n").count(), 4);
+        assert!(out.starts_with("\x1b[38;2;255;0;0m\x1b[48;2;255;0;0m▀"));
+
+        picker.set_protocol_type(ProtocolType::Sixel);
+        let out = print(&picker);
+        assert!(out.starts_with("\n\n\n\n\x1b[4A\x1b7\x1bP"));
+        assert!(out.ends_with("\x1b8\x1b[4B\r"));
+
+        picker.set_protocol_type(ProtocolType::Kitty);
+        let out = print(&picker);
+        assert!(out.starts_with("\x1b_G"));
+        assert_eq!(out.matches("\x1b[39m\r\n").count(), 4);
+        assert_eq!(out.matches('\u{10EEEE}').count(), 16);
+    }
+
+    #[test]
+    fn test_new_protocol_from_file() {
+        use base64::{engine::general_purpose, Engine};
+        use std::io::Cursor;
+
+        use crate::protocol::Protocol;
+
+        let image: DynamicImage =
+            ImageBuffer::from_pixel(20, 40, Rgba::<u8>([255, 0, 0, 255])).into();
+        let mut png = vec![];
+        image
+            .write_to(&mut Cursor::new(&mut png), image::ImageFormat::Png)
+            .unwrap();
+        let file = general_purpose::STANDARD.encode(&png);
+        let mut picker = Picker::from_fontsize((10, 20));
+
+        picker.set_protocol_type(ProtocolType::Iterm2);
+        let area = Rect::new(0, 0, 4, 4);
+        let Ok(Protocol::ITerm2(iterm2)) =
+            picker.new_protocol_from_file(&png, area, Resize::Fit(None))
+        else {
+            panic!("not iTerm2");
+        };
+        assert!(iterm2.data.contains(&format!(":{file}\x07")));
+        assert_eq!(iterm2.area, Rect::new(0, 0, 2, 2));
+
+        // Resized, so encoded again.
+        let Ok(Protocol::ITerm2(iterm2)) =
+            picker.new_protocol_from_file(&png, Rect::new(0, 0, 1, 1), Resize::Fit(None))
+        else {
+            panic!("not iTerm2");
+        };
+        assert!(!iterm2.data.contains(&file));
+
+        picker.set_protocol_type(ProtocolType::Kitty);
+        let mut kitty = picker
+            .new_protocol_from_file(&png, area, Resize::Fit(None))
+            .unwrap();
+        assert!(matches!(kitty, Protocol::Kitty(_)));
+        let mut buf = Buffer::empty(area);
+        Image::new(&mut kitty).render(area, &mut buf);
+        assert!(buf[(0, 0)]
+            .symbol()
+            .contains(&format!(",f=100,t=d,s=20,v=40,c=2,r=2,m=0;{file}")));
+    }
+
+    #[test]
+    fn test_new_generated_protocol() {
+        use std::sync::{Arc, Mutex};
+
+        let mut picker = Picker::from_fontsize((10, 20));
+        picker.set_protocol_type(ProtocolType::Halfblocks);
+        let sizes = Arc::new(Mutex::new(vec![]));
+        let generated = sizes.clone();
+        let mut protocol = picker.new_generated_protocol(move |width, height| {
+            generated.lock().unwrap().push((width, height));
+            ImageBuffer::from_pixel(width, height, Rgba::<u8>([255, 0, 0, 255])).into()
+        });
+
+        for area in [
+            Rect::new(0, 0, 4, 2),
+            Rect::new(0, 0, 4, 2),
+            Rect::new(0, 0, 2, 3),
+        ] {
+            let mut buf = Buffer::empty(area);
+            ResizeEncodeRender::resize_encode_render(
+                &mut protocol,
+                &Resize::Fit(None),
+                area,
+                &mut buf,
+            );
+            assert_eq!(protocol.area(), Rect::new(0, 0, area.width, area.height));
+        }
+        assert_eq!(*sizes.lock().unwrap(), vec![(40, 40), (20, 60)]);
+    }
+
+    #[test]
+    fn test_rich_output() {
+        let image: DynamicImage =
+            ImageBuffer::from_pixel(20, 40, Rgba::<u8>([255, 0, 0, 255])).into();
+        let mut picker = Picker::from_fontsize((10, 20));
+
+        picker.set_protocol_type(ProtocolType::Kitty);
+        let out = picker
+            .rich_output(image.clone(), 4, OutputTarget::Terminal)
+            .unwrap();
+        assert!(out.starts_with("\x1b_G"));
+        let out = picker
+            .rich_output(image.clone(), 4, OutputTarget::Pager)
+            .unwrap();
+        assert!(out.starts_with("\x1b[38;2;"));
+
+        picker.set_protocol_type(ProtocolType::Sixel);
+        let out = picker
+            .rich_output(image.clone(), 4, OutputTarget::Terminal)
+            .unwrap();
+        assert!(!out.contains("\x1bP"));
+        assert!(out.contains('▀'));
+    }
+
+    #[test]
+    fn test_capability_query_feed() {
+        let mut query = CapabilityQuery::new();
+        assert!(!query.feed(b"\x1b_Gi=31;OK\x1b\\\x1b[6;2"));
+        assert!(query.feed(b"0;10t\x1b[0nkeys"));
+        assert_eq!(query.take_input(), b"keys");
+        let picker = query.into_picker().unwrap();
+        assert_eq!(picker.font_size(), (10, 20));
+        assert_eq!(picker.protocol_type(), ProtocolType::Kitty);
+
+        let mut query = CapabilityQuery::new();
+        query.feed(b"\x1b[6;20;10t");
+        assert!(matches!(query.into_picker(), Err(Errors::NoStdinResponse)));
+
+        // Without `[16t`, measured from the text area.
+        let mut query = CapabilityQuery::new();
+        assert!(query.feed(b"\x1b[?64;4c\x1b[4;480;1280t\x1b[8;24;80t\x1b[0n"));
+        assert_eq!(query.into_picker().unwrap().font_size(), (16, 20));
+    }
+
+    #[test]
+    fn test_text_sizing_probe() {
+        let mut query = CapabilityQuery::new();
+        query.is_tmux = false;
+        assert!(!query.request().contains("\x1b]66;"));
+        let mut query = query.probe_text_sizing(true);
+        assert!(query.request().contains(super::Parser::TEXT_SIZING_PROBE));
+        assert!(query.feed(b"\x1b[6;20;10t\x1b[1;3R\x1b[0n"));
+        assert!(query.into_picker().unwrap().text_sizing());
+    }
+
+    #[test]
+    fn test_capability_query_input() {
+        let mut query = CapabilityQuery::new();
+        // Keystrokes before, between and within the responses, with an arrow key.
+        assert!(!query.feed(b"a\x1b]11;rgb:0000/0000/0000\x1b\\b\x1b[A\x1b[?64;4c"));
+        assert_eq!(query.take_input(), b"ab\x1b[A");
+        assert!(!query.feed(b"\x1b_Gi=31;OK\x1b\\\x1b[6;20;10tc\x1bO"));
+        // The last escape could still be the start of a response.
+        assert_eq!(query.take_input(), b"c");
+        assert!(query.feed(b"P\x1b[0n\x1b[B"));
+        assert_eq!(query.take_input(), b"\x1bOP\x1b[B");
+        let picker = query.into_picker().unwrap();
+        assert_eq!(picker.font_size(), (10, 20));
+        assert_eq!(picker.protocol_type(), ProtocolType::Kitty);
+    }
+
+    #[test]
+    fn test_protocol_preference() {
+        let mut query = CapabilityQuery::new();
+        query.feed(b"\x1b_Gi=31;OK\x1b\\\x1b[?64;4c\x1b[6;20;10t\x1b[0n");
+        let mut picker = query.into_picker().unwrap();
+        assert_eq!(
+            picker.detected_protocols(),
+            [
+                ProtocolType::Kitty,
+                ProtocolType::Sixel,
+                ProtocolType::Halfblocks,
+                ProtocolType::Braille
+            ]
+        );
+        assert_eq!(picker.protocol_type(), ProtocolType::Kitty);
+        let preference = [ProtocolType::Sixel, ProtocolType::Kitty];
+        assert_eq!(
+            picker.set_protocol_preference(&preference),
+            ProtocolType::Sixel
+        );
+        // Not detected.
+        let preference = [ProtocolType::Iterm2, ProtocolType::Braille];
+        assert_eq!(
+            picker.set_protocol_preference(&preference),
+            ProtocolType::Braille
+        );
+        assert_eq!(
+            picker.set_protocol_preference(&[ProtocolType::Iterm2]),
+            ProtocolType::Halfblocks
+        );
+    }
+
+    #[test]
+    fn test_apply_overrides() {
+        let mut query = CapabilityQuery::new();
+        query.feed(b"\x1b_Gi=31;OK\x1b\\\x1b[?64;4c\x1b[6;20;10t\x1b[0n");
+        let mut picker = query.into_picker().unwrap();
+        picker.apply_overrides(&Overrides {
+            disabled_protocols: vec![ProtocolType::Kitty],
+            font_size: Some((9, 18)),
+            ..Overrides::default()
+        });
+        let config = picker.effective_config();
+        assert_eq!(config.protocol_type, ProtocolType::Sixel);
+        assert_eq!(
+            config.detected_protocols,
+            [
+                ProtocolType::Sixel,
+                ProtocolType::Halfblocks,
+                ProtocolType::Braille
+            ]
+        );
+        assert_eq!(config.font_size, (9, 18));
+        assert_eq!(config.color_depth, picker.color_depth());
+
+        // Forced, even if not detected.
+        picker.apply_overrides(&Overrides {
+            protocol_type: Some(ProtocolType::Iterm2),
+            font_size: Some((0, 0)),
+            color_depth: Some(ColorDepth::Ansi16),
+            ..Overrides::default()
+        });
+        let config = picker.effective_config();
+        assert_eq!(config.protocol_type, ProtocolType::Iterm2);
+        assert_eq!(config.font_size, (9, 18));
+        assert_eq!(config.color_depth, ColorDepth::Ansi16);
+    }
+
+    #[test]
+    fn test_picker_builder() {
+        let picker = Picker::builder()
+            .query(false)
+            .font_size((9, 18))
+            .protocol_type(ProtocolType::Sixel)
+            .tmux(Some(false))
+            .background_color(Rgba([1, 2, 3, 255]))
+            .build()
+            .unwrap();
+        let config = picker.effective_config();
+        assert_eq!(config.font_size, (9, 18));
+        assert_eq!(config.protocol_type, ProtocolType::Sixel);
+        assert!(!config.is_tmux);
+        assert_eq!(config.background_color, [1, 2, 3, 255]);
+
+        // XTGETTCAP for the terminal name.
+        let mut query = CapabilityQuery::new();
+        query.extra_queries.push("\x1bP+q544e\x1b\\".to_string());
+        assert!(query.request().contains("\x1bP+q544e\x1b\\"));
+        query.feed(b"\x1b[6;20;10t\x1bP1+r544e=787465726d\x1b\\\x1b[0n");
+        let picker = query.into_picker().unwrap();
+        assert_eq!(picker.font_size(), (10, 20));
+        assert!(picker
+            .query_responses()
+            .windows(20)
+            .any(|response| response == b"\x1bP1+r544e=787465726d"));
+    }
+
+    #[test]
+    fn test_tmux_passthrough() {
+        assert_eq!(
+            TmuxPassthrough::default().args(),
+            Some(["set", "-p", "allow-passthrough", "on"])
+        );
+        assert_eq!(
+            TmuxPassthrough::Global.args(),
+            Some(["set", "-g", "allow-passthrough", "on"])
+        );
+        assert_eq!(TmuxPassthrough::Unchanged.args(), None);
+
+        let picker = Picker::builder()
+            .query(false)
+            .tmux_passthrough(TmuxPassthrough::Unchanged)
+            .build()
+            .unwrap();
+        assert_eq!(picker.font_size(), (10, 20));
+    }
+
+    #[test]
+    fn test_max_image_size() {
+        let image: DynamicImage =
+            ImageBuffer::from_pixel(400, 400, Rgba::<u8>([255, 0, 0, 255])).into();
+        let mut picker = Picker::from_fontsize((10, 20));
+        picker.set_protocol_type(ProtocolType::Sixel);
+        picker.set_max_image_size(ProtocolType::Sixel, Some((100, 105)));
+        assert_eq!(picker.max_image_size(ProtocolType::Sixel), Some((100, 105)));
+        assert_eq!(picker.max_image_size(ProtocolType::Kitty), None);
+        let area = Rect::new(2, 3, 40, 40);
+
+        let mut protocol = picker.new_resize_protocol(image.clone());
+        let rect = protocol.needs_resize(&Resize::Fit(None), area).unwrap();
+        assert_eq!(rect, Rect::new(0, 0, 10, 5));
+
+        let protocol = picker
+            .new_protocol(image.clone(), area, Resize::Fit(None))
+            .unwrap();
+        assert_eq!(protocol.area(), Rect::new(0, 0, 10, 5));
+
+        // Other protocols are not limited.
+        picker.set_protocol_type(ProtocolType::Kitty);
+        let mut protocol = picker.new_resize_protocol(image);
+        let rect = protocol.needs_resize(&Resize::Fit(None), area).unwrap();
+        assert_eq!(rect, Rect::new(0, 0, 40, 20));
+    }
+
+    #[test]
+    fn test_area_claim() {
+        let image: DynamicImage =
+            ImageBuffer::from_pixel(400, 400, Rgba::<u8>([255, 0, 0, 255])).into();
+        let mut picker = Picker::from_fontsize((10, 20));
+        picker.set_protocol_type(ProtocolType::Halfblocks);
+        let area = Rect::new(0, 0, 10, 6);
+        let red = Color::Rgb(255, 0, 0);
+
+        // The image fills its own cells, without padding to the area.
+        let mut protocol = picker
+            .new_protocol(image.clone(), area, Resize::Fit(None))
+            .unwrap();
+        assert_eq!(protocol.area(), Rect::new(0, 0, 10, 5));
+        let mut buf = Buffer::empty(area);
+        Image::new(&mut protocol).render(area, &mut buf);
+        assert_eq!((buf[(9, 4)].fg, buf[(9, 4)].bg), (red, red));
+        assert_eq!(buf[(0, 5)], Buffer::empty(area)[(0, 5)]);
+
+        picker.set_area_claim(AreaClaim::Area);
+        let mut protocol = picker
+            .new_protocol(image.clone(), area, Resize::Fit(None))
+            .unwrap();
+        assert_eq!(protocol.area(), area);
+        let mut buf = Buffer::empty(area);
+        Image::new(&mut protocol).render(area, &mut buf);
+        assert_eq!(buf[(9, 4)].fg, red);
+        assert_ne!(buf[(0, 5)].fg, red);
+
+        // A stateful protocol erases the rest of the area.
+        let mut protocol = picker.new_resize_protocol(image);
+        let mut buf = Buffer::empty(area);
+        ResizeEncodeRender::resize_encode_render(&mut protocol, &Resize::Fit(None), area, &mut buf);
+        assert_eq!(protocol.area(), Rect::new(0, 0, 10, 5));
+        assert_eq!(buf[(0, 5)].symbol(), "\x1b[10X ");
+    }
+
+    #[test]
+    fn test_new_protocol_lazy() {
+        let image: DynamicImage =
+            ImageBuffer::from_pixel(20, 40, Rgba::<u8>([255, 0, 0, 255])).into();
+        let area = Rect::new(0, 0, 2, 2);
+        let mut picker = Picker::from_fontsize((10, 20));
+        picker.set_protocol_type(ProtocolType::Sixel);
+        let mut expected = Buffer::empty(area);
+        crate::Image::new(
+            &mut picker
+                .new_protocol(image.clone(), area, Resize::Fit(None))
+                .unwrap(),
+        )
+        .render(area, &mut expected);
+
+        // Encoded on the first render.
+        let mut lazy = picker.new_protocol_lazy(image.clone(), area, Resize::Fit(None));
+        assert!(lazy.protocol().is_none());
+        let mut buf = Buffer::empty(area);
+        crate::LazyImage::new(&mut lazy).render(area, &mut buf);
+        assert_eq!(buf, expected);
+
+        // Encoded in the background.
+        let mut lazy = picker.new_protocol_lazy(image, area, Resize::Fit(None));
+        lazy.spawn();
+        lazy.encode().unwrap();
+        let mut buf = Buffer::empty(area);
+        crate::LazyImage::new(&mut lazy).render(area, &mut buf);
+        assert_eq!(buf, expected);
+    }
+
+    #[test]
+    fn test_estimate_payload_size() {
+        // Noise, the worst case for compression.
+        let image: DynamicImage = ImageBuffer::from_fn(200, 200, |x, y| {
+            let v = (x * 7919 + y * 104_729) ^ (x * y);
+            Rgba([v as u8, (v >> 8) as u8, (v >> 16) as u8, 255])
+        })
+        .into();
+        let area = Rect::new(0, 0, 20, 10);
+        for protocol_type in [
+            ProtocolType::Halfblocks,
+            ProtocolType::Sixel,
+            ProtocolType::Kitty,
+            ProtocolType::Iterm2,
+        ] {
+            let mut picker = Picker::from_fontsize((10, 20));
+            picker.set_protocol_type(protocol_type);
+            let mut protocol = picker.new_resize_protocol(image.clone());
+            let mut buf = Buffer::empty(area);
+            ResizeEncodeRender::resize_encode_render(
+                &mut protocol,
+                &Resize::Scale(None),
+                area,
+                &mut buf,
+            );
+            let actual: usize = buf.content.iter().map(|cell| cell.symbol().len()).sum();
+            let estimate = picker.estimate_payload_size(area);
+            assert!(
+                actual <= estimate,
+                "{protocol_type:?}: {actual} > {estimate}"
+            );
+            if protocol_type == ProtocolType::Kitty {
+                assert!(estimate - actual < actual / 20, "{actual} {estimate}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_deterministic_output() {
+        let image: DynamicImage = ImageBuffer::from_fn(30, 50, |x, y| {
+            Rgba([(x * 8) as u8, (y * 5) as u8, 128, 255])
+        })
+        .into();
+        let area = Rect::new(0, 0, 3, 3);
+        let render = |protocol_type| {
+            let mut picker = Picker::from_fontsize((10, 20));
+            picker.set_protocol_type(protocol_type);
+            picker.set_deterministic_seed(42);
+            let mut protocol = picker.new_resize_protocol(image.clone());
+            let mut buf = Buffer::empty(area);
+            ResizeEncodeRender::resize_encode_render(
+                &mut protocol,
+                &Resize::Fit(None),
+                area,
+                &mut buf,
+            );
+            buf
+        };
+        for protocol_type in [
+            ProtocolType::Halfblocks,
+            ProtocolType::Sixel,
+            ProtocolType::Kitty,
+            ProtocolType::Iterm2,
+        ] {
+            assert!(
+                render(protocol_type) == render(protocol_type),
+                "{protocol_type:?} output differs"
+            );
+        }
+    }
+
+    // A third-party backend that draws every cell as an `x`.
+    struct Crosses;
+
+    #[derive(Clone)]
+    struct CrossesProtocol {
+        source: ImageSource,
+        font_size: FontSize,
+        area: Rect,
+    }
+
+    impl ProtocolTrait for CrossesProtocol {
+        fn render(&mut self, area: Rect, buf: &mut Buffer) {
+            for position in self.area.intersection(area).positions() {
+                buf[position].set_symbol("x");
+            }
+        }
+        fn area(&self) -> Rect {
+            self.area
+        }
+        fn name(&self) -> &'static str {
+            "crosses"
+        }
+    }
+
+    impl StatefulProtocolTrait for CrossesProtocol {
+        fn source(&self) -> &ImageSource {
+            &self.source
+        }
+        fn source_mut(&mut self) -> &mut ImageSource {
+            &mut self.source
+        }
+        fn background_color(&self) -> Rgba<u8> {
+            self.source.background_color
+        }
+        fn needs_resize(&mut self, resize: &Resize, area: Rect) -> Option<Rect> {
+            resize.needs_resize(&self.source, self.font_size, self.area, area, false)
+        }
+        fn resize_encode(
+            &mut self,
+            resize: &Resize,
+            background_color: Rgba<u8>,
+            area: Rect,
+        ) -> crate::Result<()> {
+            let image = resize.resize(&self.source, self.font_size, area, background_color);
+            assert_eq!(area.width as u32 * self.font_size.0 as u32, image.width());
+            self.area = area;
+            Ok(())
+        }
+        fn render_placement(&self, area: Rect, buf: &mut Buffer) {
+            self.clone().render(area, buf);
+        }
+        fn font_size(&self) -> FontSize {
+            self.font_size
+        }
+        fn set_font_size(&mut self, font_size: FontSize) {
+            self.font_size = font_size;
+        }
+    }
+
+    impl ProtocolBackend for Crosses {
+        fn name(&self) -> &'static str {
+            "crosses"
+        }
+        fn new_protocol(
+            &self,
+            image: DynamicImage,
+            area: Rect,
+        ) -> crate::Result<Box<dyn ProtocolTrait>> {
+            Ok(Box::new(CrossesProtocol {
+                source: ImageSource::new(image, (1, 1), Rgba([0, 0, 0, 0])),
+                font_size: (1, 1),
+                area,
+            }))
+        }
+        fn new_stateful_protocol(
+            &self,
+            source: ImageSource,
+            font_size: FontSize,
+        ) -> Box<dyn StatefulProtocolTrait> {
+            Box::new(CrossesProtocol {
+                source,
+                font_size,
+                area: Rect::default(),
+            })
+        }
+    }
+
+    #[test]
+    fn test_custom_backend() {
+        let mut picker = Picker::from_fontsize((4, 8));
+        picker.set_backend(std::sync::Arc::new(Crosses));
+        assert_eq!(ProtocolType::Custom("crosses"), picker.protocol_type());
+
+        let image: DynamicImage = ImageBuffer::from_pixel(16, 16, Rgb([255u8, 0, 0])).into();
+        let area = Rect::new(0, 0, 8, 4);
+        let protocol = picker
+            .new_protocol(image.clone(), area, Resize::Fit(None))
+            .unwrap();
+        assert!(matches!(protocol, Protocol::Custom(_)));
+        assert_eq!(Rect::new(0, 0, 4, 2), protocol.area());
+        let mut buf = Buffer::empty(area);
+        Image::new(&mut protocol.clone()).render(area, &mut buf);
+        assert_eq!("x", buf[(3, 1)].symbol());
+        assert_eq!(" ", buf[(4, 1)].symbol());
+
+        let mut stateful = picker.new_resize_protocol(image.clone());
+        assert!(matches!(
+            stateful.protocol_type(),
+            StatefulProtocolType::Custom(_)
+        ));
+        let mut buf = Buffer::empty(area);
+        stateful.resize_encode_render(&Resize::Scale(None), Rgba([0, 0, 0, 0]), area, &mut buf);
+        assert_eq!("x", buf[(0, 0)].symbol());
+        assert_eq!(area, stateful.clone().area());
+
+        // Another name falls back to halfblocks.
+        picker.set_protocol_type(ProtocolType::Custom("other"));
+        let stateful = picker.new_resize_protocol(image);
+        assert!(matches!(
+            stateful.protocol_type(),
+            StatefulProtocolType::Halfblocks(_)
+        ));
     }
 }
@@ -1,5 +1,7 @@
 use std::fmt::Write;
 
+use image::Rgb;
+
 pub struct Parser {
     data: String,
     sequence: Response,
@@ -9,8 +11,12 @@ pub struct Parser {
 pub enum Response {
     Unknown,
     Kitty,
+    KittyAck,
     DeviceAttributes,
+    // Any window report (XTWINOPS), like the cell size.
     CellSize,
+    ForegroundColor,
+    BackgroundColor,
     Status,
 }
 
@@ -20,7 +26,22 @@ pub enum Capability {
     Sixel,
     RectangularOps,
     CellSize(Option<(u16, u16)>),
+    /// Width and height of the text area in pixels, from `[14t`.
+    TextAreaPixels(u32, u32),
+    /// Columns and rows of the text area, from `[18t`.
+    TextAreaCells(u16, u16),
+    ForegroundColor(Rgb<u8>),
+    BackgroundColor(Rgb<u8>),
     Status, // Might as well call this "End" internally.
+    /// Maximum sixel graphics size in pixels, from XTSMGRAPHICS.
+    SixelGeometry(u32, u32),
+    /// The text sizing protocol (OSC 66) moved the cursor by two cells.
+    TextSizing,
+    /// Response to a Kitty transmission, with the error message if it failed.
+    KittyAck {
+        id: u32,
+        error: Option<String>,
+    },
 }
 
 #[derive(Debug, PartialEq, Default)]
@@ -45,6 +66,11 @@ impl Parser {
             sequence: Response::Unknown,
         }
     }
+    /// Probe for the text sizing protocol (OSC 66): a space two cells wide, detected from the
+    /// cursor position report. Blanks the first two cells of the current line, and terminals
+    /// without OSC 66 print a visible space, so it is only sent when asked for. Not for tmux.
+    pub const TEXT_SIZING_PROBE: &'static str = "\x1b7\r\x1b]66;w=2; \x1b\\\x1b[6n\x1b8";
+
     // Tmux requires escapes to be escaped, and some special start/end sequences.
     pub fn escape_tmux(is_tmux: bool) -> (&'static str, &'static str, &'static str) {
         match is_tmux {
@@ -53,6 +79,14 @@ impl Parser {
         }
     }
     pub fn query(is_tmux: bool) -> String {
+        Parser::query_with(is_tmux, [])
+    }
+    /// Like [Parser::query], with more escape sequences whose responses are only collected,
+    /// before the final Device Status Report.
+    pub fn query_with<'a>(
+        is_tmux: bool,
+        extra_queries: impl IntoIterator<Item = &'a str>,
+    ) -> String {
         let (start, escape, end) = Parser::escape_tmux(is_tmux);
 
         let mut buf = String::with_capacity(100);
@@ -67,9 +101,24 @@ impl Parser {
         // Font size in pixels
         write!(buf, "{escape}[16t").unwrap();
 
+        // Text area size in pixels and in cells, for terminals that do not answer `[16t`.
+        write!(buf, "{escape}[14t{escape}[18t").unwrap();
+
+        // Sixel graphics geometry (XTSMGRAPHICS), the maximum size that the terminal accepts.
+        write!(buf, "{escape}[?2;1S").unwrap();
+
+        // Foreground and background colors (OSC 10 and OSC 11)
+        write!(buf, "{escape}]10;?{escape}\\").unwrap();
+        write!(buf, "{escape}]11;?{escape}\\").unwrap();
+
         // iTerm2 proprietary, unknown response, untested so far.
         //write!(buf, "{escape}[1337n").unwrap();
 
+        // Queries of the app, whose responses are only collected, see PickerBuilder::extra_query.
+        for extra_query in extra_queries {
+            buf.push_str(extra_query);
+        }
+
         // End with Device Status Report, implemented by all terminals, ensure that there is some
         // response and we don't hang reading forever.
         write!(buf, "{escape}[5n").unwrap();
@@ -91,12 +140,27 @@ impl Parser {
                     ("_Gi=31", ';') => {
                         self.sequence = Response::Kitty;
                     }
-                    ("[6", ';') => {
+                    (data, ';') if data.starts_with("_G") => {
+                        self.sequence = Response::KittyAck;
+                    }
+                    ("[4" | "[6" | "[8", ';') => {
                         self.sequence = Response::CellSize;
                     }
                     ("[", '0') => {
                         self.sequence = Response::Status;
                     }
+                    ("]10", ';') => {
+                        self.sequence = Response::ForegroundColor;
+                    }
+                    ("]11", ';') => {
+                        self.sequence = Response::BackgroundColor;
+                    }
+                    // Cursor position report, unless it is on row 6, see CellSize.
+                    (data, 'R') if data.starts_with('[') => {
+                        let caps = parse_cursor_position(data);
+                        self.restart();
+                        return caps;
+                    }
                     _ => {}
                 };
                 self.data.push(next);
@@ -115,6 +179,21 @@ impl Parser {
                     self.restart();
                     return caps;
                 }
+                // XTSMGRAPHICS response, like `[?2;0;1000;1000S`, starts just like DA.
+                'S' => {
+                    let inner: Vec<&str> = (self.data[2..]).split(';').collect();
+                    let caps = match inner[..] {
+                        ["2", "0", w, h] => match (w.parse(), h.parse()) {
+                            (Ok(w), Ok(h)) if w > 0 && h > 0 => {
+                                vec![Capability::SixelGeometry(w, h)]
+                            }
+                            _ => vec![],
+                        },
+                        _ => vec![],
+                    };
+                    self.restart();
+                    return caps;
+                }
                 '\x1b' => {
                     return self.restart();
                 }
@@ -137,19 +216,42 @@ impl Parser {
                 }
             },
 
+            Response::KittyAck => match next {
+                '\\' => {
+                    let caps = parse_kitty_ack(&self.data).into_iter().collect();
+                    self.restart();
+                    return caps;
+                }
+                _ => {
+                    self.data.push(next);
+                }
+            },
+
             Response::CellSize => match next {
                 't' => {
-                    let mut cell_size = None;
                     let inner: Vec<&str> = self.data.split(';').collect();
-                    if let [_, h, w] = inner[..] {
-                        if let (Ok(h), Ok(w)) = (h.parse::<u16>(), w.parse::<u16>()) {
-                            if w > 0 && h > 0 {
-                                cell_size = Some((w, h));
-                            }
-                        }
-                    }
+                    let size = match inner[..] {
+                        [_, h, w] => match (h.parse::<u32>(), w.parse::<u32>()) {
+                            (Ok(h), Ok(w)) if w > 0 && h > 0 => Some((w, h)),
+                            _ => None,
+                        },
+                        _ => None,
+                    };
+                    let cells =
+                        size.and_then(|(w, h)| Some((w.try_into().ok()?, h.try_into().ok()?)));
+                    let caps = match inner[0] {
+                        "[4" => size.map(|(w, h)| Capability::TextAreaPixels(w, h)),
+                        "[8" => cells.map(|(w, h)| Capability::TextAreaCells(w, h)),
+                        _ => Some(Capability::CellSize(cells)),
+                    };
                     self.restart();
-                    return vec![Capability::CellSize(cell_size)];
+                    return caps.into_iter().collect();
+                }
+                // Cursor position report on row 4, 6 or 8 looks like the start of a window report.
+                'R' => {
+                    let caps = parse_cursor_position(&self.data);
+                    self.restart();
+                    return caps;
                 }
                 '\x1b' => {
                     return self.restart();
@@ -158,6 +260,26 @@ impl Parser {
                     self.data.push(next);
                 }
             },
+            Response::ForegroundColor | Response::BackgroundColor => match next {
+                // Terminated by ST (`ESC \`) or BEL.
+                '\x1b' | '\x07' => {
+                    let color = parse_color(&self.data[4..]);
+                    let caps = match (&self.sequence, color) {
+                        (Response::ForegroundColor, Some(color)) => {
+                            vec![Capability::ForegroundColor(color)]
+                        }
+                        (Response::BackgroundColor, Some(color)) => {
+                            vec![Capability::BackgroundColor(color)]
+                        }
+                        _ => vec![],
+                    };
+                    self.restart();
+                    return caps;
+                }
+                _ => {
+                    self.data.push(next);
+                }
+            },
             Response::Status => match next {
                 'n' => return vec![Capability::Status],
                 '\x1b' => {
@@ -170,6 +292,16 @@ impl Parser {
         };
         vec![]
     }
+    // The response that is being parsed, Unknown until it has been identified.
+    pub(crate) fn response(&self) -> &Response {
+        &self.sequence
+    }
+
+    // Whether nothing has been pushed since the last response or escape.
+    pub(crate) fn is_idle(&self) -> bool {
+        self.sequence == Response::Unknown && self.data.is_empty()
+    }
+
     fn restart(&mut self) -> Vec<Capability> {
         self.data = String::new();
         self.sequence = Response::Unknown;
@@ -177,15 +309,55 @@ impl Parser {
     }
 }
 
+/// Parse a Kitty response like `_Gi=42;OK<ESC>` or `_Gi=42,p=1;ENOMEM:out of memory<ESC>`.
+fn parse_kitty_ack(data: &str) -> Option<Capability> {
+    let (keys, message) = data.strip_prefix("_G")?.split_once(';')?;
+    let id = keys
+        .split(',')
+        .find_map(|key| key.strip_prefix("i="))?
+        .parse()
+        .ok()?;
+    let message = message.strip_suffix('\x1b').unwrap_or(message);
+    let error = (message != "OK").then(|| message.to_string());
+    Some(Capability::KittyAck { id, error })
+}
+
+/// Parse a cursor position report like `[12;3`, after a carriage return and a double-width space.
+fn parse_cursor_position(data: &str) -> Vec<Capability> {
+    match data.strip_prefix('[').and_then(|data| data.split_once(';')) {
+        Some((_, "3")) => vec![Capability::TextSizing],
+        _ => vec![],
+    }
+}
+
+/// Parse an X11 color spec like `rgb:ffff/8080/0000`, with 1 to 4 hex digits per component.
+fn parse_color(spec: &str) -> Option<Rgb<u8>> {
+    let components: Vec<&str> = spec.strip_prefix("rgb:")?.split('/').collect();
+    let [r, g, b] = components[..] else {
+        return None;
+    };
+    let scale = |hex: &str| -> Option<u8> {
+        if hex.is_empty() || hex.len() > 4 {
+            return None;
+        }
+        let value = u32::from_str_radix(hex, 16).ok()?;
+        let max = (1u32 << (4 * hex.len())) - 1;
+        Some((value * 255 / max) as u8)
+    };
+    Some(Rgb([scale(r)?, scale(g)?, scale(b)?]))
+}
+
 #[cfg(test)]
 mod tests {
     use std::assert_eq;
 
+    use image::Rgb;
+
     use super::{Capability, Parser};
 
     #[test]
     fn test_parse_all() {
-        for (name, str, expected) in vec![
+        for (name, str, expected) in [
             (
                 "all",
                 "\x1b_Gi=31;OK\x1b\\\x1b[?64;4c\x1b[6;7;14t\x1b[0n",
@@ -196,12 +368,62 @@ mod tests {
                     Capability::Status,
                 ],
             ),
+            (
+                "colors",
+                "\x1b]10;rgb:ffff/ffff/ffff\x1b\\\x1b]11;rgb:00/80/ff\x07\x1b[0n",
+                vec![
+                    Capability::ForegroundColor(Rgb([255, 255, 255])),
+                    Capability::BackgroundColor(Rgb([0, 128, 255])),
+                    Capability::Status,
+                ],
+            ),
+            (
+                "sixel geometry",
+                "\x1b[?64;4c\x1b[?2;0;1000;800S\x1b[?2;3;0S\x1b[0n",
+                vec![
+                    Capability::Sixel,
+                    Capability::SixelGeometry(1000, 800),
+                    Capability::Status,
+                ],
+            ),
+            (
+                "text sizing",
+                "\x1b[12;3R\x1b[6;3R\x1b[4;1R\x1b[0n",
+                vec![
+                    Capability::TextSizing,
+                    Capability::TextSizing,
+                    Capability::Status,
+                ],
+            ),
             ("only garbage", "\x1bhonkey\x1btonkey\x1b[42\x1b\\", vec![]),
+            (
+                "kitty acks",
+                "\x1b_Gi=42;OK\x1b\\\x1b_Gi=7,p=1;ENOMEM:no memory\x1b\\",
+                vec![
+                    Capability::KittyAck {
+                        id: 42,
+                        error: None,
+                    },
+                    Capability::KittyAck {
+                        id: 7,
+                        error: Some("ENOMEM:no memory".to_string()),
+                    },
+                ],
+            ),
             (
                 "preceding garbage",
                 "\x1bgarbage...\x1b[?64;5c\x1b[0n",
                 vec![Capability::Status],
             ),
+            (
+                "text area",
+                "\x1b[4;480;1280t\x1b[8;24;80t\x1b[4;0;0t\x1b[0n",
+                vec![
+                    Capability::TextAreaPixels(1280, 480),
+                    Capability::TextAreaCells(80, 24),
+                    Capability::Status,
+                ],
+            ),
             (
                 "inner garbage",
                 "\x1b[6;7;14t\x1bgarbage...\x1b[?64;5c\x1b[0n",
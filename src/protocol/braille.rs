@@ -0,0 +1,358 @@
+//! Braille protocol implementations.
+//! Uses the unicode braille patterns `⠁` to `⣿`, which have 2x4 dots per cell, for a higher
+//! resolution than halfblocks at the cost of color: each dot is either on or off, and all the dots
+//! of a cell share the foreground color. Should work in all terminals with a font that has the
+//! braille patterns, and suits line art, waveforms and the like more than photos.
+use image::{imageops::FilterType, DynamicImage, Rgb, Rgba};
+use ratatui::{buffer::Buffer, layout::Rect, style::Color};
+use std::sync::Arc;
+
+use super::{encode_shared, ProtocolTrait, SharedEncodings, StatefulProtocolTrait};
+use crate::{picker::luminance, FontSize, ImageSource, Resize, Result};
+
+/// How pixels are turned into braille dots.
+///
+/// # Example
+/// ```rust
+/// use ratatui_image::protocol::braille::BrailleOptions;
+///
+/// // Dark lines on a light background.
+/// let options = BrailleOptions::new().invert(true).dither(false);
+/// ```
+#[derive(Clone, Debug, PartialEq, Hash)]
+pub struct BrailleOptions {
+    threshold: u8,
+    dither: bool,
+    invert: bool,
+}
+
+impl Default for BrailleOptions {
+    fn default() -> Self {
+        BrailleOptions {
+            threshold: 128,
+            dither: true,
+            invert: false,
+        }
+    }
+}
+
+impl BrailleOptions {
+    pub fn new() -> BrailleOptions {
+        BrailleOptions::default()
+    }
+
+    /// The luminance from which a pixel is a dot, within `0..=255` (default 128).
+    pub fn threshold(mut self, threshold: u8) -> BrailleOptions {
+        self.threshold = threshold;
+        self
+    }
+
+    /// Diffuse the error of each dot to neighbouring pixels, so that shades become dot patterns
+    /// (default true).
+    pub fn dither(mut self, dither: bool) -> BrailleOptions {
+        self.dither = dither;
+        self
+    }
+
+    /// Make dots of the dark pixels instead of the bright ones, for light backgrounds.
+    pub fn invert(mut self, invert: bool) -> BrailleOptions {
+        self.invert = invert;
+        self
+    }
+}
+
+// Fixed Braille protocol
+#[derive(Clone, Default)]
+pub struct Braille {
+    data: Arc<Vec<BrailleCell>>,
+    area: Rect,
+}
+
+#[derive(Clone, Debug)]
+struct BrailleCell {
+    // One bit per dot, in the order of the unicode braille patterns.
+    dots: u8,
+    // The average color of the dots.
+    color: Color,
+}
+
+impl Braille {
+    /// Create a FixedBraille from an image.
+    pub fn new(image: DynamicImage, area: Rect) -> Result<Self> {
+        Self::with_options(image, area, &BrailleOptions::default())
+    }
+
+    /// Create a FixedBraille from an image, with [BrailleOptions].
+    pub fn with_options(image: DynamicImage, area: Rect, options: &BrailleOptions) -> Result<Self> {
+        let data = Arc::new(encode(&image, area, options));
+        Ok(Self { data, area })
+    }
+}
+
+// The bit of the dot at (x, y) within a cell, see https://en.wikipedia.org/wiki/Braille_Patterns.
+const DOTS: [[u8; 2]; 4] = [[0x01, 0x08], [0x02, 0x10], [0x04, 0x20], [0x40, 0x80]];
+
+fn encode(img: &DynamicImage, rect: Rect, options: &BrailleOptions) -> Vec<BrailleCell> {
+    let (width, height) = (rect.width as usize * 2, rect.height as usize * 4);
+    let img = img
+        .resize_exact(width as u32, height as u32, FilterType::Triangle)
+        .to_rgba8();
+
+    // Transparent pixels are never dots.
+    let mut levels: Vec<f32> = img
+        .pixels()
+        .map(|&Rgba([r, g, b, a])| {
+            let level = luminance(Rgb([r, g, b])) * 255.0;
+            let level = if options.invert { 255.0 - level } else { level };
+            level * a as f32 / 255.0
+        })
+        .collect();
+
+    let mut sums = vec![([0u32; 3], 0u32); rect.width as usize * rect.height as usize];
+    let mut data = vec![
+        BrailleCell {
+            dots: 0,
+            color: Color::Reset,
+        };
+        sums.len()
+    ];
+    for y in 0..height {
+        for x in 0..width {
+            let level = levels[y * width + x];
+            let on = level >= options.threshold as f32;
+            if options.dither {
+                let error = level - if on { 255.0 } else { 0.0 };
+                for (dx, dy, weight) in [
+                    (1, 0, 7.0 / 16.0),
+                    (-1, 1, 3.0 / 16.0),
+                    (0, 1, 5.0 / 16.0),
+                    (1, 1, 1.0 / 16.0),
+                ] {
+                    let (nx, ny) = (x as isize + dx, y + dy);
+                    if nx < 0 || nx as usize >= width || ny >= height {
+                        continue;
+                    }
+                    levels[ny * width + nx as usize] += error * weight;
+                }
+            }
+            if !on {
+                continue;
+            }
+            let position = x / 2 + rect.width as usize * (y / 4);
+            data[position].dots |= DOTS[y % 4][x % 2];
+            let Rgba([r, g, b, _]) = img.get_pixel(x as u32, y as u32);
+            let (sum, count) = &mut sums[position];
+            for (sum, channel) in sum.iter_mut().zip([r, g, b]) {
+                *sum += *channel as u32;
+            }
+            *count += 1;
+        }
+    }
+    for (cell, (sum, count)) in data.iter_mut().zip(sums) {
+        if count > 0 {
+            let [r, g, b] = sum.map(|sum| (sum / count) as u8);
+            cell.color = Color::Rgb(r, g, b);
+        }
+    }
+    data
+}
+
+impl ProtocolTrait for Braille {
+    fn render(&mut self, area: Rect, buf: &mut Buffer) {
+        self.render_cells(area, buf);
+    }
+    fn area(&self) -> Rect {
+        self.area
+    }
+}
+
+impl Braille {
+    fn render_cells(&self, area: Rect, buf: &mut Buffer) {
+        for (i, braille) in self.data.iter().enumerate() {
+            let x = i as u16 % self.area.width;
+            let y = i as u16 / self.area.width;
+            if x >= area.width || y >= area.height {
+                continue;
+            }
+
+            let Some(cell) = buf.cell_mut((area.x + x, area.y + y)) else {
+                continue;
+            };
+            // The background of the cell is kept, like transparent halfblocks.
+            if braille.dots == 0 {
+                cell.set_char(' ');
+            } else {
+                let symbol = char::from_u32(0x2800 + braille.dots as u32).unwrap_or(' ');
+                cell.set_fg(braille.color).set_char(symbol);
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct StatefulBraille {
+    source: ImageSource,
+    font_size: FontSize,
+    current: Braille,
+    hash: u64,
+    options: BrailleOptions,
+    shared: Option<SharedEncodings>,
+}
+
+impl StatefulBraille {
+    pub fn new(source: ImageSource, font_size: FontSize) -> StatefulBraille {
+        StatefulBraille {
+            source,
+            font_size,
+            current: Braille::default(),
+            hash: u64::default(),
+            options: BrailleOptions::default(),
+            shared: None,
+        }
+    }
+
+    /// Reuse equal encodings of other protocols, see [SharedEncodings].
+    pub fn shared(mut self, shared: Option<SharedEncodings>) -> StatefulBraille {
+        self.shared = shared;
+        self
+    }
+
+    /// Turn pixels into dots with [BrailleOptions] when encoding.
+    pub fn options(mut self, options: BrailleOptions) -> StatefulBraille {
+        self.options = options;
+        self
+    }
+}
+
+impl ProtocolTrait for StatefulBraille {
+    fn render(&mut self, area: Rect, buf: &mut Buffer) {
+        Braille::render(&mut self.current, area, buf);
+    }
+
+    fn area(&self) -> Rect {
+        self.current.area
+    }
+}
+
+impl StatefulProtocolTrait for StatefulBraille {
+    fn font_size(&self) -> FontSize {
+        self.font_size
+    }
+    fn set_font_size(&mut self, font_size: FontSize) {
+        self.font_size = font_size;
+        // Encoded for the previous font size.
+        self.hash = u64::default();
+    }
+    fn set_dither(&mut self, dither: bool) {
+        self.options.dither = dither;
+    }
+    fn dither(&self) -> bool {
+        self.options.dither
+    }
+    fn source(&self) -> &ImageSource {
+        &self.source
+    }
+    fn source_mut(&mut self) -> &mut ImageSource {
+        &mut self.source
+    }
+    fn background_color(&self) -> Rgba<u8> {
+        self.source.background_color
+    }
+    fn needs_resize(&mut self, resize: &Resize, area: Rect) -> Option<Rect> {
+        resize.needs_resize(
+            &self.source,
+            self.font_size,
+            self.current.area,
+            area,
+            self.source.hash != self.hash,
+        )
+    }
+    fn resize_encode(
+        &mut self,
+        resize: &Resize,
+        background_color: Rgba<u8>,
+        area: Rect,
+    ) -> Result<()> {
+        if area.width == 0 || area.height == 0 {
+            return Ok(());
+        }
+
+        let key = (
+            "braille",
+            self.source.hash,
+            self.font_size,
+            resize,
+            background_color,
+            area,
+            &self.options,
+        );
+        let data = encode_shared(self.shared.as_ref(), key, || {
+            let img = resize.resize(&self.source, self.font_size, area, background_color);
+            Ok(encode(&img, area, &self.options))
+        })?;
+        self.current = Braille { data, area };
+        self.hash = self.source.hash;
+        Ok(())
+    }
+    fn render_placement(&self, area: Rect, buf: &mut Buffer) {
+        self.current.render_cells(area, buf);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ImageBuffer, Rgba};
+    use ratatui::{buffer::Buffer, layout::Rect, style::Color};
+
+    use super::{Braille, BrailleOptions};
+    use crate::protocol::ProtocolTrait;
+
+    #[test]
+    fn test_braille() {
+        // A diagonal line, 2x4 pixels per cell.
+        let img = ImageBuffer::from_fn(4, 4, |x, y| {
+            if x == y {
+                Rgba::<u8>([255, 255, 255, 255])
+            } else {
+                Rgba([0, 0, 0, 255])
+            }
+        });
+        let options = BrailleOptions::new().dither(false);
+        let mut braille =
+            Braille::with_options(img.clone().into(), Rect::new(0, 0, 2, 1), &options).unwrap();
+        let mut buf = Buffer::empty(Rect::new(0, 0, 3, 1));
+        braille.render(Rect::new(0, 0, 2, 1), &mut buf);
+        // Dots 1 and 5, then dots 3 and 8.
+        assert_eq!(buf[(0, 0)].symbol(), "⠑");
+        assert_eq!(buf[(0, 0)].fg, Color::Rgb(255, 255, 255));
+        assert_eq!(buf[(1, 0)].symbol(), "⢄");
+        assert_eq!(buf[(2, 0)].symbol(), " ");
+
+        // Inverted, the dots are everywhere else.
+        let options = options.invert(true);
+        let mut braille =
+            Braille::with_options(img.into(), Rect::new(0, 0, 2, 1), &options).unwrap();
+        let mut buf = Buffer::empty(Rect::new(0, 0, 2, 1));
+        braille.render(Rect::new(0, 0, 2, 1), &mut buf);
+        assert_eq!(buf[(0, 0)].symbol(), "⣮");
+        assert_eq!(buf[(0, 0)].fg, Color::Rgb(0, 0, 0));
+    }
+
+    #[test]
+    fn test_braille_dither() {
+        // Mid-gray dithers to about half of the dots.
+        let img = ImageBuffer::from_pixel(8, 8, Rgba::<u8>([128, 128, 128, 255]));
+        let area = Rect::new(0, 0, 4, 2);
+        let mut buf = Buffer::empty(area);
+        Braille::new(img.into(), area)
+            .unwrap()
+            .render(area, &mut buf);
+        let dots: u32 = buf
+            .content()
+            .iter()
+            .map(|cell| (cell.symbol().chars().next().unwrap() as u32).saturating_sub(0x2800))
+            .map(|dots| dots.count_ones())
+            .sum();
+        assert!((24..=40).contains(&dots), "{dots}");
+    }
+}
@@ -1,23 +1,67 @@
 //! Halfblocks protocol implementations.
 //! Uses the unicode character `▀` combined with foreground and background color. Assumes that the
 //! font aspect ratio is roughly 1:2. Should work in all terminals.
-use image::{imageops::FilterType, DynamicImage, Rgba};
+//! Transparent pixels are blended with the background of the cells that are already in the buffer.
+//!
+//! Like chafa, other symbols and a foreground-only mode can be chosen with [HalfblocksSymbols]
+//! and [HalfblocksQuantizer::fg_only].
+use image::{imageops::FilterType, DynamicImage, RgbImage, Rgba};
 use ratatui::{buffer::Buffer, layout::Rect, style::Color};
+use std::sync::Arc;
 
-use super::{ProtocolTrait, StatefulProtocolTrait};
-use crate::{FontSize, ImageSource, Resize, Result};
+use super::{encode_shared, ProtocolTrait, SharedEncodings, StatefulProtocolTrait};
+use crate::{picker::ColorDepth, FontSize, ImageSource, Resize, Result};
 
 // Fixed Halfblocks protocol
 #[derive(Clone, Default)]
 pub struct Halfblocks {
-    data: Vec<HalfBlock>,
+    data: Arc<Vec<Block>>,
     area: Rect,
 }
 
+// The content of a cell.
+#[derive(Clone, Debug)]
+enum Block {
+    Half(HalfBlock),
+    // A symbol of another HalfblocksSymbols, or of fg_only, without blending.
+    Glyph {
+        symbol: char,
+        fg: Color,
+        bg: Option<Color>,
+    },
+}
+
 #[derive(Clone, Debug)]
 struct HalfBlock {
     upper: Color,
     lower: Color,
+    upper_alpha: u8,
+    lower_alpha: u8,
+}
+
+// What a half of a cell should show after blending with the cell's existing background.
+#[derive(PartialEq)]
+enum Half {
+    Color(Color),
+    Transparent,
+}
+
+impl Half {
+    // Blend over the background already in the buffer, e.g. a colored panel. Only RGB colors can
+    // be mixed; otherwise the pixel is either opaque or lets the background through entirely.
+    fn blend(color: Color, alpha: u8, under: Color) -> Half {
+        match (color, under) {
+            _ if alpha == 255 => Half::Color(color),
+            (Color::Rgb(r, g, b), Color::Rgb(ur, ug, ub)) => {
+                let mix = |c: u8, u: u8| {
+                    ((c as u16 * alpha as u16 + u as u16 * (255 - alpha as u16)) / 255) as u8
+                };
+                Half::Color(Color::Rgb(mix(r, ur), mix(g, ug), mix(b, ub)))
+            }
+            _ if alpha >= 128 => Half::Color(color),
+            _ => Half::Transparent,
+        }
+    }
 }
 
 impl Halfblocks {
@@ -29,55 +73,447 @@ impl Halfblocks {
     /// Also note that the font-size is probably just some arbitrary size with a 1:2 ratio when the
     /// protocol is Halfblocks, and not the actual font size of the terminal.
     pub fn new(image: DynamicImage, area: Rect) -> Result<Self> {
-        let data = encode(&image, area);
+        Self::with_quantizer(image, area, &HalfblocksQuantizer::default())
+    }
+
+    /// Create a FixedHalfblocks from an image, mapping colors with a [HalfblocksQuantizer].
+    pub fn with_quantizer(
+        image: DynamicImage,
+        area: Rect,
+        quantizer: &HalfblocksQuantizer,
+    ) -> Result<Self> {
+        let data = Arc::new(encode(&image, area, quantizer));
         Ok(Self { data, area })
     }
 }
 
-fn encode(img: &DynamicImage, rect: Rect) -> Vec<HalfBlock> {
+/// A fixed palette for the [HalfblocksQuantizer].
+#[derive(Clone, Debug, PartialEq, Hash)]
+pub enum Palette {
+    /// The 16 ANSI colors, emitted as indexed colors so that the terminal's theme applies.
+    /// Matching is done against the xterm default colors.
+    Ansi16,
+    /// The 240 fixed colors of the 256-color palette, the 6x6x6 color cube and the grayscale
+    /// ramp, emitted as indexed colors. The first 16 are left out, because themes change them.
+    Ansi256,
+    /// Custom colors, emitted as RGB colors.
+    Custom(Vec<[u8; 3]>),
+}
+
+/// xterm's default values of the 16 ANSI colors.
+pub(crate) const ANSI16: [[u8; 3]; 16] = [
+    [0, 0, 0],
+    [205, 0, 0],
+    [0, 205, 0],
+    [205, 205, 0],
+    [0, 0, 238],
+    [205, 0, 205],
+    [0, 205, 205],
+    [229, 229, 229],
+    [127, 127, 127],
+    [255, 0, 0],
+    [0, 255, 0],
+    [255, 255, 0],
+    [92, 92, 255],
+    [255, 0, 255],
+    [0, 255, 255],
+    [255, 255, 255],
+];
+
+/// The xterm value of a color of the 256-color palette.
+pub(crate) fn ansi256(index: u8) -> [u8; 3] {
+    match index {
+        0..16 => ANSI16[index as usize],
+        // The 6x6x6 color cube.
+        16..232 => {
+            let level = |value: u8| if value == 0 { 0 } else { 55 + value * 40 };
+            let index = index - 16;
+            [level(index / 36), level(index / 6 % 6), level(index % 6)]
+        }
+        // The grayscale ramp.
+        232.. => [8 + (index - 232) * 10; 3],
+    }
+}
+
+impl Palette {
+    fn entries(&self) -> Vec<([u8; 3], Color)> {
+        match self {
+            Palette::Ansi16 => ANSI16
+                .iter()
+                .enumerate()
+                .map(|(i, rgb)| (*rgb, Color::Indexed(i as u8)))
+                .collect(),
+            Palette::Ansi256 => (16..=255)
+                .map(|i| (ansi256(i), Color::Indexed(i)))
+                .collect(),
+            Palette::Custom(colors) => colors
+                .iter()
+                .map(|rgb| (*rgb, Color::Rgb(rgb[0], rgb[1], rgb[2])))
+                .collect(),
+        }
+    }
+}
+
+/// The symbols that the halfblocks encoder draws with, like chafa's symbol selection.
+///
+/// For dots, see the [braille](super::braille) protocol.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum HalfblocksSymbols {
+    /// `▀` and `▄`, two pixels per cell with a color each (the default).
+    #[default]
+    Half,
+    /// Quadrants like `▚` or `▙`, four pixels per cell in two colors.
+    Quadrants,
+    /// ASCII characters by brightness, like ` .:-=+*#%@`, one pixel per cell. Always
+    /// foreground-only.
+    Ascii,
+}
+
+impl HalfblocksSymbols {
+    // The pixels per cell, horizontally and vertically.
+    fn pixels(&self) -> (u32, u32) {
+        match self {
+            HalfblocksSymbols::Half => (1, 2),
+            HalfblocksSymbols::Quadrants => (2, 2),
+            HalfblocksSymbols::Ascii => (1, 1),
+        }
+    }
+}
+
+/// Color quantizer for the halfblocks encoder.
+///
+/// By default, colors are emitted as 24-bit RGB. With a [Palette], each pixel is mapped to the
+/// nearest palette color, optionally with Floyd-Steinberg dithering. The symbols, and whether
+/// cells get a background color, can be chosen too.
+///
+/// # Example
+/// ```rust
+/// use ratatui_image::protocol::halfblocks::{HalfblocksQuantizer, HalfblocksSymbols, Palette};
+///
+/// let quantizer = HalfblocksQuantizer::new().palette(Palette::Ansi16).dither(true);
+/// let quantizer = HalfblocksQuantizer::new()
+///     .symbols(HalfblocksSymbols::Quadrants)
+///     .fg_only(true);
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Hash)]
+pub struct HalfblocksQuantizer {
+    palette: Option<Palette>,
+    dither: bool,
+    symbols: HalfblocksSymbols,
+    fg_only: bool,
+}
+
+impl HalfblocksQuantizer {
+    pub fn new() -> HalfblocksQuantizer {
+        HalfblocksQuantizer::default()
+    }
+
+    /// Map colors to a fixed palette.
+    pub fn palette(mut self, palette: Palette) -> HalfblocksQuantizer {
+        self.palette = Some(palette);
+        self
+    }
+
+    /// The palette of a [ColorDepth] with dithering, unless a palette was set already.
+    pub(crate) fn or_color_depth(mut self, color_depth: ColorDepth) -> HalfblocksQuantizer {
+        if self.palette.is_none() {
+            self.palette = color_depth.palette();
+            self.dither = self.palette.is_some();
+        }
+        self
+    }
+
+    // The number of colors of the palette, if any.
+    pub(crate) fn palette_len(&self) -> Option<usize> {
+        match self.palette.as_ref()? {
+            Palette::Ansi16 => Some(16),
+            Palette::Ansi256 => Some(240),
+            Palette::Custom(colors) => Some(colors.len()),
+        }
+    }
+
+    /// Diffuse the quantization error to neighbouring pixels. Has no effect without a palette.
+    pub fn dither(mut self, dither: bool) -> HalfblocksQuantizer {
+        self.dither = dither;
+        self
+    }
+
+    /// Draw with other symbols, see [HalfblocksSymbols] (default [HalfblocksSymbols::Half]).
+    pub fn symbols(mut self, symbols: HalfblocksSymbols) -> HalfblocksQuantizer {
+        self.symbols = symbols;
+        self
+    }
+
+    /// Only set foreground colors, and leave the background of the cells as it is, e.g. for
+    /// terminals with a translucent background. The darker pixels of a cell are left out, so
+    /// each cell shows one color.
+    pub fn fg_only(mut self, fg_only: bool) -> HalfblocksQuantizer {
+        self.fg_only = fg_only;
+        self
+    }
+
+    /// Quantize the pixels in row-major order.
+    fn quantize(&self, img: &RgbImage) -> Vec<Color> {
+        let entries = match &self.palette {
+            Some(palette) => palette.entries(),
+            None => {
+                return img.pixels().map(|p| Color::Rgb(p[0], p[1], p[2])).collect();
+            }
+        };
+        if entries.is_empty() {
+            return vec![Color::Reset; (img.width() * img.height()) as usize];
+        }
+
+        let (width, height) = (img.width() as usize, img.height() as usize);
+        let mut pixels: Vec<[f32; 3]> = img
+            .pixels()
+            .map(|p| [p[0] as f32, p[1] as f32, p[2] as f32])
+            .collect();
+        let mut colors = Vec::with_capacity(pixels.len());
+        for y in 0..height {
+            for x in 0..width {
+                let pixel = pixels[y * width + x];
+                let rgb = pixel.map(|c| c.clamp(0.0, 255.0) as u8);
+                let (nearest, color) = entries
+                    .iter()
+                    .min_by_key(|(candidate, _)| color_distance(rgb, *candidate))
+                    .copied()
+                    .unwrap_or(([0, 0, 0], Color::Reset));
+                colors.push(color);
+
+                if self.dither {
+                    let error = [0, 1, 2].map(|i| pixel[i] - nearest[i] as f32);
+                    for (dx, dy, weight) in [
+                        (1, 0, 7.0 / 16.0),
+                        (-1, 1, 3.0 / 16.0),
+                        (0, 1, 5.0 / 16.0),
+                        (1, 1, 1.0 / 16.0),
+                    ] {
+                        let (nx, ny) = (x as isize + dx, y + dy);
+                        if nx < 0 || nx as usize >= width || ny >= height {
+                            continue;
+                        }
+                        let neighbour = &mut pixels[ny * width + nx as usize];
+                        for i in 0..3 {
+                            neighbour[i] += error[i] * weight;
+                        }
+                    }
+                }
+            }
+        }
+        colors
+    }
+}
+
+// Weighted squared euclidean distance, roughly accounting for the eye's sensitivity to green.
+fn color_distance(a: [u8; 3], b: [u8; 3]) -> u32 {
+    let [dr, dg, db] = [0, 1, 2].map(|i| (a[i] as i32 - b[i] as i32).unsigned_abs());
+    2 * dr * dr + 4 * dg * dg + 3 * db * db
+}
+
+// The RGB value of a quantized color.
+fn color_rgb(color: Color) -> [u8; 3] {
+    match color {
+        Color::Rgb(r, g, b) => [r, g, b],
+        Color::Indexed(index) => ansi256(index),
+        _ => [0, 0, 0],
+    }
+}
+
+fn luma([r, g, b]: [u8; 3]) -> u32 {
+    (299 * r as u32 + 587 * g as u32 + 114 * b as u32) / 1000
+}
+
+// Quadrants by the mask of the foreground pixels: top left 1, top right 2, bottom left 4, bottom
+// right 8.
+const QUADRANTS: [char; 16] = [
+    ' ', '▘', '▝', '▀', '▖', '▌', '▞', '▛', '▗', '▚', '▐', '▜', '▄', '▙', '▟', '█',
+];
+// The upper pixel 1, the lower pixel 2.
+const HALVES: [char; 4] = [' ', '▀', '▄', '█'];
+const ASCII_RAMP: &[u8] = b" .:-=+*#%@";
+
+// A cell of two or four pixels (color and alpha) in two colors: the brighter of the two most
+// common colors in the foreground, the other in the background, or left out if fg_only. Pixels
+// that are mostly transparent are left out too.
+fn glyph(pixels: &[(Color, u8)], symbols: &[char], fg_only: bool) -> Block {
+    let opaque = |(_, alpha): &&(Color, u8)| *alpha >= 128;
+    let mut counts: Vec<(Color, usize)> = vec![];
+    for (color, _) in pixels.iter().filter(opaque) {
+        match counts.iter_mut().find(|(counted, _)| counted == color) {
+            Some((_, count)) => *count += 1,
+            None => counts.push((*color, 1)),
+        }
+    }
+    // Stable, so that ties keep the pixel order.
+    counts.sort_by_key(|(_, count)| usize::MAX - count);
+    let (fg, bg) = match counts.as_slice() {
+        [] => {
+            return Block::Glyph {
+                symbol: ' ',
+                fg: Color::Reset,
+                bg: None,
+            }
+        }
+        [(only, _)] => (*only, None),
+        [(first, _), (second, _), ..] => {
+            if luma(color_rgb(*first)) >= luma(color_rgb(*second)) {
+                (*first, Some(*second))
+            } else {
+                (*second, Some(*first))
+            }
+        }
+    };
+    let mut mask = 0;
+    for (i, pixel) in pixels.iter().enumerate() {
+        if !opaque(&pixel) {
+            continue;
+        }
+        let rgb = color_rgb(pixel.0);
+        let foreground = match bg {
+            Some(bg) => color_distance(rgb, color_rgb(fg)) <= color_distance(rgb, color_rgb(bg)),
+            None => true,
+        };
+        if foreground {
+            mask |= 1 << i;
+        }
+    }
+    // Transparent pixels need a cell without background.
+    let transparent = pixels.iter().any(|pixel| !opaque(&pixel));
+    Block::Glyph {
+        symbol: symbols[mask],
+        fg,
+        bg: bg.filter(|_| !fg_only && !transparent),
+    }
+}
+
+fn encode(img: &DynamicImage, rect: Rect, quantizer: &HalfblocksQuantizer) -> Vec<Block> {
+    let (columns, rows) = quantizer.symbols.pixels();
     let img = img.resize_exact(
-        rect.width as u32,
-        (rect.height * 2) as u32,
+        rect.width as u32 * columns,
+        rect.height as u32 * rows,
         FilterType::Triangle,
     );
+    if quantizer.symbols == HalfblocksSymbols::Half && !quantizer.fg_only {
+        return encode_halves(&img, rect, quantizer);
+    }
 
+    let colors = quantizer.quantize(&img.to_rgb8());
+    let alphas: Vec<u8> = img.to_rgba8().pixels().map(|p| p[3]).collect();
+    let width = img.width() as usize;
+    let pixel = |x: usize, y: usize| (colors[y * width + x], alphas[y * width + x]);
+    let mut data = Vec::with_capacity((rect.width * rect.height) as usize);
+    for y in 0..rect.height as usize {
+        for x in 0..rect.width as usize {
+            data.push(match quantizer.symbols {
+                HalfblocksSymbols::Half => glyph(
+                    &[pixel(x, y * 2), pixel(x, y * 2 + 1)],
+                    &HALVES,
+                    quantizer.fg_only,
+                ),
+                HalfblocksSymbols::Quadrants => glyph(
+                    &[
+                        pixel(x * 2, y * 2),
+                        pixel(x * 2 + 1, y * 2),
+                        pixel(x * 2, y * 2 + 1),
+                        pixel(x * 2 + 1, y * 2 + 1),
+                    ],
+                    &QUADRANTS,
+                    quantizer.fg_only,
+                ),
+                HalfblocksSymbols::Ascii => {
+                    let (color, alpha) = pixel(x, y);
+                    let level = luma(color_rgb(color)) as usize * ASCII_RAMP.len() / 256;
+                    let symbol = if alpha < 128 { b' ' } else { ASCII_RAMP[level] };
+                    Block::Glyph {
+                        symbol: symbol as char,
+                        fg: color,
+                        bg: None,
+                    }
+                }
+            });
+        }
+    }
+    data
+}
+
+// Two pixels per cell with a color each, blended with the buffer's background on render.
+fn encode_halves(img: &DynamicImage, rect: Rect, quantizer: &HalfblocksQuantizer) -> Vec<Block> {
     let mut data = vec![
         HalfBlock {
             upper: Color::Rgb(0, 0, 0),
             lower: Color::Rgb(0, 0, 0),
+            upper_alpha: 255,
+            lower_alpha: 255,
         };
         (rect.width * rect.height) as usize
     ];
 
-    for (y, row) in img.to_rgb8().rows().enumerate() {
-        for (x, pixel) in row.enumerate() {
-            let position = x + (rect.width as usize) * (y / 2);
-            if y % 2 == 0 {
-                data[position].upper = Color::Rgb(pixel[0], pixel[1], pixel[2]);
-            } else {
-                data[position].lower = Color::Rgb(pixel[0], pixel[1], pixel[2]);
-            }
+    let colors = quantizer.quantize(&img.to_rgb8());
+    let alphas = img.to_rgba8().pixels().map(|p| p[3]).collect::<Vec<_>>();
+    for (i, (color, alpha)) in colors.into_iter().zip(alphas).enumerate() {
+        let (x, y) = (i % img.width() as usize, i / img.width() as usize);
+        let position = x + (rect.width as usize) * (y / 2);
+        if y % 2 == 0 {
+            data[position].upper = color;
+            data[position].upper_alpha = alpha;
+        } else {
+            data[position].lower = color;
+            data[position].lower_alpha = alpha;
         }
     }
-    data
+    data.into_iter().map(Block::Half).collect()
 }
 
 impl ProtocolTrait for Halfblocks {
     fn render(&mut self, area: Rect, buf: &mut Buffer) {
-        for (i, hb) in self.data.iter().enumerate() {
+        self.render_cells(area, buf);
+    }
+    fn area(&self) -> Rect {
+        self.area
+    }
+}
+
+impl Halfblocks {
+    fn render_cells(&self, area: Rect, buf: &mut Buffer) {
+        for (i, block) in self.data.iter().enumerate() {
             let x = i as u16 % self.area.width;
             let y = i as u16 / self.area.width;
             if x >= area.width || y >= area.height {
                 continue;
             }
 
-            buf.cell_mut((area.x + x, area.y + y))
-                .map(|cell| cell.set_fg(hb.upper).set_bg(hb.lower).set_char('▀'));
+            let Some(cell) = buf.cell_mut((area.x + x, area.y + y)) else {
+                continue;
+            };
+            let hb = match block {
+                Block::Half(hb) => hb,
+                Block::Glyph { symbol, fg, bg } => {
+                    cell.set_char(*symbol).set_fg(*fg);
+                    if let Some(bg) = bg {
+                        cell.set_bg(*bg);
+                    }
+                    continue;
+                }
+            };
+            let upper = Half::blend(hb.upper, hb.upper_alpha, cell.bg);
+            let lower = Half::blend(hb.lower, hb.lower_alpha, cell.bg);
+            match (upper, lower) {
+                (Half::Color(upper), Half::Color(lower)) => {
+                    cell.set_fg(upper).set_bg(lower).set_char('▀');
+                }
+                (Half::Transparent, Half::Color(lower)) => {
+                    cell.set_fg(lower).set_char('▄');
+                }
+                (Half::Color(upper), Half::Transparent) => {
+                    cell.set_fg(upper).set_char('▀');
+                }
+                (Half::Transparent, Half::Transparent) => {
+                    cell.set_char(' ');
+                }
+            }
         }
     }
-    fn area(&self) -> Rect {
-        self.area
-    }
 }
 
 #[derive(Clone)]
@@ -86,6 +522,8 @@ pub struct StatefulHalfblocks {
     font_size: FontSize,
     current: Halfblocks,
     hash: u64,
+    quantizer: HalfblocksQuantizer,
+    shared: Option<SharedEncodings>,
 }
 
 impl StatefulHalfblocks {
@@ -95,8 +533,22 @@ impl StatefulHalfblocks {
             font_size,
             current: Halfblocks::default(),
             hash: u64::default(),
+            quantizer: HalfblocksQuantizer::default(),
+            shared: None,
         }
     }
+
+    /// Reuse equal encodings of other protocols, see [SharedEncodings].
+    pub fn shared(mut self, shared: Option<SharedEncodings>) -> StatefulHalfblocks {
+        self.shared = shared;
+        self
+    }
+
+    /// Map colors with a [HalfblocksQuantizer] when encoding.
+    pub fn quantizer(mut self, quantizer: HalfblocksQuantizer) -> StatefulHalfblocks {
+        self.quantizer = quantizer;
+        self
+    }
 }
 impl ProtocolTrait for StatefulHalfblocks {
     fn render(&mut self, area: Rect, buf: &mut Buffer) {
@@ -109,6 +561,26 @@ impl ProtocolTrait for StatefulHalfblocks {
 }
 
 impl StatefulProtocolTrait for StatefulHalfblocks {
+    fn font_size(&self) -> FontSize {
+        self.font_size
+    }
+    fn set_font_size(&mut self, font_size: FontSize) {
+        self.font_size = font_size;
+        // Encoded for the previous font size.
+        self.hash = u64::default();
+    }
+    fn set_dither(&mut self, dither: bool) {
+        self.quantizer.dither = dither;
+    }
+    fn dither(&self) -> bool {
+        self.quantizer.dither
+    }
+    fn source(&self) -> &ImageSource {
+        &self.source
+    }
+    fn source_mut(&mut self) -> &mut ImageSource {
+        &mut self.source
+    }
     fn background_color(&self) -> Rgba<u8> {
         self.source.background_color
     }
@@ -121,15 +593,206 @@ impl StatefulProtocolTrait for StatefulHalfblocks {
             self.source.hash != self.hash,
         )
     }
-    fn resize_encode(&mut self, resize: &Resize, background_color: Rgba<u8>, area: Rect) {
+    fn resize_encode(
+        &mut self,
+        resize: &Resize,
+        background_color: Rgba<u8>,
+        area: Rect,
+    ) -> Result<()> {
         if area.width == 0 || area.height == 0 {
-            return;
+            return Ok(());
         }
 
-        let img = resize.resize(&self.source, self.font_size, area, background_color);
-        let data = encode(&img, area);
-        let current = Halfblocks { data, area };
-        self.current = current;
+        let key = (
+            "halfblocks",
+            self.source.hash,
+            self.font_size,
+            resize,
+            background_color,
+            area,
+            &self.quantizer,
+        );
+        let data = encode_shared(self.shared.as_ref(), key, || {
+            let img = resize.resize(&self.source, self.font_size, area, background_color);
+            Ok(encode(&img, area, &self.quantizer))
+        })?;
+        self.current = Halfblocks { data, area };
         self.hash = self.source.hash;
+        Ok(())
+    }
+    fn render_placement(&self, area: Rect, buf: &mut Buffer) {
+        self.current.render_cells(area, buf);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ImageBuffer, Rgb, Rgba};
+    use ratatui::{
+        buffer::Buffer,
+        layout::Rect,
+        style::{Color, Style},
+    };
+
+    use super::{Halfblocks, HalfblocksQuantizer, HalfblocksSymbols, Palette};
+    use crate::picker::ColorDepth;
+    use crate::protocol::ProtocolTrait;
+
+    #[test]
+    fn test_quantize_ansi16() {
+        let img = ImageBuffer::from_fn(2, 1, |x, _| {
+            if x == 0 {
+                Rgb([250, 10, 10])
+            } else {
+                Rgb([20, 20, 20])
+            }
+        });
+        let colors = HalfblocksQuantizer::new()
+            .palette(Palette::Ansi16)
+            .quantize(&img);
+        assert_eq!(colors, vec![Color::Indexed(9), Color::Indexed(0)]);
+
+        let colors = HalfblocksQuantizer::new().quantize(&img);
+        assert_eq!(
+            colors,
+            vec![Color::Rgb(250, 10, 10), Color::Rgb(20, 20, 20)]
+        );
+    }
+
+    #[test]
+    fn test_quantize_ansi256() {
+        let img = ImageBuffer::from_fn(3, 1, |x, _| match x {
+            0 => Rgb([250, 10, 10]),
+            1 => Rgb([95, 135, 175]),
+            _ => Rgb([20, 20, 20]),
+        });
+        let colors = HalfblocksQuantizer::new()
+            .palette(Palette::Ansi256)
+            .quantize(&img);
+        assert_eq!(
+            colors,
+            vec![Color::Indexed(196), Color::Indexed(67), Color::Indexed(233)]
+        );
+
+        // An explicit palette wins over the color depth.
+        let quantizer = HalfblocksQuantizer::new().or_color_depth(ColorDepth::Ansi16);
+        assert_eq!(quantizer.quantize(&img)[0], Color::Indexed(9));
+        let quantizer = HalfblocksQuantizer::new()
+            .palette(Palette::Ansi256)
+            .or_color_depth(ColorDepth::Ansi16);
+        assert_eq!(quantizer.quantize(&img)[0], Color::Indexed(196));
+        let quantizer = HalfblocksQuantizer::new().or_color_depth(ColorDepth::TrueColor);
+        assert_eq!(quantizer.quantize(&img)[0], Color::Rgb(250, 10, 10));
+    }
+
+    #[test]
+    fn test_quantize_dither_mixes_colors() {
+        // Mid-gray between black and white should dither to a mix of both.
+        let img = ImageBuffer::from_pixel(8, 8, Rgb([128, 128, 128]));
+        let quantizer =
+            HalfblocksQuantizer::new().palette(Palette::Custom(vec![[0, 0, 0], [255, 255, 255]]));
+        let flat = quantizer.quantize(&img);
+        assert!(flat.iter().all(|c| *c == flat[0]));
+
+        let dithered = quantizer.dither(true).quantize(&img);
+        let white = dithered
+            .iter()
+            .filter(|c| **c == Color::Rgb(255, 255, 255))
+            .count();
+        assert!((24..=40).contains(&white), "{white}");
+    }
+
+    #[test]
+    fn test_blend_with_buffer_background() {
+        // One column, four pixel rows: transparent, opaque red, half-transparent red, transparent.
+        let img = ImageBuffer::from_fn(1, 4, |_, y| match y {
+            0 | 3 => Rgba::<u8>([0, 0, 0, 0]),
+            1 => Rgba([255, 0, 0, 255]),
+            _ => Rgba([255, 0, 0, 128]),
+        });
+        let mut halfblocks = Halfblocks::new(img.into(), Rect::new(0, 0, 1, 2)).unwrap();
+        let mut buf = Buffer::empty(Rect::new(0, 0, 2, 2));
+        buf.set_style(
+            Rect::new(0, 0, 1, 2),
+            Style::new().bg(Color::Rgb(0, 0, 255)),
+        );
+        halfblocks.render(Rect::new(0, 0, 1, 2), &mut buf);
+        assert_eq!(buf[(0, 0)].symbol(), "▀");
+        assert_eq!(buf[(0, 0)].fg, Color::Rgb(0, 0, 255));
+        assert_eq!(buf[(0, 0)].bg, Color::Rgb(255, 0, 0));
+        assert_eq!(buf[(0, 1)].fg, Color::Rgb(128, 0, 127));
+        assert_eq!(buf[(0, 1)].bg, Color::Rgb(0, 0, 255));
+
+        // Without an RGB background, transparent halves keep the cell's background.
+        let mut buf = Buffer::empty(Rect::new(0, 0, 2, 2));
+        halfblocks.render(Rect::new(1, 0, 1, 2), &mut buf);
+        assert_eq!(buf[(1, 0)].symbol(), "▄");
+        assert_eq!(buf[(1, 0)].fg, Color::Rgb(255, 0, 0));
+        assert_eq!(buf[(1, 0)].bg, Color::Reset);
+        assert_eq!(buf[(1, 1)].symbol(), "▀");
+        assert_eq!(buf[(1, 1)].fg, Color::Rgb(255, 0, 0));
+    }
+
+    #[test]
+    fn test_quadrants() {
+        // A white diagonal on black, with a transparent pixel in the second cell.
+        let img = ImageBuffer::from_fn(4, 2, |x, y| match (x, y) {
+            (3, 1) => Rgba::<u8>([0, 0, 0, 0]),
+            (0, 0) | (1, 1) | (2, 0) => Rgba([255, 255, 255, 255]),
+            _ => Rgba([0, 0, 0, 255]),
+        });
+        let quantizer = HalfblocksQuantizer::new().symbols(HalfblocksSymbols::Quadrants);
+        let mut halfblocks =
+            Halfblocks::with_quantizer(img.into(), Rect::new(0, 0, 2, 1), &quantizer).unwrap();
+        let mut buf = Buffer::empty(Rect::new(0, 0, 2, 1));
+        halfblocks.render(Rect::new(0, 0, 2, 1), &mut buf);
+        assert_eq!(buf[(0, 0)].symbol(), "▚");
+        assert_eq!(buf[(0, 0)].fg, Color::Rgb(255, 255, 255));
+        assert_eq!(buf[(0, 0)].bg, Color::Rgb(0, 0, 0));
+        // The transparent pixel keeps the cell's background.
+        assert_eq!(buf[(1, 0)].symbol(), "▘");
+        assert_eq!(buf[(1, 0)].bg, Color::Reset);
+    }
+
+    #[test]
+    fn test_fg_only() {
+        let img = ImageBuffer::from_fn(1, 2, |_, y| {
+            if y == 0 {
+                Rgba::<u8>([255, 0, 0, 255])
+            } else {
+                Rgba([0, 0, 0, 255])
+            }
+        });
+        let quantizer = HalfblocksQuantizer::new().fg_only(true);
+        let mut halfblocks =
+            Halfblocks::with_quantizer(img.into(), Rect::new(0, 0, 1, 1), &quantizer).unwrap();
+        let mut buf = Buffer::empty(Rect::new(0, 0, 1, 1));
+        buf.set_style(buf.area, Style::new().bg(Color::Rgb(0, 0, 255)));
+        halfblocks.render(Rect::new(0, 0, 1, 1), &mut buf);
+        assert_eq!(buf[(0, 0)].symbol(), "▀");
+        assert_eq!(buf[(0, 0)].fg, Color::Rgb(255, 0, 0));
+        assert_eq!(buf[(0, 0)].bg, Color::Rgb(0, 0, 255));
+    }
+
+    #[test]
+    fn test_ascii() {
+        let img = ImageBuffer::from_fn(3, 1, |x, _| match x {
+            0 => Rgba::<u8>([0, 0, 0, 255]),
+            1 => Rgba([128, 128, 128, 255]),
+            _ => Rgba([255, 255, 255, 255]),
+        });
+        let quantizer = HalfblocksQuantizer::new()
+            .symbols(HalfblocksSymbols::Ascii)
+            .palette(Palette::Ansi16)
+            .dither(true);
+        let mut halfblocks =
+            Halfblocks::with_quantizer(img.into(), Rect::new(0, 0, 3, 1), &quantizer).unwrap();
+        let mut buf = Buffer::empty(Rect::new(0, 0, 3, 1));
+        halfblocks.render(Rect::new(0, 0, 3, 1), &mut buf);
+        assert_eq!(buf[(0, 0)].symbol(), " ");
+        assert_ne!(buf[(1, 0)].symbol(), " ");
+        assert_eq!(buf[(2, 0)].symbol(), "@");
+        assert_eq!(buf[(2, 0)].fg, Color::Indexed(15));
+        assert_eq!(buf[(2, 0)].bg, Color::Reset);
     }
 }
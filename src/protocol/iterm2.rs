@@ -1,66 +1,134 @@
 //! ITerm2 protocol implementation.
 use base64::{engine::general_purpose, Engine};
-use image::{DynamicImage, Rgba};
+use image::{codecs::jpeg::JpegEncoder, DynamicImage, ImageFormat, Rgba};
 use ratatui::{buffer::Buffer, layout::Rect};
-use std::{cmp::min, format, io::Cursor};
+use std::{cmp::min, format, io::Cursor, sync::Arc};
 
 use crate::{errors, picker::cap_parser::Parser, FontSize, ImageSource, Resize, Result};
 
-use super::{ProtocolTrait, StatefulProtocolTrait};
+use super::{
+    encode_shared, erase_sequence, erase_uncovered, protect_cells, CellProtection, ProtocolTrait,
+    SharedEncodings, StatefulProtocolTrait,
+};
+
+/// The image format of the payload.
+///
+/// PNG is lossless and keeps transparency, but is slow to encode and large for photos. JPEG cuts
+/// both by an order of magnitude for photos, at the cost of transparency, which becomes black.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Iterm2Format {
+    #[default]
+    Png,
+    /// With a quality within `1..=100`.
+    Jpeg(u8),
+    /// Lossless WebP, which is smaller than PNG. Needs the `webp` feature of the image crate,
+    /// e.g. through the default `image-defaults` feature, otherwise encoding fails.
+    WebP,
+}
 
 #[derive(Clone, Default)]
 pub struct Iterm2 {
     pub data: String,
     pub area: Rect,
     pub is_tmux: bool,
+    /// See [Iterm2::cell_protection].
+    pub cell_protection: CellProtection,
 }
 
 impl Iterm2 {
     pub fn new(image: DynamicImage, area: Rect, is_tmux: bool) -> Result<Self> {
-        let data = encode(&image, area, is_tmux)?;
+        Self::with_format(image, area, is_tmux, Iterm2Format::default())
+    }
+
+    /// Create an Iterm2 with the payload in another [Iterm2Format].
+    pub fn with_format(
+        image: DynamicImage,
+        area: Rect,
+        is_tmux: bool,
+        format: Iterm2Format,
+    ) -> Result<Self> {
+        let data = encode(&image, area, is_tmux, format)?;
         Ok(Self {
             data,
             area,
             is_tmux,
+            cell_protection: CellProtection::default(),
         })
     }
+
+    // Transmit an image file as it is, instead of decoding and encoding it again.
+    pub(crate) fn from_file(file: &[u8], size: (u32, u32), area: Rect, is_tmux: bool) -> Self {
+        Self {
+            data: sequence(file, size, area, is_tmux),
+            area,
+            is_tmux,
+            cell_protection: CellProtection::default(),
+        }
+    }
+
+    /// How the cells covered by the image are protected, see [CellProtection].
+    pub fn cell_protection(mut self, cell_protection: CellProtection) -> Self {
+        self.cell_protection = cell_protection;
+        self
+    }
 }
 
-fn encode(img: &DynamicImage, render_area: Rect, is_tmux: bool) -> Result<String> {
-    let mut png: Vec<u8> = vec![];
-    img.write_to(&mut Cursor::new(&mut png), image::ImageFormat::Png)?;
+fn encode(
+    img: &DynamicImage,
+    render_area: Rect,
+    is_tmux: bool,
+    format: Iterm2Format,
+) -> Result<String> {
+    let mut file: Vec<u8> = vec![];
+    match format {
+        Iterm2Format::Png => img.write_to(&mut Cursor::new(&mut file), ImageFormat::Png)?,
+        Iterm2Format::Jpeg(quality) => {
+            let encoder = JpegEncoder::new_with_quality(&mut file, quality.clamp(1, 100));
+            img.to_rgb8().write_with_encoder(encoder)?;
+        }
+        Iterm2Format::WebP => img.write_to(&mut Cursor::new(&mut file), ImageFormat::WebP)?,
+    }
+    Ok::<String, errors::Errors>(sequence(
+        &file,
+        (img.width(), img.height()),
+        render_area,
+        is_tmux,
+    ))
+}
 
-    let data = general_purpose::STANDARD.encode(&png);
+fn sequence(file: &[u8], (width, height): (u32, u32), render_area: Rect, is_tmux: bool) -> String {
+    let data = general_purpose::STANDARD.encode(file);
 
     let (start, escape, end) = Parser::escape_tmux(is_tmux);
 
     // Transparency needs explicit erasing of stale characters, or they stay behind the rendered
     // image due to skipping of the following characters _in the buffer_.
-    // DECERA does not work in WezTerm, however ECH and and cursor CUD and CUU do.
     // For each line, erase `width` characters, then move back and place image.
-    let width = render_area.width;
-    let height = render_area.height;
     let mut seq = String::from(start);
-    for _ in 0..height {
-        seq.push_str(&format!("{escape}[{width}X{escape}[1B").to_string());
-    }
-    seq.push_str(&format!("{escape}[{height}A").to_string());
+    seq.push_str(&erase_sequence(
+        escape,
+        render_area.width,
+        render_area.height,
+    ));
 
     seq.push_str(&format!(
-        "{escape}]1337;File=inline=1;size={};width={}px;height={}px;doNotMoveCursor=1:{}\x07",
-        png.len(),
-        img.width(),
-        img.height(),
-        data,
+        "{escape}]1337;File=inline=1;size={};width={width}px;height={height}px;doNotMoveCursor=1:{data}\x07",
+        file.len(),
     ));
     seq.push_str(end);
-
-    Ok::<String, errors::Errors>(seq)
+    seq
 }
 
 impl ProtocolTrait for Iterm2 {
     fn render(&mut self, area: Rect, buf: &mut Buffer) {
-        render(self.area, &self.data, area, buf, false)
+        render(
+            self.area,
+            &self.data,
+            area,
+            buf,
+            false,
+            self.cell_protection,
+        );
     }
 
     fn area(&self) -> Rect {
@@ -68,7 +136,14 @@ impl ProtocolTrait for Iterm2 {
     }
 }
 
-fn render(rect: Rect, data: &str, area: Rect, buf: &mut Buffer, overdraw: bool) {
+pub(super) fn render(
+    rect: Rect,
+    data: &str,
+    area: Rect,
+    buf: &mut Buffer,
+    overdraw: bool,
+    cell_protection: CellProtection,
+) -> Option<Rect> {
     let render_area = match render_area(rect, area, overdraw) {
         None => {
             // If we render out of area, then the buffer will attempt to write regular text (or
@@ -76,24 +151,14 @@ fn render(rect: Rect, data: &str, area: Rect, buf: &mut Buffer, overdraw: bool)
             //
             // Note that [StatefulProtocol] forces to ignore this early return, since it will
             // always resize itself to the area.
-            return;
+            return None;
         }
         Some(r) => r,
     };
 
     buf.cell_mut(render_area).map(|cell| cell.set_symbol(data));
-    let mut skip_first = false;
-
-    // Skip entire area
-    for y in render_area.top()..render_area.bottom() {
-        for x in render_area.left()..render_area.right() {
-            if !skip_first {
-                skip_first = true;
-                continue;
-            }
-            buf.cell_mut((x, y)).map(|cell| cell.set_skip(true));
-        }
-    }
+    protect_cells(render_area, buf, cell_protection);
+    Some(render_area)
 }
 
 fn render_area(rect: Rect, area: Rect, overdraw: bool) -> Option<Rect> {
@@ -116,8 +181,15 @@ fn render_area(rect: Rect, area: Rect, overdraw: bool) -> Option<Rect> {
 pub struct StatefulIterm2 {
     source: ImageSource,
     font_size: FontSize,
-    current: Iterm2,
+    data: Arc<String>,
+    area: Rect,
+    is_tmux: bool,
+    cell_protection: CellProtection,
+    format: Iterm2Format,
     hash: u64,
+    /// Where the image was last rendered, to erase leftovers when it shrinks or moves.
+    last_render_area: Option<Rect>,
+    shared: Option<SharedEncodings>,
 }
 
 impl StatefulIterm2 {
@@ -125,26 +197,67 @@ impl StatefulIterm2 {
         StatefulIterm2 {
             source,
             font_size,
-            current: Iterm2 {
-                is_tmux,
-                ..Iterm2::default()
-            },
+            data: Arc::default(),
+            area: Rect::default(),
+            is_tmux,
+            cell_protection: CellProtection::default(),
+            format: Iterm2Format::default(),
             hash: u64::default(),
+            last_render_area: None,
+            shared: None,
         }
     }
+
+    /// Reuse equal encodings of other protocols, see [SharedEncodings].
+    pub fn shared(mut self, shared: Option<SharedEncodings>) -> StatefulIterm2 {
+        self.shared = shared;
+        self
+    }
+
+    /// See [Iterm2::cell_protection].
+    pub fn cell_protection(mut self, cell_protection: CellProtection) -> StatefulIterm2 {
+        self.cell_protection = cell_protection;
+        self
+    }
+
+    /// Encode the payload in another [Iterm2Format].
+    pub fn format(mut self, format: Iterm2Format) -> StatefulIterm2 {
+        self.format = format;
+        self
+    }
 }
 
 impl ProtocolTrait for StatefulIterm2 {
     fn render(&mut self, area: Rect, buf: &mut Buffer) {
-        render(self.current.area, &self.current.data, area, buf, true);
+        let render_area = render(self.area, &self.data, area, buf, true, self.cell_protection);
+        if let Some(previous) = self.last_render_area {
+            if render_area != Some(previous) {
+                erase_uncovered(previous, render_area.unwrap_or_default(), buf);
+            }
+        }
+        self.last_render_area = render_area;
     }
 
     fn area(&self) -> Rect {
-        self.current.area
+        self.area
     }
 }
 
 impl StatefulProtocolTrait for StatefulIterm2 {
+    fn font_size(&self) -> FontSize {
+        self.font_size
+    }
+    fn set_font_size(&mut self, font_size: FontSize) {
+        self.font_size = font_size;
+        // Encoded for the previous font size.
+        self.hash = u64::default();
+    }
+    fn source(&self) -> &ImageSource {
+        &self.source
+    }
+    fn source_mut(&mut self) -> &mut ImageSource {
+        &mut self.source
+    }
     fn background_color(&self) -> Rgba<u8> {
         self.source.background_color
     }
@@ -152,30 +265,72 @@ impl StatefulProtocolTrait for StatefulIterm2 {
         resize.needs_resize(
             &self.source,
             self.font_size,
-            self.current.area,
+            self.area,
             area,
             self.source.hash != self.hash,
         )
     }
-    fn resize_encode(&mut self, resize: &Resize, background_color: Rgba<u8>, area: Rect) {
+    fn resize_encode(
+        &mut self,
+        resize: &Resize,
+        background_color: Rgba<u8>,
+        area: Rect,
+    ) -> Result<()> {
         if area.width == 0 || area.height == 0 {
-            return;
+            return Ok(());
         }
 
-        let img = resize.resize(&self.source, self.font_size, area, background_color);
-        let is_tmux = self.current.is_tmux;
-        match encode(&img, area, is_tmux) {
-            Ok(data) => {
-                self.current = Iterm2 {
-                    data,
-                    area,
-                    is_tmux,
-                };
-                self.hash = self.source.hash;
-            }
-            Err(_err) => {
-                // TODO: save err in struct and expose in trait?
-            }
+        let (is_tmux, format) = (self.is_tmux, self.format);
+        let key = (
+            "iterm2",
+            self.source.hash,
+            self.font_size,
+            resize,
+            background_color,
+            area,
+            is_tmux,
+            format,
+        );
+        self.data = encode_shared(self.shared.as_ref(), key, || {
+            let img = resize.resize(&self.source, self.font_size, area, background_color);
+            encode(&img, area, is_tmux, format)
+        })?;
+        self.area = area;
+        self.hash = self.source.hash;
+        Ok(())
+    }
+    fn render_placement(&self, area: Rect, buf: &mut Buffer) {
+        render(self.area, &self.data, area, buf, true, self.cell_protection);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, ImageBuffer, Rgba};
+    use ratatui::layout::Rect;
+
+    use super::{Iterm2, Iterm2Format};
+
+    #[test]
+    fn test_format() {
+        let image: DynamicImage = ImageBuffer::from_fn(40, 40, |x, y| {
+            Rgba::<u8>([(x * 6) as u8, (y * 6) as u8, 128, 255])
+        })
+        .into();
+        let area = Rect::new(0, 0, 4, 2);
+        let payload = |format| {
+            let data = Iterm2::with_format(image.clone(), area, false, format)
+                .unwrap()
+                .data;
+            data.split_once(':').unwrap().1.to_string()
+        };
+        // The base64 of the magic bytes.
+        assert!(payload(Iterm2Format::Png).starts_with("iVBORw0KGgo"));
+        let jpeg = payload(Iterm2Format::Jpeg(80));
+        assert!(jpeg.starts_with("/9j/"));
+        assert!(payload(Iterm2Format::Jpeg(10)).len() < jpeg.len());
+        if cfg!(feature = "image-defaults") {
+            assert!(payload(Iterm2Format::WebP).starts_with("UklGR"));
         }
     }
 }
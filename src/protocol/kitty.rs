@@ -1,61 +1,631 @@
 /// https://sw.kovidgoyal.net/kitty/graphics-protocol/#unicode-placeholders
-use std::fmt::Write;
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, VecDeque},
+    env,
+    fmt::Write,
+    io::{Cursor, Write as _},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, MutexGuard, OnceLock, PoisonError,
+    },
+};
 
 use base64::{engine::general_purpose, Engine};
-use image::{DynamicImage, Rgba};
+use flate2::{write::ZlibEncoder, Compression};
+use image::{imageops, DynamicImage, ImageFormat, Rgba, RgbaImage};
 use ratatui::{buffer::Buffer, layout::Rect};
+use unicode_width::UnicodeWidthStr;
 
-use crate::{picker::cap_parser::Parser, FontSize, ImageSource, Resize, Result};
+use crate::{
+    picker::cap_parser::{Capability, Parser},
+    FontSize, ImageSource, Resize, Result,
+};
 
 use super::{ProtocolTrait, StatefulProtocolTrait};
 
 #[derive(Default, Clone, PartialEq)]
-enum KittyProtoState {
+pub(crate) enum KittyProtoState {
     #[default]
     Place,
-    TransmitAndPlace(String),
+    // The remaining chunks, without tmux passthrough wrapping.
+    TransmitAndPlace(VecDeque<String>),
 }
 
 impl KittyProtoState {
-    // Produce the transmit sequence or None if it has already been produced before.
-    fn make_transmit(&mut self) -> Option<String> {
+    // Produce the (next part of the) transmit sequence, or None if it has been produced before.
+    pub(crate) fn make_transmit(
+        &mut self,
+        is_tmux: bool,
+        options: &KittyOptions,
+    ) -> Option<String> {
+        let KittyProtoState::TransmitAndPlace(chunks) = self else {
+            return None;
+        };
+        let count = options
+            .chunks_per_render
+            .unwrap_or(chunks.len())
+            .min(chunks.len());
+        let (start, _, end) = Parser::escape_tmux(is_tmux);
+        let mut seq = String::new();
+        if options.delete_on_drop {
+            // Free the images of dropped protocols before adding another one.
+            seq = crate::cleanup::take_dropped_sequence();
+        }
+        seq.push_str(start);
+        for chunk in chunks.drain(..count) {
+            seq.push_str(&chunk);
+        }
+        seq.push_str(end);
+        if chunks.is_empty() {
+            *self = KittyProtoState::Place;
+        }
+        Some(seq)
+    }
+}
+
+/// How the image data is transmitted and placed.
+///
+/// Some ssh and tmux combinations drop data when many chunks arrive back-to-back. Smaller chunks,
+/// and spreading the chunks over several renders (each frame is written and flushed by ratatui
+/// separately), can make the transmission more reliable over flaky transports. Slow transports
+/// benefit from [KittyFormat::Png] or [KittyOptions::compression], and terminals on the same
+/// machine from [KittyOptions::shared_memory].
+#[derive(PartialEq, Clone, Debug, Copy)]
+pub struct KittyOptions {
+    chunk_size: usize,
+    chunks_per_render: Option<usize>,
+    acknowledge: bool,
+    format: KittyFormat,
+    compression: bool,
+    shared_memory: bool,
+    delete_on_drop: bool,
+    z_index: i32,
+    placement: KittyPlacement,
+    rescale_tolerance: Option<f32>,
+    diff_updates: bool,
+}
+
+#[deprecated(note = "renamed to KittyOptions")]
+pub type KittyTransmitOptions = KittyOptions;
+
+impl Default for KittyOptions {
+    fn default() -> Self {
+        KittyOptions {
+            chunk_size: 4096,
+            chunks_per_render: None,
+            acknowledge: false,
+            format: KittyFormat::default(),
+            compression: false,
+            shared_memory: false,
+            delete_on_drop: false,
+            z_index: 0,
+            placement: KittyPlacement::default(),
+            rescale_tolerance: None,
+            diff_updates: false,
+        }
+    }
+}
+
+/// The pixel format of the transmitted image data.
+#[derive(PartialEq, Clone, Debug, Copy, Default)]
+pub enum KittyFormat {
+    /// Raw RGBA pixels (`f=32`), fast to encode but large.
+    #[default]
+    Rgba,
+    /// PNG (`f=100`), slower to encode but usually much smaller. Falls back to RGBA if the
+    /// image could not be encoded, e.g. when the `png` feature of `image` is not enabled.
+    Png,
+    /// PNG if the terminal is probably on another machine, i.e. `SSH_CONNECTION` or `SSH_TTY`
+    /// is set, where the bytes over the wire matter more than the encoding time. RGBA otherwise.
+    Auto,
+}
+
+impl KittyFormat {
+    // Resolve Auto to a concrete format.
+    fn resolve(self, remote: bool) -> KittyFormat {
         match self {
-            KittyProtoState::TransmitAndPlace(seq) => {
-                let seq = std::mem::take(seq);
-                *self = KittyProtoState::Place;
-                Some(seq)
-            }
-            KittyProtoState::Place => None,
+            KittyFormat::Auto if remote => KittyFormat::Png,
+            KittyFormat::Auto => KittyFormat::Rgba,
+            format => format,
+        }
+    }
+}
+
+// Whether the program runs over ssh, read from the environment once.
+fn is_remote() -> bool {
+    static REMOTE: OnceLock<bool> = OnceLock::new();
+    *REMOTE.get_or_init(|| {
+        ["SSH_CONNECTION", "SSH_TTY"]
+            .iter()
+            .any(|var| env::var_os(var).is_some_and(|value| !value.is_empty()))
+    })
+}
+
+/// How the image is placed on the screen.
+#[derive(PartialEq, Clone, Debug, Copy, Default)]
+pub enum KittyPlacement {
+    /// A virtual placement (`U=1`) shown by unicode placeholders, which are regular text: they
+    /// are cut off, scrolled and cleared like any other text.
+    #[default]
+    Virtual,
+    /// A classic placement at the cursor, for terminals without unicode placeholders. It is
+    /// only rendered if it fits into the area, it is moved when rendered somewhere else, and it
+    /// stays on the screen until deleted, e.g. by [crate::cleanup].
+    Classic,
+}
+
+impl KittyOptions {
+    pub fn new() -> KittyOptions {
+        KittyOptions::default()
+    }
+
+    /// Size of each chunk of base64 data, rounded down to a multiple of 4 within `4..=4096`.
+    pub fn chunk_size(mut self, chunk_size: usize) -> KittyOptions {
+        self.chunk_size = chunk_size.clamp(4, 4096) / 4 * 4;
+        self
+    }
+
+    /// Transmit at most this many chunks per render, or all at once with `None` (the default).
+    ///
+    /// The image appears once the last chunk has been transmitted.
+    pub fn chunks_per_render(mut self, chunks_per_render: Option<usize>) -> KittyOptions {
+        self.chunks_per_render = chunks_per_render.map(|n| n.max(1));
+        self
+    }
+
+    /// Ask the terminal to respond to each transmission (`q=0` instead of `q=2`).
+    ///
+    /// The responses arrive on the terminal's input, where the application must read them and
+    /// pass them to [KittyAck::parse] and [crate::protocol::StatefulProtocol::handle_kitty_ack].
+    pub fn acknowledge(mut self, acknowledge: bool) -> KittyOptions {
+        self.acknowledge = acknowledge;
+        self
+    }
+
+    /// The pixel format of the image data (default [KittyFormat::Rgba]).
+    pub fn format(mut self, format: KittyFormat) -> KittyOptions {
+        self.format = format;
+        self
+    }
+
+    /// Compress the image data with zlib (`o=z`) before the base64 encoding.
+    pub fn compression(mut self, compression: bool) -> KittyOptions {
+        self.compression = compression;
+        self
+    }
+
+    /// Transmit the image data through a POSIX shared memory object (`t=s`) instead of base64
+    /// chunks over the terminal, which the terminal reads and unlinks.
+    ///
+    /// Only works if the terminal runs on the same machine, e.g. not over ssh. Falls back to
+    /// direct transmission (`t=d`) if the object could not be created, and on windows.
+    pub fn shared_memory(mut self, shared_memory: bool) -> KittyOptions {
+        self.shared_memory = shared_memory;
+        self
+    }
+
+    /// Delete the image from the terminal's memory when the last clone of the protocol is
+    /// dropped, for long-running apps that cycle through many images.
+    ///
+    /// The deletion is sent along with the next transmission of a protocol with this option, or
+    /// by [crate::cleanup::take_dropped_sequence].
+    pub fn delete_on_drop(mut self, delete_on_drop: bool) -> KittyOptions {
+        self.delete_on_drop = delete_on_drop;
+        self
+    }
+
+    /// The z-index of the placement (default 0). Negative values are drawn below text.
+    pub fn z_index(mut self, z_index: i32) -> KittyOptions {
+        self.z_index = z_index;
+        self
+    }
+
+    /// Virtual or classic placement (default [KittyPlacement::Virtual]).
+    pub fn placement(mut self, placement: KittyPlacement) -> KittyOptions {
+        self.placement = placement;
+        self
+    }
+
+    /// When the area changes by at most this fraction of the transmitted columns and rows, e.g.
+    /// `Some(0.25)`, keep the transmitted image and only place it again scaled to the new size,
+    /// instead of encoding and transmitting it again (default `None`, always transmit).
+    ///
+    /// The scaled image is blurrier and may be slightly distorted, in exchange for not sending the
+    /// image data on every small resize, e.g. while dragging a split.
+    pub fn rescale_tolerance(mut self, rescale_tolerance: Option<f32>) -> KittyOptions {
+        self.rescale_tolerance = rescale_tolerance.map(|tolerance| tolerance.max(0.0));
+        self
+    }
+
+    /// When the image is replaced with one of the same size, e.g. by
+    /// [crate::protocol::StatefulProtocol::set_image], only transmit the bounding box of the
+    /// pixels that changed, as an edit of the transmitted image (`a=f`), instead of the whole image
+    /// (default false).
+    ///
+    /// Keeps a copy of the transmitted pixels to compare against. Meant for dashboards, whose
+    /// charts change only in a small part between frames.
+    pub fn diff_updates(mut self, diff_updates: bool) -> KittyOptions {
+        self.diff_updates = diff_updates;
+        self
+    }
+
+    // The keys of the placement that is created along with the transmission.
+    fn placement_keys(&self) -> String {
+        let mut keys = String::from(match self.placement {
+            KittyPlacement::Virtual => "U=1",
+            KittyPlacement::Classic => "p=1,C=1",
+        });
+        if self.z_index != 0 {
+            write!(keys, ",z={}", self.z_index).unwrap();
+        }
+        keys
+    }
+}
+
+/// A response of the terminal to a Kitty transmission, see [KittyOptions::acknowledge].
+#[derive(PartialEq, Clone, Debug)]
+pub struct KittyAck {
+    /// The image id.
+    pub id: u32,
+    /// The error message, e.g. `ENOMEM:...`, or `None` if the transmission succeeded.
+    pub error: Option<String>,
+}
+
+impl KittyAck {
+    /// Parse all Kitty responses out of some terminal input, ignoring anything else.
+    pub fn parse(input: &str) -> Vec<KittyAck> {
+        let mut parser = Parser::new();
+        input
+            .chars()
+            .flat_map(|ch| parser.push(ch))
+            .filter_map(|capability| match capability {
+                Capability::KittyAck { id, error } => Some(KittyAck { id, error }),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+/// A placement of a transmitted image, positioned relative to a placement of another image.
+///
+/// The images of this crate are placed with unicode placeholders, which are regular text cells
+/// and scroll along with the scrolling region like any other text. A placement whose parent is
+/// such a virtual placement is positioned relative to the placeholder cells, so it tracks the
+/// scrolled content too, where the terminal supports it (Kitty 0.31 and later). The application
+/// writes [KittyRelativePlacement::sequence] to the terminal itself.
+#[derive(PartialEq, Clone, Debug, Copy)]
+pub struct KittyRelativePlacement {
+    id: u32,
+    placement_id: u32,
+    parent_id: u32,
+    parent_placement_id: Option<u32>,
+    offset: (i32, i32),
+}
+
+impl KittyRelativePlacement {
+    /// Place the image `id` relative to the image `parent_id`, e.g. [StatefulKitty::unique_id].
+    pub fn new(id: u32, parent_id: u32) -> KittyRelativePlacement {
+        KittyRelativePlacement {
+            id,
+            placement_id: 1,
+            parent_id,
+            parent_placement_id: None,
+            offset: (0, 0),
+        }
+    }
+
+    /// The id of this placement, to replace or delete it later (default 1).
+    pub fn placement_id(mut self, placement_id: u32) -> KittyRelativePlacement {
+        self.placement_id = placement_id.max(1);
+        self
+    }
+
+    /// The placement of the parent image, if it has more than one.
+    pub fn parent_placement_id(mut self, parent_placement_id: u32) -> KittyRelativePlacement {
+        self.parent_placement_id = Some(parent_placement_id);
+        self
+    }
+
+    /// Offset in cells (columns, rows) from the top-left corner of the parent placement.
+    pub fn offset(mut self, columns: i32, rows: i32) -> KittyRelativePlacement {
+        self.offset = (columns, rows);
+        self
+    }
+
+    /// The escape sequence that creates (or moves) the placement.
+    pub fn sequence(&self, is_tmux: bool) -> String {
+        let (start, escape, end) = Parser::escape_tmux(is_tmux);
+        let (h, v) = self.offset;
+        let mut seq = String::from(start);
+        write!(
+            seq,
+            "{escape}_Gq=2,a=p,i={},p={},P={},H={h},V={v}",
+            self.id, self.placement_id, self.parent_id
+        )
+        .unwrap();
+        if let Some(parent_placement_id) = self.parent_placement_id {
+            write!(seq, ",Q={parent_placement_id}").unwrap();
+        }
+        write!(seq, "{escape}\\{end}").unwrap();
+        seq
+    }
+
+    /// The escape sequence that deletes the placement, but keeps the image.
+    pub fn delete_sequence(&self, is_tmux: bool) -> String {
+        let (start, escape, end) = Parser::escape_tmux(is_tmux);
+        format!(
+            "{start}{escape}_Gq=2,a=d,d=i,i={},p={}{escape}\\{end}",
+            self.id, self.placement_id
+        )
+    }
+}
+
+/// How the image id is encoded into the foreground color of the unicode placeholders.
+///
+/// Some terminals, multiplexers or recorders normalize SGR sequences, which can mangle the id
+/// and break the placement.
+#[derive(PartialEq, Clone, Debug, Copy, Default)]
+pub enum KittyIdColor {
+    /// 24-bit color `ESC[38;2;r;g;bm`, with the most significant byte in the third diacritic.
+    #[default]
+    Rgb,
+    /// The ITU T.416 colon form `ESC[38:2::r:g:bm`, for terminals that rewrite to it anyway.
+    RgbColon,
+    /// 256-color `ESC[38;5;nm`, with the most significant byte in the third diacritic. The id
+    /// must not use the two middle bytes, see [indexed_id], which [crate::picker::Picker] takes
+    /// care of.
+    Indexed,
+}
+
+impl KittyIdColor {
+    // The SGR sequence and the extra byte for the third diacritic.
+    fn encode(&self, id: u32) -> (String, u8) {
+        let [id_extra, id_r, id_g, id_b] = id.to_be_bytes();
+        match self {
+            KittyIdColor::Rgb => (format!("\x1b[38;2;{id_r};{id_g};{id_b}m"), id_extra),
+            KittyIdColor::RgbColon => (format!("\x1b[38:2::{id_r}:{id_g}:{id_b}m"), id_extra),
+            KittyIdColor::Indexed => (format!("\x1b[38;5;{id_b}m"), id_extra),
         }
     }
 }
 
+/// Map a 16-bit number to a Kitty image id for [KittyIdColor::Indexed].
+///
+/// Kitty reads the id of a 256-color placeholder as `(third_diacritic << 24) | color`, so the
+/// high byte of `n` goes to the most significant byte of the id. 0 is not a valid id and maps to 1.
+pub fn indexed_id(n: u16) -> u32 {
+    let [high, low] = n.to_be_bytes();
+    (((high as u32) << 24) | low as u32).max(1)
+}
+
 // Fixed Kitty protocol (transmits image data on every render!)
 #[derive(Clone, Default)]
 pub struct Kitty {
     proto_state: KittyProtoState,
     unique_id: u32,
     area: Rect,
+    id_color: KittyIdColor,
+    is_tmux: bool,
+    transmit_options: KittyOptions,
+    // Only held for its Drop.
+    _id_guard: Option<Arc<IdGuard>>,
+}
+
+// The ids of the live Kitty protocols of this process, with the number of protocols per id.
+// Clones of a protocol share one IdGuard.
+static LIVE_IDS: Mutex<BTreeMap<u32, usize>> = Mutex::new(BTreeMap::new());
+
+fn live_ids() -> MutexGuard<'static, BTreeMap<u32, usize>> {
+    LIVE_IDS.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Whether a Kitty image id is used by a protocol of this process, until the protocol and all its
+/// clones are dropped. The random ids of [crate::picker::Picker] are never ids that are in use.
+pub fn is_id_in_use(id: u32) -> bool {
+    live_ids().contains_key(&id)
+}
+
+// The first id from `next` that is not in use. Gives up after a while, e.g. when all ids of
+// KittyIdColor::Indexed are in use, and returns the next one anyway.
+pub(crate) fn allocate_id(mut next: impl FnMut() -> u32) -> u32 {
+    let live = live_ids();
+    for _ in 0..1024 {
+        let id = next();
+        if !live.contains_key(&id) {
+            return id;
+        }
+    }
+    next()
+}
+
+// Reserves the id of an image while its protocol or a clone of it is alive. When the last one is
+// dropped, the id is freed, and the image is deleted if KittyOptions::delete_on_drop is set.
+struct IdGuard {
+    id: u32,
+    is_tmux: bool,
+    delete_on_drop: AtomicBool,
+}
+
+impl IdGuard {
+    fn new(id: u32, is_tmux: bool, options: &KittyOptions) -> Arc<IdGuard> {
+        *live_ids().entry(id).or_default() += 1;
+        Arc::new(IdGuard {
+            id,
+            is_tmux,
+            delete_on_drop: AtomicBool::new(options.delete_on_drop),
+        })
+    }
+}
+
+impl Drop for IdGuard {
+    fn drop(&mut self) {
+        let mut live = live_ids();
+        if let Some(count) = live.get_mut(&self.id) {
+            *count -= 1;
+            if *count == 0 {
+                live.remove(&self.id);
+            }
+        }
+        drop(live);
+        if self.delete_on_drop.load(Ordering::Relaxed) {
+            crate::cleanup::queue_kitty_deletion(self.id, self.is_tmux);
+        }
+    }
 }
 
 impl Kitty {
     /// Create a FixedKitty from an image.
     pub fn new(image: DynamicImage, area: Rect, id: u32, is_tmux: bool) -> Result<Self> {
-        let proto_state = KittyProtoState::TransmitAndPlace(transmit_virtual(&image, id, is_tmux));
+        Self::with_transmit_options(image, area, id, is_tmux, KittyOptions::default())
+    }
+
+    /// Create a FixedKitty from an image, transmitting it with [KittyOptions].
+    pub fn with_transmit_options(
+        image: DynamicImage,
+        area: Rect,
+        id: u32,
+        is_tmux: bool,
+        transmit_options: KittyOptions,
+    ) -> Result<Self> {
+        // Without the font size, assume cells that just cover the image in the given area.
+        let (columns, rows) = (area.width.max(1) as u32, area.height.max(1) as u32);
+        let cell_size = (
+            image.width().div_ceil(columns) as u16,
+            image.height().div_ceil(rows) as u16,
+        );
+        Self::with_font_size(image, area, id, is_tmux, transmit_options, cell_size)
+    }
+
+    // Transmit a PNG file as it is, instead of decoding and encoding it again. The file must
+    // cover whole cells of the font size, because it cannot be padded.
+    pub(crate) fn from_png(
+        png: &[u8],
+        (width, height): (u32, u32),
+        area: Rect,
+        id: u32,
+        is_tmux: bool,
+        transmit_options: KittyOptions,
+        font_size: FontSize,
+    ) -> Self {
+        crate::cleanup::register_kitty(id, is_tmux);
+        let cells = placement_cells((width, height), font_size);
+        let chunks = transmit_bytes(
+            Cow::Borrowed(png),
+            100,
+            (width, height),
+            id,
+            is_tmux,
+            &transmit_options,
+            &transmit_options.placement_keys(),
+            cells,
+        );
+        Self {
+            proto_state: KittyProtoState::TransmitAndPlace(chunks),
+            unique_id: id,
+            area: Rect::new(area.x, area.y, cells.0, cells.1),
+            id_color: KittyIdColor::default(),
+            is_tmux,
+            transmit_options,
+            _id_guard: Some(IdGuard::new(id, is_tmux, &transmit_options)),
+        }
+    }
+
+    // Like with_transmit_options, but with the exact font size that the image was resized for.
+    pub(crate) fn with_font_size(
+        image: DynamicImage,
+        area: Rect,
+        id: u32,
+        is_tmux: bool,
+        transmit_options: KittyOptions,
+        font_size: FontSize,
+    ) -> Result<Self> {
+        let (chunks, cells) = transmit_virtual(&image, id, is_tmux, &transmit_options, font_size);
         Ok(Self {
-            proto_state,
+            proto_state: KittyProtoState::TransmitAndPlace(chunks),
             unique_id: id,
-            area,
+            area: Rect::new(area.x, area.y, cells.0, cells.1),
+            id_color: KittyIdColor::default(),
+            is_tmux,
+            transmit_options,
+            _id_guard: Some(IdGuard::new(id, is_tmux, &transmit_options)),
         })
     }
+
+    /// The escape sequence that deletes the image from the terminal's memory, after which it
+    /// cannot be rendered anymore.
+    pub fn delete_sequence(&self) -> String {
+        crate::cleanup::unregister_kitty(self.unique_id, self.is_tmux);
+        crate::cleanup::kitty_delete_sequence(self.unique_id, self.is_tmux)
+    }
+
+    /// The image id, e.g. for a [KittyRelativePlacement].
+    pub fn id(&self) -> u32 {
+        self.unique_id
+    }
+
+    // The whole transmission and the lines of placeholders, for printing into the scrollback.
+    pub(crate) fn print_sequence(&mut self) -> String {
+        let mut seq = String::new();
+        while let Some(part) = self
+            .proto_state
+            .make_transmit(self.is_tmux, &self.transmit_options)
+        {
+            seq.push_str(&part);
+        }
+        if self.transmit_options.placement == KittyPlacement::Classic {
+            // The transmission places the image at the cursor, which does not move.
+            if seq.is_empty() {
+                seq = classic_placement(
+                    self.unique_id,
+                    self.area,
+                    self.is_tmux,
+                    &self.transmit_options,
+                );
+            }
+            seq.push_str(&"\r\n".repeat(self.area.height as usize));
+            return seq;
+        }
+        let (id_color, id_extra) = self.id_color.encode(self.unique_id);
+        for y in 0..self.area.height {
+            seq.push_str(&id_color);
+            add_placeholder(&mut seq, 0, y, id_extra);
+            for _ in 1..self.area.width {
+                seq.push('\u{10EEEE}');
+            }
+            seq.push_str("\x1b[39m\r\n");
+        }
+        seq
+    }
+
+    /// Set how the id is encoded in the placeholders' foreground color.
+    pub fn id_color(mut self, id_color: KittyIdColor) -> Self {
+        self.id_color = id_color;
+        self
+    }
 }
 
 impl ProtocolTrait for Kitty {
     fn render(&mut self, area: Rect, buf: &mut Buffer) {
         // Transmit only once. This is why self is mut.
-        let seq = self.proto_state.make_transmit();
+        let seq = self
+            .proto_state
+            .make_transmit(self.is_tmux, &self.transmit_options);
 
-        render(area, self.area, buf, self.unique_id, seq);
+        if self.transmit_options.placement == KittyPlacement::Classic {
+            let options = &self.transmit_options;
+            render_classic(
+                area,
+                self.area,
+                buf,
+                self.unique_id,
+                self.is_tmux,
+                options,
+                seq,
+            );
+        } else {
+            render(area, self.area, buf, self.unique_id, self.id_color, seq);
+        }
     }
 
     fn area(&self) -> Rect {
@@ -72,6 +642,13 @@ pub struct StatefulKitty {
     hash: u64,
     proto_state: KittyProtoState,
     is_tmux: bool,
+    id_color: KittyIdColor,
+    transmit_options: KittyOptions,
+    id_guard: Arc<IdGuard>,
+    // The resize and area of the image that the terminal holds, see KittyOptions::rescale_tolerance.
+    transmitted: Option<(Resize, Rect)>,
+    // The transmitted pixels, padded to whole cells, see KittyOptions::diff_updates.
+    pixels: Option<Arc<RgbaImage>>,
 }
 
 impl StatefulKitty {
@@ -84,16 +661,154 @@ impl StatefulKitty {
             hash: u64::default(),
             proto_state: KittyProtoState::default(),
             is_tmux,
+            id_color: KittyIdColor::default(),
+            transmit_options: KittyOptions::default(),
+            id_guard: IdGuard::new(id, is_tmux, &KittyOptions::default()),
+            transmitted: None,
+            pixels: None,
+        }
+    }
+
+    /// See [Kitty::id_color].
+    pub fn id_color(mut self, id_color: KittyIdColor) -> StatefulKitty {
+        self.id_color = id_color;
+        self
+    }
+
+    /// See [KittyOptions].
+    pub fn transmit_options(mut self, transmit_options: KittyOptions) -> StatefulKitty {
+        self.transmit_options = transmit_options;
+        self.id_guard
+            .delete_on_drop
+            .store(transmit_options.delete_on_drop, Ordering::Relaxed);
+        self
+    }
+
+    /// See [KittyOptions::z_index]. A virtual placement that was transmitted already is placed
+    /// again on the next render, classic placements are moved with the new z-index anyway.
+    pub fn set_z_index(&mut self, z_index: i32) {
+        if self.transmit_options.z_index == z_index {
+            return;
+        }
+        self.transmit_options.z_index = z_index;
+        if self.transmit_options.placement == KittyPlacement::Classic || self.transmitted.is_none()
+        {
+            return;
+        }
+        let seq = self.replace_virtual_placement(self.rect);
+        match &mut self.proto_state {
+            // After the rest of the transmission, which still places it with the old z-index.
+            KittyProtoState::TransmitAndPlace(chunks) => chunks.push_back(seq),
+            KittyProtoState::Place => {
+                self.proto_state = KittyProtoState::TransmitAndPlace(VecDeque::from([seq]))
+            }
+        }
+    }
+
+    /// The escape sequence that deletes the image from the terminal's memory. The image is
+    /// transmitted again on the next resize and encode, e.g. when it is shown again.
+    pub fn delete(&mut self) -> String {
+        self.proto_state = KittyProtoState::Place;
+        self.rect = Rect::default();
+        self.transmitted = None;
+        self.pixels = None;
+        crate::cleanup::unregister_kitty(self.unique_id, self.is_tmux);
+        crate::cleanup::kitty_delete_sequence(self.unique_id, self.is_tmux)
+    }
+}
+
+impl StatefulKitty {
+    // The sequences that edit the changed region of the transmitted image, if it was transmitted
+    // completely with the same resize, area and size. Empty if nothing changed. None if the whole
+    // image should be transmitted, also when most of it changed.
+    fn transmit_diff(
+        &self,
+        resize: &Resize,
+        rect: Rect,
+        img: &RgbaImage,
+    ) -> Option<VecDeque<String>> {
+        let previous = self.pixels.as_deref()?;
+        let (transmitted_resize, transmitted) = self.transmitted.as_ref()?;
+        if transmitted_resize != resize
+            || *transmitted != rect
+            || self.rect != rect
+            || self.proto_state != KittyProtoState::Place
+            || previous.dimensions() != img.dimensions()
+        {
+            return None;
+        }
+        let Some((x, y, w, h)) = changed_region(previous, img) else {
+            return Some(VecDeque::new());
+        };
+        if w as u64 * h as u64 * 2 > img.width() as u64 * img.height() as u64 {
+            return None;
+        }
+        let region = imageops::crop_imm(img, x, y, w, h).to_image();
+        Some(transmit_region(
+            &region,
+            (x, y),
+            self.unique_id,
+            self.is_tmux,
+            &self.transmit_options,
+        ))
+    }
+
+    // The sequence that places the transmitted image scaled to the area, if it is close enough
+    // to the transmitted size. Empty for classic placements, which are placed on every render.
+    fn rescale(&self, resize: &Resize, area: Rect) -> Option<String> {
+        let tolerance = self.transmit_options.rescale_tolerance?;
+        let (transmitted_resize, transmitted) = self.transmitted.as_ref()?;
+        if transmitted_resize != resize
+            || self.source.hash != self.hash
+            || self.proto_state != KittyProtoState::Place
+        {
+            return None;
         }
+        let close = |new: u16, old: u16| new.abs_diff(old) as f32 <= old as f32 * tolerance;
+        if !close(area.width, transmitted.width) || !close(area.height, transmitted.height) {
+            return None;
+        }
+        if self.transmit_options.placement == KittyPlacement::Classic {
+            return Some(String::new());
+        }
+        Some(self.replace_virtual_placement(area))
+    }
+
+    // The sequence that replaces the virtual placement with one of the current options at the
+    // area: delete the placements but keep the image data (d=i), and place it again.
+    fn replace_virtual_placement(&self, area: Rect) -> String {
+        let (_, escape, _) = Parser::escape_tmux(self.is_tmux);
+        let id = self.unique_id;
+        format!(
+            "{escape}_Gq=2,a=d,d=i,i={id}{escape}\\{escape}_Gq=2,a=p,i={id},{},c={},r={}{escape}\\",
+            self.transmit_options.placement_keys(),
+            area.width,
+            area.height
+        )
     }
 }
 
 impl ProtocolTrait for StatefulKitty {
     fn render(&mut self, area: Rect, buf: &mut Buffer) {
         // Transmit only once. This is why self is mut.
-        let seq = self.proto_state.make_transmit();
+        let seq = self
+            .proto_state
+            .make_transmit(self.is_tmux, &self.transmit_options);
 
-        render(area, self.rect, buf, self.unique_id, seq);
+        if self.transmit_options.placement == KittyPlacement::Classic {
+            let options = &self.transmit_options;
+            render_classic(
+                area,
+                self.rect,
+                buf,
+                self.unique_id,
+                self.is_tmux,
+                options,
+                seq,
+            );
+        } else {
+            render(area, self.rect, buf, self.unique_id, self.id_color, seq);
+        }
     }
 
     fn area(&self) -> Rect {
@@ -102,6 +817,20 @@ impl ProtocolTrait for StatefulKitty {
 }
 
 impl StatefulProtocolTrait for StatefulKitty {
+    fn font_size(&self) -> FontSize {
+        self.font_size
+    }
+    fn set_font_size(&mut self, font_size: FontSize) {
+        self.font_size = font_size;
+        // Encoded for the previous font size.
+        self.hash = u64::default();
+    }
+    fn source(&self) -> &ImageSource {
+        &self.source
+    }
+    fn source_mut(&mut self) -> &mut ImageSource {
+        &mut self.source
+    }
     fn background_color(&self) -> Rgba<u8> {
         self.source.background_color
     }
@@ -114,24 +843,123 @@ impl StatefulProtocolTrait for StatefulKitty {
             self.source.hash != self.hash,
         )
     }
-    fn resize_encode(&mut self, resize: &Resize, background_color: Rgba<u8>, area: Rect) {
+    fn resize_encode(
+        &mut self,
+        resize: &Resize,
+        background_color: Rgba<u8>,
+        area: Rect,
+    ) -> Result<()> {
         if area.width == 0 || area.height == 0 {
-            return;
+            return Ok(());
+        }
+
+        if let Some(seq) = self.rescale(resize, area) {
+            self.rect = area;
+            if !seq.is_empty() {
+                self.proto_state = KittyProtoState::TransmitAndPlace(VecDeque::from([seq]));
+            }
+            return Ok(());
         }
 
         let img = resize.resize(&self.source, self.font_size, area, background_color);
-        let data = transmit_virtual(&img, self.unique_id, self.is_tmux);
+        let (img, (columns, rows)) = pad_to_cells(&img, self.font_size);
+        let rect = Rect::new(area.x, area.y, columns, rows);
+        if let Some(data) = self.transmit_diff(resize, rect, &img) {
+            self.hash = self.source.hash;
+            self.pixels = Some(Arc::new(img));
+            if !data.is_empty() {
+                self.proto_state = KittyProtoState::TransmitAndPlace(data);
+            }
+            return Ok(());
+        }
+        let data = transmit_padded(
+            &img,
+            self.unique_id,
+            self.is_tmux,
+            &self.transmit_options,
+            (columns, rows),
+        );
         self.hash = self.source.hash;
-        self.rect = area;
+        self.rect = rect;
+        self.transmitted = Some((resize.clone(), self.rect));
+        self.pixels = self.transmit_options.diff_updates.then(|| Arc::new(img));
         // If resized then we must transmit again.
         self.proto_state = KittyProtoState::TransmitAndPlace(data);
+        Ok(())
+    }
+    fn render_placement(&self, area: Rect, buf: &mut Buffer) {
+        if self.transmit_options.placement == KittyPlacement::Classic {
+            // There is only one classic placement, which is moved here.
+            let options = &self.transmit_options;
+            render_classic(
+                area,
+                self.rect,
+                buf,
+                self.unique_id,
+                self.is_tmux,
+                options,
+                None,
+            );
+        } else {
+            // The virtual placement can be referenced by any number of placeholders.
+            render(area, self.rect, buf, self.unique_id, self.id_color, None);
+        }
+    }
+    fn pending_transmit_len(&self) -> usize {
+        match &self.proto_state {
+            KittyProtoState::TransmitAndPlace(chunks) => chunks.iter().map(String::len).sum(),
+            KittyProtoState::Place => 0,
+        }
     }
 }
 
-fn render(area: Rect, rect: Rect, buf: &mut Buffer, id: u32, mut seq: Option<String>) {
-    let [id_extra, id_r, id_g, id_b] = id.to_be_bytes();
-    // Set the background color to the kitty id
-    let id_color = format!("\x1b[38;2;{id_r};{id_g};{id_b}m");
+// Render a classic placement into the first cell of the area, if the whole image fits.
+//
+// The (rest of the) transmission places the image at the cursor when it completes, afterwards
+// the placement is moved to the cursor again on every render that writes the first cell.
+fn render_classic(
+    area: Rect,
+    rect: Rect,
+    buf: &mut Buffer,
+    id: u32,
+    is_tmux: bool,
+    options: &KittyOptions,
+    seq: Option<String>,
+) {
+    if rect.width > area.width || rect.height > area.height {
+        return;
+    }
+    let symbol = seq.unwrap_or_else(|| classic_placement(id, rect, is_tmux, options));
+    for y in area.top()..area.top() + rect.height {
+        for x in area.left()..area.left() + rect.width {
+            buf.cell_mut((x, y)).map(|cell| cell.set_skip(true));
+        }
+    }
+    buf.cell_mut((area.left(), area.top()))
+        .map(|cell| cell.set_symbol(&symbol).set_skip(false));
+}
+
+// The escape sequence that places (or moves) the classic placement of an image at the cursor.
+fn classic_placement(id: u32, rect: Rect, is_tmux: bool, options: &KittyOptions) -> String {
+    let (start, escape, end) = Parser::escape_tmux(is_tmux);
+    format!(
+        "{start}{escape}_Gq=2,a=p,i={id},{},c={},r={}{escape}\\{end}",
+        options.placement_keys(),
+        rect.width,
+        rect.height
+    )
+}
+
+fn render(
+    area: Rect,
+    rect: Rect,
+    buf: &mut Buffer,
+    id: u32,
+    id_color: KittyIdColor,
+    mut seq: Option<String>,
+) {
+    // Set the foreground color to the kitty id
+    let (id_color, id_extra) = id_color.encode(id);
 
     // Draw each line of unicode placeholders but all into the first cell.
     // I couldn't work out actually drawing into each cell of the buffer so
@@ -147,6 +975,19 @@ fn render(area: Rect, rect: Rect, buf: &mut Buffer, id: u32, mut seq: Option<Str
         // Save cursor postion, including fg color which is what we want.
         symbol.push_str("\x1b[s");
 
+        // A wide character starting just left of the area spills into the first cell, and
+        // ratatui's diff would then skip the cell that carries the whole row of placeholders.
+        // Blank it like ratatui does with wide characters that are partially overwritten.
+        if let Some(cell) = area
+            .left()
+            .checked_sub(1)
+            .and_then(|x| buf.cell_mut((x, area.top() + y)))
+        {
+            if cell.symbol().width() > 1 {
+                cell.set_symbol(" ");
+            }
+        }
+
         // Start unicode placeholder sequence
         symbol.push_str(&id_color);
         add_placeholder(&mut symbol, 0, y, id_extra);
@@ -161,65 +1002,278 @@ fn render(area: Rect, rect: Rect, buf: &mut Buffer, id: u32, mut seq: Option<Str
         }
 
         // Restore saved cursor position including color, and now we have to move back to
-        // the end of the area.
+        // the end of the area. This does not depend on how wide the terminal thinks the
+        // placeholders are. A parameter of 0 means 1 for cursor movements, so leave those out.
+        symbol.push_str("\x1b[u");
         let right = area.width - 1;
         let down = area.height - 1;
-        symbol.push_str(&format!("\x1b[u\x1b[{right}C\x1b[{down}B"));
+        if right > 0 {
+            symbol.push_str(&format!("\x1b[{right}C"));
+        }
+        if down > 0 {
+            symbol.push_str(&format!("\x1b[{down}B"));
+        }
 
         buf.cell_mut((area.left(), area.top() + y))
             .map(|cell| cell.set_symbol(&symbol));
     }
 }
 
-/// Create a kitty escape sequence for transmitting and virtual-placement.
+/// The columns and rows of cells that an image of some pixel size covers, rounding up.
+fn placement_cells((width, height): (u32, u32), font_size: FontSize) -> (u16, u16) {
+    let (font_width, font_height) = (font_size.0.max(1) as u32, font_size.1.max(1) as u32);
+    (
+        width.div_ceil(font_width).max(1) as u16,
+        height.div_ceil(font_height).max(1) as u16,
+    )
+}
+
+/// Create the kitty escape sequences for transmitting and placing, one per chunk, and the columns
+/// and rows of the placement.
+///
+/// The image will be transmitted in chunks of (at most) 4096 bytes of base64 data.
+/// By default a "virtual placement" (U=1) is created so that we can place it using unicode
+/// placeholders. Removing the placements when the unicode placeholder is no longer there is being
+/// handled automatically by kitty.
 ///
-/// The image will be transmitted as RGB8 in chunks of 4096 bytes.
-/// A "virtual placement" (U=1) is created so that we can place it using unicode placeholders.
-/// Removing the placements when the unicode placeholder is no longer there is being handled
-/// automatically by kitty.
-fn transmit_virtual(img: &DynamicImage, id: u32, is_tmux: bool) -> String {
-    let (w, h) = (img.width(), img.height());
-    let img_rgba8 = img.to_rgba8();
-    let bytes = img_rgba8.as_raw();
+/// The image is padded with transparent pixels to whole cells, and the placement size is given
+/// explicitly with `c` and `r`. Otherwise, terminals round a partial bottom row or right column
+/// differently, which shows up as a glitched last row of placeholders.
+fn transmit_virtual(
+    img: &DynamicImage,
+    id: u32,
+    is_tmux: bool,
+    options: &KittyOptions,
+    font_size: FontSize,
+) -> (VecDeque<String>, (u16, u16)) {
+    let (img_rgba8, cells) = pad_to_cells(img, font_size);
+    (
+        transmit_padded(&img_rgba8, id, is_tmux, options, cells),
+        cells,
+    )
+}
 
-    let (start, escape, end) = Parser::escape_tmux(is_tmux);
-    let mut data = String::from(start);
+// Like transmit_virtual, with an image that is already padded to `columns` and `rows` cells.
+fn transmit_padded(
+    img_rgba8: &RgbaImage,
+    id: u32,
+    is_tmux: bool,
+    options: &KittyOptions,
+    (columns, rows): (u16, u16),
+) -> VecDeque<String> {
+    crate::cleanup::register_kitty(id, is_tmux);
+    let placement = options.placement_keys();
+    transmit(img_rgba8, id, is_tmux, options, &placement, (columns, rows))
+}
+
+// The image padded with transparent pixels to whole cells, see transmit_virtual, and the columns
+// and rows of the cells.
+fn pad_to_cells(img: &DynamicImage, font_size: FontSize) -> (RgbaImage, (u16, u16)) {
+    let (columns, rows) = placement_cells((img.width(), img.height()), font_size);
+    let (w, h) = (
+        columns as u32 * font_size.0.max(1) as u32,
+        rows as u32 * font_size.1.max(1) as u32,
+    );
+    let mut img_rgba8 = img.to_rgba8();
+    if (w, h) != img_rgba8.dimensions() {
+        let mut padded = RgbaImage::new(w, h);
+        imageops::replace(&mut padded, &img_rgba8, 0, 0);
+        img_rgba8 = padded;
+    }
+    (img_rgba8, (columns, rows))
+}
+
+// The bounding box of the pixels that differ, as x, y, width and height, or None if the images
+// are the same. Both images must have the same dimensions.
+fn changed_region(previous: &RgbaImage, img: &RgbaImage) -> Option<(u32, u32, u32, u32)> {
+    let width = img.width() as usize;
+    let (mut left, mut top, mut right, mut bottom) = (u32::MAX, u32::MAX, 0, 0);
+    let rows = previous
+        .as_raw()
+        .chunks(width * 4)
+        .zip(img.as_raw().chunks(width * 4));
+    for (y, (previous_row, row)) in rows.enumerate() {
+        if previous_row == row {
+            continue;
+        }
+        let y = y as u32;
+        let changed = |(a, b): (&[u8], &[u8])| a != b;
+        let pixels = || previous_row.chunks_exact(4).zip(row.chunks_exact(4));
+        let first = pixels().position(changed).unwrap_or(0) as u32;
+        let last = (width - 1 - pixels().rev().position(changed).unwrap_or(0)) as u32;
+        left = left.min(first);
+        right = right.max(last);
+        top = top.min(y);
+        bottom = y;
+    }
+    (top != u32::MAX).then(|| (left, top, right - left + 1, bottom - top + 1))
+}
 
-    // Max chunk size is 4096 bytes of base64 encoded data
-    let chunks = bytes.chunks(4096 / 4 * 3);
+// The escape sequences that replace a region of the pixels of the transmitted image, by editing
+// its root frame (`a=f,r=1`) without blending (`X=1`), one per chunk.
+fn transmit_region(
+    region: &RgbaImage,
+    (x, y): (u32, u32),
+    id: u32,
+    is_tmux: bool,
+    options: &KittyOptions,
+) -> VecDeque<String> {
+    let (bytes, compression) = compress(Cow::Borrowed(region.as_raw().as_slice()), options);
+    let (w, h) = region.dimensions();
+    let keys = format!("a=f,r=1,x={x},y={y},X=1,f=32,t=d,s={w},v={h}{compression}");
+    chunked(&bytes, &keys, id, is_tmux, options)
+}
+
+/// Create the kitty escape sequences for transmitting and placing an image with some placement
+/// keys, one per chunk, covering `columns` and `rows` cells.
+pub(crate) fn transmit(
+    img_rgba8: &RgbaImage,
+    id: u32,
+    is_tmux: bool,
+    options: &KittyOptions,
+    placement: &str,
+    (columns, rows): (u16, u16),
+) -> VecDeque<String> {
+    let mut bytes = Cow::Borrowed(img_rgba8.as_raw().as_slice());
+    let mut format = 32;
+    if options.format.resolve(is_remote()) == KittyFormat::Png {
+        let mut png = vec![];
+        if img_rgba8
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .is_ok()
+        {
+            bytes = Cow::Owned(png);
+            format = 100;
+        }
+    }
+    let size = img_rgba8.dimensions();
+    transmit_bytes(
+        bytes,
+        format,
+        size,
+        id,
+        is_tmux,
+        options,
+        placement,
+        (columns, rows),
+    )
+}
+
+// Like transmit, with bytes that are already encoded in the `f` format, of an image of `w` by `h`
+// pixels.
+#[allow(clippy::too_many_arguments)]
+fn transmit_bytes(
+    bytes: Cow<[u8]>,
+    format: u32,
+    (w, h): (u32, u32),
+    id: u32,
+    is_tmux: bool,
+    options: &KittyOptions,
+    placement: &str,
+    (columns, rows): (u16, u16),
+) -> VecDeque<String> {
+    let (bytes, compression) = compress(bytes, options);
+    if options.shared_memory {
+        let (_, escape, _) = Parser::escape_tmux(is_tmux);
+        let q = if options.acknowledge { 0 } else { 2 };
+        if let Ok(name) = write_shared_memory(&bytes) {
+            let payload = general_purpose::STANDARD.encode(name);
+            let len = bytes.len();
+            return VecDeque::from([format!(
+                "{escape}_Gq={q},i={id},a=T,{placement},f={format},t=s,s={w},v={h},c={columns},r={rows},S={len}{compression};{payload}{escape}\\"
+            )]);
+        }
+    }
+    let keys =
+        format!("a=T,{placement},f={format},t=d,s={w},v={h},c={columns},r={rows}{compression}");
+    chunked(&bytes, &keys, id, is_tmux, options)
+}
+
+// The bytes compressed with zlib if enabled and possible, and the key that says so.
+fn compress<'a>(bytes: Cow<'a, [u8]>, options: &KittyOptions) -> (Cow<'a, [u8]>, &'static str) {
+    if options.compression {
+        let mut encoder = ZlibEncoder::new(vec![], Compression::default());
+        if let Ok(compressed) = encoder.write_all(&bytes).and_then(|_| encoder.finish()) {
+            return (Cow::Owned(compressed), ",o=z");
+        }
+    }
+    (bytes, "")
+}
+
+// The escape sequences that send the bytes in chunks, the first one with the control keys.
+fn chunked(
+    bytes: &[u8],
+    keys: &str,
+    id: u32,
+    is_tmux: bool,
+    options: &KittyOptions,
+) -> VecDeque<String> {
+    let (_, escape, _) = Parser::escape_tmux(is_tmux);
+    let q = if options.acknowledge { 0 } else { 2 };
+    let chunks = bytes.chunks(options.chunk_size / 4 * 3);
     let chunk_count = chunks.len();
-    for (i, chunk) in chunks.enumerate() {
-        let payload = general_purpose::STANDARD.encode(chunk);
-        // tmux seems to only allow a limited amount of data in each passthrough sequence, since
-        // we're already chunking the data for the kitty protocol that's a good enough chunk size to
-        // use for the passthrough chunks too.
-        data.push_str(escape);
-
-        match i {
-            0 => {
-                // Transmit and virtual-place but keep sending chunks
-                let more = if chunk_count > 1 { 1 } else { 0 };
-                write!(
-                    data,
-                    "_Gq=2,i={id},a=T,U=1,f=32,t=d,s={w},v={h},m={more};{payload}"
-                )
-                .unwrap();
-            }
-            n if n + 1 == chunk_count => {
-                // m=0 means over
-                write!(data, "_Gq=2,m=0;{payload}").unwrap();
-            }
-            _ => {
-                // Keep adding chunks
-                write!(data, "_Gq=2,m=1;{payload}").unwrap();
+    chunks
+        .enumerate()
+        .map(|(i, chunk)| {
+            let payload = general_purpose::STANDARD.encode(chunk);
+            // tmux seems to only allow a limited amount of data in each passthrough sequence,
+            // since we're already chunking the data for the kitty protocol that's a good enough
+            // chunk size to use for the passthrough chunks too.
+            let mut data = String::from(escape);
+
+            match i {
+                0 => {
+                    // Transmit and virtual-place but keep sending chunks
+                    let more = if chunk_count > 1 { 1 } else { 0 };
+                    write!(data, "_Gq={q},i={id},{keys},m={more};{payload}").unwrap();
+                }
+                n if n + 1 == chunk_count => {
+                    // m=0 means over
+                    write!(data, "_Gq={q},m=0;{payload}").unwrap();
+                }
+                _ => {
+                    // Keep adding chunks
+                    write!(data, "_Gq={q},m=1;{payload}").unwrap();
+                }
             }
-        }
-        data.push_str(escape);
-        write!(data, "\\").unwrap();
+            data.push_str(escape);
+            data.push('\\');
+            data
+        })
+        .collect()
+}
+
+/// Write the data into a new shared memory object, returning its name.
+#[cfg(not(windows))]
+fn write_shared_memory(bytes: &[u8]) -> std::io::Result<String> {
+    use rustix::{fs::Mode, shm};
+    use std::{
+        fs::File,
+        process,
+        sync::atomic::{AtomicU32, Ordering},
+    };
+
+    static COUNTER: AtomicU32 = AtomicU32::new(0);
+    let name = format!(
+        "/ratatui-image-{}-{}",
+        process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    );
+    let fd = shm::open(
+        name.as_str(),
+        shm::OFlags::CREATE | shm::OFlags::EXCL | shm::OFlags::RDWR,
+        Mode::RUSR | Mode::WUSR,
+    )?;
+    if let Err(err) = File::from(fd).write_all(bytes) {
+        let _ = shm::unlink(name.as_str());
+        return Err(err);
     }
-    data.push_str(end);
+    Ok(name)
+}
 
-    data
+#[cfg(windows)]
+fn write_shared_memory(_bytes: &[u8]) -> std::io::Result<String> {
+    Err(std::io::ErrorKind::Unsupported.into())
 }
 
 fn add_placeholder(str: &mut String, x: u16, y: u16, id_extra: u8) {
@@ -538,3 +1592,449 @@ fn diacritic(y: u16) -> char {
         DIACRITICS[y as usize]
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, ImageBuffer, Rgba};
+    use ratatui::{buffer::Buffer, layout::Rect};
+
+    use super::{
+        allocate_id, indexed_id, is_id_in_use, transmit_virtual, Kitty, KittyFormat, KittyIdColor,
+        KittyOptions, KittyPlacement, KittyRelativePlacement, StatefulKitty, DIACRITICS,
+    };
+    use crate::{
+        protocol::{ProtocolTrait, StatefulProtocolTrait},
+        ImageSource, Resize,
+    };
+
+    // Decode the rows of placeholders of one image: the id from the foreground color and the
+    // third diacritic, and the row, first column, and width.
+    fn decode(buf: &Buffer, area: Rect) -> Vec<(u32, u16, u16, u16)> {
+        (area.top()..area.bottom())
+            .map(|y| {
+                let symbol = buf[(area.left(), y)].symbol();
+                let placeholders = &symbol[symbol.find("\x1b[s").unwrap() + "\x1b[s".len()..];
+                decode_row(placeholders)
+            })
+            .collect()
+    }
+
+    // Decode one row of placeholders that starts with the SGR sequence of the id, either 24-bit
+    // or 256-color.
+    fn decode_row(placeholders: &str) -> (u32, u16, u16, u16) {
+        let index = |c: char| DIACRITICS.iter().position(|d| *d == c).unwrap() as u16;
+        let color = &placeholders["\x1b[38;".len()..placeholders.find('m').unwrap()];
+        let color: Vec<u32> = color.split(';').map(|c| c.parse().unwrap()).collect();
+        let (r, g, b) = match color[..] {
+            [2, r, g, b] => (r, g, b),
+            [5, n] => (0, 0, n),
+            _ => panic!("unexpected color {color:?}"),
+        };
+        let mut chars = placeholders[placeholders.find('m').unwrap() + 1..].chars();
+        assert_eq!(chars.next(), Some('\u{10EEEE}'));
+        let row = index(chars.next().unwrap());
+        let column = index(chars.next().unwrap());
+        let extra = index(chars.next().unwrap()) as u32;
+        let width = 1 + chars.take_while(|c| *c == '\u{10EEEE}').count() as u16;
+        ((extra << 24) | (r << 16) | (g << 8) | b, row, column, width)
+    }
+
+    #[test]
+    fn test_placement_cells_odd_sizes() {
+        let font_size = (10, 20);
+        for ((width, height), (columns, rows)) in [
+            ((30, 40), (3, 2)),
+            ((25, 45), (3, 3)),
+            ((31, 41), (4, 3)),
+            ((1, 1), (1, 1)),
+        ] {
+            let image: DynamicImage =
+                ImageBuffer::from_pixel(width, height, Rgba::<u8>([255, 0, 0, 255])).into();
+            let (chunks, cells) =
+                transmit_virtual(&image, 1, false, &KittyOptions::default(), font_size);
+            assert_eq!(cells, (columns, rows), "{width}x{height}");
+            // Padded to whole cells, with the placement size given explicitly.
+            let (w, h) = (columns * font_size.0, rows * font_size.1);
+            assert!(
+                chunks[0].contains(&format!("s={w},v={h},c={columns},r={rows},")),
+                "{width}x{height}"
+            );
+
+            let area = Rect::new(0, 0, 5, 5);
+            let mut buf = Buffer::empty(area);
+            let mut kitty = StatefulKitty::new(
+                ImageSource::new(image, font_size, Rgba([0; 4])),
+                font_size,
+                1,
+                false,
+            );
+            let resize = Resize::Fit(None);
+            let rect = kitty.needs_resize(&resize, area).unwrap();
+            kitty.resize_encode(&resize, Rgba([0; 4]), rect).unwrap();
+            assert_eq!(kitty.area(), Rect::new(0, 0, columns, rows));
+            kitty.render(area, &mut buf);
+            assert_eq!(decode(&buf, kitty.area()).len(), rows as usize);
+        }
+    }
+
+    #[test]
+    fn test_many_images_full_id_space() {
+        let image: DynamicImage =
+            ImageBuffer::from_pixel(30, 40, Rgba::<u8>([255, 0, 0, 255])).into();
+        let (columns, rows) = (3, 2);
+        let buf_area = Rect::new(0, 0, columns * 10, rows * 20);
+        let mut buf = Buffer::empty(buf_area);
+
+        let ids: Vec<u32> = (0..200u32)
+            .map(|i| (i.wrapping_mul(0x9E37_79B9) | 1) ^ (i << 24))
+            .collect();
+        let mut unique = ids.clone();
+        unique.sort();
+        unique.dedup();
+        assert_eq!(unique.len(), ids.len());
+        let areas: Vec<Rect> = (0..200u16)
+            .map(|i| Rect::new(i % 10 * columns, i / 10 * rows, columns, rows))
+            .collect();
+        for (id, area) in ids.iter().zip(&areas) {
+            let mut kitty =
+                Kitty::new(image.clone(), Rect::new(0, 0, columns, rows), *id, false).unwrap();
+            kitty.render(*area, &mut buf);
+        }
+
+        for (id, area) in ids.iter().zip(&areas) {
+            let decoded = decode(&buf, *area);
+            let expected: Vec<_> = (0..rows).map(|y| (*id, y, 0, columns)).collect();
+            assert_eq!(decoded, expected);
+            // Only the first cell of each row has content, the rest are skipped.
+            for y in area.top()..area.bottom() {
+                for x in area.left() + 1..area.right() {
+                    assert!(buf[(x, y)].skip);
+                }
+            }
+        }
+        assert!(ids.iter().any(|id| id >> 24 > 0xf0));
+    }
+
+    #[test]
+    fn test_indexed_id_color() {
+        assert_eq!(indexed_id(0), 1);
+        assert_eq!(indexed_id(4), 4);
+        assert_eq!(indexed_id(772), 0x0300_0004);
+
+        let image: DynamicImage =
+            ImageBuffer::from_pixel(20, 40, Rgba::<u8>([255, 0, 0, 255])).into();
+        let area = Rect::new(0, 0, 2, 2);
+        let id = indexed_id(772);
+        let mut kitty = Kitty::new(image, area, id, false)
+            .unwrap()
+            .id_color(KittyIdColor::Indexed);
+        let printed = kitty.print_sequence();
+        assert!(printed.contains(&format!(",i={id},")));
+        let rows: Vec<_> = printed
+            .match_indices("\x1b[38;5;")
+            .map(|(start, _)| decode_row(&printed[start..]))
+            .collect();
+        assert_eq!(rows, vec![(id, 0, 0, 2), (id, 1, 0, 2)]);
+
+        let mut kitty = Kitty::new(
+            ImageBuffer::from_pixel(20, 40, Rgba::<u8>([255, 0, 0, 255])).into(),
+            area,
+            id,
+            false,
+        )
+        .unwrap()
+        .id_color(KittyIdColor::Indexed);
+        let mut buf = Buffer::empty(area);
+        kitty.render(area, &mut buf);
+        assert!(buf[(0, 0)].symbol().contains(&format!(",i={id},")));
+        assert_eq!(decode(&buf, area), vec![(id, 0, 0, 2), (id, 1, 0, 2)]);
+    }
+
+    #[test]
+    fn test_transmit_options() {
+        // 20x20 RGBA is 1600 bytes, in chunks of 300 bytes (400 base64 characters).
+        let image: DynamicImage =
+            ImageBuffer::from_pixel(20, 20, Rgba::<u8>([255, 0, 0, 255])).into();
+        let options = KittyOptions::new()
+            .chunk_size(401)
+            .chunks_per_render(Some(4));
+        let area = Rect::new(0, 0, 2, 1);
+        let mut kitty = Kitty::with_transmit_options(image, area, 1, true, options).unwrap();
+
+        let mut symbols = vec![];
+        for _ in 0..3 {
+            let mut buf = Buffer::empty(area);
+            kitty.render(area, &mut buf);
+            symbols.push(buf[(0, 0)].symbol().to_string());
+        }
+        // Each batch is wrapped in its own tmux passthrough.
+        assert!(symbols[0].starts_with("\x1bPtmux;\x1b\x1b_Gq=2,i=1,a=T"));
+        assert_eq!(symbols[0].matches("_Gq=2").count(), 4);
+        assert!(symbols[1].starts_with("\x1bPtmux;\x1b\x1b_Gq=2,m=1;"));
+        assert_eq!(symbols[1].matches("_Gq=2").count(), 2);
+        assert!(symbols[1].contains("_Gq=2,m=0;"));
+        assert!(symbols[1].contains(&format!("{}\x1b\\\x1b[s", "=\x1b\x1b\\")));
+        assert!(symbols[2].starts_with("\x1b[s"));
+        // "\x1bPtmux;", then the control data, then the payload of the first chunk.
+        let payload = symbols[0].split(';').nth(2).unwrap();
+        assert_eq!(payload.find('\x1b'), Some(400));
+    }
+
+    #[test]
+    fn test_kitty_options() {
+        let image: DynamicImage =
+            ImageBuffer::from_pixel(20, 20, Rgba::<u8>([255, 0, 0, 255])).into();
+        let font_size = (10, 20);
+        let chunks = |options| transmit_virtual(&image, 1, false, &options, font_size).0;
+
+        let raw = chunks(KittyOptions::new());
+        assert!(raw[0].starts_with("\x1b_Gq=2,i=1,a=T,U=1,f=32,t=d,s=20,v=20,c=2,r=1,m=0;"));
+
+        // "iVBORw0KGgo" is the base64 PNG signature.
+        let png = chunks(KittyOptions::new().format(KittyFormat::Png));
+        assert!(png[0].starts_with("\x1b_Gq=2,i=1,a=T,U=1,f=100,t=d,s=20,v=20,c=2,r=1,m=0;"));
+        assert!(png[0].contains(";iVBORw0KGgo"));
+
+        assert_eq!(KittyFormat::Auto.resolve(true), KittyFormat::Png);
+        assert_eq!(KittyFormat::Auto.resolve(false), KittyFormat::Rgba);
+        assert_eq!(KittyFormat::Rgba.resolve(true), KittyFormat::Rgba);
+
+        let compressed = chunks(KittyOptions::new().compression(true).z_index(-1));
+        assert_eq!(compressed.len(), 1);
+        assert!(compressed[0]
+            .starts_with("\x1b_Gq=2,i=1,a=T,U=1,z=-1,f=32,t=d,s=20,v=20,c=2,r=1,o=z,m=0;"));
+
+        // A classic placement is placed by the transmission, and moved by placing it again.
+        let options = KittyOptions::new().placement(KittyPlacement::Classic);
+        let mut kitty =
+            Kitty::with_transmit_options(image.clone(), Rect::new(0, 0, 2, 1), 7, false, options)
+                .unwrap();
+        let buf_area = Rect::new(0, 0, 4, 2);
+        let mut buf = Buffer::empty(buf_area);
+        kitty.render(Rect::new(0, 0, 4, 2), &mut buf);
+        assert!(buf[(0, 0)]
+            .symbol()
+            .starts_with("\x1b_Gq=2,i=7,a=T,p=1,C=1,f=32,"));
+        assert!(buf[(1, 0)].skip && !buf[(2, 0)].skip && !buf[(0, 1)].skip);
+
+        let mut buf = Buffer::empty(buf_area);
+        kitty.render(Rect::new(1, 1, 3, 1), &mut buf);
+        assert_eq!(
+            buf[(1, 1)].symbol(),
+            "\x1b_Gq=2,a=p,i=7,p=1,C=1,c=2,r=1\x1b\\"
+        );
+        assert!(buf[(2, 1)].skip);
+
+        // It cannot be cut off.
+        let mut buf = Buffer::empty(buf_area);
+        kitty.render(Rect::new(3, 0, 1, 2), &mut buf);
+        assert_eq!(buf, Buffer::empty(buf_area));
+    }
+
+    #[test]
+    #[cfg(not(windows))]
+    fn test_kitty_shared_memory() {
+        use base64::{engine::general_purpose, Engine};
+
+        let image: DynamicImage =
+            ImageBuffer::from_pixel(20, 20, Rgba::<u8>([255, 0, 0, 255])).into();
+        let options = KittyOptions::new().shared_memory(true);
+        let shared = transmit_virtual(&image, 1, false, &options, (10, 20)).0;
+        assert_eq!(shared.len(), 1);
+        let (control, payload) = shared[0].split_once(';').unwrap();
+        assert_eq!(
+            control,
+            "\x1b_Gq=2,i=1,a=T,U=1,f=32,t=s,s=20,v=20,c=2,r=1,S=1600"
+        );
+        let name = general_purpose::STANDARD
+            .decode(payload.trim_end_matches("\x1b\\"))
+            .unwrap();
+        let name = String::from_utf8(name).unwrap();
+        assert!(name.starts_with("/ratatui-image-"));
+        // The terminal would unlink it after reading.
+        rustix::shm::unlink(name.as_str()).unwrap();
+    }
+
+    #[test]
+    fn test_id_registry() {
+        let image: DynamicImage =
+            ImageBuffer::from_pixel(20, 20, Rgba::<u8>([255, 0, 0, 255])).into();
+        let kitty = Kitty::new(image, Rect::new(0, 0, 2, 1), 0xfeed01, false).unwrap();
+        assert!(is_id_in_use(0xfeed01));
+        let mut candidates = [0xfeed01, 0xfeed02].into_iter();
+        assert_eq!(allocate_id(|| candidates.next().unwrap()), 0xfeed02);
+
+        // Clones share the id.
+        let clone = kitty.clone();
+        drop(kitty);
+        assert!(is_id_in_use(0xfeed01));
+        drop(clone);
+        assert!(!is_id_in_use(0xfeed01));
+    }
+
+    #[test]
+    fn test_relative_placement() {
+        let placement = KittyRelativePlacement::new(7, 3).offset(2, -1);
+        assert_eq!(
+            placement.sequence(false),
+            "\x1b_Gq=2,a=p,i=7,p=1,P=3,H=2,V=-1\x1b\\"
+        );
+        let placement = placement.placement_id(5).parent_placement_id(9);
+        assert_eq!(
+            placement.sequence(true),
+            "\x1bPtmux;\x1b\x1b_Gq=2,a=p,i=7,p=5,P=3,H=2,V=-1,Q=9\x1b\x1b\\\x1b\\"
+        );
+        assert_eq!(
+            placement.delete_sequence(false),
+            "\x1b_Gq=2,a=d,d=i,i=7,p=5\x1b\\"
+        );
+    }
+
+    #[test]
+    fn test_wide_characters_adjacent() {
+        let image: DynamicImage =
+            ImageBuffer::from_pixel(30, 40, Rgba::<u8>([255, 0, 0, 255])).into();
+        let buf_area = Rect::new(0, 0, 12, 3);
+        let style = ratatui::style::Style::new();
+        for (columns, rows) in [(3, 2), (1, 1)] {
+            let area = Rect::new(5, 0, columns, rows);
+            let mut buf = Buffer::empty(buf_area);
+            for y in 0..rows + 1 {
+                // Ends exactly at the left edge, and spills into the image on the second row.
+                buf.set_string(1 + y % 2, y, "漢字", style);
+                buf.set_string(area.right(), y, "漢字", style);
+            }
+            let mut kitty =
+                Kitty::new(image.clone(), Rect::new(0, 0, columns, rows), 42, false).unwrap();
+            kitty.render(area, &mut buf);
+
+            let expected: Vec<_> = (0..rows).map(|y| (42, y, 0, columns)).collect();
+            assert_eq!(decode(&buf, area), expected);
+            let updates = Buffer::empty(buf_area).diff(&buf);
+            for y in 0..rows {
+                // The first cell of each row must not be swallowed by a wide character.
+                assert!(updates.iter().any(|(x, uy, _)| (*x, *uy) == (area.x, y)));
+                let symbol = buf[(area.x, y)].symbol();
+                assert!(!symbol.contains("\x1b[0C") && !symbol.contains("\x1b[0B"));
+            }
+            // The text left of the image is intact, except the character that spilled into it.
+            assert_eq!(buf[(3, 0)].symbol(), "字");
+            let spilled = if rows > 1 { " " } else { "字" };
+            assert_eq!(buf[(4, 1)].symbol(), spilled);
+            // The text right of the image is intact.
+            for y in 0..rows + 1 {
+                assert_eq!(buf[(area.right(), y)].symbol(), "漢");
+                assert_eq!(buf[(area.right() + 2, y)].symbol(), "字");
+            }
+        }
+    }
+
+    #[test]
+    fn test_rescale_tolerance() {
+        let image: DynamicImage =
+            ImageBuffer::from_pixel(200, 200, Rgba::<u8>([255, 0, 0, 255])).into();
+        let font_size = (10, 10);
+        let mut kitty = StatefulKitty::new(
+            ImageSource::new(image, font_size, Rgba([0; 4])),
+            font_size,
+            7,
+            false,
+        )
+        .transmit_options(KittyOptions::new().rescale_tolerance(Some(0.25)));
+        let resize = Resize::Scale(None);
+        let transmission = |kitty: &mut StatefulKitty, size: u16| {
+            let area = Rect::new(0, 0, size, size);
+            let rect = kitty.needs_resize(&resize, area).unwrap();
+            kitty.resize_encode(&resize, Rgba([0; 4]), rect).unwrap();
+            assert_eq!(kitty.area(), area);
+            let mut buf = Buffer::empty(area);
+            kitty.render(area, &mut buf);
+            assert_eq!(decode(&buf, area).len(), size as usize);
+            buf[(0, 0)].symbol().to_string()
+        };
+
+        assert!(transmission(&mut kitty, 20).contains("a=T"));
+        // Only placed again, scaled to the new size.
+        let placed = transmission(&mut kitty, 16);
+        assert!(!placed.contains("a=T"));
+        assert!(placed.contains("\x1b_Gq=2,a=d,d=i,i=7\x1b\\\x1b_Gq=2,a=p,i=7,U=1,c=16,r=16"));
+        // Compared with the transmitted size, not the last placement.
+        assert!(transmission(&mut kitty, 14).contains("a=T"));
+    }
+
+    #[test]
+    fn test_diff_updates() {
+        let image = |changed: Option<(u32, u32)>| -> DynamicImage {
+            let mut image = ImageBuffer::from_pixel(40, 40, Rgba::<u8>([255, 0, 0, 255]));
+            if let Some((x, y)) = changed {
+                image.put_pixel(x, y, Rgba([0, 0, 255, 255]));
+                image.put_pixel(x + 2, y + 1, Rgba([0, 0, 255, 255]));
+            }
+            image.into()
+        };
+        let font_size = (10, 20);
+        let mut kitty = StatefulKitty::new(
+            ImageSource::new(image(None), font_size, Rgba([0; 4])),
+            font_size,
+            9,
+            false,
+        )
+        .transmit_options(KittyOptions::new().diff_updates(true));
+        let area = Rect::new(0, 0, 4, 2);
+        let resize = Resize::Fit(None);
+        let update = |kitty: &mut StatefulKitty, changed| {
+            *kitty.source_mut() = ImageSource::new(image(changed), font_size, Rgba([0; 4]));
+            if let Some(rect) = kitty.needs_resize(&resize, area) {
+                kitty.resize_encode(&resize, Rgba([0; 4]), rect).unwrap();
+            }
+            let mut buf = Buffer::empty(area);
+            kitty.render(area, &mut buf);
+            buf[(0, 0)].symbol().to_string()
+        };
+
+        assert!(update(&mut kitty, None).contains("a=T"));
+        let edit = update(&mut kitty, Some((5, 7)));
+        assert!(
+            edit.starts_with("\x1b_Gq=2,i=9,a=f,r=1,x=5,y=7,X=1,f=32,t=d,s=3,v=2,m=0;"),
+            "{edit:?}"
+        );
+        // Back to the first image, which is the same region.
+        assert!(update(&mut kitty, None).contains("x=5,y=7,X=1,f=32,t=d,s=3,v=2"));
+        // Nothing changed.
+        assert!(!update(&mut kitty, None).contains("\x1b_G"));
+        // Deleted from the terminal, so transmitted completely again.
+        kitty.delete();
+        assert!(update(&mut kitty, Some((0, 0))).contains("a=T"));
+    }
+
+    #[test]
+    fn test_set_z_index() {
+        let image: DynamicImage =
+            ImageBuffer::from_pixel(30, 40, Rgba::<u8>([255, 0, 0, 255])).into();
+        let font_size = (10, 20);
+        let mut kitty = StatefulKitty::new(
+            ImageSource::new(image, font_size, Rgba([0; 4])),
+            font_size,
+            8,
+            false,
+        );
+        let area = Rect::new(0, 0, 3, 2);
+        let render = |kitty: &mut StatefulKitty| {
+            let mut buf = Buffer::empty(area);
+            kitty.render(area, &mut buf);
+            buf[(0, 0)].symbol().to_string()
+        };
+        // Not transmitted yet, the transmission places it with the z-index.
+        kitty.set_z_index(-1);
+        let resize = Resize::Fit(None);
+        kitty.resize_encode(&resize, Rgba([0; 4]), area).unwrap();
+        assert!(render(&mut kitty).contains("a=T,U=1,z=-1,"));
+
+        kitty.set_z_index(2);
+        assert!(render(&mut kitty)
+            .starts_with("\x1b_Gq=2,a=d,d=i,i=8\x1b\\\x1b_Gq=2,a=p,i=8,U=1,z=2,c=3,r=2\x1b\\"));
+        // Only once.
+        kitty.set_z_index(2);
+        assert!(!render(&mut kitty).contains("\x1b_G"));
+    }
+}
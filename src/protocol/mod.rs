@@ -1,73 +1,498 @@
 //! Protocol backends for the widgets
 
 use std::{
-    collections::hash_map::DefaultHasher,
+    any::Any,
+    collections::{hash_map::DefaultHasher, HashMap},
+    fmt,
     hash::{Hash, Hasher},
+    io::{self, Write},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, Receiver},
+        Arc, Mutex, MutexGuard, PoisonError, Weak,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
-use image::{imageops, DynamicImage, ImageBuffer, Rgba};
-use ratatui::{buffer::Buffer, layout::Rect};
+use image::{imageops, DynamicImage, GenericImageView, ImageBuffer, Rgba};
+use ratatui::{
+    buffer::Buffer,
+    layout::Rect,
+    style::{Color, Style},
+};
 
-use crate::FontSize;
+use crate::{
+    analysis::ImageAnalysis,
+    audit::{self, SkipReason},
+    cleanup,
+    errors::Errors,
+    metrics,
+    picker::{Picker, ProtocolType},
+    reflow::ReflowTracker,
+    sequence_log::SequenceLog,
+    watermark::Watermark,
+    AreaClaim, ContentHint, ErrorIndicator, FilterType, FontSize, Result, ViewportOptions,
+};
 
 use self::{
+    braille::{Braille, StatefulBraille},
     halfblocks::{Halfblocks, StatefulHalfblocks},
     iterm2::{Iterm2, StatefulIterm2},
     kitty::{Kitty, StatefulKitty},
     sixel::{Sixel, StatefulSixel},
+    terminology::{StatefulTerminology, Terminology},
 };
 
-use super::Resize;
+use super::{Resize, ResizeEncodeRender};
 
+pub mod braille;
 pub mod halfblocks;
 pub mod iterm2;
 pub mod kitty;
 pub mod sixel;
+pub mod terminology;
+
+// Shrink the area to at most `max` cells, keeping its position.
+pub(crate) fn clamp_area(area: Rect, max: Option<(u16, u16)>) -> Rect {
+    match max {
+        Some((width, height)) => Rect {
+            width: area.width.min(width),
+            height: area.height.min(height),
+            ..area
+        },
+        None => area,
+    }
+}
+
+/// Erase `width` cells on each of `height` rows with ECH and CUD, then move back up with CUU.
+///
+/// DECERA would erase the entire rectangle with one sequence, but it is not implemented correctly
+/// (or not at all) in some major terminals, e.g. WezTerm.
+pub(crate) fn erase_sequence(escape: &str, width: u16, height: u16) -> String {
+    let mut seq = String::new();
+    for _ in 0..height {
+        seq.push_str(&format!("{escape}[{width}X{escape}[1B"));
+    }
+    seq.push_str(&format!("{escape}[{height}A"));
+    seq
+}
+
+/// Erase the cells of `previous` that are not covered by `current`, by prefixing the first cell
+/// of each uncovered row segment with ECH.
+///
+/// ratatui only redraws cells that changed, and on some terminals writing text over graphics
+/// does not remove the graphics, so shrinking an image would leave fragments behind.
+pub(crate) fn erase_uncovered(previous: Rect, current: Rect, buf: &mut Buffer) {
+    let previous = previous.intersection(buf.area);
+    for y in previous.top()..previous.bottom() {
+        let covered = if current.top() <= y && y < current.bottom() {
+            current.left().max(previous.left())..current.right().min(previous.right())
+        } else {
+            previous.left()..previous.left()
+        };
+        for segment in [
+            previous.left()..covered.start,
+            covered.end..previous.right(),
+        ] {
+            if segment.is_empty() {
+                continue;
+            }
+            if let Some(cell) = buf.cell_mut((segment.start, y)) {
+                let symbol = format!("\x1b[{}X{}", segment.len(), cell.symbol());
+                cell.set_symbol(&symbol);
+            }
+        }
+    }
+}
+
+/// Prefix the first cell with escape sequences of every row of `area` with an absolute cursor
+/// position (CUP), for a buffer whose origin is at `origin` on the terminal.
+///
+/// Every row, because some protocols (e.g. Kitty placeholders) start a sequence on each row.
+/// Text cells are left alone, they are positioned by the backend.
+pub(crate) fn position_at_origin(area: Rect, origin: (u16, u16), buf: &mut Buffer) {
+    for y in area.top()..area.bottom() {
+        let Some(x) = (area.left()..area.right()).find(|&x| {
+            buf.cell((x, y))
+                .is_some_and(|cell| cell.symbol().contains('\x1b'))
+        }) else {
+            continue;
+        };
+        let cell = &mut buf[(x, y)];
+        let row = origin.1 as u32 + y as u32 + 1;
+        let column = origin.0 as u32 + x as u32 + 1;
+        let symbol = format!("\x1b[{row};{column}H{}", cell.symbol());
+        cell.set_symbol(&symbol);
+    }
+}
+
+// Write the cells of a buffer as lines of text with SGR colors.
+fn write_cells(w: &mut impl Write, buf: &Buffer) -> io::Result<()> {
+    let sgr = |color: Color, base: u8| match color {
+        Color::Rgb(r, g, b) => format!("\x1b[{};2;{r};{g};{b}m", base + 8),
+        Color::Indexed(i) => format!("\x1b[{};5;{i}m", base + 8),
+        _ => format!("\x1b[{}m", base + 9),
+    };
+    let area = buf.area;
+    for y in area.top()..area.bottom() {
+        for x in area.left()..area.right() {
+            let cell = &buf[(x, y)];
+            write!(
+                w,
+                "{}{}{}",
+                sgr(cell.fg, 30),
+                sgr(cell.bg, 40),
+                cell.symbol()
+            )?;
+        }
+        w.write_all(b"\x1b[0m\r\n")?;
+    }
+    Ok(())
+}
+
+/// How the cells covered by a sixel or iTerm2 image are kept from being drawn over the image.
+///
+/// ratatui writes every cell of the buffer that changed. Terminals differ in whether text
+/// written after an image erases the graphics (e.g. xterm), is drawn below it (e.g. foot), or
+/// leaves stale text under transparent parts. Kitty always skips its cells, because the whole row
+/// of placeholders is written into the first cell.
+#[derive(PartialEq, Clone, Copy, Debug, Default)]
+pub enum CellProtection {
+    /// Mark the cells as skipped, so that ratatui never writes them (the default).
+    #[default]
+    Skip,
+    /// Write blank cells with the default background, clearing stale text. Only suitable for
+    /// terminals that draw the image above text.
+    Spaces,
+    /// Leave the cells as they are, for apps that handle them themselves.
+    Nothing,
+}
 
-trait ProtocolTrait: Send + Sync {
+/// Protect the cells of `render_area` except the first one, which holds the image data.
+pub(crate) fn protect_cells(render_area: Rect, buf: &mut Buffer, protection: CellProtection) {
+    let first = (render_area.x, render_area.y);
+    for y in render_area.top()..render_area.bottom() {
+        for x in render_area.left()..render_area.right() {
+            if (x, y) == first {
+                continue;
+            }
+            let Some(cell) = buf.cell_mut((x, y)) else {
+                continue;
+            };
+            match protection {
+                CellProtection::Skip => {
+                    cell.set_skip(true);
+                }
+                CellProtection::Spaces => {
+                    cell.reset();
+                    cell.set_symbol(" ");
+                }
+                CellProtection::Nothing => {}
+            }
+        }
+    }
+}
+
+type SharedEntries = HashMap<u64, Weak<dyn Any + Send + Sync>>;
+
+/// Encoded payloads shared between stateful protocols, see [crate::picker::Picker::set_share_encodings].
+///
+/// When two protocols show the same image at the same size with the same options, the second one
+/// reuses the payload of the first one instead of encoding it again. Entries are only kept alive
+/// by the protocols that use them. Kitty is not shared, since the image id is part of its payload;
+/// use [crate::PlacementView] instead.
+#[derive(Clone, Default)]
+pub struct SharedEncodings {
+    entries: Arc<Mutex<SharedEntries>>,
+}
+
+impl SharedEncodings {
+    pub fn new() -> SharedEncodings {
+        SharedEncodings::default()
+    }
+
+    /// Number of encodings that are currently in use by some protocol.
+    pub fn len(&self) -> usize {
+        self.lock()
+            .values()
+            .filter(|entry| entry.strong_count() > 0)
+            .count()
+    }
+
+    /// Whether no encodings are in use.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn lock(&self) -> MutexGuard<'_, SharedEntries> {
+        self.entries.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn get_or_encode<T: Any + Send + Sync>(
+        &self,
+        key: u64,
+        encode: impl FnOnce() -> Result<T>,
+    ) -> Result<Arc<T>> {
+        let existing = self
+            .lock()
+            .get(&key)
+            .and_then(Weak::upgrade)
+            .and_then(|data| data.downcast::<T>().ok());
+        metrics::cache_lookup("shared", existing.is_some());
+        if let Some(existing) = existing {
+            return Ok(existing);
+        }
+
+        // Encode without holding the lock, other threads may be encoding too.
+        let data = Arc::new(encode()?);
+        let any: Arc<dyn Any + Send + Sync> = data.clone();
+        let mut entries = self.lock();
+        entries.retain(|_, entry| entry.strong_count() > 0);
+        entries.insert(key, Arc::downgrade(&any));
+        Ok(data)
+    }
+}
+
+impl fmt::Debug for SharedEncodings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SharedEncodings")
+            .field("len", &self.len())
+            .finish()
+    }
+}
+
+/// Encode through the [SharedEncodings] if there are any, where `key` must identify everything
+/// that the encoding depends on.
+pub(crate) fn encode_shared<T: Any + Send + Sync>(
+    shared: Option<&SharedEncodings>,
+    key: impl Hash,
+    encode: impl FnOnce() -> Result<T>,
+) -> Result<Arc<T>> {
+    match shared {
+        Some(shared) => {
+            let mut hasher = DefaultHasher::new();
+            key.hash(&mut hasher);
+            shared.get_or_encode(hasher.finish(), encode)
+        }
+        None => encode().map(Arc::new),
+    }
+}
+
+/// A fixed-size image protocol, that [Protocol::Custom] holds for a third-party backend.
+///
+/// The built-in protocols implement this too.
+pub trait ProtocolTrait: CloneProtocol + Send + Sync {
     /// Render the currently resized and encoded data to the buffer.
     fn render(&mut self, area: Rect, buf: &mut Buffer);
 
-    // Get the area of the image.
-    #[allow(dead_code)]
+    /// Get the area of the image.
     fn area(&self) -> Rect;
+
+    /// The name of a third-party backend, that [ProtocolType::Custom] carries.
+    fn name(&self) -> &'static str {
+        "custom"
+    }
+}
+
+/// Clones a boxed [ProtocolTrait], implemented for every one that is [Clone].
+pub trait CloneProtocol {
+    fn clone_protocol(&self) -> Box<dyn ProtocolTrait>;
+}
+
+impl<T: ProtocolTrait + Clone + 'static> CloneProtocol for T {
+    fn clone_protocol(&self) -> Box<dyn ProtocolTrait> {
+        Box::new(self.clone())
+    }
 }
 
-trait StatefulProtocolTrait: ProtocolTrait {
-    // Get the background color that fills in when resizing.
+impl Clone for Box<dyn ProtocolTrait> {
+    fn clone(&self) -> Self {
+        (**self).clone_protocol()
+    }
+}
+
+/// A resizing image protocol, that [StatefulProtocolType::Custom] holds for a third-party
+/// backend.
+///
+/// [StatefulProtocol] does the caching, filter tiers, content hints and so on around it, so a
+/// backend only resizes and encodes, usually with [Resize::needs_resize] and [Resize::resize].
+pub trait StatefulProtocolTrait: ProtocolTrait + CloneStatefulProtocol {
+    /// Get the source image.
+    fn source(&self) -> &ImageSource;
+    /// Get the source image mutably, to replace it.
+    fn source_mut(&mut self) -> &mut ImageSource;
+    /// Get the background color that fills in when resizing.
     fn background_color(&self) -> Rgba<u8>;
     /// Check if the current image state would need resizing (grow or shrink) for the given area.
     ///
     /// This can be called by the UI thread to check if this [StatefulProtocol] should be sent off
-    /// toprotoco
-    /// some background thread/task to do the resizing and encoding, instead of rendering. The
-    /// thread should then return the [StatefulProtocol] so that it can be rendered.protoco
+    /// to some background thread/task to do the resizing and encoding, instead of rendering. The
+    /// thread should then return the [StatefulProtocol] so that it can be rendered.
     fn needs_resize(&mut self, resize: &Resize, area: Rect) -> Option<Rect>;
 
     /// Resize the image and encode it for rendering. The result should be stored statefully so
     /// that next call for the given area does not need to redo the work.
     ///
     /// This can be done in a background thread, and the result is stored in this [StatefulProtocol].
-    fn resize_encode(&mut self, resize: &Resize, background_color: Rgba<u8>, area: Rect);
+    fn resize_encode(
+        &mut self,
+        resize: &Resize,
+        background_color: Rgba<u8>,
+        area: Rect,
+    ) -> Result<()>;
+
+    /// Render the current encoding at another area, without resizing or transmitting anything.
+    fn render_placement(&self, area: Rect, buf: &mut Buffer);
+
+    /// Bytes that the next render transmits in addition to the placement.
+    fn pending_transmit_len(&self) -> usize {
+        0
+    }
+
+    /// The font size that the image is resized for.
+    fn font_size(&self) -> FontSize;
+    /// Change the font size, so that the next needs_resize encodes again.
+    fn set_font_size(&mut self, font_size: FontSize);
+
+    /// Dither on the next encode, for protocols that reduce colors or dots.
+    fn set_dither(&mut self, _dither: bool) {}
+    fn dither(&self) -> bool {
+        false
+    }
+}
+
+/// Clones a boxed [StatefulProtocolTrait], implemented for every one that is [Clone].
+pub trait CloneStatefulProtocol {
+    fn clone_stateful_protocol(&self) -> Box<dyn StatefulProtocolTrait>;
+}
+
+impl<T: StatefulProtocolTrait + Clone + 'static> CloneStatefulProtocol for T {
+    fn clone_stateful_protocol(&self) -> Box<dyn StatefulProtocolTrait> {
+        Box::new(self.clone())
+    }
+}
+
+impl Clone for Box<dyn StatefulProtocolTrait> {
+    fn clone(&self) -> Self {
+        (**self).clone_stateful_protocol()
+    }
+}
+
+/// A third-party image protocol, that a [Picker] uses for [ProtocolType::Custom], see
+/// [Picker::set_backend].
+pub trait ProtocolBackend: Send + Sync {
+    /// The name that [ProtocolType::Custom] carries, should be the same as [ProtocolTrait::name].
+    fn name(&self) -> &'static str;
+
+    /// A protocol for the [crate::Image] widget, of an image that is already resized to `area`.
+    fn new_protocol(&self, image: DynamicImage, area: Rect) -> Result<Box<dyn ProtocolTrait>>;
+
+    /// A protocol for the [crate::StatefulImage] widget, that resizes `source` on demand.
+    fn new_stateful_protocol(
+        &self,
+        source: ImageSource,
+        font_size: FontSize,
+    ) -> Box<dyn StatefulProtocolTrait>;
 }
 
 /// A fixed-size image protocol for the [crate::Image] widget.
 #[derive(Clone)]
+#[non_exhaustive]
 pub enum Protocol {
     Halfblocks(Halfblocks),
     Sixel(Sixel),
     Kitty(Kitty),
     ITerm2(Iterm2),
+    Terminology(Terminology),
+    Braille(Braille),
+    /// A third-party backend, see [ProtocolBackend].
+    Custom(Box<dyn ProtocolTrait>),
 }
 impl Protocol {
     pub(crate) fn render(&mut self, area: Rect, buf: &mut Buffer) {
-        let inner: &mut dyn ProtocolTrait = match self {
+        self.inner_mut().render(area, buf);
+
+        if audit::is_enabled() {
+            let image_area = self.area();
+            let protocol = self.kind();
+            let clipping = matches!(
+                protocol,
+                ProtocolType::Sixel | ProtocolType::Iterm2 | ProtocolType::Terminology
+            );
+            // Sixel, iTerm2 and Terminology render nothing rather than a cut off image.
+            let skipped = (clipping
+                && (image_area.width > area.width || image_area.height > area.height))
+                .then_some(SkipReason::DoesNotFit);
+            audit::record(protocol, area, image_area, buf, false, skipped);
+        }
+    }
+    fn inner_mut(&mut self) -> &mut dyn ProtocolTrait {
+        match self {
             Self::Halfblocks(halfblocks) => halfblocks,
             Self::Sixel(sixel) => sixel,
             Self::Kitty(kitty) => kitty,
             Self::ITerm2(iterm2) => iterm2,
-        };
-        inner.render(area, buf);
+            Self::Terminology(terminology) => terminology,
+            Self::Braille(braille) => braille,
+            Self::Custom(custom) => custom.as_mut(),
+        }
+    }
+
+    fn kind(&self) -> ProtocolType {
+        match self {
+            Self::Halfblocks(_) => ProtocolType::Halfblocks,
+            Self::Sixel(_) => ProtocolType::Sixel,
+            Self::Kitty(_) => ProtocolType::Kitty,
+            Self::ITerm2(_) => ProtocolType::Iterm2,
+            Self::Terminology(_) => ProtocolType::Terminology,
+            Self::Braille(_) => ProtocolType::Braille,
+            Self::Custom(custom) => ProtocolType::Custom(custom.name()),
+        }
+    }
+
+    // Log what the first render will emit, on a copy so that e.g. Kitty still transmits.
+    pub(crate) fn log_sequences(&self, log: &SequenceLog) {
+        let area = self.area();
+        let mut buf = Buffer::empty(area);
+        self.clone().inner_mut().render(area, &mut buf);
+        log.record(self.kind(), area, &buf, None);
+    }
+
+    /// Write the image as a standalone block of lines, see [Picker::print_image].
+    pub(crate) fn print(&mut self, w: &mut impl Write) -> io::Result<()> {
+        let area = self.area();
+        match self {
+            Self::Halfblocks(halfblocks) => {
+                let mut buf = Buffer::empty(area);
+                halfblocks.render(area, &mut buf);
+                write_cells(w, &buf)?;
+            }
+            Self::Braille(braille) => {
+                let mut buf = Buffer::empty(area);
+                braille.render(area, &mut buf);
+                write_cells(w, &buf)?;
+            }
+            Self::Custom(custom) => {
+                let mut buf = Buffer::empty(area);
+                custom.render(area, &mut buf);
+                write_cells(w, &buf)?;
+            }
+            Self::Kitty(kitty) => {
+                // Unicode placeholders are text, and scroll like text.
+                w.write_all(kitty.print_sequence().as_bytes())?;
+            }
+            Self::Sixel(Sixel { data, .. })
+            | Self::ITerm2(Iterm2 { data, .. })
+            | Self::Terminology(Terminology { data, .. }) => {
+                // Reserve the rows first, so that drawing the image does not scroll the terminal,
+                // then place the image and move below it.
+                let rows = area.height;
+                w.write_all("\n".repeat(rows as usize).as_bytes())?;
+                write!(w, "\x1b[{rows}A\x1b7{data}\x1b8\x1b[{rows}B\r")?;
+            }
+        }
+        w.flush()
     }
     pub fn area(&self) -> Rect {
         let inner: &dyn ProtocolTrait = match self {
@@ -75,29 +500,269 @@ impl Protocol {
             Self::Sixel(sixel) => sixel,
             Self::Kitty(kitty) => kitty,
             Self::ITerm2(iterm2) => iterm2,
+            Self::Terminology(terminology) => terminology,
+            Self::Braille(braille) => braille,
+            Self::Custom(custom) => custom.as_ref(),
         };
         inner.area()
     }
 }
 
+/// A [Protocol] that is resized and encoded later, for the [crate::LazyImage] widget.
+///
+/// See [Picker::new_protocol_lazy]. The encoding happens on the first render, or on a background
+/// thread after [LazyProtocol::spawn], in which case nothing is rendered until it completes.
+pub struct LazyProtocol {
+    state: LazyState,
+}
+
+enum LazyState {
+    Deferred {
+        picker: Box<Picker>,
+        image: DynamicImage,
+        size: Rect,
+        resize: Resize,
+    },
+    Background(Receiver<Result<Protocol>>),
+    Ready(Protocol),
+    Failed(Errors),
+    // Only while taking the state apart.
+    Empty,
+}
+
+impl LazyProtocol {
+    pub(crate) fn new(picker: Picker, image: DynamicImage, size: Rect, resize: Resize) -> Self {
+        LazyProtocol {
+            state: LazyState::Deferred {
+                picker: Box::new(picker),
+                image,
+                size,
+                resize,
+            },
+        }
+    }
+
+    /// Resize and encode on a new thread, if that has not happened yet.
+    pub fn spawn(&mut self) {
+        let LazyState::Deferred {
+            picker,
+            image,
+            size,
+            resize,
+        } = std::mem::replace(&mut self.state, LazyState::Empty)
+        else {
+            return;
+        };
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            // The LazyProtocol may have been dropped meanwhile.
+            let _ = tx.send(picker.new_protocol(image, size, resize));
+        });
+        self.state = LazyState::Background(rx);
+    }
+
+    /// Resize and encode now, or wait for the background thread if it was spawned.
+    pub fn encode(&mut self) -> std::result::Result<&mut Protocol, &Errors> {
+        self.state = match std::mem::replace(&mut self.state, LazyState::Empty) {
+            LazyState::Deferred {
+                picker,
+                image,
+                size,
+                resize,
+            } => Self::done(picker.new_protocol(image, size, resize)),
+            LazyState::Background(rx) => Self::done(rx.recv().unwrap_or_else(|_| {
+                Err(Errors::IO(std::io::Error::other(
+                    "encoding thread panicked",
+                )))
+            })),
+            state => state,
+        };
+        match &mut self.state {
+            LazyState::Ready(protocol) => Ok(protocol),
+            LazyState::Failed(err) => Err(err),
+            _ => unreachable!("state was just resolved"),
+        }
+    }
+
+    /// The encoded protocol, if it is ready.
+    pub fn protocol(&mut self) -> Option<&mut Protocol> {
+        self.poll();
+        match &mut self.state {
+            LazyState::Ready(protocol) => Some(protocol),
+            _ => None,
+        }
+    }
+
+    /// The error of the resize and encode, if it failed.
+    pub fn error(&self) -> Option<&Errors> {
+        match &self.state {
+            LazyState::Failed(err) => Some(err),
+            _ => None,
+        }
+    }
+
+    /// Encode if still deferred and render, or render nothing while the background thread works.
+    pub(crate) fn render(&mut self, area: Rect, buf: &mut Buffer) {
+        if let LazyState::Deferred { .. } = self.state {
+            // The error is kept in the state.
+            let _ = self.encode();
+        }
+        if let Some(protocol) = self.protocol() {
+            protocol.render(area, buf);
+        }
+    }
+
+    // Pick up the result of the background thread, if it is done.
+    fn poll(&mut self) {
+        if let LazyState::Background(rx) = &self.state {
+            self.state = match rx.try_recv() {
+                Ok(result) => Self::done(result),
+                Err(mpsc::TryRecvError::Empty) => return,
+                Err(mpsc::TryRecvError::Disconnected) => LazyState::Failed(Errors::IO(
+                    std::io::Error::other("encoding thread panicked"),
+                )),
+            };
+        }
+    }
+
+    fn done(result: Result<Protocol>) -> LazyState {
+        match result {
+            Ok(protocol) => LazyState::Ready(protocol),
+            Err(err) => LazyState::Failed(err),
+        }
+    }
+}
+
 /// A stateful resizing image protocol for the [crate::StatefulImage] widget.
 ///
 /// The [create::thread::ThreadImage] widget also uses this, and is the reason why resizing is
 /// split from rendering.
 #[derive(Clone)]
-pub enum StatefulProtocol {
+pub struct StatefulProtocol {
+    protocol_type: StatefulProtocolType,
+    redraw: RedrawNonce,
+    needs_resize_cache: Option<NeedsResizeCache>,
+    // The resize and area of the last needs_resize, outlives the cache.
+    last_request: Option<(Resize, Rect)>,
+    last_encoding_result: Option<std::result::Result<(), Arc<Errors>>>,
+    analysis: Option<Arc<ImageAnalysis>>,
+    redacted: bool,
+    // Boxed, this is rarely used and would bloat every protocol.
+    filter_tiers: Option<Box<(FilterTiers, Option<TierState>)>>,
+    max_area: Option<(u16, u16)>,
+    generator: Option<ImageGenerator>,
+    // The tracker and the generation that this protocol was last refreshed at.
+    reflow: Option<(ReflowTracker, u64)>,
+    // The log and the hash of the last logged render.
+    sequence_log: Option<(SequenceLog, Option<u64>)>,
+    area_claim: AreaClaim,
+    content_hint: Option<ContentHint>,
+    // The dither of the backend before a content hint replaced it.
+    default_dither: Option<bool>,
+    // The indicator, and the error that was last notified.
+    error_indicator: (ErrorIndicator, Option<Arc<Errors>>),
+    // The id of the payload that has to be transmitted before rendering, see encode().
+    held: Option<u64>,
+    // The hash of the escape sequences of the last render, see metrics::PAYLOAD_BYTES.
+    written: Option<u64>,
+}
+
+/// An encoding of a [StatefulProtocol] that waits to be transmitted, see
+/// [StatefulProtocol::encode].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Payload {
+    id: u64,
+    area: Rect,
+    len: usize,
+}
+
+impl Payload {
+    /// Bytes of escape sequences that the transmission puts into the buffer, e.g. sixel data or
+    /// Kitty chunks. Text-based protocols like halfblocks have no payload.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The size of the encoding in cells.
+    pub fn area(&self) -> Rect {
+        self.area
+    }
+}
+
+/// Produces the source image at an exact pixel size, see [Picker::new_generated_protocol].
+#[derive(Clone)]
+struct ImageGenerator {
+    generate: Arc<dyn Fn(u32, u32) -> DynamicImage + Send + Sync>,
+    font_size: FontSize,
+}
+
+/// Filter quality tiers, see [StatefulProtocol::set_filter_tiers].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FilterTiers {
+    /// Used while the area keeps changing, e.g. [FilterType::Nearest] during a window drag.
+    pub interactive: FilterType,
+    /// Used once the area has been stable for `settle`, e.g. [FilterType::Lanczos3].
+    pub final_filter: FilterType,
+    pub settle: Duration,
+}
+
+impl FilterTiers {
+    pub fn new(interactive: FilterType, final_filter: FilterType, settle: Duration) -> Self {
+        FilterTiers {
+            interactive,
+            final_filter,
+            settle,
+        }
+    }
+}
+
+// The area of the last interactive encode, and whether it has been upgraded since.
+#[derive(Clone, Copy)]
+struct TierState {
+    area: Rect,
+    since: Instant,
+    upgraded: bool,
+}
+
+/// The backend of a [StatefulProtocol].
+#[derive(Clone)]
+#[non_exhaustive]
+pub enum StatefulProtocolType {
     Halfblocks(StatefulHalfblocks),
     Sixel(StatefulSixel),
     Kitty(StatefulKitty),
     ITerm2(StatefulIterm2),
+    Terminology(StatefulTerminology),
+    Braille(StatefulBraille),
+    /// A third-party backend, see [ProtocolBackend].
+    Custom(Box<dyn StatefulProtocolTrait>),
 }
-impl StatefulProtocol {
+
+impl StatefulProtocolType {
+    fn kind(&self) -> ProtocolType {
+        match self {
+            Self::Halfblocks(_) => ProtocolType::Halfblocks,
+            Self::Sixel(_) => ProtocolType::Sixel,
+            Self::Kitty(_) => ProtocolType::Kitty,
+            Self::ITerm2(_) => ProtocolType::Iterm2,
+            Self::Terminology(_) => ProtocolType::Terminology,
+            Self::Braille(_) => ProtocolType::Braille,
+            Self::Custom(custom) => ProtocolType::Custom(custom.name()),
+        }
+    }
     fn inner_trait(&self) -> &dyn StatefulProtocolTrait {
         match self {
             Self::Halfblocks(halfblocks) => halfblocks,
             Self::Sixel(sixel) => sixel,
             Self::Kitty(kitty) => kitty,
             Self::ITerm2(iterm2) => iterm2,
+            Self::Terminology(terminology) => terminology,
+            Self::Braille(braille) => braille,
+            Self::Custom(custom) => custom.as_ref(),
         }
     }
     fn inner_trait_mut(&mut self) -> &mut dyn StatefulProtocolTrait {
@@ -106,14 +771,254 @@ impl StatefulProtocol {
             Self::Sixel(sixel) => sixel,
             Self::Kitty(kitty) => kitty,
             Self::ITerm2(iterm2) => iterm2,
+            Self::Terminology(terminology) => terminology,
+            Self::Braille(braille) => braille,
+            Self::Custom(custom) => custom.as_mut(),
         }
     }
+}
+
+/// Forced redraw state, see [StatefulProtocol::force_redraw_next_frame].
+#[derive(Clone, Copy, Default)]
+struct RedrawNonce {
+    pending: bool,
+    // Alternates on every forced redraw, so that two consecutive ones also differ.
+    odd: bool,
+}
+
+// The last result of needs_resize, valid until the encoded state changes.
+#[derive(Clone)]
+struct NeedsResizeCache {
+    resize: Resize,
+    area: Rect,
+    result: Option<Rect>,
+}
+
+impl StatefulProtocol {
+    pub fn new(protocol_type: StatefulProtocolType) -> StatefulProtocol {
+        StatefulProtocol {
+            protocol_type,
+            redraw: RedrawNonce::default(),
+            needs_resize_cache: None,
+            last_request: None,
+            last_encoding_result: None,
+            analysis: None,
+            redacted: false,
+            filter_tiers: None,
+            max_area: None,
+            generator: None,
+            reflow: None,
+            sequence_log: None,
+            area_claim: AreaClaim::default(),
+            content_hint: None,
+            default_dither: None,
+            error_indicator: (ErrorIndicator::default(), None),
+            held: None,
+            written: None,
+        }
+    }
+
+    // Regenerate the source image for every new area instead of resizing it.
+    pub(crate) fn set_generator(
+        &mut self,
+        generate: Arc<dyn Fn(u32, u32) -> DynamicImage + Send + Sync>,
+        font_size: FontSize,
+    ) {
+        self.generator = Some(ImageGenerator {
+            generate,
+            font_size,
+        });
+        self.needs_resize_cache = None;
+    }
+
+    /// Refresh after the terminal reflows, with the rate limit of the tracker, see
+    /// [crate::reflow].
+    pub fn set_reflow_tracker(&mut self, tracker: Option<ReflowTracker>) {
+        self.reflow = tracker.map(|tracker| {
+            let generation = tracker.generation();
+            (tracker, generation)
+        });
+    }
+
+    /// Write the escape sequences of each changed render to a log, see [crate::sequence_log].
+    pub fn set_sequence_log(&mut self, log: Option<SequenceLog>) {
+        self.sequence_log = log.map(|log| (log, None));
+    }
+
+    /// Which cells of the render area are claimed when the image is smaller, see
+    /// [Picker::set_area_claim].
+    pub fn set_area_claim(&mut self, area_claim: AreaClaim) {
+        self.area_claim = area_claim;
+    }
+
+    /// Show failed encodings and transmissions, see [ErrorIndicator].
+    pub fn set_error_indicator(&mut self, error_indicator: ErrorIndicator) {
+        self.error_indicator.0 = error_indicator;
+    }
+
+    /// Resize, scale and dither to suit the kind of image, see [ContentHint]. Takes effect on the
+    /// next resize and encode. `None` restores the dither of the backend.
+    pub fn set_content_hint(&mut self, content_hint: Option<ContentHint>) {
+        self.content_hint = content_hint;
+        match content_hint {
+            Some(hint) => {
+                let dither = self.inner_trait().dither();
+                self.default_dither.get_or_insert(dither);
+                self.inner_trait_mut().set_dither(hint.dither());
+            }
+            None => {
+                if let Some(dither) = self.default_dither.take() {
+                    self.inner_trait_mut().set_dither(dither);
+                }
+            }
+        }
+        self.needs_resize_cache = None;
+    }
+
+    /// Place the image below (negative) or above text, see [kitty::KittyOptions::z_index]. An
+    /// image that was transmitted already is placed again. Only applies to Kitty.
+    pub fn set_z_index(&mut self, z_index: i32) {
+        if let StatefulProtocolType::Kitty(kitty) = &mut self.protocol_type {
+            kitty.set_z_index(z_index);
+        }
+    }
+
+    // The resize with the filter and scaling of the content hint filled in, for an area.
+    fn hinted_resize(&self, resize: &Resize, area: Rect) -> Option<Resize> {
+        let hint = self.content_hint?;
+        if resize.filter_type().is_some() {
+            return None;
+        }
+        match resize {
+            Resize::Fit(_) | Resize::Scale(_) if hint == ContentHint::PixelArt => {
+                let (font_width, font_height) = self.inner_trait().font_size();
+                let screen = (
+                    area.width as u32 * font_width as u32,
+                    area.height as u32 * font_height as u32,
+                );
+                let image = &self.inner_trait().source().image;
+                let upscale = matches!(resize, Resize::Scale(_));
+                let zoom = crate::integer_zoom((image.width(), image.height()), screen, upscale)?;
+                Some(Resize::Viewport(ViewportOptions {
+                    zoom,
+                    offset: (0, 0),
+                    filter_type: Some(FilterType::Nearest),
+                }))
+            }
+            _ => resize.with_filter(hint.filter_type()),
+        }
+    }
+
+    /// The backend of this protocol.
+    pub fn protocol_type(&self) -> &StatefulProtocolType {
+        &self.protocol_type
+    }
+
+    /// The backend of this protocol, mutably.
+    pub fn protocol_type_mut(&mut self) -> &mut StatefulProtocolType {
+        self.needs_resize_cache = None;
+        self.analysis = None;
+        &mut self.protocol_type
+    }
+
+    // The original image, before any resizing.
+    pub(crate) fn source_image(&self) -> &DynamicImage {
+        &self.inner_trait().source().image
+    }
+
+    /// The font size that the image is resized for.
+    pub fn font_size(&self) -> FontSize {
+        self.inner_trait().font_size()
+    }
+
+    /// Resize the image for another font size, e.g. after the terminal was zoomed, see
+    /// [Picker::refresh_font_size]. The next [StatefulProtocol::needs_resize] encodes it again.
+    ///
+    /// A generated image (see [Picker::new_generated_protocol]) is only generated again when
+    /// its area changes.
+    pub fn set_font_size(&mut self, font_size: FontSize) {
+        if font_size == self.font_size() || font_size.0 == 0 || font_size.1 == 0 {
+            return;
+        }
+        let inner = self.inner_trait_mut();
+        inner.set_font_size(font_size);
+        let source = inner.source_mut();
+        source.desired = ImageSource::round_pixel_size_to_cells(
+            source.image.width(),
+            source.image.height(),
+            font_size,
+        );
+        if let Some(generator) = &mut self.generator {
+            generator.font_size = font_size;
+        }
+        self.needs_resize_cache = None;
+        self.redraw.pending = true;
+    }
+
+    /// Replace the image, e.g. the next frame of a chart on a dashboard. The next
+    /// [StatefulProtocol::needs_resize] encodes it again.
+    ///
+    /// With [kitty::KittyOptions::diff_updates], only the region that changed since the last
+    /// transmission is sent to the terminal, if the image keeps its size. The other protocols
+    /// encode and send the whole image.
+    pub fn set_image(&mut self, image: DynamicImage) {
+        let font_size = self.font_size();
+        let background_color = self.background_color();
+        let source = self.inner_trait_mut().source_mut();
+        let watermark = source.watermark.take();
+        *source = ImageSource::new(image, font_size, background_color).with_watermark(watermark);
+        self.analysis = None;
+        self.needs_resize_cache = None;
+    }
+
+    /// Switch to the backend of another picker, e.g. from halfblocks to Kitty when the
+    /// capability detection finished after the first frames, see [Picker::adopt_protocol].
+    ///
+    /// The backend is built from the current image, with the protocol type, font size and
+    /// settings of the picker. Settings of this protocol like [StatefulProtocol::redact] are
+    /// kept. It is encoded right away for the area and [Resize] of the last render, so that the
+    /// next render shows the image without any more work.
+    pub fn upgrade_protocol(&mut self, picker: &Picker) {
+        let replacement = picker.new_resize_protocol(self.source_image().clone());
+        self.max_area = replacement.max_area;
+        self.reflow = replacement.reflow;
+        self.sequence_log = replacement.sequence_log;
+        if let Some(generator) = &mut self.generator {
+            generator.font_size = picker.font_size();
+        }
+        self.protocol_type = replacement.protocol_type;
+        self.needs_resize_cache = None;
+        self.last_encoding_result = None;
+        self.analysis = None;
+        if let Some((resize, area)) = self.last_request.take() {
+            if let Some(rect) = self.needs_resize(&resize, area) {
+                self.resize_encode(&resize, self.background_color(), rect);
+            }
+        }
+    }
+
+    fn inner_trait(&self) -> &dyn StatefulProtocolTrait {
+        self.protocol_type.inner_trait()
+    }
+    fn inner_trait_mut(&mut self) -> &mut dyn StatefulProtocolTrait {
+        self.protocol_type.inner_trait_mut()
+    }
 
     pub fn background_color(&self) -> Rgba<u8> {
         let proto = self.inner_trait();
         proto.background_color()
     }
 
+    /// Make sure that the image is sent to the terminal again on the next render.
+    ///
+    /// ratatui only writes cells that changed since the last frame, so if the terminal lost the
+    /// graphics (e.g. after `clear`) but the buffer did not change, nothing would be re-sent.
+    /// This appends a tiny, invisible, varying suffix (save and restore cursor) to the cell that
+    /// carries the image data, so that it is always considered changed.
+    pub fn force_redraw_next_frame(&mut self) {
+        self.redraw.pending = true;
+    }
+
     /// Resize and encode if necessary, and render immediately.
     ///
     /// This blocks the UI thread but requires neither threads nor async.
@@ -124,20 +1029,106 @@ impl StatefulProtocol {
         area: Rect,
         buf: &mut Buffer,
     ) {
-        let proto = self.inner_trait_mut();
-        if let Some(rect) = proto.needs_resize(resize, area) {
-            proto.resize_encode(resize, background_color, rect);
+        if let Some(rect) = self.needs_resize(resize, area) {
+            self.resize_encode(resize, background_color, rect);
         }
-        proto.render(area, buf);
+        self.render(area, buf);
     }
 
     /// Check if the current image state would need resizing (grow or shrink) for the given area.
     ///
     /// This can be called by the UI thread to check if this [StatefulProtocol] should be sent off
     /// to some background thread/task to do the resizing and encoding, instead of rendering. The
-    /// thread should then return the [StatefulProtocol] so that it can be rendered.protoco
+    /// thread should then return the [StatefulProtocol] so that it can be rendered.
+    ///
+    /// The result is cached until the next [StatefulProtocol::resize_encode], so that steady-state
+    /// frames only compare the area and resize method.
     pub fn needs_resize(&mut self, resize: &Resize, area: Rect) -> Option<Rect> {
-        self.inner_trait_mut().needs_resize(resize, area)
+        if let Some((tracker, generation)) = &mut self.reflow {
+            let current = tracker.generation();
+            if *generation != current {
+                if !tracker.try_refresh() {
+                    // Wait for a later frame, and keep rendering the previous encoding.
+                    return None;
+                }
+                *generation = current;
+                self.needs_resize_cache = None;
+                self.redraw.pending = true;
+            }
+        }
+        if let Some(cache) = &self.needs_resize_cache {
+            if cache.area == area && cache.resize == *resize {
+                return cache.result;
+            }
+        }
+        let clamped = clamp_area(area, self.max_area);
+        let current = self.area();
+        let result = match self.generator {
+            // A generated image always fills the area exactly.
+            Some(_) if (current.width, current.height) != (clamped.width, clamped.height) => {
+                Some(Rect::new(0, 0, clamped.width, clamped.height))
+            }
+            _ => {
+                let hinted = self.hinted_resize(resize, clamped);
+                let resize = hinted.as_ref().unwrap_or(resize);
+                self.inner_trait_mut().needs_resize(resize, clamped)
+            }
+        };
+        self.needs_resize_cache = Some(NeedsResizeCache {
+            resize: resize.clone(),
+            area,
+            result,
+        });
+        self.last_request = Some((resize.clone(), area));
+        result.or_else(|| self.needs_filter_upgrade(resize))
+    }
+
+    /// Never resize the image to more than `(width, height)` cells, see
+    /// [Picker::set_max_image_size].
+    pub fn set_max_area(&mut self, max_area: Option<(u16, u16)>) {
+        self.max_area = max_area;
+        self.needs_resize_cache = None;
+    }
+
+    /// Encode with a fast filter while the area changes, and again with a better one once it has
+    /// been stable for a while. Only applies to [Resize::Fit] and [Resize::Scale].
+    ///
+    /// The upgrade happens on the first [StatefulProtocol::needs_resize] after `settle`, so the
+    /// app must keep rendering, e.g. on a tick, for it to happen without other events.
+    pub fn set_filter_tiers(&mut self, filter_tiers: Option<FilterTiers>) {
+        self.filter_tiers = filter_tiers.map(|tiers| Box::new((tiers, None)));
+    }
+
+    // The current area again, if it is due for the final filter. Not cached in needs_resize_cache
+    // because it depends on the time.
+    fn needs_filter_upgrade(&self, resize: &Resize) -> Option<Rect> {
+        let (tiers, state) = self.filter_tiers.as_deref()?;
+        let state = (*state)?;
+        let due = !state.upgraded && state.since.elapsed() >= tiers.settle;
+        (due && resize.with_filter(tiers.final_filter).is_some()).then_some(state.area)
+    }
+
+    // The resize with the filter of the current tier, updating the tier state.
+    fn tiered_resize(&mut self, resize: &Resize, area: Rect) -> Option<Resize> {
+        let (tiers, state) = self.filter_tiers.as_deref_mut()?;
+        match state {
+            Some(state)
+                if state.area == area
+                    && !state.upgraded
+                    && state.since.elapsed() >= tiers.settle =>
+            {
+                state.upgraded = true;
+                resize.with_filter(tiers.final_filter)
+            }
+            _ => {
+                *state = Some(TierState {
+                    area,
+                    since: Instant::now(),
+                    upgraded: false,
+                });
+                resize.with_filter(tiers.interactive)
+            }
+        }
     }
 
     /// Resize the image and encode it for rendering. The result should be stored statefully so
@@ -145,17 +1136,331 @@ impl StatefulProtocol {
     ///
     /// This can be done in a background thread, and the result is stored in this [StatefulProtocol].
     pub fn resize_encode(&mut self, resize: &Resize, background_color: Rgba<u8>, area: Rect) {
-        self.inner_trait_mut()
-            .resize_encode(resize, background_color, area)
+        self.needs_resize_cache = None;
+        if let Some(generator) = &self.generator {
+            let (font_width, font_height) = generator.font_size;
+            let image = (generator.generate)(
+                area.width as u32 * font_width as u32,
+                area.height as u32 * font_height as u32,
+            );
+            let source = ImageSource::new(image, generator.font_size, background_color);
+            let previous = self.inner_trait_mut().source_mut();
+            let watermark = previous.watermark.take();
+            *previous = source.with_watermark(watermark);
+            self.analysis = None;
+        }
+        if self.analysis.is_none() {
+            // Here, because this is where a ThreadProtocol does the heavy lifting.
+            self.analysis = Some(Arc::new(ImageAnalysis::new(
+                &self.inner_trait().source().image,
+            )));
+        }
+        // The encoded area fits the same integer zoom as the requested area it was calculated
+        // from, so the hint does not depend on the last needs_resize.
+        let hinted = self.hinted_resize(resize, area);
+        let resize = hinted.as_ref().unwrap_or(resize);
+        let tiered = self.tiered_resize(resize, area);
+        let timer = metrics::EncodeTimer::start(self.protocol_type.kind());
+        let result = self.inner_trait_mut().resize_encode(
+            tiered.as_ref().unwrap_or(resize),
+            background_color,
+            area,
+        );
+        drop(timer);
+        self.last_encoding_result = Some(result.map_err(Arc::new));
+    }
+
+    // Bytes of the escape sequences that the next render of the current encoding puts into the
+    // buffer.
+    fn transmit_len(&self) -> usize {
+        let area = self.area();
+        let mut buf = Buffer::empty(area);
+        self.inner_trait().render_placement(area, &mut buf);
+        let placement_len: usize = buf
+            .content()
+            .iter()
+            .map(|cell| cell.symbol())
+            .filter(|symbol| symbol.contains('\x1b'))
+            .map(str::len)
+            .sum();
+        placement_len + self.inner_trait().pending_transmit_len()
+    }
+
+    /// Resize and encode if necessary like [StatefulProtocol::resize_encode], but hold the new
+    /// encoding back until the returned [Payload] is passed to [StatefulProtocol::transmit].
+    ///
+    /// Until then, renders draw nothing, so that a scheduler can decide in which frame the bytes
+    /// hit the terminal, e.g. to stay within a bandwidth budget:
+    ///
+    /// ```rust
+    /// # use ratatui::layout::Rect;
+    /// # use ratatui_image::{protocol::{Payload, StatefulProtocol}, Resize};
+    /// struct Scheduled {
+    ///     protocol: StatefulProtocol,
+    ///     area: Rect,
+    ///     pending: Option<Payload>,
+    /// }
+    /// // Transmit up to 64 KiB per frame, and at least one payload so that large ones get through.
+    /// fn schedule(images: &mut [Scheduled]) {
+    ///     let mut budget = 64 * 1024;
+    ///     let mut first = true;
+    ///     for image in images {
+    ///         if let Some(payload) = image.protocol.encode(&Resize::Fit(None), image.area) {
+    ///             image.pending = Some(payload);
+    ///         }
+    ///         if let Some(payload) = image.pending.take_if(|p| first || p.len() <= budget) {
+    ///             budget = budget.saturating_sub(payload.len());
+    ///             first = false;
+    ///             image.protocol.transmit(payload);
+    ///         }
+    ///     }
+    /// }
+    /// ```
+    ///
+    /// Returns `None` if the current encoding fits the area, in which case an earlier payload may
+    /// still be pending.
+    pub fn encode(&mut self, resize: &Resize, area: Rect) -> Option<Payload> {
+        static IDS: AtomicU64 = AtomicU64::new(0);
+        let rect = self.needs_resize(resize, area)?;
+        self.resize_encode(resize, self.background_color(), rect);
+        let id = IDS.fetch_add(1, Ordering::Relaxed);
+        self.held = Some(id);
+
+        Some(Payload {
+            id,
+            area: self.area(),
+            len: self.transmit_len(),
+        })
+    }
+
+    /// Release the encoding of a [Payload] from [StatefulProtocol::encode], so that the next
+    /// render puts it into the buffer. Payloads of older encodings are ignored.
+    pub fn transmit(&mut self, payload: Payload) {
+        if self.held == Some(payload.id) {
+            self.held = None;
+        }
+    }
+
+    /// Whether an encoding is held back until its [Payload] is transmitted.
+    pub fn is_held(&self) -> bool {
+        self.held.is_some()
+    }
+
+    /// Dominant colors and luminance of the source image, for theming the UI around it.
+    ///
+    /// This is computed along with the first [StatefulProtocol::resize_encode], so that it runs
+    /// on the worker thread with [crate::thread::ThreadProtocol]. `None` until then.
+    pub fn analysis(&self) -> Option<&ImageAnalysis> {
+        self.analysis.as_deref()
+    }
+
+    /// The result of the last [StatefulProtocol::resize_encode], or `None` if it has not been
+    /// encoded yet.
+    ///
+    /// On errors, the previous encoding (if any) is kept and rendered.
+    pub fn last_encoding_result(&self) -> Option<std::result::Result<(), &Errors>> {
+        self.last_encoding_result
+            .as_ref()
+            .map(|result| result.as_ref().map(|_| ()).map_err(|err| &**err))
+    }
+
+    /// The escape sequence that deletes a Kitty image from the terminal's memory, or `None` for
+    /// other protocols. The image is transmitted again on the next resize and encode.
+    ///
+    /// See also [kitty::KittyOptions::delete_on_drop].
+    pub fn delete(&mut self) -> Option<String> {
+        match &mut self.protocol_type {
+            StatefulProtocolType::Kitty(kitty) => {
+                self.needs_resize_cache = None;
+                Some(kitty.delete())
+            }
+            _ => None,
+        }
+    }
+
+    /// Record a Kitty transmission response in [StatefulProtocol::last_encoding_result], if it is
+    /// for this protocol's image. Returns whether it was.
+    ///
+    /// See [kitty::KittyOptions::acknowledge].
+    pub fn handle_kitty_ack(&mut self, ack: &kitty::KittyAck) -> bool {
+        match &self.protocol_type {
+            StatefulProtocolType::Kitty(kitty) if kitty.unique_id == ack.id => {
+                self.last_encoding_result = Some(match &ack.error {
+                    None => Ok(()),
+                    Some(error) => Err(Arc::new(Errors::Kitty(error.clone()))),
+                });
+                true
+            }
+            _ => false,
+        }
     }
 
     /// Render the currently resized and encoded data to the buffer.
     pub fn render(&mut self, area: Rect, buf: &mut Buffer) {
+        if self.redacted {
+            self.render_redacted(area, buf);
+            self.audit(area, buf, false);
+            return;
+        }
+        if self.held.is_some() {
+            return;
+        }
         self.inner_trait_mut().render(area, buf);
+        if self.area_claim == AreaClaim::Area {
+            let image = self.area();
+            let image = Rect::new(area.x, area.y, image.width, image.height);
+            erase_uncovered(area, image.intersection(area), buf);
+        }
+        self.audit(area, buf, false);
+
+        if self.redraw.pending {
+            self.redraw.pending = false;
+            self.redraw.odd = !self.redraw.odd;
+            if let Some(cell) = buf.cell_mut((area.x, area.y)) {
+                let suffix = if self.redraw.odd {
+                    "\x1b7\x1b8"
+                } else {
+                    "\x1b7\x1b8\x1b7\x1b8"
+                };
+                let symbol = format!("{}{suffix}", cell.symbol());
+                cell.set_symbol(&symbol);
+            }
+        }
+
+        self.render_error_indicator(area, buf);
+
+        let protocol = self.protocol_type.kind();
+        metrics::payload_written(protocol, area, buf, &mut self.written);
+        if let Some((log, last)) = &mut self.sequence_log {
+            *last = log.record(protocol, area, buf, *last);
+        }
+    }
+
+    // Mark the area if the last encoding failed, see ErrorIndicator.
+    fn render_error_indicator(&mut self, area: Rect, buf: &mut Buffer) {
+        let (indicator, notified) = &mut self.error_indicator;
+        let Some(Err(err)) = &self.last_encoding_result else {
+            return;
+        };
+        let area = area.intersection(buf.area);
+        if *indicator == ErrorIndicator::Off || area.is_empty() {
+            return;
+        }
+        if *indicator == ErrorIndicator::Notify
+            && !notified
+                .as_ref()
+                .is_some_and(|notified| Arc::ptr_eq(notified, err))
+        {
+            cleanup::queue_notification(&err.to_string());
+            *notified = Some(err.clone());
+        }
+        let cell = &mut buf[(area.right() - 1, area.bottom() - 1)];
+        cell.reset();
+        cell.set_symbol("!")
+            .set_style(Style::new().fg(Color::White).bg(Color::Red));
     }
     pub fn area(&self) -> Rect {
         self.inner_trait().area()
     }
+
+    /// Render the current encoding at another area, as an additional placement of the same image.
+    ///
+    /// See [crate::PlacementView].
+    pub fn render_placement(&self, area: Rect, buf: &mut Buffer) {
+        if self.redacted {
+            self.render_redacted(area, buf);
+        } else {
+            self.inner_trait().render_placement(area, buf);
+        }
+        self.audit(area, buf, true);
+    }
+
+    fn audit(&self, area: Rect, buf: &Buffer, placement: bool) {
+        if audit::is_enabled() {
+            let skipped = self.redacted.then_some(SkipReason::Redacted);
+            let protocol = self.protocol_type.kind();
+            audit::record(protocol, area, self.area(), buf, placement, skipped);
+        }
+    }
+
+    /// The pixel of the source image that is shown at a screen position, e.g. under the mouse
+    /// cursor for an eyedropper or a tooltip. `cell` is the column and row in the terminal, and
+    /// `offset` the pixel within that cell, `(0, 0)` being its top left corner.
+    ///
+    /// Follows the resize and area of the last render. `None` outside of the image, on its
+    /// padding, and before the first render.
+    pub fn pixel_at(&self, cell: (u16, u16), offset: (u16, u16)) -> Option<Rgba<u8>> {
+        let (resize, area) = self.last_request.as_ref()?;
+        let (font_width, font_height) = self.inner_trait().font_size();
+        let rect = self.area();
+        let column = cell.0.checked_sub(area.x)?;
+        let row = cell.1.checked_sub(area.y)?;
+        if column >= rect.width
+            || row >= rect.height
+            || offset.0 >= font_width
+            || offset.1 >= font_height
+        {
+            return None;
+        }
+        let hinted = self.hinted_resize(resize, clamp_area(*area, self.max_area));
+        let resize = hinted.as_ref().unwrap_or(resize);
+        let image = &self.inner_trait().source().image;
+        let (x, y) = resize.source_pixel(
+            (image.width(), image.height()),
+            rect.width as u32 * font_width as u32,
+            rect.height as u32 * font_height as u32,
+            (
+                column as u32 * font_width as u32 + offset.0 as u32,
+                row as u32 * font_height as u32 + offset.1 as u32,
+            ),
+        )?;
+        Some(image.get_pixel(x, y))
+    }
+
+    /// Render a hatched placeholder instead of the image, e.g. while screen sharing.
+    ///
+    /// The encoded data is kept, so that un-redacting shows the image again without any
+    /// resizing or encoding.
+    pub fn redact(&mut self, redacted: bool) {
+        self.redacted = redacted;
+    }
+
+    /// Whether the image is currently redacted, see [StatefulProtocol::redact].
+    pub fn is_redacted(&self) -> bool {
+        self.redacted
+    }
+
+    // Fill the area that the image would cover, overwriting any graphics left in the terminal.
+    fn render_redacted(&self, area: Rect, buf: &mut Buffer) {
+        let image_area = self.area();
+        let placeholder = Rect::new(area.x, area.y, image_area.width, image_area.height)
+            .intersection(area)
+            .intersection(buf.area);
+        for y in placeholder.top()..placeholder.bottom() {
+            for x in placeholder.left()..placeholder.right() {
+                let cell = &mut buf[(x, y)];
+                cell.reset();
+                cell.set_symbol("╱")
+                    .set_style(Style::new().fg(Color::DarkGray));
+            }
+        }
+    }
+}
+
+impl ResizeEncodeRender for StatefulProtocol {
+    fn needs_resize(&mut self, resize: &Resize, area: Rect) -> Option<Rect> {
+        StatefulProtocol::needs_resize(self, resize, area)
+    }
+    fn resize_encode(&mut self, resize: &Resize, area: Rect) {
+        let background_color = self.background_color();
+        StatefulProtocol::resize_encode(self, resize, background_color, area)
+    }
+    fn render(&mut self, area: Rect, buf: &mut Buffer) {
+        StatefulProtocol::render(self, area, buf)
+    }
+    fn set_z_index(&mut self, z_index: i32) {
+        StatefulProtocol::set_z_index(self, z_index)
+    }
 }
 
 #[derive(Clone)]
@@ -183,6 +1488,8 @@ pub struct ImageSource {
     pub hash: u64,
     /// The background color that should be used for padding or background when resizing.
     pub background_color: Rgba<u8>,
+    /// Composited onto the image after resizing, see [ImageSource::with_watermark].
+    pub(crate) watermark: Option<Watermark>,
 }
 
 impl ImageSource {
@@ -212,8 +1519,21 @@ impl ImageSource {
             desired,
             hash,
             background_color,
+            watermark: None,
         }
     }
+    /// Composite a watermark onto the image after resizing, see
+    /// [crate::picker::Picker::set_watermark]. The [ImageSource::hash] covers the watermark
+    /// too.
+    pub fn with_watermark(mut self, watermark: Option<Watermark>) -> ImageSource {
+        if let Some(watermark) = &watermark {
+            let mut state = DefaultHasher::new();
+            (self.hash, watermark).hash(&mut state);
+            self.hash = state.finish();
+        }
+        self.watermark = watermark;
+        self
+    }
     /// Round an image pixel size to the nearest matching cell size, given a font size.
     pub fn round_pixel_size_to_cells(
         img_width: u32,
@@ -225,3 +1545,676 @@ impl ImageSource {
         Rect::new(0, 0, width, height)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use image::{DynamicImage, ImageBuffer, Rgba};
+    use ratatui::{buffer::Buffer, layout::Rect};
+
+    use super::erase_uncovered;
+    use crate::{
+        picker::{Picker, ProtocolType},
+        FilterType,
+    };
+
+    #[test]
+    fn test_erase_uncovered() {
+        let mut buf = Buffer::empty(Rect::new(0, 0, 6, 4));
+        erase_uncovered(Rect::new(0, 0, 6, 4), Rect::new(0, 0, 4, 2), &mut buf);
+        assert_eq!(buf[(4, 0)].symbol(), "\x1b[2X ");
+        assert_eq!(buf[(4, 1)].symbol(), "\x1b[2X ");
+        assert_eq!(buf[(0, 2)].symbol(), "\x1b[6X ");
+        assert_eq!(buf[(0, 3)].symbol(), "\x1b[6X ");
+        assert_eq!(buf[(0, 0)].symbol(), " ");
+        assert_eq!(buf[(5, 0)].symbol(), " ");
+    }
+
+    #[test]
+    fn test_needs_resize_cache() {
+        let mut picker = Picker::from_fontsize((10, 20));
+        picker.set_protocol_type(ProtocolType::Halfblocks);
+        let image: DynamicImage =
+            ImageBuffer::from_pixel(40, 40, Rgba::<u8>([255, 0, 0, 255])).into();
+        let mut protocol = picker.new_resize_protocol(image);
+        let resize = crate::Resize::Fit(None);
+        let background_color = protocol.background_color();
+
+        let area = Rect::new(0, 0, 10, 10);
+        assert_eq!(
+            protocol.needs_resize(&resize, area),
+            Some(Rect::new(0, 0, 4, 2))
+        );
+        assert_eq!(
+            protocol.needs_resize(&resize, area),
+            Some(Rect::new(0, 0, 4, 2))
+        );
+        protocol.resize_encode(&resize, background_color, Rect::new(0, 0, 4, 2));
+        assert_eq!(protocol.needs_resize(&resize, area), None);
+        assert_eq!(protocol.needs_resize(&resize, area), None);
+
+        let smaller = Rect::new(0, 0, 2, 2);
+        assert_eq!(
+            protocol.needs_resize(&resize, smaller),
+            Some(Rect::new(0, 0, 2, 1))
+        );
+        let crop = crate::Resize::Crop(None);
+        assert_eq!(
+            protocol.needs_resize(&crop, smaller),
+            Some(Rect::new(0, 0, 2, 2))
+        );
+    }
+
+    #[test]
+    fn test_set_font_size() {
+        let image: DynamicImage =
+            ImageBuffer::from_pixel(40, 40, Rgba::<u8>([255, 0, 0, 255])).into();
+        for protocol_type in [ProtocolType::Halfblocks, ProtocolType::Sixel] {
+            let mut picker = Picker::from_fontsize((10, 20));
+            picker.set_protocol_type(protocol_type);
+            let mut protocol = picker.new_resize_protocol(image.clone());
+            let resize = crate::Resize::Fit(None);
+            let area = Rect::new(0, 0, 10, 10);
+            let mut buf = Buffer::empty(area);
+            protocol.resize_encode_render(&resize, protocol.background_color(), area, &mut buf);
+            assert_eq!(protocol.area(), Rect::new(0, 0, 4, 2));
+
+            protocol.set_font_size((10, 20));
+            assert_eq!(protocol.needs_resize(&resize, area), None);
+            // Zoomed out, the same image covers twice the cells.
+            protocol.set_font_size((5, 10));
+            assert_eq!(protocol.font_size(), (5, 10));
+            assert_eq!(
+                protocol.needs_resize(&resize, area),
+                Some(Rect::new(0, 0, 8, 4))
+            );
+        }
+    }
+
+    #[test]
+    fn test_upgrade_protocol() {
+        let image: DynamicImage =
+            ImageBuffer::from_pixel(40, 40, Rgba::<u8>([255, 0, 0, 255])).into();
+        let mut picker = Picker::from_fontsize((10, 20));
+        picker.set_protocol_type(ProtocolType::Halfblocks);
+        let mut protocol = picker.new_resize_protocol(image.clone());
+        let resize = crate::Resize::Fit(None);
+        let area = Rect::new(0, 0, 10, 10);
+        let mut buf = Buffer::empty(area);
+        protocol.resize_encode_render(&resize, protocol.background_color(), area, &mut buf);
+        assert_eq!(protocol.area(), Rect::new(0, 0, 4, 2));
+
+        picker.set_protocol_type(ProtocolType::Kitty);
+        protocol.upgrade_protocol(&picker);
+        assert!(matches!(
+            protocol.protocol_type(),
+            super::StatefulProtocolType::Kitty(_)
+        ));
+        // Already encoded for the same area.
+        assert_eq!(protocol.area(), Rect::new(0, 0, 4, 2));
+        assert_eq!(protocol.needs_resize(&resize, area), None);
+        let mut buf = Buffer::empty(area);
+        protocol.render(area, &mut buf);
+        assert!(buf[(0, 0)].symbol().contains("_Gq=2"));
+    }
+
+    #[test]
+    fn test_encode_transmit() {
+        let image: DynamicImage =
+            ImageBuffer::from_pixel(40, 40, Rgba::<u8>([255, 0, 0, 255])).into();
+        let mut picker = Picker::from_fontsize((10, 20));
+        picker.set_protocol_type(ProtocolType::Kitty);
+        let mut protocol = picker.new_resize_protocol(image);
+        let resize = crate::Resize::Fit(None);
+        let area = Rect::new(0, 0, 10, 10);
+
+        let stale = protocol.encode(&resize, Rect::new(0, 0, 2, 1)).unwrap();
+        let payload = protocol.encode(&resize, area).unwrap();
+        assert_eq!(protocol.encode(&resize, area), None);
+        assert_eq!(payload.area(), Rect::new(0, 0, 4, 2));
+        // 40x40 RGBA pixels, padded to 40x40, in base64.
+        assert!(payload.len() > 40 * 40 * 4 * 4 / 3);
+
+        // Nothing is rendered until the current payload is transmitted.
+        protocol.transmit(stale);
+        assert!(protocol.is_held());
+        let mut buf = Buffer::empty(area);
+        protocol.render(area, &mut buf);
+        assert_eq!(buf, Buffer::empty(area));
+
+        let len = payload.len();
+        protocol.transmit(payload);
+        assert!(!protocol.is_held());
+        protocol.render(area, &mut buf);
+        let symbol = buf[(0, 0)].symbol();
+        assert!(symbol.contains("_Gq=2,i="));
+        assert!(symbol.len() <= len);
+    }
+
+    #[test]
+    fn test_shared_encodings() {
+        use super::{
+            halfblocks::StatefulHalfblocks, iterm2::StatefulIterm2, sixel::StatefulSixel,
+            ImageSource, SharedEncodings, StatefulProtocol, StatefulProtocolType,
+        };
+
+        let image: DynamicImage =
+            ImageBuffer::from_pixel(40, 40, Rgba::<u8>([255, 0, 0, 255])).into();
+        let shared = SharedEncodings::new();
+        let new = |kind: usize| {
+            let source = ImageSource::new(image.clone(), (10, 20), Rgba([0, 0, 0, 0]));
+            let shared = Some(shared.clone());
+            StatefulProtocol::new(match kind {
+                0 => StatefulProtocolType::Halfblocks(
+                    StatefulHalfblocks::new(source, (10, 20)).shared(shared),
+                ),
+                1 => StatefulProtocolType::Sixel(
+                    StatefulSixel::new(source, (10, 20), false).shared(shared),
+                ),
+                _ => StatefulProtocolType::ITerm2(
+                    StatefulIterm2::new(source, (10, 20), false).shared(shared),
+                ),
+            })
+        };
+        let resize = crate::Resize::Fit(None);
+        let (area, smaller) = (Rect::new(0, 0, 4, 2), Rect::new(0, 0, 2, 1));
+
+        for kind in 0..3 {
+            let (mut first, mut second) = (new(kind), new(kind));
+            let background_color = first.background_color();
+            first.resize_encode(&resize, background_color, area);
+            second.resize_encode(&resize, background_color, area);
+            assert_eq!(shared.len(), 1);
+
+            let (mut buf_first, mut buf_second) = (Buffer::empty(area), Buffer::empty(area));
+            first.render(area, &mut buf_first);
+            second.render(area, &mut buf_second);
+            assert!(buf_first == buf_second);
+
+            second.resize_encode(&resize, background_color, smaller);
+            assert_eq!(shared.len(), 2);
+            drop(first);
+            assert_eq!(shared.len(), 1);
+            drop(second);
+            assert!(shared.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_handle_kitty_ack() {
+        use super::kitty::KittyAck;
+
+        let mut picker = Picker::from_fontsize((10, 20));
+        picker.set_protocol_type(ProtocolType::Kitty);
+        picker.set_deterministic_seed(7);
+        let image: DynamicImage =
+            ImageBuffer::from_pixel(20, 40, Rgba::<u8>([255, 0, 0, 255])).into();
+        let mut protocol = picker.new_resize_protocol(image);
+        let background_color = protocol.background_color();
+        protocol.resize_encode(
+            &crate::Resize::Fit(None),
+            background_color,
+            Rect::new(0, 0, 2, 2),
+        );
+        assert!(matches!(protocol.last_encoding_result(), Some(Ok(()))));
+
+        let acks = KittyAck::parse("\x1b_Gi=8;OK\x1b\\\x1b_Gi=7;ENOMEM:out of memory\x1b\\");
+        assert!(!protocol.handle_kitty_ack(&acks[0]));
+        assert!(protocol.handle_kitty_ack(&acks[1]));
+        assert_eq!(
+            protocol
+                .last_encoding_result()
+                .unwrap()
+                .unwrap_err()
+                .to_string(),
+            "Kitty error: ENOMEM:out of memory"
+        );
+    }
+
+    #[test]
+    fn test_error_indicator() {
+        use super::kitty::KittyAck;
+
+        let mut picker = Picker::from_fontsize((10, 20));
+        picker.set_protocol_type(ProtocolType::Kitty);
+        picker.set_deterministic_seed(7);
+        picker.set_error_indicator(crate::ErrorIndicator::Notify);
+        let image: DynamicImage =
+            ImageBuffer::from_pixel(20, 40, Rgba::<u8>([255, 0, 0, 255])).into();
+        let mut protocol = picker.new_resize_protocol(image);
+        let area = Rect::new(0, 0, 3, 3);
+        let render = |protocol: &mut super::StatefulProtocol| {
+            let mut buf = Buffer::empty(area);
+            crate::ResizeEncodeRender::resize_encode_render(
+                protocol,
+                &crate::Resize::Fit(None),
+                area,
+                &mut buf,
+            );
+            buf[(2, 2)].symbol().to_string()
+        };
+        assert_eq!(render(&mut protocol), " ");
+
+        let acks = KittyAck::parse("\x1b_Gi=7;ENOMEM:out of memory\x1b\\");
+        assert!(protocol.handle_kitty_ack(&acks[0]));
+        // Notified once out of band, and marked.
+        let notification = "\x1b]9;ratatui-image: Kitty error: ENOMEM:out of memory\x07";
+        assert_eq!(render(&mut protocol), "!");
+        assert!(crate::cleanup::take_notification_sequence().contains(notification));
+        assert_eq!(render(&mut protocol), "!");
+        assert!(!crate::cleanup::take_notification_sequence().contains(notification));
+
+        protocol.set_error_indicator(crate::ErrorIndicator::Off);
+        assert_eq!(render(&mut protocol), " ");
+    }
+
+    #[test]
+    fn test_content_hint_pixel_art() {
+        let mut picker = Picker::from_fontsize((10, 20));
+        picker.set_protocol_type(ProtocolType::Halfblocks);
+        let encoded_area = |width, height, resize: crate::Resize, area: Rect| {
+            let image: DynamicImage =
+                ImageBuffer::from_pixel(width, height, Rgba::<u8>([255, 0, 0, 255])).into();
+            let mut protocol = picker.new_resize_protocol(image);
+            protocol.set_content_hint(Some(crate::ContentHint::PixelArt));
+            let rect = protocol.needs_resize(&resize, area).unwrap();
+            protocol.resize_encode(&resize, protocol.background_color(), rect);
+            let encoded = protocol.area();
+
+            // The same without needs_resize, e.g. on another thread with a cloned protocol.
+            let mut protocol = picker.new_resize_protocol(protocol.source_image().clone());
+            protocol.set_content_hint(Some(crate::ContentHint::PixelArt));
+            protocol.resize_encode(&resize, protocol.background_color(), rect);
+            assert_eq!(protocol.area(), encoded);
+            encoded
+        };
+
+        // Scaled up 3 times, not to the 100x133 pixels that would fit.
+        let area = Rect::new(0, 0, 10, 10);
+        assert_eq!(
+            encoded_area(30, 40, crate::Resize::Scale(None), area),
+            Rect::new(0, 0, 9, 6)
+        );
+        assert_eq!(
+            encoded_area(30, 40, crate::Resize::Fit(None), area),
+            Rect::new(0, 0, 3, 2)
+        );
+        // Scaled down to a quarter, not to the 80x107 pixels that would fit.
+        let area = Rect::new(0, 0, 8, 10);
+        assert_eq!(
+            encoded_area(300, 400, crate::Resize::Fit(None), area),
+            Rect::new(0, 0, 8, 5)
+        );
+        // An explicit filter is kept, and the image is fit as usual.
+        assert_eq!(
+            encoded_area(
+                300,
+                400,
+                crate::Resize::Fit(Some(FilterType::Triangle)),
+                area
+            ),
+            Rect::new(0, 0, 8, 6)
+        );
+    }
+
+    #[test]
+    fn test_content_hint_dither() {
+        let mut picker = Picker::from_fontsize((10, 20));
+        picker.set_protocol_type(ProtocolType::Halfblocks);
+        let image: DynamicImage =
+            ImageBuffer::from_pixel(20, 40, Rgba::<u8>([255, 0, 0, 255])).into();
+        let mut protocol = picker.new_resize_protocol(image);
+        let default = protocol.inner_trait().dither();
+
+        protocol.set_content_hint(Some(crate::ContentHint::Photo));
+        assert!(protocol.inner_trait().dither());
+        protocol.set_content_hint(Some(crate::ContentHint::LineArt));
+        assert!(!protocol.inner_trait().dither());
+        protocol.set_content_hint(None);
+        assert_eq!(protocol.inner_trait().dither(), default);
+    }
+
+    #[test]
+    fn test_filter_tiers() {
+        let mut picker = Picker::from_fontsize((10, 20));
+        picker.set_protocol_type(ProtocolType::Halfblocks);
+        let image: DynamicImage =
+            ImageBuffer::from_pixel(200, 400, Rgba::<u8>([255, 0, 0, 255])).into();
+        let mut protocol = picker.new_resize_protocol(image);
+        let resize = crate::Resize::Fit(None);
+        let encode = |protocol: &mut super::StatefulProtocol, area: Rect| {
+            let rect = protocol.needs_resize(&resize, area)?;
+            protocol.resize_encode(&resize, protocol.background_color(), rect);
+            Some(rect)
+        };
+
+        protocol.set_filter_tiers(Some(super::FilterTiers::new(
+            FilterType::Nearest,
+            FilterType::Lanczos3,
+            Duration::from_secs(3600),
+        )));
+        let area = Rect::new(0, 0, 4, 4);
+        assert!(encode(&mut protocol, area).is_some());
+        // Not settled yet.
+        assert_eq!(encode(&mut protocol, area), None);
+        assert!(!protocol.filter_tiers.as_ref().unwrap().1.unwrap().upgraded);
+
+        protocol.set_filter_tiers(Some(super::FilterTiers::new(
+            FilterType::Nearest,
+            FilterType::Lanczos3,
+            Duration::ZERO,
+        )));
+        let area = Rect::new(0, 0, 6, 6);
+        let rect = encode(&mut protocol, area).unwrap();
+        assert!(!protocol.filter_tiers.as_ref().unwrap().1.unwrap().upgraded);
+        // Upgraded at the same area, and only once.
+        assert_eq!(encode(&mut protocol, area), Some(rect));
+        assert!(protocol.filter_tiers.as_ref().unwrap().1.unwrap().upgraded);
+        assert_eq!(encode(&mut protocol, area), None);
+
+        // Crop has no filter to upgrade.
+        let resize = crate::Resize::Crop(None);
+        protocol.set_filter_tiers(Some(super::FilterTiers::new(
+            FilterType::Nearest,
+            FilterType::Lanczos3,
+            Duration::ZERO,
+        )));
+        let rect = protocol
+            .needs_resize(&resize, Rect::new(0, 0, 3, 3))
+            .unwrap();
+        protocol.resize_encode(&resize, protocol.background_color(), rect);
+        assert_eq!(protocol.needs_resize(&resize, Rect::new(0, 0, 3, 3)), None);
+    }
+
+    #[test]
+    fn test_redact() {
+        let mut picker = Picker::from_fontsize((10, 20));
+        picker.set_protocol_type(ProtocolType::Sixel);
+        let image: DynamicImage =
+            ImageBuffer::from_pixel(20, 40, Rgba::<u8>([255, 0, 0, 255])).into();
+        let mut protocol = picker.new_resize_protocol(image);
+        let area = Rect::new(0, 0, 4, 4);
+        let render = |protocol: &mut super::StatefulProtocol| {
+            let mut buf = Buffer::empty(area);
+            crate::ResizeEncodeRender::resize_encode_render(
+                protocol,
+                &crate::Resize::Fit(None),
+                area,
+                &mut buf,
+            );
+            buf
+        };
+
+        let shown = render(&mut protocol);
+        assert!(shown[(0, 0)].symbol().starts_with("\x1bP"));
+
+        protocol.redact(true);
+        let redacted = render(&mut protocol);
+        for (x, y) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+            assert_eq!(redacted[(x, y)].symbol(), "╱");
+            assert!(!redacted[(x, y)].skip);
+        }
+        // Only the image area is covered.
+        assert_eq!(redacted[(2, 0)].symbol(), " ");
+
+        protocol.redact(false);
+        assert_eq!(render(&mut protocol), shown);
+    }
+
+    #[test]
+    fn test_pixel_at() {
+        let mut picker = Picker::from_fontsize((10, 20));
+        picker.set_protocol_type(ProtocolType::Halfblocks);
+        // Red on the left half, blue on the right half.
+        let image: DynamicImage = ImageBuffer::from_fn(40, 40, |x, _| {
+            Rgba::<u8>(if x < 20 {
+                [255, 0, 0, 255]
+            } else {
+                [0, 0, 255, 255]
+            })
+        })
+        .into();
+        let (red, blue) = (Rgba([255, 0, 0, 255]), Rgba([0, 0, 255, 255]));
+        let mut protocol = picker.new_resize_protocol(image);
+        assert_eq!(protocol.pixel_at((5, 5), (0, 0)), None);
+
+        let area = Rect::new(5, 5, 8, 4);
+        let render = |protocol: &mut super::StatefulProtocol, resize: crate::Resize| {
+            let mut buf = Buffer::empty(area);
+            crate::ResizeEncodeRender::resize_encode_render(protocol, &resize, area, &mut buf);
+        };
+        // Unscaled, 4x2 cells.
+        render(&mut protocol, crate::Resize::Fit(None));
+        assert_eq!(protocol.pixel_at((5, 5), (0, 0)), Some(red));
+        assert_eq!(protocol.pixel_at((6, 6), (9, 19)), Some(red));
+        assert_eq!(protocol.pixel_at((7, 5), (0, 0)), Some(blue));
+        assert_eq!(protocol.pixel_at((8, 6), (9, 19)), Some(blue));
+        // Outside of the image, and outside of the cell.
+        assert_eq!(protocol.pixel_at((9, 5), (0, 0)), None);
+        assert_eq!(protocol.pixel_at((4, 5), (0, 0)), None);
+        assert_eq!(protocol.pixel_at((5, 5), (10, 0)), None);
+
+        // Stretched to 80x80 pixels.
+        render(&mut protocol, crate::Resize::Stretch(None));
+        assert_eq!(protocol.pixel_at((8, 5), (9, 0)), Some(red));
+        assert_eq!(protocol.pixel_at((9, 5), (0, 0)), Some(blue));
+        assert_eq!(protocol.pixel_at((12, 8), (9, 19)), Some(blue));
+    }
+
+    #[test]
+    fn test_audit() {
+        use crate::audit::{self, SkipReason};
+
+        let mut picker = Picker::from_fontsize((10, 20));
+        picker.set_protocol_type(ProtocolType::Sixel);
+        let image: DynamicImage =
+            ImageBuffer::from_pixel(20, 40, Rgba::<u8>([255, 0, 0, 255])).into();
+        // Other tests may render concurrently, tell the records apart by area.
+        let fits = Rect::new(7, 3, 4, 4);
+        let too_small = Rect::new(7, 3, 1, 1);
+        let mut protocol = picker
+            .new_protocol(image.clone(), fits, crate::Resize::Fit(None))
+            .unwrap();
+        let mut stateful = picker.new_resize_protocol(image);
+
+        audit::enable(true);
+        let mut buf = Buffer::empty(Rect::new(0, 0, 20, 20));
+        protocol.render(fits, &mut buf);
+        protocol.render(too_small, &mut buf);
+        stateful.render(fits, &mut buf);
+        let records: Vec<_> = audit::take()
+            .into_iter()
+            .filter(|record| record.area == fits || record.area == too_small)
+            .collect();
+        audit::enable(false);
+
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0].claimed, Rect::new(7, 3, 2, 2));
+        assert!(records[0].payload_bytes > 0);
+        assert_eq!(records[0].skipped, None);
+        assert_eq!(records[1].skipped, Some(SkipReason::DoesNotFit));
+        assert_eq!(records[1].payload_bytes, 0);
+        assert_eq!(records[2].skipped, Some(SkipReason::NotEncoded));
+    }
+
+    #[test]
+    fn test_force_redraw_next_frame() {
+        let mut picker = Picker::from_fontsize((10, 20));
+        picker.set_protocol_type(ProtocolType::Sixel);
+        let image: DynamicImage =
+            ImageBuffer::from_pixel(20, 40, Rgba::<u8>([255, 0, 0, 255])).into();
+        let mut protocol = picker.new_resize_protocol(image);
+        let area = Rect::new(0, 0, 2, 2);
+        let render = |protocol: &mut super::StatefulProtocol| {
+            let mut buf = Buffer::empty(area);
+            crate::ResizeEncodeRender::resize_encode_render(
+                protocol,
+                &crate::Resize::Fit(None),
+                area,
+                &mut buf,
+            );
+            buf[(0, 0)].symbol().to_string()
+        };
+
+        let plain = render(&mut protocol);
+        assert!(render(&mut protocol) == plain);
+
+        protocol.force_redraw_next_frame();
+        let first = render(&mut protocol);
+        assert!(first != plain);
+        protocol.force_redraw_next_frame();
+        let second = render(&mut protocol);
+        assert!(second != first);
+        assert!(second != plain);
+        assert!(render(&mut protocol) == plain);
+    }
+
+    /// Wire-format snapshots of every protocol, for small fixed images and fixed Kitty ids.
+    ///
+    /// These are meant to catch accidental changes of the emitted sequences, like chunk sizes,
+    /// parameter ordering or tmux escaping. If a change is intentional, review and accept the new
+    /// snapshots with `cargo insta review`.
+    mod wire_format {
+        use image::{DynamicImage, Rgba, RgbaImage};
+        use insta::assert_snapshot;
+        use ratatui::{
+            buffer::Buffer,
+            layout::Rect,
+            widgets::{StatefulWidget, Widget},
+        };
+
+        use crate::{
+            picker::{Picker, ProtocolType},
+            protocol::{
+                iterm2::Iterm2,
+                kitty::{self, Kitty, KittyIdColor},
+                sixel::Sixel,
+                Protocol,
+            },
+            Image, Resize, StatefulImage,
+        };
+
+        // 2x2 cells at font size (10, 20), with four colored quadrants.
+        fn image(width: u32, height: u32) -> DynamicImage {
+            RgbaImage::from_fn(width, height, |x, y| {
+                match (x < width / 2, y < height / 2) {
+                    (true, true) => Rgba([255, 0, 0, 255]),
+                    (false, true) => Rgba([0, 255, 0, 255]),
+                    (true, false) => Rgba([0, 0, 255, 255]),
+                    (false, false) => Rgba([255, 255, 255, 255]),
+                }
+            })
+            .into()
+        }
+
+        // All non-blank cell symbols, one per line, with control characters made visible.
+        fn symbols(buf: &Buffer) -> String {
+            let mut lines = vec![];
+            for y in buf.area.top()..buf.area.bottom() {
+                for x in buf.area.left()..buf.area.right() {
+                    let symbol = buf[(x, y)].symbol();
+                    if symbol != " " {
+                        lines.push(format!(
+                            "({x},{y}) {}",
+                            symbol.replace('\x1b', "\\e").replace('\x07', "\\a")
+                        ));
+                    }
+                }
+            }
+            lines.join("\n")
+        }
+
+        fn render(mut protocol: Protocol, area: Rect) -> String {
+            let mut buf = Buffer::empty(area);
+            Image::new(&mut protocol).render(area, &mut buf);
+            symbols(&buf)
+        }
+
+        const AREA: Rect = Rect::new(0, 0, 2, 2);
+        const ID: u32 = 0x0102_0304;
+
+        #[test]
+        fn kitty() {
+            let kitty = Kitty::new(image(20, 40), AREA, ID, false).unwrap();
+            assert_snapshot!(render(Protocol::Kitty(kitty), AREA));
+        }
+
+        #[test]
+        fn kitty_tmux() {
+            let kitty = Kitty::new(image(20, 40), AREA, ID, true).unwrap();
+            assert_snapshot!(render(Protocol::Kitty(kitty), AREA));
+        }
+
+        #[test]
+        fn kitty_id_color_colon() {
+            let kitty = Kitty::new(image(2, 4), Rect::new(0, 0, 1, 1), ID, false)
+                .unwrap()
+                .id_color(KittyIdColor::RgbColon);
+            assert_snapshot!(render(Protocol::Kitty(kitty), Rect::new(0, 0, 1, 1)));
+        }
+
+        #[test]
+        fn kitty_id_color_indexed() {
+            let id = kitty::indexed_id(0x0304);
+            let kitty = Kitty::new(image(2, 4), Rect::new(0, 0, 1, 1), id, false)
+                .unwrap()
+                .id_color(KittyIdColor::Indexed);
+            assert_snapshot!(render(Protocol::Kitty(kitty), Rect::new(0, 0, 1, 1)));
+        }
+
+        #[test]
+        fn kitty_chunks() {
+            // 40x40 RGBA is 6400 bytes, which is split into three chunks.
+            let area = Rect::new(0, 0, 4, 2);
+            let kitty = Kitty::new(image(40, 40), area, ID, false).unwrap();
+            assert_snapshot!(render(Protocol::Kitty(kitty), area));
+        }
+
+        #[test]
+        fn sixel() {
+            let sixel = Sixel::new(image(20, 40), AREA, false).unwrap();
+            assert_snapshot!(render(Protocol::Sixel(sixel), AREA));
+        }
+
+        #[test]
+        fn sixel_tmux() {
+            let sixel = Sixel::new(image(20, 40), AREA, true).unwrap();
+            assert_snapshot!(render(Protocol::Sixel(sixel), AREA));
+        }
+
+        #[test]
+        fn iterm2() {
+            let iterm2 = Iterm2::new(image(20, 40), AREA, false).unwrap();
+            assert_snapshot!(render(Protocol::ITerm2(iterm2), AREA));
+        }
+
+        #[test]
+        fn iterm2_tmux() {
+            let iterm2 = Iterm2::new(image(20, 40), AREA, true).unwrap();
+            assert_snapshot!(render(Protocol::ITerm2(iterm2), AREA));
+        }
+
+        #[test]
+        fn stateful_kitty_deterministic_ids() {
+            let mut picker = Picker::from_fontsize((10, 20));
+            picker.set_protocol_type(ProtocolType::Kitty);
+            picker.set_deterministic_ids(true);
+            let mut first = picker.new_resize_protocol(image(20, 40));
+            let mut second = picker.clone().new_resize_protocol(image(20, 40));
+            let area = Rect::new(0, 0, 4, 2);
+            let mut buf = Buffer::empty(area);
+            StatefulImage::default().resize(Resize::Fit(None)).render(
+                Rect::new(0, 0, 2, 2),
+                &mut buf,
+                &mut first,
+            );
+            StatefulImage::default().resize(Resize::Fit(None)).render(
+                Rect::new(2, 0, 2, 2),
+                &mut buf,
+                &mut second,
+            );
+            assert_snapshot!(symbols(&buf));
+        }
+    }
+}
@@ -6,13 +6,17 @@
 //! [supports]: https://arewesixelyet.com
 //! [Sixel]: https://en.wikipedia.org/wiki/Sixel
 use icy_sixel::{
-    sixel_string, DiffusionMethod, MethodForLargest, MethodForRep, PixelFormat, Quality,
+    dither::sixel_dither, output::sixel_output, sixel_string, DiffusionMethod, EncodePolicy,
+    MethodForLargest, MethodForRep, PixelFormat, Quality, SixelResult,
 };
 use image::{DynamicImage, Rgba};
 use ratatui::{buffer::Buffer, layout::Rect};
-use std::cmp::min;
+use std::{cmp::min, sync::Arc};
 
-use super::{ProtocolTrait, StatefulProtocolTrait};
+use super::{
+    encode_shared, erase_sequence, erase_uncovered, protect_cells, CellProtection, ProtocolTrait,
+    SharedEncodings, StatefulProtocolTrait,
+};
 use crate::{errors::Errors, picker::cap_parser::Parser, FontSize, ImageSource, Resize, Result};
 
 // Fixed sixel protocol
@@ -21,35 +25,89 @@ pub struct Sixel {
     pub data: String,
     pub area: Rect,
     pub is_tmux: bool,
+    /// Erase the area before placing the image, see [Sixel::erase].
+    pub erase: bool,
+    /// See [Sixel::cell_protection].
+    pub cell_protection: CellProtection,
 }
 
 impl Sixel {
     pub fn new(image: DynamicImage, area: Rect, is_tmux: bool) -> Result<Self> {
-        let data = encode(&image, is_tmux)?;
+        Self::with_transparency(image, area, is_tmux, false)
+    }
+
+    /// Create a Sixel, leaving transparent pixels out (`P2=1`) if `transparent`, see
+    /// [StatefulSixel::transparent].
+    pub fn with_transparency(
+        image: DynamicImage,
+        area: Rect,
+        is_tmux: bool,
+        transparent: bool,
+    ) -> Result<Self> {
+        let data = encode(&image, is_tmux, transparent)?;
         Ok(Self {
             data,
             area,
             is_tmux,
+            erase: false,
+            cell_protection: CellProtection::default(),
         })
     }
+
+    /// Erase the area with ECH before placing the image on every render.
+    ///
+    /// This is a workaround for terminals that do not let text overdraw sixel regions (e.g.
+    /// Konsole, WezTerm, or patched Alacritty), where old graphics would otherwise linger as
+    /// ghosts after layout changes.
+    pub fn erase(mut self, erase: bool) -> Self {
+        self.erase = erase;
+        self
+    }
+
+    /// How the cells covered by the image are protected, see [CellProtection].
+    pub fn cell_protection(mut self, cell_protection: CellProtection) -> Self {
+        self.cell_protection = cell_protection;
+        self
+    }
 }
 
 // TODO: change E to sixel_rs::status::Error and map when calling
-fn encode(img: &DynamicImage, is_tmux: bool) -> Result<String> {
-    let (w, h) = (img.width(), img.height());
+fn encode(img: &DynamicImage, is_tmux: bool, transparent: bool) -> Result<String> {
+    encode_with_palette(img, is_tmux, transparent, None)
+}
+
+fn encode_with_palette(
+    img: &DynamicImage,
+    is_tmux: bool,
+    transparent: bool,
+    palette: Option<&mut PaletteCache>,
+) -> Result<String> {
+    crate::cleanup::register_sixel(is_tmux);
+    let (w, h) = (img.width() as i32, img.height() as i32);
     let img_rgb8 = img.to_rgb8();
     let bytes = img_rgb8.as_raw();
+    let mask = transparent.then(|| transparency_mask(img)).flatten();
 
-    let mut data = sixel_string(
-        bytes,
-        w as i32,
-        h as i32,
-        PixelFormat::RGB888,
-        DiffusionMethod::Stucki,
-        MethodForLargest::Auto,
-        MethodForRep::Auto,
-        Quality::HIGH,
-    )
+    let mut data = match (palette, mask) {
+        (None, None) => sixel_string(
+            bytes,
+            w,
+            h,
+            PixelFormat::RGB888,
+            DiffusionMethod::Stucki,
+            MethodForLargest::Auto,
+            MethodForRep::Auto,
+            Quality::HIGH,
+        ),
+        (None, Some(mask)) => opaque_dither(bytes, &mask)
+            .and_then(|mut dither| sixel_string_transparent(&mut dither, bytes, &mask, w, h)),
+        (Some(palette), None) => palette
+            .dither(bytes, None, w, h)
+            .and_then(|mut dither| sixel_string_opaque(&mut dither, bytes, w, h)),
+        (Some(palette), Some(mask)) => palette
+            .dither(bytes, Some(&mask), w, h)
+            .and_then(|mut dither| sixel_string_transparent(&mut dither, bytes, &mask, w, h)),
+    }
     .map_err(|err| Errors::Sixel(err.to_string()))?;
 
     if is_tmux {
@@ -67,7 +125,12 @@ fn encode(img: &DynamicImage, is_tmux: bool) -> Result<String> {
 
 impl ProtocolTrait for Sixel {
     fn render(&mut self, area: Rect, buf: &mut Buffer) {
-        render(self.area, &self.data, area, buf, false)
+        let options = RenderOptions {
+            overdraw: false,
+            erase: self.erase,
+            cell_protection: self.cell_protection,
+        };
+        render(self.area, &self.data, area, buf, options);
     }
 
     fn area(&self) -> Rect {
@@ -75,7 +138,28 @@ impl ProtocolTrait for Sixel {
     }
 }
 
-fn render(rect: Rect, data: &str, area: Rect, buf: &mut Buffer, overdraw: bool) {
+// How render() puts the sixel data into the buffer.
+#[derive(Clone, Copy)]
+struct RenderOptions {
+    // Render clipped to the area even if the image does not fit.
+    overdraw: bool,
+    // Erase the cells before drawing, see Sixel::erase.
+    erase: bool,
+    cell_protection: CellProtection,
+}
+
+fn render(
+    rect: Rect,
+    data: &str,
+    area: Rect,
+    buf: &mut Buffer,
+    options: RenderOptions,
+) -> Option<Rect> {
+    let RenderOptions {
+        overdraw,
+        erase,
+        cell_protection,
+    } = options;
     let render_area = match render_area(rect, area, overdraw) {
         None => {
             // If we render out of area, then the buffer will attempt to write regular text (or
@@ -95,24 +179,21 @@ fn render(rect: Rect, data: &str, area: Rect, buf: &mut Buffer, overdraw: bool)
             //
             // Note that [ResizeProtocol] forces to ignore this early return, since it will
             // always resize itself to the area.
-            return;
+            return None;
         }
         Some(r) => r,
     };
 
-    buf.cell_mut(render_area).map(|cell| cell.set_symbol(data));
-    let mut skip_first = false;
-
-    // Skip entire area
-    for y in render_area.top()..render_area.bottom() {
-        for x in render_area.left()..render_area.right() {
-            if !skip_first {
-                skip_first = true;
-                continue;
-            }
-            buf.cell_mut((x, y)).map(|cell| cell.set_skip(true));
-        }
+    if erase {
+        // Not part of any tmux passthrough, tmux must know about the erasing too.
+        let mut seq = erase_sequence("\x1b", render_area.width, render_area.height);
+        seq.push_str(data);
+        buf.cell_mut(render_area).map(|cell| cell.set_symbol(&seq));
+    } else {
+        buf.cell_mut(render_area).map(|cell| cell.set_symbol(data));
     }
+    protect_cells(render_area, buf, cell_protection);
+    Some(render_area)
 }
 
 fn render_area(rect: Rect, area: Rect, overdraw: bool) -> Option<Rect> {
@@ -131,12 +212,174 @@ fn render_area(rect: Rect, area: Rect, overdraw: bool) -> Option<Rect> {
     Some(Rect::new(area.x, area.y, rect.width, rect.height))
 }
 
+/// The palette of a previous encode, see [StatefulSixel::reuse_palette].
+#[derive(Clone, Default)]
+struct PaletteCache {
+    refresh: u32,
+    // The palette and its number of colors, and how many encodes have used it.
+    palette: Option<(Vec<u8>, i32)>,
+    uses: u32,
+}
+
+impl PaletteCache {
+    fn new(refresh: u32) -> PaletteCache {
+        PaletteCache {
+            refresh: refresh.max(1),
+            ..PaletteCache::default()
+        }
+    }
+
+    // A dither for the pixels with the cached palette, quantizing only when it is missing or
+    // stale. With a transparency mask, the palette leaves an index for the transparent pixels.
+    fn dither(
+        &mut self,
+        bytes: &[u8],
+        mask: Option<&[bool]>,
+        width: i32,
+        height: i32,
+    ) -> SixelResult<sixel_dither> {
+        let mut dither = match &self.palette {
+            Some((palette, ncolors))
+                if self.uses < self.refresh && (mask.is_none() || *ncolors <= 255) =>
+            {
+                let mut dither = sixel_dither::new(256)?;
+                dither.palette = palette.clone();
+                dither.ncolors = *ncolors;
+                dither.optimized = true;
+                dither
+            }
+            _ => {
+                let dither = match mask {
+                    Some(mask) => opaque_dither(bytes, mask)?,
+                    None => {
+                        let mut dither = sixel_dither::new(256)?;
+                        dither.initialize(
+                            bytes,
+                            width,
+                            height,
+                            PixelFormat::RGB888,
+                            MethodForLargest::Auto,
+                            MethodForRep::Auto,
+                            Quality::HIGH,
+                        )?;
+                        dither
+                    }
+                };
+                self.palette = Some((dither.palette.clone(), dither.ncolors));
+                self.uses = 0;
+                dither
+            }
+        };
+        self.uses += 1;
+        dither.set_pixelformat(PixelFormat::RGB888);
+        dither.set_diffusion_type(DiffusionMethod::Stucki);
+        Ok(dither)
+    }
+}
+
+// Which pixels are transparent, or None if there are none.
+fn transparency_mask(img: &DynamicImage) -> Option<Vec<bool>> {
+    if !img.color().has_alpha() {
+        return None;
+    }
+    let mask: Vec<bool> = img
+        .to_rgba8()
+        .pixels()
+        .map(|pixel| pixel[3] < 128)
+        .collect();
+    mask.contains(&true).then_some(mask)
+}
+
+// A dither with a palette for the opaque pixels, which leaves an index for the transparent ones.
+// The quantizer may return a few more colors than requested, so it is asked for less than 255.
+fn opaque_dither(bytes: &[u8], mask: &[bool]) -> SixelResult<sixel_dither> {
+    let mut opaque: Vec<u8> = bytes
+        .chunks_exact(3)
+        .zip(mask)
+        .filter(|(_, transparent)| !**transparent)
+        .flat_map(|(pixel, _)| pixel.iter().copied())
+        .collect();
+    if opaque.is_empty() {
+        opaque = vec![0; 3];
+    }
+    let mut dither = sixel_dither::new(240)?;
+    dither.initialize(
+        &opaque,
+        (opaque.len() / 3) as i32,
+        1,
+        PixelFormat::RGB888,
+        MethodForLargest::Auto,
+        MethodForRep::Auto,
+        Quality::HIGH,
+    )?;
+    dither.set_pixelformat(PixelFormat::RGB888);
+    dither.set_diffusion_type(DiffusionMethod::Stucki);
+    Ok(dither)
+}
+
+// Like icy_sixel::sixel_string, with a prepared dither.
+fn sixel_string_opaque(
+    dither: &mut sixel_dither,
+    bytes: &[u8],
+    width: i32,
+    height: i32,
+) -> SixelResult<String> {
+    let mut sixel_data: Vec<u8> = Vec::new();
+    let mut output = sixel_output::new(&mut sixel_data);
+    output.set_encode_policy(EncodePolicy::AUTO);
+    output.encode(&mut bytes.to_vec(), width, height, 0, dither)?;
+    Ok(String::from_utf8_lossy(&sixel_data).to_string())
+}
+
+// Encode the pixels with the dither's palette, leaving the transparent pixels of the mask as they
+// are on the screen: they get an extra palette index, which is the key color that is not drawn.
+fn sixel_string_transparent(
+    dither: &mut sixel_dither,
+    bytes: &[u8],
+    mask: &[bool],
+    width: i32,
+    height: i32,
+) -> SixelResult<String> {
+    let mut indices = dither.apply_palette(bytes, width, height)?;
+    let key = dither.ncolors;
+    if key > 255 {
+        // No index left, draw the transparent pixels after all.
+        return sixel_string_opaque(dither, bytes, width, height);
+    }
+    dither.palette.truncate(key as usize * 3);
+    dither.palette.extend([0, 0, 0]);
+    dither.ncolors = key + 1;
+    dither.keycolor = key;
+    for (index, transparent) in indices.iter_mut().zip(mask) {
+        if *transparent {
+            *index = key as u8;
+        }
+    }
+    dither.set_pixelformat(PixelFormat::PAL8);
+
+    let mut sixel_data: Vec<u8> = Vec::new();
+    let mut output = sixel_output::new(&mut sixel_data);
+    output.set_encode_policy(EncodePolicy::AUTO);
+    output.encode(&mut indices, width, height, 0, dither)?;
+    // P2=1: pixels that are not drawn keep their current color, instead of the background.
+    Ok(String::from_utf8_lossy(&sixel_data).replacen("\x1bPq", "\x1bP0;1q", 1))
+}
+
 #[derive(Clone)]
 pub struct StatefulSixel {
     source: ImageSource,
     font_size: FontSize,
-    current: Sixel,
+    data: Arc<String>,
+    area: Rect,
+    is_tmux: bool,
+    erase: bool,
+    cell_protection: CellProtection,
     hash: u64,
+    /// Where the image was last rendered, to erase leftovers when it shrinks or moves.
+    last_render_area: Option<Rect>,
+    shared: Option<SharedEncodings>,
+    palette: Option<PaletteCache>,
+    transparent: bool,
 }
 
 impl StatefulSixel {
@@ -144,26 +387,95 @@ impl StatefulSixel {
         StatefulSixel {
             source,
             font_size,
-            current: Sixel {
-                is_tmux,
-                ..Sixel::default()
-            },
+            data: Arc::default(),
+            area: Rect::default(),
+            is_tmux,
+            erase: false,
+            cell_protection: CellProtection::default(),
             hash: u64::default(),
+            last_render_area: None,
+            shared: None,
+            palette: None,
+            transparent: false,
         }
     }
+
+    /// See [Sixel::erase].
+    pub fn erase(mut self, erase: bool) -> StatefulSixel {
+        self.erase = erase;
+        self
+    }
+
+    /// See [Sixel::cell_protection].
+    pub fn cell_protection(mut self, cell_protection: CellProtection) -> StatefulSixel {
+        self.cell_protection = cell_protection;
+        self
+    }
+
+    /// Reuse equal encodings of other protocols, see [SharedEncodings].
+    pub fn shared(mut self, shared: Option<SharedEncodings>) -> StatefulSixel {
+        self.shared = shared;
+        self
+    }
+
+    /// Reuse the color palette of the previous encode, computing a new one only every `refresh`
+    /// encodes, or never reuse it with `None` (the default).
+    ///
+    /// Computing the palette dominates the encoding time, so this speeds up animations whose
+    /// frames have similar colors, at the cost of color fidelity when they drift apart. The
+    /// encodings depend on the previous ones, so they are not shared with [SharedEncodings].
+    pub fn reuse_palette(mut self, refresh: Option<u32>) -> StatefulSixel {
+        self.palette = refresh.map(PaletteCache::new);
+        self
+    }
+
+    /// Leave transparent pixels out (`P2=1`), so that whatever is on the screen shows through,
+    /// instead of drawing them with the background color (default false).
+    ///
+    /// Pixels that are left out also keep a previous image that was drawn at the same place.
+    /// Terminals that do not support `P2=1` draw them with the terminal's background color.
+    pub fn transparent(mut self, transparent: bool) -> StatefulSixel {
+        self.transparent = transparent;
+        self
+    }
 }
 
 impl ProtocolTrait for StatefulSixel {
     fn render(&mut self, area: Rect, buf: &mut Buffer) {
-        render(self.current.area, &self.current.data, area, buf, true);
+        let options = RenderOptions {
+            overdraw: true,
+            erase: self.erase,
+            cell_protection: self.cell_protection,
+        };
+        let render_area = render(self.area, &self.data, area, buf, options);
+        if let Some(previous) = self.last_render_area {
+            if render_area != Some(previous) {
+                erase_uncovered(previous, render_area.unwrap_or_default(), buf);
+            }
+        }
+        self.last_render_area = render_area;
     }
 
     fn area(&self) -> Rect {
-        self.current.area
+        self.area
     }
 }
 
 impl StatefulProtocolTrait for StatefulSixel {
+    fn font_size(&self) -> FontSize {
+        self.font_size
+    }
+    fn set_font_size(&mut self, font_size: FontSize) {
+        self.font_size = font_size;
+        // Encoded for the previous font size.
+        self.hash = u64::default();
+    }
+    fn source(&self) -> &ImageSource {
+        &self.source
+    }
+    fn source_mut(&mut self) -> &mut ImageSource {
+        &mut self.source
+    }
     fn background_color(&self) -> Rgba<u8> {
         self.source.background_color
     }
@@ -171,30 +483,160 @@ impl StatefulProtocolTrait for StatefulSixel {
         resize.needs_resize(
             &self.source,
             self.font_size,
-            self.current.area,
+            self.area,
             area,
             self.source.hash != self.hash,
         )
     }
-    fn resize_encode(&mut self, resize: &Resize, background_color: Rgba<u8>, area: Rect) {
+    fn resize_encode(
+        &mut self,
+        resize: &Resize,
+        background_color: Rgba<u8>,
+        area: Rect,
+    ) -> Result<()> {
         if area.width == 0 || area.height == 0 {
-            return;
+            return Ok(());
         }
 
-        let img = resize.resize(&self.source, self.font_size, area, background_color);
-        let is_tmux = self.current.is_tmux;
-        match encode(&img, is_tmux) {
-            Ok(data) => {
-                self.current = Sixel {
-                    data,
-                    area,
+        let (is_tmux, transparent) = (self.is_tmux, self.transparent);
+        let key = (
+            "sixel",
+            self.source.hash,
+            self.font_size,
+            resize,
+            background_color,
+            area,
+            is_tmux,
+            transparent,
+        );
+        self.data = match self.palette.as_mut() {
+            Some(palette) => {
+                let img = resize.resize(&self.source, self.font_size, area, background_color);
+                Arc::new(encode_with_palette(
+                    &img,
                     is_tmux,
-                };
-                self.hash = self.source.hash;
-            }
-            Err(_err) => {
-                // TODO: save err in struct and expose in trait?
+                    transparent,
+                    Some(palette),
+                )?)
             }
-        }
+            None => encode_shared(self.shared.as_ref(), key, || {
+                let img = resize.resize(&self.source, self.font_size, area, background_color);
+                encode(&img, is_tmux, transparent)
+            })?,
+        };
+        self.area = area;
+        self.hash = self.source.hash;
+        Ok(())
+    }
+    fn render_placement(&self, area: Rect, buf: &mut Buffer) {
+        let options = RenderOptions {
+            overdraw: true,
+            erase: self.erase,
+            cell_protection: self.cell_protection,
+        };
+        render(self.area, &self.data, area, buf, options);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, ImageBuffer, Rgba};
+    use ratatui::{buffer::Buffer, layout::Rect};
+
+    use super::{Sixel, StatefulSixel};
+    use crate::{
+        protocol::{CellProtection, ProtocolTrait, StatefulProtocolTrait},
+        ImageSource, Resize,
+    };
+
+    #[test]
+    fn test_reuse_palette() {
+        let font_size = (10, 20);
+        let source = |color: [u8; 4]| {
+            let image: DynamicImage = ImageBuffer::from_fn(20, 40, |x, _| match x {
+                0..10 => Rgba::<u8>(color),
+                _ => Rgba([255, 255, 255, 255]),
+            })
+            .into();
+            ImageSource::new(image, font_size, Rgba([0; 4]))
+        };
+        let area = Rect::new(0, 0, 2, 2);
+        let encode = |sixel: &mut StatefulSixel, color: [u8; 4]| {
+            sixel.source = source(color);
+            sixel
+                .resize_encode(&Resize::Fit(None), Rgba([0; 4]), area)
+                .unwrap();
+            sixel.data.to_string()
+        };
+        let fresh = |color| {
+            encode(
+                &mut StatefulSixel::new(source(color), font_size, false),
+                color,
+            )
+        };
+        let (red, blue) = ([255, 0, 0, 255], [0, 0, 255, 255]);
+
+        let mut sixel = StatefulSixel::new(source(red), font_size, false).reuse_palette(Some(2));
+        // The first encode computes the palette just like without reusing.
+        assert_eq!(encode(&mut sixel, red), fresh(red));
+        // The second one has no blue in the palette.
+        assert_ne!(encode(&mut sixel, blue), fresh(blue));
+        // Refreshed.
+        assert_eq!(encode(&mut sixel, blue), fresh(blue));
+    }
+
+    #[test]
+    fn test_transparent() {
+        // Red on the left, transparent on the right.
+        let image: DynamicImage = ImageBuffer::from_fn(20, 12, |x, _| match x {
+            0..10 => Rgba::<u8>([255, 0, 0, 255]),
+            _ => Rgba([0, 0, 0, 0]),
+        })
+        .into();
+        let area = Rect::new(0, 0, 2, 1);
+        let opaque = Sixel::new(image.clone(), area, false).unwrap().data;
+        assert!(opaque.starts_with("\x1bPq"));
+
+        let data = Sixel::with_transparency(image, area, false, true)
+            .unwrap()
+            .data;
+        assert!(data.starts_with("\x1bP0;1q"));
+        assert_ne!(data, opaque.replacen("\x1bPq", "\x1bP0;1q", 1));
+
+        // Opaque images are encoded as before.
+        let red: DynamicImage =
+            ImageBuffer::from_pixel(20, 12, Rgba::<u8>([255, 0, 0, 255])).into();
+        assert_eq!(
+            Sixel::with_transparency(red.clone(), area, false, true)
+                .unwrap()
+                .data,
+            Sixel::new(red, area, false).unwrap().data
+        );
+    }
+
+    #[test]
+    fn test_cell_protection() {
+        let image: DynamicImage =
+            ImageBuffer::from_pixel(20, 40, Rgba::<u8>([255, 0, 0, 255])).into();
+        let area = Rect::new(0, 0, 2, 2);
+        let render = |protection| {
+            let mut sixel = Sixel::new(image.clone(), area, false)
+                .unwrap()
+                .cell_protection(protection);
+            let mut buf = Buffer::empty(area);
+            buf.set_string(0, 0, "xx", ratatui::style::Style::new());
+            sixel.render(area, &mut buf);
+            assert!(buf[(0, 0)].symbol().starts_with("\x1bP"));
+            buf
+        };
+
+        let buf = render(CellProtection::Skip);
+        assert!(buf[(1, 0)].skip && buf[(1, 1)].skip);
+        let buf = render(CellProtection::Spaces);
+        assert!(!buf[(1, 0)].skip);
+        assert_eq!(buf[(1, 0)].symbol(), " ");
+        let buf = render(CellProtection::Nothing);
+        assert!(!buf[(1, 0)].skip);
+        assert_eq!(buf[(1, 0)].symbol(), "x");
     }
 }
@@ -0,0 +1,326 @@
+//! Terminology protocol implementation.
+//!
+//! Terminology loads images from files, see `tycat`. The image is written to a temporary PNG
+//! file that is deleted when the protocol is dropped, and each row of the image is a run of
+//! placeholder characters that Terminology replaces with the image.
+use image::{DynamicImage, Rgba};
+use ratatui::{buffer::Buffer, layout::Rect};
+use std::{
+    env,
+    fmt::Write,
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter},
+    path::{Path, PathBuf},
+    process,
+    sync::Arc,
+};
+
+use crate::{picker::cap_parser::Parser, FontSize, ImageSource, Resize, Result};
+
+use super::{
+    erase_uncovered, iterm2::render, CellProtection, ProtocolTrait, StatefulProtocolTrait,
+};
+
+#[derive(Clone, Default)]
+pub struct Terminology {
+    pub data: String,
+    pub area: Rect,
+    pub is_tmux: bool,
+    /// See [Terminology::cell_protection].
+    pub cell_protection: CellProtection,
+    // Keeps the file alive for as long as the data refers to it.
+    file: Option<Arc<TempImage>>,
+}
+
+impl Terminology {
+    pub fn new(image: DynamicImage, area: Rect, is_tmux: bool) -> Result<Self> {
+        let (data, file) = encode(&image, area, is_tmux)?;
+        Ok(Self {
+            data,
+            area,
+            is_tmux,
+            cell_protection: CellProtection::default(),
+            file: Some(file),
+        })
+    }
+
+    /// How the cells covered by the image are protected, see [CellProtection].
+    pub fn cell_protection(mut self, cell_protection: CellProtection) -> Self {
+        self.cell_protection = cell_protection;
+        self
+    }
+
+    /// The temporary image file that the data refers to.
+    pub fn path(&self) -> Option<&Path> {
+        self.file.as_ref().map(|file| file.path.as_path())
+    }
+}
+
+// A temporary image file, deleted on drop.
+struct TempImage {
+    path: PathBuf,
+}
+
+impl TempImage {
+    fn write(img: &DynamicImage) -> Result<Arc<TempImage>> {
+        let (file, path) = Self::create()?;
+        let image = TempImage { path };
+        img.write_to(&mut BufWriter::new(file), image::ImageFormat::Png)?;
+        Ok(Arc::new(image))
+    }
+
+    // Create a new file with an unpredictable name, and never open an existing file (or symlink)
+    // that someone else placed in the shared temporary directory.
+    fn create() -> io::Result<(File, PathBuf)> {
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut attempts = 0;
+        loop {
+            let name = format!(
+                "ratatui-image-{}-{:016x}.png",
+                process::id(),
+                rand::random::<u64>()
+            );
+            let path = env::temp_dir().join(name);
+            match options.open(&path) {
+                Ok(file) => return Ok((file, path)),
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists && attempts < 16 => {
+                    attempts += 1;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+impl Drop for TempImage {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn encode(
+    img: &DynamicImage,
+    render_area: Rect,
+    is_tmux: bool,
+) -> Result<(String, Arc<TempImage>)> {
+    let file = TempImage::write(img)?;
+    let (width, height) = (render_area.width, render_area.height);
+    let (start, escape, end) = Parser::escape_tmux(is_tmux);
+
+    // Stretch the image over the placeholders, which start at the saved cursor position on
+    // every row.
+    let mut seq = String::from(start);
+    write!(
+        seq,
+        "{escape}7{escape}}}is#{width};{height};{}\0",
+        file.path.display()
+    )
+    .unwrap();
+    for y in 0..height {
+        if y > 0 {
+            write!(seq, "{escape}8{escape}[{y}B").unwrap();
+        }
+        write!(
+            seq,
+            "{escape}}}ib\0{}{escape}}}ie\0",
+            "#".repeat(width as usize)
+        )
+        .unwrap();
+    }
+    seq.push_str(end);
+    Ok((seq, file))
+}
+
+impl ProtocolTrait for Terminology {
+    fn render(&mut self, area: Rect, buf: &mut Buffer) {
+        render(
+            self.area,
+            &self.data,
+            area,
+            buf,
+            false,
+            self.cell_protection,
+        );
+    }
+
+    fn area(&self) -> Rect {
+        self.area
+    }
+}
+
+#[derive(Clone)]
+pub struct StatefulTerminology {
+    source: ImageSource,
+    font_size: FontSize,
+    data: String,
+    file: Option<Arc<TempImage>>,
+    area: Rect,
+    is_tmux: bool,
+    cell_protection: CellProtection,
+    hash: u64,
+    /// Where the image was last rendered, to erase leftovers when it shrinks or moves.
+    last_render_area: Option<Rect>,
+}
+
+impl StatefulTerminology {
+    pub fn new(source: ImageSource, font_size: FontSize, is_tmux: bool) -> StatefulTerminology {
+        StatefulTerminology {
+            source,
+            font_size,
+            data: String::new(),
+            file: None,
+            area: Rect::default(),
+            is_tmux,
+            cell_protection: CellProtection::default(),
+            hash: u64::default(),
+            last_render_area: None,
+        }
+    }
+
+    /// See [Terminology::cell_protection].
+    pub fn cell_protection(mut self, cell_protection: CellProtection) -> StatefulTerminology {
+        self.cell_protection = cell_protection;
+        self
+    }
+}
+
+impl ProtocolTrait for StatefulTerminology {
+    fn render(&mut self, area: Rect, buf: &mut Buffer) {
+        let render_area = render(self.area, &self.data, area, buf, true, self.cell_protection);
+        if let Some(previous) = self.last_render_area {
+            if render_area != Some(previous) {
+                erase_uncovered(previous, render_area.unwrap_or_default(), buf);
+            }
+        }
+        self.last_render_area = render_area;
+    }
+
+    fn area(&self) -> Rect {
+        self.area
+    }
+}
+
+impl StatefulProtocolTrait for StatefulTerminology {
+    fn font_size(&self) -> FontSize {
+        self.font_size
+    }
+    fn set_font_size(&mut self, font_size: FontSize) {
+        self.font_size = font_size;
+        // Encoded for the previous font size.
+        self.hash = u64::default();
+    }
+    fn source(&self) -> &ImageSource {
+        &self.source
+    }
+    fn source_mut(&mut self) -> &mut ImageSource {
+        &mut self.source
+    }
+    fn background_color(&self) -> Rgba<u8> {
+        self.source.background_color
+    }
+    fn needs_resize(&mut self, resize: &Resize, area: Rect) -> Option<Rect> {
+        resize.needs_resize(
+            &self.source,
+            self.font_size,
+            self.area,
+            area,
+            self.source.hash != self.hash,
+        )
+    }
+    fn resize_encode(
+        &mut self,
+        resize: &Resize,
+        background_color: Rgba<u8>,
+        area: Rect,
+    ) -> Result<()> {
+        if area.width == 0 || area.height == 0 {
+            return Ok(());
+        }
+        let img = resize.resize(&self.source, self.font_size, area, background_color);
+        let (data, file) = encode(&img, area, self.is_tmux)?;
+        self.data = data;
+        self.file = Some(file);
+        self.area = area;
+        self.hash = self.source.hash;
+        Ok(())
+    }
+    fn render_placement(&self, area: Rect, buf: &mut Buffer) {
+        render(self.area, &self.data, area, buf, true, self.cell_protection);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, ImageBuffer, Rgba};
+    use ratatui::{buffer::Buffer, layout::Rect};
+
+    use super::Terminology;
+    use crate::{
+        picker::{Picker, ProtocolType},
+        protocol::{CellProtection, ProtocolTrait},
+        Resize,
+    };
+
+    #[test]
+    fn test_terminology() {
+        let image: DynamicImage =
+            ImageBuffer::from_pixel(20, 40, Rgba::<u8>([255, 0, 0, 255])).into();
+        let area = Rect::new(0, 0, 2, 2);
+        let mut terminology = Terminology::new(image, area, false).unwrap();
+        let path = terminology.path().unwrap().to_path_buf();
+        assert!(path.exists());
+        assert_eq!(
+            terminology.data,
+            format!(
+                "\x1b7\x1b}}is#2;2;{}\0\x1b}}ib\0##\x1b}}ie\0\x1b8\x1b[1B\x1b}}ib\0##\x1b}}ie\0",
+                path.display()
+            )
+        );
+
+        let mut buf = Buffer::empty(Rect::new(0, 0, 3, 3));
+        terminology.render(Rect::new(1, 1, 2, 2), &mut buf);
+        assert_eq!(buf[(1, 1)].symbol(), terminology.data);
+        assert!(buf[(2, 2)].skip);
+
+        drop(terminology);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_picker_cell_protection() {
+        let image: DynamicImage =
+            ImageBuffer::from_pixel(20, 40, Rgba::<u8>([255, 0, 0, 255])).into();
+        let mut picker = Picker::from_fontsize((10, 20));
+        picker.set_protocol_type(ProtocolType::Terminology);
+        picker.set_cell_protection(ProtocolType::Terminology, CellProtection::Nothing);
+        let area = Rect::new(0, 0, 2, 2);
+
+        let mut protocol = picker
+            .new_protocol(image.clone(), area, Resize::Fit(None))
+            .unwrap();
+        let mut buf = Buffer::empty(area);
+        protocol.render(area, &mut buf);
+        assert!(!buf[(1, 1)].skip);
+
+        let mut protocol = picker.new_resize_protocol(image);
+        protocol.resize_encode(&Resize::Fit(None), Rgba([0; 4]), area);
+        let mut buf = Buffer::empty(area);
+        protocol.render(area, &mut buf);
+        assert!(!buf[(1, 1)].skip);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_temp_file_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let image: DynamicImage =
+            ImageBuffer::from_pixel(20, 40, Rgba::<u8>([255, 0, 0, 255])).into();
+        let terminology = Terminology::new(image, Rect::new(0, 0, 2, 2), false).unwrap();
+        let metadata = std::fs::metadata(terminology.path().unwrap()).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+    }
+}
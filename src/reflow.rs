@@ -0,0 +1,181 @@
+//! Staggered redraws after the terminal reflows.
+//!
+//! When the font is zoomed (e.g. ctrl+scroll), the terminal resizes and many terminals clear all
+//! graphics. Every image then has to be sent again, and most of them re-encoded for their new
+//! area, which can freeze the app for a while if it all happens in the same frame.
+//!
+//! A [ReflowTracker] marks all the stateful protocols of a [crate::picker::Picker] as stale on
+//! a resize, and lets only a few of them refresh in each interval. The others keep rendering
+//! their previous encoding until their turn, so the app must keep rendering, e.g. on a tick.
+//!
+//! ```rust
+//! # use ratatui_image::{picker::Picker, reflow::ReflowTracker};
+//! let mut picker = Picker::from_fontsize((8, 16));
+//! let tracker = ReflowTracker::new();
+//! picker.set_reflow_tracker(Some(tracker.clone()));
+//! // ... create protocols with the picker, and on every resize event:
+//! tracker.resized(120, 40);
+//! ```
+
+use std::{
+    fmt,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::{Duration, Instant},
+};
+
+/// Marks stateful protocols as stale when the terminal reflows, see the [module docs](self).
+#[derive(Clone, Default)]
+pub struct ReflowTracker {
+    state: Arc<Mutex<ReflowState>>,
+}
+
+struct ReflowState {
+    generation: u64,
+    size: Option<(u16, u16)>,
+    per_interval: usize,
+    interval: Duration,
+    // Start of the current interval, and how many protocols refreshed in it.
+    window: Option<(Instant, usize)>,
+}
+
+impl Default for ReflowState {
+    fn default() -> Self {
+        ReflowState {
+            generation: 0,
+            size: None,
+            per_interval: 2,
+            interval: Duration::from_millis(16),
+            window: None,
+        }
+    }
+}
+
+impl ReflowTracker {
+    pub fn new() -> ReflowTracker {
+        ReflowTracker::default()
+    }
+
+    /// Let at most `per_interval` protocols refresh within each `interval` (default 2 per 16ms,
+    /// about one frame).
+    pub fn rate_limit(self, per_interval: usize, interval: Duration) -> ReflowTracker {
+        {
+            let mut state = self.lock();
+            state.per_interval = per_interval.max(1);
+            state.interval = interval;
+        }
+        self
+    }
+
+    /// The terminal size in cells, e.g. from a resize event. Marks all protocols as stale if the
+    /// size changed since the last call.
+    pub fn resized(&self, columns: u16, rows: u16) {
+        let mut state = self.lock();
+        if state.size.is_some_and(|size| size != (columns, rows)) {
+            state.generation += 1;
+        }
+        state.size = Some((columns, rows));
+    }
+
+    /// Mark all protocols as stale, e.g. after the screen was cleared.
+    pub fn invalidate(&self) {
+        self.lock().generation += 1;
+    }
+
+    pub(crate) fn generation(&self) -> u64 {
+        self.lock().generation
+    }
+
+    // Whether a stale protocol may refresh now, counting it against the rate limit.
+    pub(crate) fn try_refresh(&self) -> bool {
+        let mut state = self.lock();
+        let now = Instant::now();
+        let (start, count) = match state.window {
+            Some((start, count)) if now.duration_since(start) < state.interval => (start, count),
+            _ => (now, 0),
+        };
+        let allowed = count < state.per_interval;
+        state.window = Some((start, count + allowed as usize));
+        allowed
+    }
+
+    // Let the current interval run out, as if `interval` had passed.
+    #[cfg(test)]
+    fn elapse(&self) {
+        self.lock().window = None;
+    }
+
+    fn lock(&self) -> MutexGuard<'_, ReflowState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl fmt::Debug for ReflowTracker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.lock();
+        f.debug_struct("ReflowTracker")
+            .field("generation", &state.generation)
+            .field("size", &state.size)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use image::{DynamicImage, ImageBuffer, Rgba};
+    use ratatui::{buffer::Buffer, layout::Rect};
+
+    use super::ReflowTracker;
+    use crate::{
+        picker::{Picker, ProtocolType},
+        Resize,
+    };
+
+    #[test]
+    fn test_staggered_refresh() {
+        let image: DynamicImage =
+            ImageBuffer::from_pixel(40, 40, Rgba::<u8>([255, 0, 0, 255])).into();
+        let mut picker = Picker::from_fontsize((10, 20));
+        picker.set_protocol_type(ProtocolType::Sixel);
+        let tracker = ReflowTracker::new().rate_limit(1, Duration::from_millis(100));
+        picker.set_reflow_tracker(Some(tracker.clone()));
+
+        let resize = Resize::Scale(None);
+        let area = Rect::new(0, 0, 4, 2);
+        let mut protocols: Vec<_> = (0..3)
+            .map(|_| {
+                let mut protocol = picker.new_resize_protocol(image.clone());
+                protocol.resize_encode(&resize, Rgba([0; 4]), area);
+                assert_eq!(protocol.needs_resize(&resize, area), None);
+                protocol
+            })
+            .collect();
+
+        // The first size is only recorded.
+        tracker.resized(80, 24);
+        assert_eq!(protocols[0].needs_resize(&resize, area), None);
+        tracker.resized(100, 30);
+
+        // Only one protocol refreshes, the others wait even though their area changed.
+        let bigger = Rect::new(0, 0, 8, 4);
+        assert_eq!(protocols[0].needs_resize(&resize, bigger), Some(bigger));
+        assert_eq!(protocols[1].needs_resize(&resize, bigger), None);
+        assert_eq!(protocols[2].needs_resize(&resize, area), None);
+
+        tracker.elapse();
+        assert_eq!(protocols[1].needs_resize(&resize, bigger), Some(bigger));
+        tracker.elapse();
+        // The same area does not need encoding, but the image is sent again.
+        assert_eq!(protocols[2].needs_resize(&resize, area), None);
+        let mut buf = Buffer::empty(area);
+        protocols[2].render(area, &mut buf);
+        assert!(buf[(0, 0)].symbol().ends_with("\x1b7\x1b8"));
+
+        // Refreshed protocols are not stale anymore.
+        assert_eq!(protocols[0].needs_resize(&resize, bigger), Some(bigger));
+        let mut buf = Buffer::empty(area);
+        protocols[2].render(area, &mut buf);
+        assert!(!buf[(0, 0)].symbol().ends_with("\x1b7\x1b8"));
+    }
+}
@@ -0,0 +1,299 @@
+//! Images anchored in a scrolling document, e.g. the history of a chat.
+//!
+//! Each image has an [Anchor] in document space: a row that can be far beyond the screen, a
+//! column, and a size in cells. [ScrollAnchoredView] renders all of them at a scroll offset, so
+//! the app only passes the offset instead of recomputing every image's area:
+//!
+//! ```rust
+//! # use ratatui::Frame;
+//! # use ratatui_image::scroll::{ScrollAnchoredImages, ScrollAnchoredView};
+//! fn ui(f: &mut Frame<'_>, images: &mut ScrollAnchoredImages<u64>, scroll: usize) {
+//!     // Render the text of the history, then the images over it.
+//!     f.render_stateful_widget(ScrollAnchoredView::new(scroll), f.area(), images);
+//! }
+//! ```
+//!
+//! Images that are cut by the top or bottom edge of the viewport are cropped, which encodes them
+//! again at every scroll position while they are cut. Disable it with
+//! [ScrollAnchoredImages::crop] to only show images that are completely visible.
+
+use std::{borrow::Borrow, collections::BTreeMap, marker::PhantomData};
+
+use ratatui::{buffer::Buffer, layout::Rect, widgets::StatefulWidget};
+
+use crate::{protocol::StatefulProtocol, Resize, ResizeEncodeRender, ViewportOptions};
+
+/// Where an image is in the document, in cells.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Anchor {
+    /// The document row of the top of the image.
+    pub row: usize,
+    /// The column within the viewport.
+    pub column: u16,
+    pub width: u16,
+    pub height: u16,
+}
+
+impl Anchor {
+    pub fn new(row: usize, column: u16, width: u16, height: u16) -> Anchor {
+        Anchor {
+            row,
+            column,
+            width,
+            height,
+        }
+    }
+}
+
+/// The visible part of an anchored image, see [ScrollAnchoredImages::placements].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Placement {
+    /// The cells of the viewport that show the image.
+    pub area: Rect,
+    /// How many rows of the image are above the viewport, and cut off.
+    pub hidden_rows: u16,
+    /// Whether the image is cut by an edge of the viewport.
+    pub cropped: bool,
+}
+
+/// The protocols and anchors of the images of a document, keyed by e.g. a message id.
+pub struct ScrollAnchoredImages<K> {
+    images: BTreeMap<K, (StatefulProtocol, Anchor)>,
+    crop: bool,
+}
+
+impl<K: Ord> Default for ScrollAnchoredImages<K> {
+    fn default() -> Self {
+        ScrollAnchoredImages::new()
+    }
+}
+
+impl<K: Ord> ScrollAnchoredImages<K> {
+    pub fn new() -> ScrollAnchoredImages<K> {
+        ScrollAnchoredImages {
+            images: BTreeMap::new(),
+            crop: true,
+        }
+    }
+
+    /// Crop images that are cut by the edges of the viewport (default true), otherwise they are
+    /// not rendered until they are completely visible.
+    pub fn crop(mut self, crop: bool) -> ScrollAnchoredImages<K> {
+        self.crop = crop;
+        self
+    }
+
+    /// Add or replace an image.
+    pub fn insert(&mut self, key: K, protocol: StatefulProtocol, anchor: Anchor) {
+        self.images.insert(key, (protocol, anchor));
+    }
+
+    /// Move an image, e.g. after the text above it was reflowed. Returns false if there is no
+    /// image for the key.
+    pub fn set_anchor<Q>(&mut self, key: &Q, anchor: Anchor) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        match self.images.get_mut(key) {
+            Some((_, previous)) => {
+                *previous = anchor;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<StatefulProtocol>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.images.remove(key).map(|(protocol, _)| protocol)
+    }
+
+    pub fn protocol_mut<Q>(&mut self, key: &Q) -> Option<&mut StatefulProtocol>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.images.get_mut(key).map(|(protocol, _)| protocol)
+    }
+
+    pub fn len(&self) -> usize {
+        self.images.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.images.is_empty()
+    }
+
+    /// The visible images at a scroll offset, in key order.
+    pub fn placements(&self, scroll: usize, viewport: Rect) -> Vec<(&K, Placement)> {
+        self.images
+            .iter()
+            .filter_map(|(key, (_, anchor))| {
+                let placement = placement(*anchor, scroll, viewport)?;
+                (self.crop || !placement.cropped).then_some((key, placement))
+            })
+            .collect()
+    }
+}
+
+fn placement(anchor: Anchor, scroll: usize, viewport: Rect) -> Option<Placement> {
+    let top = anchor.row.max(scroll);
+    let bottom = (anchor.row + anchor.height as usize).min(scroll + viewport.height as usize);
+    let width = anchor
+        .width
+        .min(viewport.width.saturating_sub(anchor.column));
+    if top >= bottom || width == 0 {
+        return None;
+    }
+    let area = Rect::new(
+        viewport.x + anchor.column,
+        viewport.y + (top - scroll) as u16,
+        width,
+        (bottom - top) as u16,
+    );
+    Some(Placement {
+        area,
+        hidden_rows: (top - anchor.row) as u16,
+        cropped: area.width < anchor.width || area.height < anchor.height,
+    })
+}
+
+// Show the part of the image that is in the placement, at the scale that fits the whole image
+// into the anchor, like Resize::Fit.
+fn cropped_resize(protocol: &StatefulProtocol, anchor: Anchor, placement: Placement) -> Resize {
+    let image = protocol.source_image();
+    let (font_width, font_height) = protocol.font_size();
+    let zoom = f32::min(
+        (anchor.width as u32 * font_width as u32) as f32 / image.width().max(1) as f32,
+        (anchor.height as u32 * font_height as u32) as f32 / image.height().max(1) as f32,
+    )
+    .min(1.0);
+    let offset = placement.hidden_rows as f32 * font_height as f32 / zoom;
+    Resize::Viewport(ViewportOptions::new(zoom, (0, offset.round() as u32)))
+}
+
+/// Widget that renders the images of a [ScrollAnchoredImages] at a scroll offset, in rows.
+pub struct ScrollAnchoredView<K> {
+    scroll: usize,
+    resize: Resize,
+    phantom: PhantomData<K>,
+}
+
+impl<K> ScrollAnchoredView<K> {
+    pub fn new(scroll: usize) -> ScrollAnchoredView<K> {
+        ScrollAnchoredView {
+            scroll,
+            resize: Resize::Fit(None),
+            phantom: PhantomData,
+        }
+    }
+
+    /// How completely visible images are resized into their anchor (default [Resize::Fit]).
+    pub fn resize(mut self, resize: Resize) -> ScrollAnchoredView<K> {
+        self.resize = resize;
+        self
+    }
+}
+
+impl<K: Ord> StatefulWidget for ScrollAnchoredView<K> {
+    type State = ScrollAnchoredImages<K>;
+    fn render(self, area: Rect, buf: &mut Buffer, state: &mut Self::State) {
+        for (protocol, anchor) in state.images.values_mut() {
+            let Some(placement) = placement(*anchor, self.scroll, area) else {
+                continue;
+            };
+            if !placement.cropped {
+                ResizeEncodeRender::resize_encode_render(
+                    protocol,
+                    &self.resize,
+                    placement.area,
+                    buf,
+                );
+            } else if state.crop {
+                let resize = cropped_resize(protocol, *anchor, placement);
+                ResizeEncodeRender::resize_encode_render(protocol, &resize, placement.area, buf);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, ImageBuffer, Rgba};
+    use ratatui::{buffer::Buffer, layout::Rect, style::Color, widgets::StatefulWidget};
+
+    use super::{Anchor, Placement, ScrollAnchoredImages, ScrollAnchoredView};
+    use crate::picker::{Picker, ProtocolType};
+
+    #[test]
+    fn test_scroll_anchored_images() {
+        let mut picker = Picker::from_fontsize((10, 20));
+        picker.set_protocol_type(ProtocolType::Halfblocks);
+        // Red on the top half and blue on the bottom half, 4x4 cells.
+        let image: DynamicImage = ImageBuffer::from_fn(40, 80, |_, y| {
+            if y < 40 {
+                Rgba::<u8>([255, 0, 0, 255])
+            } else {
+                Rgba([0, 0, 255, 255])
+            }
+        })
+        .into();
+        let mut images = ScrollAnchoredImages::new();
+        images.insert(
+            1,
+            picker.new_resize_protocol(image.clone()),
+            Anchor::new(2, 0, 4, 4),
+        );
+        images.insert(
+            2,
+            picker.new_resize_protocol(image),
+            Anchor::new(100, 0, 4, 4),
+        );
+        let viewport = Rect::new(0, 1, 10, 5);
+
+        assert_eq!(
+            images.placements(0, viewport),
+            vec![(
+                &1,
+                Placement {
+                    area: Rect::new(0, 3, 4, 3),
+                    hidden_rows: 0,
+                    cropped: true,
+                }
+            )]
+        );
+        assert_eq!(images.placements(10, viewport), vec![]);
+        assert_eq!(
+            images.placements(98, viewport)[0].1,
+            Placement {
+                area: Rect::new(0, 3, 4, 3),
+                hidden_rows: 0,
+                cropped: true,
+            }
+        );
+
+        // Scrolled so that the top half of the first image is above the viewport.
+        let mut buf = Buffer::empty(Rect::new(0, 0, 10, 6));
+        ScrollAnchoredView::new(4).render(viewport, &mut buf, &mut images);
+        assert_eq!(buf[(0, 1)].fg, Color::Rgb(0, 0, 255));
+        assert_eq!(buf[(0, 3)].symbol(), " ");
+
+        // Completely visible.
+        let mut buf = Buffer::empty(Rect::new(0, 0, 10, 6));
+        ScrollAnchoredView::new(1).render(viewport, &mut buf, &mut images);
+        assert_eq!(buf[(0, 2)].fg, Color::Rgb(255, 0, 0));
+        assert_eq!(buf[(0, 5)].fg, Color::Rgb(0, 0, 255));
+
+        // Without cropping, the cut image is not rendered.
+        let mut images = images.crop(false);
+        assert!(images.set_anchor(&1, Anchor::new(0, 0, 4, 4)));
+        assert_eq!(images.placements(2, viewport), vec![]);
+        let mut buf = Buffer::empty(Rect::new(0, 0, 10, 6));
+        ScrollAnchoredView::new(2).render(viewport, &mut buf, &mut images);
+        assert_eq!(buf[(0, 1)].symbol(), " ");
+    }
+}
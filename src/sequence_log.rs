@@ -0,0 +1,335 @@
+//! A log of the raw escape sequences of the image protocols, for replaying them offline.
+//!
+//! Graphics bugs are often specific to one terminal, and hard to reproduce without it. With a
+//! [SequenceLog] set on the [crate::picker::Picker], every graphics payload that a protocol puts
+//! into the buffer is also written to a file, with a timestamp, the protocol and the area:
+//!
+//! ```rust,no_run
+//! # use ratatui_image::{picker::Picker, sequence_log::SequenceLog};
+//! # fn main() -> Result<(), ratatui_image::errors::Errors> {
+//! let mut picker = Picker::from_fontsize((8, 16));
+//! picker.set_sequence_log(Some(SequenceLog::create("images.dump")?));
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Each record is a line `@ <seconds> <protocol> <x>,<y> <width>x<height> <bytes>`, followed by
+//! the payload as it is written at the top-left cell of the area, and a newline. Escape sequences
+//! of other cells, e.g. the rows of Kitty placeholders, are prefixed with their absolute position
+//! (CUP) in the buffer. The payloads can be viewed with `cat images.dump` or `less -R`, or
+//! replayed with their original timing and position by `ratatui-image --replay images.dump`, see
+//! [read_records].
+//!
+//! A [crate::protocol::StatefulProtocol] is logged on render, only when its payload or area
+//! changed since the last logged render. Additional placements are not logged. A fixed
+//! [crate::protocol::Protocol] is logged once, when it is created. Like with [crate::audit],
+//! ratatui only writes the cells that changed, so a payload is not necessarily written to the
+//! terminal again.
+
+use std::{
+    fmt,
+    fs::File,
+    hash::{DefaultHasher, Hash, Hasher},
+    io::{self, BufRead, BufWriter, Write},
+    path::Path,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use ratatui::{buffer::Buffer, layout::Rect};
+
+use crate::{metrics, picker::ProtocolType, Result};
+
+/// Writes the escape sequences of image protocols to a file, see the [module docs](self).
+///
+/// Clones write to the same file. Errors while writing are ignored, so that logging never breaks
+/// rendering.
+#[derive(Clone)]
+pub struct SequenceLog {
+    state: Arc<Mutex<LogState>>,
+}
+
+struct LogState {
+    writer: Box<dyn Write + Send>,
+    start: Instant,
+}
+
+impl SequenceLog {
+    /// Create or truncate the file at `path`.
+    pub fn create(path: impl AsRef<Path>) -> Result<SequenceLog> {
+        let file = File::create(path)?;
+        Ok(SequenceLog::new(BufWriter::new(file)))
+    }
+
+    /// Log into any writer, e.g. a pipe or an in-memory buffer.
+    pub fn new(writer: impl Write + Send + 'static) -> SequenceLog {
+        let log = SequenceLog {
+            state: Arc::new(Mutex::new(LogState {
+                writer: Box::new(writer),
+                start: Instant::now(),
+            })),
+        };
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        let mut state = log.lock();
+        let _ = writeln!(
+            state.writer,
+            "# ratatui-image sequence log, started at unix time {started:.3}"
+        );
+        let _ = state.writer.flush();
+        drop(state);
+        log
+    }
+
+    // Record the payload of the cells of an area, if there is any. Returns the hash of the record,
+    // to skip it next time if it did not change.
+    pub(crate) fn record(
+        &self,
+        protocol: ProtocolType,
+        area: Rect,
+        buf: &Buffer,
+        skip: Option<u64>,
+    ) -> Option<u64> {
+        let payload = payload(area, buf);
+        if payload.is_empty() {
+            return None;
+        }
+        let mut hasher = DefaultHasher::new();
+        (area, &payload).hash(&mut hasher);
+        let hash = hasher.finish();
+        if skip == Some(hash) {
+            return Some(hash);
+        }
+
+        let mut state = self.lock();
+        let seconds = state.start.elapsed().as_secs_f64();
+        let _ = write!(
+            state.writer,
+            "@ {seconds:.6} {} {},{} {}x{} {}\n{payload}\n",
+            metrics::protocol_label(protocol),
+            area.x,
+            area.y,
+            area.width,
+            area.height,
+            payload.len()
+        );
+        // Flush every record, so that the log is complete even if the app crashes.
+        let _ = state.writer.flush();
+        Some(hash)
+    }
+
+    /// Flush the writer, e.g. before reading the log back.
+    pub fn flush(&self) -> io::Result<()> {
+        self.lock().writer.flush()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, LogState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl fmt::Debug for SequenceLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SequenceLog")
+            .field("start", &self.lock().start)
+            .finish()
+    }
+}
+
+/// A record of a [SequenceLog], see [read_records].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LogRecord {
+    /// The time since the log was created.
+    pub time: Duration,
+    /// The protocol name, like `sixel` or `kitty`.
+    pub protocol: String,
+    /// The area that the image was rendered into.
+    pub area: Rect,
+    /// The escape sequences, as written at the top-left cell of the area. Those of other cells
+    /// are prefixed with their position (CUP).
+    pub payload: String,
+}
+
+/// Read the records of a [SequenceLog], e.g. to replay them against another terminal.
+///
+/// Payloads are read by their length, so they may contain anything, including newlines.
+pub fn read_records(mut reader: impl BufRead) -> Result<Vec<LogRecord>> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+    let mut records = vec![];
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(records);
+        }
+        let line = line.trim_end_matches('\n');
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let header = line
+            .strip_prefix("@ ")
+            .and_then(parse_header)
+            .ok_or_else(|| invalid("invalid record header"))?;
+        let (time, protocol, area, len) = header;
+        // The payload, and the newline after it.
+        let mut payload = vec![0; len + 1];
+        reader.read_exact(&mut payload)?;
+        payload.pop();
+        let payload = String::from_utf8(payload).map_err(|_| invalid("payload is not UTF-8"))?;
+        records.push(LogRecord {
+            time,
+            protocol,
+            area,
+            payload,
+        });
+    }
+}
+
+// `<seconds> <protocol> <x>,<y> <width>x<height> <bytes>`
+fn parse_header(header: &str) -> Option<(Duration, String, Rect, usize)> {
+    let mut fields = header.split(' ');
+    let seconds: f64 = fields.next()?.parse().ok()?;
+    let time = Duration::try_from_secs_f64(seconds).ok()?;
+    let protocol = fields.next()?.to_string();
+    let (x, y) = fields.next()?.split_once(',')?;
+    let (width, height) = fields.next()?.split_once('x')?;
+    let area = Rect::new(
+        x.parse().ok()?,
+        y.parse().ok()?,
+        width.parse().ok()?,
+        height.parse().ok()?,
+    );
+    let len = fields.next()?.parse().ok()?;
+    fields
+        .next()
+        .is_none()
+        .then_some((time, protocol, area, len))
+}
+
+// The symbols of the cells with escape sequences, in the order that they are written. All but
+// the one at the top-left cell are positioned, like the backend would.
+fn payload(area: Rect, buf: &Buffer) -> String {
+    let origin = (area.x, area.y);
+    let area = area.intersection(buf.area);
+    let mut payload = String::new();
+    for y in area.top()..area.bottom() {
+        for x in area.left()..area.right() {
+            let symbol = buf[(x, y)].symbol();
+            if symbol.contains('\x1b') {
+                if (x, y) != origin {
+                    payload.push_str(&format!("\x1b[{};{}H", y as u32 + 1, x as u32 + 1));
+                }
+                payload.push_str(symbol);
+            }
+        }
+    }
+    payload
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{self, Write},
+        sync::{Arc, Mutex},
+    };
+
+    use image::{DynamicImage, ImageBuffer, Rgba};
+    use ratatui::{buffer::Buffer, layout::Rect};
+
+    use super::{read_records, SequenceLog};
+    use crate::{
+        picker::{Picker, ProtocolType},
+        Resize,
+    };
+
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_sequence_log() {
+        let out = Shared::default();
+        let mut picker = Picker::from_fontsize((10, 20));
+        picker.set_protocol_type(ProtocolType::Sixel);
+        picker.set_sequence_log(Some(SequenceLog::new(out.clone())));
+        let image: DynamicImage =
+            ImageBuffer::from_pixel(40, 40, Rgba::<u8>([255, 0, 0, 255])).into();
+
+        let mut protocol = picker.new_resize_protocol(image.clone());
+        let area = Rect::new(1, 2, 4, 2);
+        protocol.resize_encode(&Resize::Fit(None), Rgba([0; 4]), area);
+        for _ in 0..2 {
+            let mut buf = Buffer::empty(Rect::new(0, 0, 8, 8));
+            protocol.render(area, &mut buf);
+        }
+        // A fixed protocol is logged on creation.
+        picker
+            .new_protocol(image, Rect::new(0, 0, 4, 2), Resize::Fit(None))
+            .unwrap();
+
+        let log = String::from_utf8(out.0.lock().unwrap().clone()).unwrap();
+        let headers: Vec<_> = log.lines().filter(|line| line.starts_with("@ ")).collect();
+        assert!(log.starts_with("# ratatui-image sequence log"));
+        // The second render was the same, and is not logged again.
+        assert_eq!(headers.len(), 2, "{headers:?}");
+        assert!(headers[0].contains(" sixel 1,2 4x2 "));
+        assert!(headers[1].contains(" sixel 0,0 4x2 "));
+        assert!(log.contains("\x1bPq"));
+
+        let records = read_records(log.as_bytes()).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].protocol, "sixel");
+        assert_eq!(records[0].area, area);
+        assert!(records[0].payload.starts_with("\x1bPq"));
+        assert!(records[0].time <= records[1].time);
+        assert!(read_records("@ 0.1 sixel 0,0 1x1 100\nshort\n".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_kitty_replay() {
+        let out = Shared::default();
+        let mut picker = Picker::from_fontsize((10, 20));
+        picker.set_protocol_type(ProtocolType::Kitty);
+        picker.set_sequence_log(Some(SequenceLog::new(out.clone())));
+        let image: DynamicImage =
+            ImageBuffer::from_pixel(40, 60, Rgba::<u8>([255, 0, 0, 255])).into();
+        let mut protocol = picker.new_resize_protocol(image);
+        let area = Rect::new(2, 1, 4, 3);
+        protocol.resize_encode(&Resize::Fit(None), Rgba([0; 4]), area);
+        let mut buf = Buffer::empty(Rect::new(0, 0, 8, 8));
+        protocol.render(area, &mut buf);
+
+        let log = out.0.lock().unwrap().clone();
+        let records = read_records(log.as_slice()).unwrap();
+        assert_eq!(records.len(), 1);
+        let payload = &records[0].payload;
+        // The replay moves to the area for the first row, the other rows are positioned.
+        assert!(payload.starts_with("\x1b_G"));
+        let second = payload.find("\x1b[3;3H").unwrap();
+        let third = payload.find("\x1b[4;3H").unwrap();
+        assert!(second < third);
+        for (row, placeholders) in [
+            &payload[..second],
+            &payload[second..third],
+            &payload[third..],
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let cell = &buf[(area.x, area.y + row as u16)];
+            assert!(placeholders.ends_with(cell.symbol()), "row {row}");
+            assert_eq!(placeholders.matches('\u{10EEEE}').count(), 4, "row {row}");
+        }
+    }
+}
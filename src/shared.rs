@@ -0,0 +1,142 @@
+//! A [StatefulProtocol] shared between threads, e.g. the render thread and a background worker.
+//!
+//! Rendering needs `&mut StatefulProtocol`, because it resizes and encodes on demand and keeps
+//! track of what it rendered. A [SharedProtocol] is a cheap handle to an `Arc<RwLock<_>>` around
+//! the protocol, which renders through `&self` with [SharedImage]:
+//!
+//! ```rust
+//! # use ratatui::Frame;
+//! # use ratatui_image::{picker::Picker, shared::{SharedImage, SharedProtocol}};
+//! let picker = Picker::from_fontsize((8, 16));
+//! let shared = SharedProtocol::new(picker.new_resize_protocol(image::DynamicImage::new_rgb8(8, 8)));
+//!
+//! let status = shared.clone();
+//! std::thread::spawn(move || {
+//!     let area = status.read().area();
+//!     // ...
+//! });
+//!
+//! fn ui(f: &mut Frame<'_>, shared: &SharedProtocol) {
+//!     f.render_widget(SharedImage::new(shared), f.area());
+//! }
+//! ```
+//!
+//! # Concurrency model
+//!
+//! Rendering takes the write lock for the whole render, including any resizing and encoding that
+//! the area requires, and readers wait meanwhile. Readers only hold the read lock while they look
+//! at the protocol, and must not hold it across a frame, or rendering waits for them. To keep
+//! encoding off the render thread, use [crate::thread::ThreadProtocol] instead.
+//!
+//! A panic while a lock is held does not poison the handle: the protocol stays usable, because
+//! every method leaves it consistent.
+
+use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use ratatui::{buffer::Buffer, layout::Rect, widgets::Widget};
+
+use crate::{protocol::StatefulProtocol, Resize, ResizeEncodeRender};
+
+/// A [StatefulProtocol] that can be shared between threads, see the [module docs](self).
+///
+/// Clones refer to the same protocol.
+#[derive(Clone)]
+pub struct SharedProtocol {
+    protocol: Arc<RwLock<StatefulProtocol>>,
+}
+
+impl SharedProtocol {
+    pub fn new(protocol: StatefulProtocol) -> SharedProtocol {
+        SharedProtocol {
+            protocol: Arc::new(RwLock::new(protocol)),
+        }
+    }
+
+    /// Lock the protocol for reading, e.g. [StatefulProtocol::area] or
+    /// [StatefulProtocol::font_size].
+    pub fn read(&self) -> RwLockReadGuard<'_, StatefulProtocol> {
+        self.protocol.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Lock the protocol for changes, e.g. [StatefulProtocol::set_image].
+    pub fn write(&self) -> RwLockWriteGuard<'_, StatefulProtocol> {
+        self.protocol
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Resize and encode if necessary, and render, like [crate::StatefulImage].
+    pub fn render(&self, resize: &Resize, area: Rect, buf: &mut Buffer) {
+        ResizeEncodeRender::resize_encode_render(&mut *self.write(), resize, area, buf);
+    }
+}
+
+/// Widget that renders a [SharedProtocol] through a shared reference.
+pub struct SharedImage<'a> {
+    protocol: &'a SharedProtocol,
+    resize: Resize,
+}
+
+impl<'a> SharedImage<'a> {
+    pub fn new(protocol: &'a SharedProtocol) -> SharedImage<'a> {
+        SharedImage {
+            protocol,
+            resize: Resize::Fit(None),
+        }
+    }
+
+    pub fn resize(mut self, resize: Resize) -> SharedImage<'a> {
+        self.resize = resize;
+        self
+    }
+}
+
+impl Widget for SharedImage<'_> {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        self.protocol.render(&self.resize, area, buf);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use image::{DynamicImage, ImageBuffer, Rgba};
+    use ratatui::{buffer::Buffer, layout::Rect, style::Color, widgets::Widget};
+
+    use super::{SharedImage, SharedProtocol};
+    use crate::picker::{Picker, ProtocolType};
+
+    #[test]
+    fn test_shared_protocol() {
+        let mut picker = Picker::from_fontsize((10, 20));
+        picker.set_protocol_type(ProtocolType::Halfblocks);
+        let image: DynamicImage =
+            ImageBuffer::from_pixel(40, 40, Rgba::<u8>([255, 0, 0, 255])).into();
+        let shared = SharedProtocol::new(picker.new_resize_protocol(image));
+
+        let reader = shared.clone();
+        let font_size = thread::spawn(move || reader.read().font_size())
+            .join()
+            .unwrap();
+        assert_eq!(font_size, (10, 20));
+
+        let area = Rect::new(0, 0, 4, 2);
+        let mut buf = Buffer::empty(area);
+        SharedImage::new(&shared).render(area, &mut buf);
+        assert_eq!(buf[(0, 0)].fg, Color::Rgb(255, 0, 0));
+        assert_eq!(shared.read().area(), area);
+
+        // Replaced from another thread, rendered on the next frame.
+        let writer = shared.clone();
+        thread::spawn(move || {
+            let blue = ImageBuffer::from_pixel(40, 40, Rgba::<u8>([0, 0, 255, 255]));
+            writer.write().set_image(blue.into());
+        })
+        .join()
+        .unwrap();
+        let mut buf = Buffer::empty(area);
+        SharedImage::new(&shared).render(area, &mut buf);
+        assert_eq!(buf[(0, 0)].fg, Color::Rgb(0, 0, 255));
+    }
+}
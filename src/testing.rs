@@ -0,0 +1,271 @@
+//! Helpers for regression tests of image screens, without a real terminal.
+//!
+//! Graphics protocols are drawn by the terminal, so a test cannot look at them. Instead, the image
+//! of a protocol is rendered with halfblocks, and the cells of the buffer are rasterized to one
+//! pixel per half cell. The result is compared against a golden PNG, with a tolerance for small
+//! differences of the resizing filters:
+//!
+//! ```rust,no_run
+//! # use ratatui::layout::Rect;
+//! # use ratatui_image::{assert_renders_like, picker::Picker};
+//! let picker = Picker::from_fontsize((8, 16));
+//! let protocol = picker.new_resize_protocol(image::DynamicImage::new_rgb8(64, 64));
+//! assert_renders_like!(protocol, Rect::new(0, 0, 8, 4), "tests/golden/screen.png");
+//! ```
+//!
+//! A missing golden file fails the test. Run the tests with `RATATUI_IMAGE_UPDATE_GOLDEN=1` to
+//! write the current output as golden files.
+//!
+//! Needs the `test-utils` feature.
+
+use std::{env, fmt, path::Path};
+
+use image::{Rgba, RgbaImage};
+use ratatui::{buffer::Buffer, layout::Rect, style::Color, widgets::StatefulWidget};
+
+use crate::{
+    picker::{Picker, ProtocolType},
+    protocol::{halfblocks::ansi256, StatefulProtocol},
+    Resize, StatefulImage,
+};
+
+/// Set to write the golden files instead of comparing against them.
+pub const UPDATE_GOLDEN_ENV: &str = "RATATUI_IMAGE_UPDATE_GOLDEN";
+
+/// The default tolerance of [assert_renders_like!], per color channel.
+pub const DEFAULT_TOLERANCE: u8 = 8;
+
+/// Assert that a [StatefulProtocol] renders like a golden PNG, see the [module docs](self).
+///
+/// `assert_renders_like!(protocol, area, golden)` uses [Resize::Fit] and the
+/// [DEFAULT_TOLERANCE], `assert_renders_like!(protocol, area, golden, resize, tolerance)`
+/// overrides them.
+#[macro_export]
+macro_rules! assert_renders_like {
+    ($protocol:expr, $area:expr, $golden:expr $(,)?) => {
+        $crate::assert_renders_like!(
+            $protocol,
+            $area,
+            $golden,
+            $crate::Resize::Fit(None),
+            $crate::testing::DEFAULT_TOLERANCE
+        )
+    };
+    ($protocol:expr, $area:expr, $golden:expr, $resize:expr, $tolerance:expr $(,)?) => {
+        $crate::testing::assert_renders_like(&$protocol, $area, $golden, $resize, $tolerance)
+    };
+}
+
+/// The function behind [assert_renders_like!].
+#[track_caller]
+pub fn assert_renders_like(
+    protocol: &StatefulProtocol,
+    area: Rect,
+    golden: impl AsRef<Path>,
+    resize: Resize,
+    tolerance: u8,
+) {
+    let golden = golden.as_ref();
+    let actual = render_halfblocks(protocol, area, resize);
+    if env::var_os(UPDATE_GOLDEN_ENV).is_some() {
+        if let Some(dir) = golden.parent() {
+            std::fs::create_dir_all(dir).unwrap();
+        }
+        actual.save(golden).unwrap();
+        return;
+    }
+    let expected = match image::open(golden) {
+        Ok(expected) => expected.to_rgba8(),
+        Err(err) => panic!(
+            "could not read golden {}: {err}, run with {UPDATE_GOLDEN_ENV}=1 to write it",
+            golden.display()
+        ),
+    };
+    if let Some(diff) = compare(&actual, &expected, tolerance) {
+        panic!("render does not match golden {}: {diff}", golden.display());
+    }
+}
+
+/// Render the image of a protocol with halfblocks into an area, and rasterize the cells.
+///
+/// The font size of the protocol is kept, so that the image is resized like the real protocol
+/// would resize it.
+pub fn render_halfblocks(protocol: &StatefulProtocol, area: Rect, resize: Resize) -> RgbaImage {
+    let mut picker = Picker::from_fontsize(protocol.font_size());
+    picker.set_protocol_type(ProtocolType::Halfblocks);
+    let mut halfblocks = picker.new_resize_protocol(protocol.source_image().clone());
+    let mut buf = Buffer::empty(area);
+    StatefulImage::default()
+        .resize(resize)
+        .render(area, &mut buf, &mut halfblocks);
+    rasterize_buffer(&buf, area)
+}
+
+/// Rasterize the cells of an area to two pixels per cell, the upper and the lower half.
+///
+/// Halfblocks and full blocks are drawn with their colors, braille with the foreground color
+/// where a half has any dots. Any other symbol is drawn with the background color. The default
+/// color ([Color::Reset]) is transparent.
+pub fn rasterize_buffer(buf: &Buffer, area: Rect) -> RgbaImage {
+    let area = area.intersection(buf.area);
+    let mut image = RgbaImage::new(area.width as u32, area.height as u32 * 2);
+    for y in 0..area.height {
+        for x in 0..area.width {
+            let cell = &buf[(area.x + x, area.y + y)];
+            let (fg, bg) = (rgba(cell.fg), rgba(cell.bg));
+            let (upper, lower) = match cell.symbol().chars().next().unwrap_or(' ') {
+                '▀' => (fg, bg),
+                '▄' => (bg, fg),
+                '█' => (fg, fg),
+                braille @ '\u{2800}'..='\u{28ff}' => {
+                    let dots = braille as u32 - 0x2800;
+                    // Dots 1, 2, 4 and 5 are the upper half.
+                    let half = |mask| if dots & mask != 0 { fg } else { bg };
+                    (half(0x1b), half(0xe4))
+                }
+                _ => (bg, bg),
+            };
+            image.put_pixel(x as u32, y as u32 * 2, upper);
+            image.put_pixel(x as u32, y as u32 * 2 + 1, lower);
+        }
+    }
+    image
+}
+
+/// How two images differ, see [compare].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ImageDiff {
+    Size {
+        actual: (u32, u32),
+        expected: (u32, u32),
+    },
+    Pixels {
+        /// How many pixels differ by more than the tolerance.
+        count: usize,
+        /// The first of them.
+        first: (u32, u32),
+        /// The largest difference of any channel.
+        max: u8,
+    },
+}
+
+impl fmt::Display for ImageDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImageDiff::Size { actual, expected } => {
+                write!(f, "size is {actual:?}, expected {expected:?}")
+            }
+            ImageDiff::Pixels { count, first, max } => write!(
+                f,
+                "{count} pixels differ, the first at {first:?}, by up to {max}"
+            ),
+        }
+    }
+}
+
+/// Compare two images, where channels may differ by up to `tolerance`.
+pub fn compare(actual: &RgbaImage, expected: &RgbaImage, tolerance: u8) -> Option<ImageDiff> {
+    if actual.dimensions() != expected.dimensions() {
+        return Some(ImageDiff::Size {
+            actual: actual.dimensions(),
+            expected: expected.dimensions(),
+        });
+    }
+    let mut diff = None;
+    for ((x, y, a), b) in actual.enumerate_pixels().zip(expected.pixels()) {
+        let difference = a.0.iter().zip(b.0).map(|(a, b)| a.abs_diff(b)).max();
+        let difference = difference.unwrap_or(0);
+        if difference > tolerance {
+            let (count, _, max) = diff.get_or_insert((0, (x, y), 0));
+            *count += 1;
+            *max = difference.max(*max);
+        }
+    }
+    diff.map(|(count, first, max)| ImageDiff::Pixels { count, first, max })
+}
+
+fn rgba(color: Color) -> Rgba<u8> {
+    let index = match color {
+        Color::Reset => return Rgba([0, 0, 0, 0]),
+        Color::Rgb(r, g, b) => return Rgba([r, g, b, 255]),
+        Color::Indexed(index) => index,
+        Color::Black => 0,
+        Color::Red => 1,
+        Color::Green => 2,
+        Color::Yellow => 3,
+        Color::Blue => 4,
+        Color::Magenta => 5,
+        Color::Cyan => 6,
+        Color::Gray => 7,
+        Color::DarkGray => 8,
+        Color::LightRed => 9,
+        Color::LightGreen => 10,
+        Color::LightYellow => 11,
+        Color::LightBlue => 12,
+        Color::LightMagenta => 13,
+        Color::LightCyan => 14,
+        Color::White => 15,
+    };
+    let [r, g, b] = ansi256(index);
+    Rgba([r, g, b, 255])
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, ImageBuffer, Rgba};
+    use ratatui::{buffer::Buffer, layout::Rect, style::Color};
+
+    use super::{compare, rasterize_buffer, render_halfblocks, ImageDiff};
+    use crate::{picker::Picker, Resize};
+
+    #[test]
+    fn test_rasterize_and_compare() {
+        let mut buf = Buffer::empty(Rect::new(0, 0, 2, 1));
+        buf[(0, 0)]
+            .set_char('▀')
+            .set_fg(Color::Rgb(255, 0, 0))
+            .set_bg(Color::Indexed(4));
+        buf[(1, 0)].set_char('⣀').set_fg(Color::White);
+        let image = rasterize_buffer(&buf, buf.area);
+        assert_eq!(image.dimensions(), (2, 2));
+        assert_eq!(image.get_pixel(0, 0), &Rgba([255, 0, 0, 255]));
+        assert_eq!(image.get_pixel(0, 1), &Rgba([0, 0, 238, 255]));
+        // Only the bottom row of dots.
+        assert_eq!(image.get_pixel(1, 0), &Rgba([0, 0, 0, 0]));
+        assert_eq!(image.get_pixel(1, 1), &Rgba([255, 255, 255, 255]));
+
+        let mut other = image.clone();
+        other.put_pixel(0, 0, Rgba([250, 0, 0, 255]));
+        assert_eq!(compare(&image, &other, 8), None);
+        other.put_pixel(0, 1, Rgba([0, 0, 200, 255]));
+        assert_eq!(
+            compare(&image, &other, 8),
+            Some(ImageDiff::Pixels {
+                count: 1,
+                first: (0, 1),
+                max: 38
+            })
+        );
+    }
+
+    #[test]
+    fn test_render_halfblocks() {
+        // Red on the left and blue on the right, for any protocol.
+        let image: DynamicImage = ImageBuffer::from_fn(40, 40, |x, _| {
+            if x < 20 {
+                Rgba::<u8>([255, 0, 0, 255])
+            } else {
+                Rgba([0, 0, 255, 255])
+            }
+        })
+        .into();
+        let mut picker = Picker::from_fontsize((10, 20));
+        picker.set_protocol_type(crate::picker::ProtocolType::Sixel);
+        let protocol = picker.new_resize_protocol(image);
+        let area = Rect::new(0, 0, 4, 2);
+        let rendered = render_halfblocks(&protocol, area, Resize::Fit(None));
+        assert_eq!(rendered.dimensions(), (4, 4));
+        assert_eq!(rendered.get_pixel(0, 0), &Rgba([255, 0, 0, 255]));
+        assert_eq!(rendered.get_pixel(3, 3), &Rgba([0, 0, 255, 255]));
+    }
+}
@@ -11,7 +11,7 @@ use ratatui::{
     widgets::StatefulWidget,
 };
 
-use crate::{protocol::StatefulProtocol, Resize};
+use crate::{errors::Errors, metrics, protocol::StatefulProtocol, Resize, ResizeEncodeRender};
 
 /// A widget that uses a custom ThreadProtocol as state to offload resizing and encoding to a
 /// background thread.
@@ -38,33 +38,45 @@ impl StatefulWidget for ThreadImage {
     type State = ThreadProtocol;
 
     fn render(self, area: Rect, buf: &mut Buffer, state: &mut Self::State) {
-        state.inner = match state.inner.take() {
-            // We have the `protocol` and should either resize or render.
-            Some(mut protocol) => {
-                // If it needs resizing (grow or shrink) then send it away instead of rendering.
-                // Send the requested area instead of the calculated area
-                // to ensure consistent calculations between the render thread and the UI thread.
-                if let Some(area) = protocol.needs_resize(&self.resize, area) {
-                    state.tx.send((protocol, self.resize, area)).unwrap();
-                    None
-                } else {
-                    protocol.render(area, buf);
-                    Some(protocol)
-                }
-            }
-            // We are waiting to get back the protocol.
-            None => None,
-        };
+        state.resize_encode_render(&self.resize, area, buf);
     }
 }
 
+/// What a [ThreadProtocol] is doing, see [ThreadProtocol::state].
+#[derive(Debug, Clone, Copy)]
+pub enum ThreadState<'a> {
+    /// Not encoded yet, either waiting for the first render or away on the worker thread.
+    Loading,
+    /// Encoded, and rendered on every frame.
+    Ready,
+    /// Away on the worker thread being resized and encoded again, e.g. after the area changed.
+    /// Nothing is rendered until it comes back, unless switching protocols.
+    Encoding,
+    /// The last encoding failed. The previous encoding, if any, is still rendered.
+    Failed(&'a Errors),
+}
+
+// Where the protocol of a ThreadProtocol is. There is only one per ThreadProtocol, so the size of
+// the Away variant does not matter.
+#[allow(clippy::large_enum_variant)]
+enum ProtocolState {
+    // On the UI thread, to render or to send off.
+    Here(StatefulProtocol),
+    // Sent to the worker thread, and whether it had been encoded before.
+    Away { encoded: bool },
+}
+
 /// The state of a ThreadImage.
 ///
-/// Has `inner` [ResizeProtocol] that is sent off to the `tx` mspc channel to do the
+/// Has a [StatefulProtocol] that is sent off to the `tx` mspc channel to do the
 /// `resize_encode()` work.
 pub struct ThreadProtocol {
-    inner: Option<StatefulProtocol>,
+    inner: ProtocolState,
     tx: Sender<(StatefulProtocol, Resize, Rect)>,
+    // Still rendered while the protocol switched to with `switch_protocol` is being encoded.
+    previous: Option<StatefulProtocol>,
+    // Switched to while `inner` was away being resized, swapped in when that one comes back.
+    pending: Option<StatefulProtocol>,
 }
 
 impl ThreadProtocol {
@@ -73,11 +85,237 @@ impl ThreadProtocol {
         inner: StatefulProtocol,
     ) -> ThreadProtocol {
         ThreadProtocol {
-            inner: Some(inner),
+            inner: ProtocolState::Here(inner),
             tx,
+            previous: None,
+            pending: None,
         }
     }
+
+    /// Whether the protocol is loading, ready, being encoded or failed, e.g. to show a spinner or
+    /// an error instead of the image.
+    pub fn state(&self) -> ThreadState<'_> {
+        match &self.inner {
+            ProtocolState::Here(protocol) => match protocol.last_encoding_result() {
+                None => ThreadState::Loading,
+                Some(Ok(())) => ThreadState::Ready,
+                Some(Err(err)) => ThreadState::Failed(err),
+            },
+            ProtocolState::Away { encoded: false } => ThreadState::Loading,
+            ProtocolState::Away { encoded: true } => ThreadState::Encoding,
+        }
+    }
+
+    /// The protocol, unless it is away on the worker thread.
+    pub fn protocol(&self) -> Option<&StatefulProtocol> {
+        match &self.inner {
+            ProtocolState::Here(protocol) => Some(protocol),
+            ProtocolState::Away { .. } => None,
+        }
+    }
+
+    fn protocol_mut(&mut self) -> Option<&mut StatefulProtocol> {
+        match &mut self.inner {
+            ProtocolState::Here(protocol) => Some(protocol),
+            ProtocolState::Away { .. } => None,
+        }
+    }
+
+    /// Set the protocol that came back from the worker thread.
     pub fn set_protocol(&mut self, proto: StatefulProtocol) {
-        self.inner = Some(proto);
+        if matches!(self.inner, ProtocolState::Away { .. }) {
+            metrics::queue_depth(-1.0);
+        }
+        match self.pending.take() {
+            // The old protocol came back, keep it around until the new one has been encoded.
+            Some(pending) => {
+                self.previous = Some(proto);
+                self.inner = ProtocolState::Here(pending);
+            }
+            None => {
+                self.previous = None;
+                self.inner = ProtocolState::Here(proto);
+            }
+        }
+    }
+
+    /// Switch to another protocol, e.g. after [crate::picker::Picker::set_protocol_type], without
+    /// flashing blank while it is encoded.
+    ///
+    /// The current protocol keeps being rendered until the new one comes back from its first
+    /// resize+encode, and is then replaced in the same frame.
+    pub fn switch_protocol(&mut self, proto: StatefulProtocol) {
+        match &mut self.inner {
+            ProtocolState::Here(current) => {
+                let current = std::mem::replace(current, proto);
+                // If switching again before the last switch completed, keep the one shown now.
+                if self.previous.is_none() {
+                    self.previous = Some(current);
+                }
+            }
+            // The current protocol is being resized, swap when it comes back.
+            ProtocolState::Away { .. } => self.pending = Some(proto),
+        }
+    }
+
+    /// See [StatefulProtocol::force_redraw_next_frame]. Has no effect while the protocol is being
+    /// resized, since it will be rendered anew when it comes back anyway.
+    pub fn force_redraw_next_frame(&mut self) {
+        if let Some(protocol) = self.protocol_mut() {
+            protocol.force_redraw_next_frame();
+        }
+    }
+}
+
+impl Drop for ThreadProtocol {
+    fn drop(&mut self) {
+        // The protocol will never be set back, so it is not waiting anymore.
+        if matches!(self.inner, ProtocolState::Away { .. }) {
+            metrics::queue_depth(-1.0);
+        }
+    }
+}
+
+impl ResizeEncodeRender for ThreadProtocol {
+    fn needs_resize(&mut self, resize: &Resize, area: Rect) -> Option<Rect> {
+        self.protocol_mut()
+            .and_then(|protocol| protocol.needs_resize(resize, area))
+    }
+
+    /// Resize and encode on the current thread, blocking.
+    fn resize_encode(&mut self, resize: &Resize, area: Rect) {
+        if let Some(protocol) = self.protocol_mut() {
+            ResizeEncodeRender::resize_encode(protocol, resize, area);
+        }
+    }
+
+    fn render(&mut self, area: Rect, buf: &mut Buffer) {
+        if let Some(protocol) = self.protocol_mut() {
+            protocol.render(area, buf);
+        }
+    }
+
+    /// Applies to the previous protocol too. While the protocol is being resized, the next call
+    /// after it came back applies it.
+    fn set_z_index(&mut self, z_index: i32) {
+        if let Some(protocol) = self.protocol_mut() {
+            protocol.set_z_index(z_index);
+        }
+        if let Some(previous) = self.previous.as_mut() {
+            previous.set_z_index(z_index);
+        }
+    }
+
+    /// Send the protocol off to the worker thread if it needs resizing, otherwise render it.
+    fn resize_encode_render(&mut self, resize: &Resize, area: Rect, buf: &mut Buffer) {
+        if let ProtocolState::Here(protocol) = &mut self.inner {
+            // If it needs resizing (grow or shrink) then send it away instead of rendering.
+            // Send the requested area instead of the calculated area
+            // to ensure consistent calculations between the render thread and the UI thread.
+            match protocol.needs_resize(resize, area) {
+                Some(area) => {
+                    let encoded = protocol.last_encoding_result().is_some();
+                    let away = ProtocolState::Away { encoded };
+                    if let ProtocolState::Here(protocol) = std::mem::replace(&mut self.inner, away)
+                    {
+                        self.tx.send((protocol, resize.clone(), area)).unwrap();
+                        metrics::queue_depth(1.0);
+                    }
+                }
+                None => {
+                    protocol.render(area, buf);
+                    return;
+                }
+            }
+        }
+        // We are waiting to get back the protocol.
+        if let Some(previous) = self.previous.as_mut() {
+            previous.render(area, buf);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use image::{DynamicImage, ImageBuffer, Rgba};
+    use ratatui::{
+        buffer::Buffer,
+        layout::Rect,
+        style::{Color, Style},
+    };
+
+    use crate::{
+        picker::{Picker, ProtocolType},
+        Resize, ResizeEncodeRender,
+    };
+
+    use super::{ThreadProtocol, ThreadState};
+
+    #[test]
+    fn test_switch_protocol() {
+        let image: DynamicImage =
+            ImageBuffer::from_pixel(40, 40, Rgba::<u8>([255, 0, 0, 255])).into();
+        let mut picker = Picker::from_fontsize((10, 10));
+        picker.set_protocol_type(ProtocolType::Halfblocks);
+        let (tx, rx) = mpsc::channel();
+        let mut state = ThreadProtocol::new(tx, picker.new_resize_protocol(image.clone()));
+        let resize = Resize::Fit(None);
+        let area = Rect::new(0, 0, 4, 4);
+        let mut buf = Buffer::empty(area);
+
+        // Encode the first protocol on the "worker".
+        assert!(matches!(state.state(), ThreadState::Loading));
+        state.resize_encode_render(&resize, area, &mut buf);
+        assert!(matches!(state.state(), ThreadState::Loading));
+        let (mut protocol, resize, requested) = rx.try_recv().unwrap();
+        protocol.resize_encode(&resize, protocol.background_color(), requested);
+        state.set_protocol(protocol);
+        assert!(matches!(state.state(), ThreadState::Ready));
+        state.resize_encode_render(&resize, area, &mut buf);
+        assert_eq!(buf[(0, 0)].symbol(), "▀");
+
+        // Encoding again for another area.
+        state.resize_encode_render(&resize, Rect::new(0, 0, 2, 2), &mut buf);
+        assert!(matches!(state.state(), ThreadState::Encoding));
+        assert!(state.protocol().is_none());
+        let (protocol, _, _) = rx.try_recv().unwrap();
+        state.set_protocol(protocol);
+
+        // The new protocol is away being encoded, but the old one is still rendered.
+        picker.set_protocol_type(ProtocolType::Kitty);
+        state.switch_protocol(picker.new_resize_protocol(image));
+        let mut buf = Buffer::empty(area);
+        state.resize_encode_render(&resize, area, &mut buf);
+        assert_eq!(buf[(0, 0)].symbol(), "▀");
+        assert_eq!(
+            buf[(0, 0)].style(),
+            Style::new()
+                .fg(Color::Rgb(255, 0, 0))
+                .bg(Color::Rgb(255, 0, 0))
+        );
+
+        // The new protocol replaces the old one as soon as it is back.
+        let (mut protocol, resize, requested) = rx.try_recv().unwrap();
+        protocol.resize_encode(&resize, protocol.background_color(), requested);
+        state.set_protocol(protocol);
+        let mut buf = Buffer::empty(area);
+        state.resize_encode_render(&resize, area, &mut buf);
+        assert!(buf[(0, 0)].symbol().starts_with("\x1b_G"));
+        assert!(state.previous.is_none());
+    }
+
+    #[test]
+    fn test_failed_state() {
+        let image: DynamicImage =
+            ImageBuffer::from_pixel(40, 40, Rgba::<u8>([255, 0, 0, 255])).into();
+        // Without a font size, the image is resized to nothing, which is not a valid PNG.
+        let mut picker = Picker::from_fontsize((0, 0));
+        picker.set_protocol_type(ProtocolType::Iterm2);
+        let (tx, _rx) = mpsc::channel();
+        let mut state = ThreadProtocol::new(tx, picker.new_resize_protocol(image));
+        ResizeEncodeRender::resize_encode(&mut state, &Resize::Fit(None), Rect::new(0, 0, 4, 4));
+        assert!(matches!(state.state(), ThreadState::Failed(_)));
     }
 }
@@ -0,0 +1,334 @@
+//! Per-pane state inside tmux.
+//!
+//! Graphics are passed through tmux to the outer terminal, which draws them at absolute screen
+//! positions. When panes are resized, moved or zoomed, the images of one pane can stay visible
+//! over its neighbours, because tmux only knows about the text that it redraws.
+//!
+//! A [TmuxPane] identifies the pane of the program by `$TMUX_PANE`, and detects changes of the
+//! pane's geometry. On a change, the images of a [ReflowTracker] are marked as stale so that they
+//! are sent again at their new position, and [TmuxPane::refresh_client] makes tmux redraw the
+//! panes over any leftovers:
+//!
+//! ```rust
+//! # use ratatui_image::tmux::TmuxPane;
+//! // The pane is created once with `picker.tmux_pane()`, and polled on every resize event.
+//! fn on_resize(pane: &mut Option<TmuxPane>) -> Result<(), ratatui_image::errors::Errors> {
+//!     if let Some(pane) = pane {
+//!         if pane.poll()? {
+//!             pane.refresh_client()?;
+//!         }
+//!     }
+//!     Ok(())
+//! }
+//! ```
+//!
+//! The [OuterTerminal] is the terminal that the tmux client runs in, which is what actually
+//! draws the graphics. The picker asks tmux about it, instead of guessing from environment
+//! variables that tmux may have inherited from another terminal.
+
+use std::{env, process::Command};
+
+use crate::{errors::Errors, picker::ProtocolType, reflow::ReflowTracker, Result};
+
+/// The position and size of a pane within the tmux window, in cells.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub struct PaneGeometry {
+    pub left: u16,
+    pub top: u16,
+    pub width: u16,
+    pub height: u16,
+}
+
+impl PaneGeometry {
+    // The format for `tmux display-message`, see PaneGeometry::parse.
+    const FORMAT: &'static str = "#{pane_left} #{pane_top} #{pane_width} #{pane_height}";
+
+    /// Parse the output of `tmux display-message -p '#{pane_left} #{pane_top} #{pane_width}
+    /// #{pane_height}'`.
+    pub fn parse(output: &str) -> Option<PaneGeometry> {
+        let mut values = output.split_whitespace().map(|value| value.parse().ok());
+        let geometry = PaneGeometry {
+            left: values.next()??,
+            top: values.next()??,
+            width: values.next()??,
+            height: values.next()??,
+        };
+        values.next().is_none().then_some(geometry)
+    }
+
+    /// Convert a position within the pane to a position within the tmux window, which is where
+    /// the outer terminal draws passed-through graphics.
+    pub fn to_window(&self, (x, y): (u16, u16)) -> (u16, u16) {
+        (self.left.saturating_add(x), self.top.saturating_add(y))
+    }
+
+    /// Convert a position within the tmux window to a position within the pane, if it is inside.
+    pub fn to_pane(&self, (x, y): (u16, u16)) -> Option<(u16, u16)> {
+        let (x, y) = (x.checked_sub(self.left)?, y.checked_sub(self.top)?);
+        (x < self.width && y < self.height).then_some((x, y))
+    }
+}
+
+/// The terminal of the tmux client, see the [module docs](self).
+#[derive(PartialEq, Eq, Clone, Debug, Default)]
+pub struct OuterTerminal {
+    /// The `TERM` of the client, e.g. `xterm-kitty`.
+    pub name: String,
+    /// The name and version that the terminal reported, e.g. `iTerm2 3.5.0`, or empty if it did
+    /// not answer (or before tmux 3.3).
+    pub version: String,
+    /// The features that tmux uses with the terminal, e.g. `sixel` or `RGB`.
+    pub features: Vec<String>,
+}
+
+impl OuterTerminal {
+    // The format for `tmux display-message`, see OuterTerminal::parse.
+    const FORMAT: &'static str = "#{client_termname}\t#{client_termtype}\t#{client_termfeatures}";
+
+    /// Parse the output of `tmux display-message -p
+    /// '#{client_termname}\t#{client_termtype}\t#{client_termfeatures}'`.
+    pub fn parse(output: &str) -> Option<OuterTerminal> {
+        let mut fields = output.trim_end_matches('\n').split('\t');
+        let terminal = OuterTerminal {
+            name: fields.next()?.to_string(),
+            version: fields.next()?.to_string(),
+            features: fields
+                .next()?
+                .split(',')
+                .filter(|feature| !feature.is_empty())
+                .map(String::from)
+                .collect(),
+        };
+        (fields.next().is_none() && !terminal.name.is_empty()).then_some(terminal)
+    }
+
+    /// Ask tmux about the terminal of the current client.
+    pub fn query() -> Result<OuterTerminal> {
+        let output = Command::new("tmux")
+            .args(["display-message", "-p", OuterTerminal::FORMAT])
+            .stdin(std::process::Stdio::null())
+            .output()?;
+        if !output.status.success() {
+            return Err(Errors::Tmux("display-message failed"));
+        }
+        OuterTerminal::parse(&String::from_utf8_lossy(&output.stdout))
+            .ok_or(Errors::Tmux("unexpected display-message output"))
+    }
+
+    /// The graphics protocol that the terminal is known to support, if any.
+    pub fn protocol_type(&self) -> Option<ProtocolType> {
+        let name = format!("{} {}", self.name, self.version).to_lowercase();
+        const HINTS: [(&str, ProtocolType); 5] = [
+            ("kitty", ProtocolType::Kitty),
+            ("ghostty", ProtocolType::Kitty),
+            ("iterm", ProtocolType::Iterm2),
+            ("wezterm", ProtocolType::Iterm2),
+            ("terminology", ProtocolType::Terminology),
+        ];
+        HINTS
+            .iter()
+            .find(|(hint, _)| name.contains(hint))
+            .map(|(_, protocol_type)| *protocol_type)
+            .or_else(|| {
+                self.features
+                    .iter()
+                    .any(|feature| feature == "sixel")
+                    .then_some(ProtocolType::Sixel)
+            })
+    }
+}
+
+/// The tmux pane of the program, see the [module docs](self).
+#[derive(Clone, Debug)]
+pub struct TmuxPane {
+    id: String,
+    geometry: Option<PaneGeometry>,
+    tracker: Option<ReflowTracker>,
+}
+
+impl TmuxPane {
+    /// The pane from `$TMUX_PANE`, or `None` outside of tmux.
+    pub fn from_env() -> Option<TmuxPane> {
+        env::var("TMUX_PANE")
+            .ok()
+            .filter(|id| !id.is_empty())
+            .map(TmuxPane::new)
+    }
+
+    /// A pane by its id, like `%3`.
+    pub fn new(id: impl Into<String>) -> TmuxPane {
+        TmuxPane {
+            id: id.into(),
+            geometry: None,
+            tracker: None,
+        }
+    }
+
+    /// Mark the protocols of the tracker as stale when the geometry changes, so that they are
+    /// sent again at their new position. See [crate::picker::Picker::set_reflow_tracker].
+    pub fn reflow_tracker(mut self, tracker: Option<ReflowTracker>) -> TmuxPane {
+        self.tracker = tracker;
+        self
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// The geometry of the last [TmuxPane::update] or [TmuxPane::poll].
+    pub fn geometry(&self) -> Option<PaneGeometry> {
+        self.geometry
+    }
+
+    /// Ask tmux for the current geometry of the pane.
+    pub fn query_geometry(&self) -> Result<PaneGeometry> {
+        let output = Command::new("tmux")
+            .args([
+                "display-message",
+                "-p",
+                "-t",
+                &self.id,
+                PaneGeometry::FORMAT,
+            ])
+            .output()?;
+        if !output.status.success() {
+            return Err(Errors::Tmux("display-message failed"));
+        }
+        PaneGeometry::parse(&String::from_utf8_lossy(&output.stdout))
+            .ok_or(Errors::Tmux("unexpected display-message output"))
+    }
+
+    /// Record the geometry of the pane, e.g. from [TmuxPane::query_geometry]. Returns whether it
+    /// changed since the last call, in which case the protocols of the tracker are stale.
+    ///
+    /// The first geometry is only recorded.
+    pub fn update(&mut self, geometry: PaneGeometry) -> bool {
+        let changed = self.geometry.is_some_and(|previous| previous != geometry);
+        self.geometry = Some(geometry);
+        if changed {
+            if let Some(tracker) = &self.tracker {
+                tracker.invalidate();
+            }
+        }
+        changed
+    }
+
+    /// Query and [TmuxPane::update] the geometry, e.g. on every resize event of the app.
+    pub fn poll(&mut self) -> Result<bool> {
+        let geometry = self.query_geometry()?;
+        Ok(self.update(geometry))
+    }
+
+    /// Make tmux redraw all panes of the client, which overwrites graphics that were left over
+    /// in other panes. Must be followed by a render of the app, to send its own images again.
+    pub fn refresh_client(&self) -> Result<()> {
+        // `refresh-client -t` takes a client, not a pane, so ask which client shows the pane.
+        let output = Command::new("tmux")
+            .args(self.client_name_args())
+            .stdin(std::process::Stdio::null())
+            .output()?;
+        if !output.status.success() {
+            return Err(Errors::Tmux("display-message failed"));
+        }
+        let client = String::from_utf8_lossy(&output.stdout)
+            .trim_end()
+            .to_string();
+        if client.is_empty() {
+            return Err(Errors::Tmux("no client attached"));
+        }
+        let status = Command::new("tmux")
+            .args(refresh_client_args(&client))
+            .status()?;
+        if !status.success() {
+            return Err(Errors::Tmux("refresh-client failed"));
+        }
+        Ok(())
+    }
+
+    fn client_name_args(&self) -> [&str; 5] {
+        ["display-message", "-p", "-t", &self.id, "#{client_name}"]
+    }
+}
+
+fn refresh_client_args(client: &str) -> [&str; 3] {
+    ["refresh-client", "-t", client]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{refresh_client_args, OuterTerminal, PaneGeometry, TmuxPane};
+    use crate::picker::ProtocolType;
+    use crate::reflow::ReflowTracker;
+
+    #[test]
+    fn test_pane_geometry() {
+        let geometry = PaneGeometry::parse("81 0 80 24\n").unwrap();
+        assert_eq!(
+            geometry,
+            PaneGeometry {
+                left: 81,
+                top: 0,
+                width: 80,
+                height: 24
+            }
+        );
+        assert_eq!(PaneGeometry::parse("81 0 80"), None);
+        assert_eq!(PaneGeometry::parse("81 0 80 24 1"), None);
+        assert_eq!(PaneGeometry::parse("a b c d"), None);
+
+        assert_eq!(geometry.to_window((2, 3)), (83, 3));
+        assert_eq!(geometry.to_pane((83, 3)), Some((2, 3)));
+        assert_eq!(geometry.to_pane((80, 3)), None);
+        assert_eq!(geometry.to_pane((161, 3)), None);
+
+        let tracker = ReflowTracker::new();
+        let mut pane = TmuxPane::new("%3").reflow_tracker(Some(tracker.clone()));
+        assert!(!pane.update(geometry));
+        assert!(!pane.update(geometry));
+        assert_eq!(tracker.generation(), 0);
+        // The neighbouring pane was closed.
+        assert!(pane.update(PaneGeometry {
+            left: 0,
+            ..geometry
+        }));
+        assert_eq!(tracker.generation(), 1);
+        assert_eq!(pane.geometry().unwrap().left, 0);
+    }
+
+    #[test]
+    fn test_refresh_client_args() {
+        let pane = TmuxPane::new("%3");
+        assert_eq!(
+            pane.client_name_args(),
+            ["display-message", "-p", "-t", "%3", "#{client_name}"]
+        );
+        assert_eq!(
+            refresh_client_args("/dev/pts/4"),
+            ["refresh-client", "-t", "/dev/pts/4"]
+        );
+    }
+
+    #[test]
+    fn test_outer_terminal() {
+        let kitty = OuterTerminal::parse("xterm-kitty\tkitty(0.35.2)\t256,RGB,title\n").unwrap();
+        assert_eq!(kitty.name, "xterm-kitty");
+        assert_eq!(kitty.features, ["256", "RGB", "title"]);
+        assert_eq!(kitty.protocol_type(), Some(ProtocolType::Kitty));
+
+        // iTerm2 only tells by its version, as its TERM is generic.
+        let iterm2 = OuterTerminal::parse("xterm-256color\tiTerm2 3.5.0\t256,RGB\n").unwrap();
+        assert_eq!(iterm2.protocol_type(), Some(ProtocolType::Iterm2));
+
+        let xterm = OuterTerminal::parse("xterm-256color\t\t256,sixel\n").unwrap();
+        assert_eq!(xterm.version, "");
+        assert_eq!(xterm.protocol_type(), Some(ProtocolType::Sixel));
+
+        // No more guessing from the environment, e.g. a KITTY_WINDOW_ID from another terminal.
+        let plain = OuterTerminal::parse("xterm-256color\t\t\n").unwrap();
+        assert!(plain.features.is_empty());
+        assert_eq!(plain.protocol_type(), None);
+
+        assert_eq!(OuterTerminal::parse("xterm\t\n"), None);
+        assert_eq!(OuterTerminal::parse("\t\t\n"), None);
+    }
+}
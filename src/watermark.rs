@@ -0,0 +1,272 @@
+//! Attribution or watermark strips, composited onto the bottom of images.
+//!
+//! Apps that show remote or stock images must often keep the attribution visible, but the text
+//! layer under a graphics protocol is not theirs to draw on. A [Watermark] is composited into the
+//! pixels instead, after resizing, so that it stays legible at any size:
+//!
+//! ```rust
+//! # use ratatui_image::{picker::Picker, watermark::Watermark};
+//! let mut picker = Picker::from_fontsize((8, 16));
+//! picker.set_watermark(Some(Watermark::text("(C) Jane Doe / Example Photos")));
+//! // Every protocol that is created afterwards shows the strip.
+//! ```
+//!
+//! Text is drawn with a tiny built-in 3x5 pixel font, which has digits, capital letters (lower
+//! case is shown as upper case), and some punctuation. Any other characters are drawn as `?`. For
+//! anything else, e.g. a logo, supply an image with [Watermark::image].
+
+use std::sync::Arc;
+
+use image::{
+    imageops::{self, FilterType},
+    DynamicImage, Rgba, RgbaImage,
+};
+
+/// A strip that is composited onto the bottom right corner of images, see the
+/// [module docs](self).
+#[derive(Clone, Debug, PartialEq, Hash)]
+pub struct Watermark {
+    content: Content,
+    scale: u32,
+    foreground: Rgba<u8>,
+    background: Rgba<u8>,
+}
+
+#[derive(Clone, Debug, PartialEq, Hash)]
+enum Content {
+    Text(String),
+    Image(Arc<RgbaImage>),
+}
+
+impl Watermark {
+    /// A strip of text, drawn with the built-in font.
+    pub fn text(text: impl Into<String>) -> Watermark {
+        Watermark::new(Content::Text(text.into()))
+    }
+
+    /// A strip from an image, e.g. a rendered attribution or a logo, drawn at its pixel size.
+    pub fn image(image: RgbaImage) -> Watermark {
+        Watermark::new(Content::Image(Arc::new(image)))
+    }
+
+    fn new(content: Content) -> Watermark {
+        Watermark {
+            content,
+            scale: 2,
+            foreground: Rgba([255, 255, 255, 255]),
+            background: Rgba([0, 0, 0, 160]),
+        }
+    }
+
+    /// How many pixels each pixel of the font covers (default 2, for a strip 14 pixels tall).
+    pub fn scale(mut self, scale: u32) -> Watermark {
+        self.scale = scale.max(1);
+        self
+    }
+
+    /// The text color and the color of the strip behind it (default white on translucent black).
+    pub fn colors(mut self, foreground: Rgba<u8>, background: Rgba<u8>) -> Watermark {
+        self.foreground = foreground;
+        self.background = background;
+        self
+    }
+
+    /// The strip, as it is composited.
+    pub fn strip(&self) -> RgbaImage {
+        match &self.content {
+            Content::Text(text) => self.render_text(text),
+            Content::Image(image) => (**image).clone(),
+        }
+    }
+
+    /// Composite the strip onto the bottom right corner of an image, cut off on the left if it is
+    /// wider than the image.
+    pub fn apply(&self, image: &mut DynamicImage) {
+        let (width, height) = (image.width(), image.height());
+        self.apply_within(image, (0, 0, width, height));
+    }
+
+    // Like apply, within the region of the image that `x`, `y`, `width` and `height` describe,
+    // e.g. without the padding of a resized image.
+    pub(crate) fn apply_within(
+        &self,
+        image: &mut DynamicImage,
+        (x, y, width, height): (u32, u32, u32, u32),
+    ) {
+        let strip = self.strip();
+        let (strip_width, strip_height) = strip.dimensions();
+        let visible = imageops::crop_imm(
+            &strip,
+            strip_width.saturating_sub(width),
+            strip_height.saturating_sub(height),
+            strip_width.min(width),
+            strip_height.min(height),
+        )
+        .to_image();
+        let left = x + width - visible.width();
+        let top = y + height - visible.height();
+        imageops::overlay(image, &DynamicImage::from(visible), left as i64, top as i64);
+    }
+
+    fn render_text(&self, text: &str) -> RgbaImage {
+        let glyphs: Vec<[u8; 5]> = text.chars().map(glyph).collect();
+        // One pixel of padding around the text, and between glyphs.
+        let (width, height) = (glyphs.len() as u32 * 4 + 1, GLYPH_HEIGHT + 2);
+        let mut strip = RgbaImage::from_pixel(width, height, self.background);
+        for (i, rows) in glyphs.iter().enumerate() {
+            for (row, bits) in rows.iter().enumerate() {
+                for column in (0..3).filter(|column| bits & (0b100 >> column) != 0) {
+                    strip.put_pixel(1 + i as u32 * 4 + column, 1 + row as u32, self.foreground);
+                }
+            }
+        }
+        let (width, height) = (width * self.scale, height * self.scale);
+        imageops::resize(&strip, width, height, FilterType::Nearest)
+    }
+}
+
+const GLYPH_HEIGHT: u32 = 5;
+
+// The rows of a glyph, three pixels wide, the leftmost pixel in the highest bit.
+fn glyph(c: char) -> [u8; 5] {
+    let c = c.to_ascii_uppercase();
+    GLYPHS
+        .iter()
+        .find(|(glyph, _)| *glyph == c)
+        .map_or(UNKNOWN, |(_, rows)| *rows)
+}
+
+const UNKNOWN: [u8; 5] = [0b111, 0b001, 0b010, 0b000, 0b010];
+
+static GLYPHS: [(char, [u8; 5]); 48] = [
+    (' ', [0b000, 0b000, 0b000, 0b000, 0b000]),
+    ('0', [0b111, 0b101, 0b101, 0b101, 0b111]),
+    ('1', [0b010, 0b110, 0b010, 0b010, 0b111]),
+    ('2', [0b111, 0b001, 0b111, 0b100, 0b111]),
+    ('3', [0b111, 0b001, 0b111, 0b001, 0b111]),
+    ('4', [0b101, 0b101, 0b111, 0b001, 0b001]),
+    ('5', [0b111, 0b100, 0b111, 0b001, 0b111]),
+    ('6', [0b111, 0b100, 0b111, 0b101, 0b111]),
+    ('7', [0b111, 0b001, 0b001, 0b001, 0b001]),
+    ('8', [0b111, 0b101, 0b111, 0b101, 0b111]),
+    ('9', [0b111, 0b101, 0b111, 0b001, 0b111]),
+    ('A', [0b010, 0b101, 0b111, 0b101, 0b101]),
+    ('B', [0b110, 0b101, 0b110, 0b101, 0b110]),
+    ('C', [0b011, 0b100, 0b100, 0b100, 0b011]),
+    ('D', [0b110, 0b101, 0b101, 0b101, 0b110]),
+    ('E', [0b111, 0b100, 0b110, 0b100, 0b111]),
+    ('F', [0b111, 0b100, 0b110, 0b100, 0b100]),
+    ('G', [0b011, 0b100, 0b101, 0b101, 0b011]),
+    ('H', [0b101, 0b101, 0b111, 0b101, 0b101]),
+    ('I', [0b111, 0b010, 0b010, 0b010, 0b111]),
+    ('J', [0b001, 0b001, 0b001, 0b101, 0b010]),
+    ('K', [0b101, 0b101, 0b110, 0b101, 0b101]),
+    ('L', [0b100, 0b100, 0b100, 0b100, 0b111]),
+    ('M', [0b101, 0b111, 0b111, 0b101, 0b101]),
+    ('N', [0b110, 0b101, 0b101, 0b101, 0b101]),
+    ('O', [0b010, 0b101, 0b101, 0b101, 0b010]),
+    ('P', [0b110, 0b101, 0b110, 0b100, 0b100]),
+    ('Q', [0b010, 0b101, 0b101, 0b110, 0b011]),
+    ('R', [0b110, 0b101, 0b110, 0b101, 0b101]),
+    ('S', [0b011, 0b100, 0b010, 0b001, 0b110]),
+    ('T', [0b111, 0b010, 0b010, 0b010, 0b010]),
+    ('U', [0b101, 0b101, 0b101, 0b101, 0b111]),
+    ('V', [0b101, 0b101, 0b101, 0b101, 0b010]),
+    ('W', [0b101, 0b101, 0b111, 0b111, 0b101]),
+    ('X', [0b101, 0b101, 0b010, 0b101, 0b101]),
+    ('Y', [0b101, 0b101, 0b010, 0b010, 0b010]),
+    ('Z', [0b111, 0b001, 0b010, 0b100, 0b111]),
+    ('.', [0b000, 0b000, 0b000, 0b000, 0b010]),
+    (',', [0b000, 0b000, 0b000, 0b010, 0b100]),
+    (':', [0b000, 0b010, 0b000, 0b010, 0b000]),
+    ('-', [0b000, 0b000, 0b111, 0b000, 0b000]),
+    ('/', [0b001, 0b001, 0b010, 0b100, 0b100]),
+    ('(', [0b010, 0b100, 0b100, 0b100, 0b010]),
+    (')', [0b010, 0b001, 0b001, 0b001, 0b010]),
+    ('!', [0b010, 0b010, 0b010, 0b000, 0b010]),
+    ('\'', [0b010, 0b010, 0b000, 0b000, 0b000]),
+    ('&', [0b010, 0b101, 0b010, 0b101, 0b011]),
+    ('©', [0b111, 0b101, 0b101, 0b111, 0b000]),
+];
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, GenericImageView, Rgba, RgbaImage};
+    use ratatui::{buffer::Buffer, layout::Rect};
+
+    use super::Watermark;
+    use crate::{
+        picker::{Picker, ProtocolType},
+        ImageSource, Resize,
+    };
+
+    #[test]
+    fn test_watermark() {
+        let watermark = Watermark::text("a1").scale(1);
+        let strip = watermark.strip();
+        // Two glyphs of 3 pixels, with padding and spacing.
+        assert_eq!(strip.dimensions(), (9, 7));
+        // The top of the A, and the padding.
+        assert_eq!(strip.get_pixel(2, 1), &Rgba([255, 255, 255, 255]));
+        assert_eq!(strip.get_pixel(1, 1), &Rgba([0, 0, 0, 160]));
+        assert_eq!(Watermark::text("a1").strip().dimensions(), (18, 14));
+
+        let red = Rgba([255, 0, 0, 255]);
+        let mut image = DynamicImage::from(RgbaImage::from_pixel(20, 10, red));
+        let white = Rgba([255, 255, 255, 255]);
+        let watermark = watermark.colors(white, white);
+        watermark.apply(&mut image);
+        assert_eq!(image.get_pixel(11, 3), white);
+        assert_eq!(image.get_pixel(10, 3), red);
+        assert_eq!(image.get_pixel(19, 9), white);
+
+        // Cut off on the left.
+        let mut image = DynamicImage::from(RgbaImage::from_pixel(4, 10, red));
+        watermark.apply(&mut image);
+        assert_eq!(image.get_pixel(0, 3), white);
+        assert_eq!(image.get_pixel(0, 2), red);
+
+        // After resizing, on the image but not on the padding.
+        let image = DynamicImage::from(RgbaImage::from_pixel(20, 40, red));
+        let source = ImageSource::new(image, (10, 20), Rgba([0; 4]));
+        let source = source.with_watermark(Some(watermark));
+        let resized =
+            Resize::Fit(None).resize(&source, (10, 20), Rect::new(0, 0, 4, 2), Rgba([0; 4]));
+        assert_eq!(resized.dimensions(), (40, 40));
+        assert_eq!(resized.get_pixel(19, 39), white);
+        assert_eq!(resized.get_pixel(11, 33), white);
+        assert_eq!(resized.get_pixel(10, 33), red);
+        assert_eq!(resized.get_pixel(20, 39), Rgba([0; 4]));
+    }
+
+    #[test]
+    fn test_watermark_hash() {
+        let red = Rgba([255, 0, 0, 255]);
+        let image = DynamicImage::from(RgbaImage::from_pixel(40, 40, red));
+        let plain = ImageSource::new(image.clone(), (10, 20), Rgba([0; 4]));
+        let hash = plain.hash;
+        let marked = plain.with_watermark(Some(Watermark::text("a")));
+        assert_ne!(marked.hash, hash);
+        let other = ImageSource::new(image.clone(), (10, 20), Rgba([0; 4]))
+            .with_watermark(Some(Watermark::text("b")));
+        assert_ne!(other.hash, marked.hash);
+
+        // Shared encodings do not mix up images with and without the watermark.
+        let mut picker = Picker::from_fontsize((10, 20));
+        picker.set_protocol_type(ProtocolType::Halfblocks);
+        picker.set_share_encodings(true);
+        let area = Rect::new(0, 0, 4, 2);
+        // The protocols are kept, so that their encodings stay shared.
+        let render = |picker: &Picker| {
+            let mut protocol = picker.new_resize_protocol(image.clone());
+            protocol.resize_encode(&Resize::Fit(None), Rgba([0; 4]), area);
+            let mut buf = Buffer::empty(area);
+            protocol.render(area, &mut buf);
+            (protocol, buf)
+        };
+        let (_plain, without) = render(&picker);
+        picker.set_watermark(Some(Watermark::text("a").scale(1)));
+        let (_marked, with) = render(&picker);
+        assert_ne!(without, with);
+    }
+}
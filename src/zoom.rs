@@ -0,0 +1,243 @@
+//! Zooming and panning around large images, e.g. for image viewers.
+//!
+//! ```rust
+//! # use ratatui::Frame;
+//! # use ratatui_image::zoom::{ZoomPanImage, ZoomPanState};
+//! fn ui(f: &mut Frame<'_>, state: &mut ZoomPanState) {
+//!     f.render_stateful_widget(ZoomPanImage, f.area(), state);
+//! }
+//! // On a key press:
+//! # fn on_key(state: &mut ZoomPanState) {
+//! state.viewport_mut().pan(10, 0);
+//! # }
+//! ```
+//!
+//! Zoomed out, every encoding resizes a region of the image that is much larger than the screen.
+//! For large images, build an [ImagePyramid] of downscaled copies once, e.g. on a worker thread,
+//! so that the region is always taken from the level that is closest to the zoom:
+//!
+//! ```rust,no_run
+//! # use ratatui_image::{picker::Picker, zoom::{ImagePyramid, ZoomPanState}};
+//! let picker = Picker::from_fontsize((8, 16));
+//! let image = image::open("scan.png").unwrap();
+//! let pyramid = ImagePyramid::new(&picker, image, 512);
+//! let state = ZoomPanState::from_pyramid(pyramid);
+//! ```
+
+use image::{imageops::FilterType, DynamicImage};
+use ratatui::{buffer::Buffer, layout::Rect, widgets::StatefulWidget};
+
+use crate::{
+    picker::Picker, protocol::StatefulProtocol, Resize, ResizeEncodeRender, ViewportOptions,
+};
+
+/// Copies of an image that halve the size at every level, see the [module docs](self).
+pub struct ImagePyramid {
+    // The full image first.
+    levels: Vec<StatefulProtocol>,
+}
+
+impl ImagePyramid {
+    /// Downscale the image until its longer side is at most `min_size` pixels, and create a
+    /// protocol for every level. This is slow for large images.
+    pub fn new(picker: &Picker, image: DynamicImage, min_size: u32) -> ImagePyramid {
+        let mut images = vec![image];
+        while let Some(last) = images.last() {
+            let (width, height) = (last.width(), last.height());
+            if width.max(height) <= min_size.max(1) {
+                break;
+            }
+            // Each level from the previous one, which is fast and good enough at half the size.
+            let level =
+                last.resize_exact(width.div_ceil(2), height.div_ceil(2), FilterType::Triangle);
+            images.push(level);
+        }
+        let levels = images
+            .into_iter()
+            .map(|image| picker.new_resize_protocol(image))
+            .collect();
+        ImagePyramid { levels }
+    }
+
+    /// The number of levels, including the full image.
+    pub fn len(&self) -> usize {
+        self.levels.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.levels.is_empty()
+    }
+
+    /// The smallest level that still has at least as many pixels as shown at `zoom`, so that
+    /// the region is only ever downscaled by less than half.
+    pub fn level_for(&self, zoom: f32) -> usize {
+        (1..self.levels.len())
+            .take_while(|&level| self.scale(level) >= zoom)
+            .last()
+            .unwrap_or(0)
+    }
+
+    // The size of a level relative to the full image.
+    fn scale(&self, level: usize) -> f32 {
+        let full = self.levels[0].source_image().width().max(1);
+        self.levels[level].source_image().width() as f32 / full as f32
+    }
+}
+
+/// State of a [ZoomPanImage]: a protocol and the region of the image that is shown.
+pub struct ZoomPanState {
+    pyramid: ImagePyramid,
+    viewport: ViewportOptions,
+    // The level, viewport and area of the current encoding.
+    encoded: Option<(usize, ViewportOptions, Rect)>,
+}
+
+impl ZoomPanState {
+    pub fn new(protocol: StatefulProtocol) -> ZoomPanState {
+        ZoomPanState::from_pyramid(ImagePyramid {
+            levels: vec![protocol],
+        })
+    }
+
+    /// Show the level of the pyramid that is closest to the zoom, see the [module docs](self).
+    pub fn from_pyramid(pyramid: ImagePyramid) -> ZoomPanState {
+        ZoomPanState {
+            pyramid,
+            viewport: ViewportOptions::default(),
+            encoded: None,
+        }
+    }
+
+    pub fn viewport(&self) -> ViewportOptions {
+        self.viewport
+    }
+
+    /// Change the zoom and offset, the image is encoded again on the next render.
+    pub fn viewport_mut(&mut self) -> &mut ViewportOptions {
+        &mut self.viewport
+    }
+
+    /// The protocol of the level that was last encoded.
+    pub fn protocol(&self) -> &StatefulProtocol {
+        &self.pyramid.levels[self.level()]
+    }
+
+    /// The level of the pyramid that was last encoded, 0 for the full image.
+    pub fn level(&self) -> usize {
+        self.encoded.map_or(0, |(level, _, _)| level)
+    }
+}
+
+/// Widget that renders the region of the image of a [ZoomPanState], see [Resize::Viewport].
+///
+/// Every change of the viewport encodes the image again, which blocks like [crate::StatefulImage].
+pub struct ZoomPanImage;
+
+impl StatefulWidget for ZoomPanImage {
+    type State = ZoomPanState;
+    fn render(self, area: Rect, buf: &mut Buffer, state: &mut Self::State) {
+        if area.width == 0 || area.height == 0 {
+            return;
+        }
+        let level = state.pyramid.level_for(state.viewport.zoom);
+        // The same region in the pixels of the level.
+        let scale = state.pyramid.scale(level);
+        let offset = |offset: u32| (offset as f32 * scale) as u32;
+        let viewport = ViewportOptions {
+            zoom: state.viewport.zoom / scale,
+            offset: (
+                offset(state.viewport.offset.0),
+                offset(state.viewport.offset.1),
+            ),
+            ..state.viewport
+        };
+        let resize = Resize::Viewport(viewport);
+        let protocol = &mut state.pyramid.levels[level];
+        let rect = protocol.needs_resize(&resize, area);
+        if state.encoded != Some((level, state.viewport, area)) || rect.is_some() {
+            // The encoded area does not change when only panning.
+            let rect = rect.unwrap_or_else(|| protocol.area());
+            ResizeEncodeRender::resize_encode(protocol, &resize, rect);
+            state.encoded = Some((level, state.viewport, area));
+        }
+        protocol.render(area, buf);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, ImageBuffer, Rgba};
+    use ratatui::{buffer::Buffer, layout::Rect, style::Color, widgets::StatefulWidget};
+
+    use super::{ImagePyramid, ZoomPanImage, ZoomPanState};
+    use crate::picker::{Picker, ProtocolType};
+
+    #[test]
+    fn test_zoom_pan_image() {
+        let mut picker = Picker::from_fontsize((10, 20));
+        picker.set_protocol_type(ProtocolType::Halfblocks);
+        // Red on the left half and blue on the right half.
+        let image: DynamicImage = ImageBuffer::from_fn(100, 100, |x, _| {
+            if x < 50 {
+                Rgba::<u8>([255, 0, 0, 255])
+            } else {
+                Rgba([0, 0, 255, 255])
+            }
+        })
+        .into();
+        let mut state = ZoomPanState::new(picker.new_resize_protocol(image));
+        let area = Rect::new(0, 0, 4, 2);
+
+        state.viewport_mut().zoom = 4.0;
+        let mut buf = Buffer::empty(area);
+        ZoomPanImage.render(area, &mut buf, &mut state);
+        assert_eq!(buf[(3, 1)].fg, Color::Rgb(255, 0, 0));
+
+        // Panning into the blue half encodes again at the same area.
+        state.viewport_mut().pan(60, 0);
+        let mut buf = Buffer::empty(area);
+        ZoomPanImage.render(area, &mut buf, &mut state);
+        assert_eq!(buf[(0, 0)].fg, Color::Rgb(0, 0, 255));
+        assert_eq!(buf[(3, 1)].fg, Color::Rgb(0, 0, 255));
+        assert_eq!(state.protocol().area(), area);
+    }
+
+    #[test]
+    fn test_image_pyramid() {
+        let mut picker = Picker::from_fontsize((10, 20));
+        picker.set_protocol_type(ProtocolType::Halfblocks);
+        let image: DynamicImage = ImageBuffer::from_fn(100, 100, |x, _| {
+            if x < 50 {
+                Rgba::<u8>([255, 0, 0, 255])
+            } else {
+                Rgba([0, 0, 255, 255])
+            }
+        })
+        .into();
+        // 100, 50, 25 and 13 pixels wide.
+        let pyramid = ImagePyramid::new(&picker, image, 20);
+        assert_eq!(pyramid.len(), 4);
+        assert_eq!(pyramid.level_for(2.0), 0);
+        assert_eq!(pyramid.level_for(0.5), 1);
+        assert_eq!(pyramid.level_for(0.3), 1);
+        assert_eq!(pyramid.level_for(0.1), 3);
+
+        let mut state = ZoomPanState::from_pyramid(pyramid);
+        let area = Rect::new(0, 0, 4, 2);
+        state.viewport_mut().zoom = 0.25;
+        let mut buf = Buffer::empty(area);
+        ZoomPanImage.render(area, &mut buf, &mut state);
+        assert_eq!(state.level(), 2);
+        assert_eq!(state.protocol().source_image().width(), 25);
+        // The downscaled levels blend the colors a bit.
+        let reddish = |color| matches!(color, Color::Rgb(r, _, b) if r > 200 && b < 50);
+        assert!(reddish(buf[(0, 0)].fg), "{:?}", buf[(0, 0)].fg);
+        assert!(!reddish(buf[(2, 0)].fg), "{:?}", buf[(2, 0)].fg);
+
+        // Panned by image pixels of the full image.
+        state.viewport_mut().pan(60, 0);
+        let mut buf = Buffer::empty(area);
+        ZoomPanImage.render(area, &mut buf, &mut state);
+        assert!(!reddish(buf[(0, 0)].fg), "{:?}", buf[(0, 0)].fg);
+    }
+}